@@ -145,14 +145,11 @@ async fn has_bandwidth_with_retry(socks_port: u16, max_retries: u32) -> bool {
 /// Checks if we need to build more circuits based on stream count.
 /// Returns (total_streams, needs_more_circuits)
 pub async fn check_stream_capacity(rpc_config: &crate::types::RpcConfig) -> (usize, bool) {
+    // Both queries go over the shared control session (called every 2s from the payments loop)
+    let session = crate::rpc::control_session(rpc_config);
+
     // Get stream status
-    let stream_status_config = crate::types::RpcConfig {
-        addr: rpc_config.addr.clone(),
-        rpc_password: rpc_config.rpc_password.clone(),
-        command: "GETINFO stream-status".to_string(),
-    };
-    
-    let stream_response = match crate::rpc::rpc_client(stream_status_config).await {
+    let stream_response = match session.command("GETINFO stream-status").await {
         Ok(resp) => resp,
         Err(e) => {
             warn!("Failed to get stream status: {}", e);
@@ -171,13 +168,7 @@ pub async fn check_stream_capacity(rpc_config: &crate::types::RpcConfig) -> (usi
         .count();
     
     // Get circuit count
-    let circuit_status_config = crate::types::RpcConfig {
-        addr: rpc_config.addr.clone(),
-        rpc_password: rpc_config.rpc_password.clone(),
-        command: "GETINFO circuit-status".to_string(),
-    };
-    
-    let circuit_response = match crate::rpc::rpc_client(circuit_status_config).await {
+    let circuit_response = match session.command("GETINFO circuit-status").await {
         Ok(resp) => resp,
         Err(_) => return (active_streams, false),
     };
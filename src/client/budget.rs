@@ -0,0 +1,340 @@
+use crate::client_warn;
+use crate::database::{Db, DbError, Payment};
+use serde::Serialize;
+use std::env;
+use std::sync::Mutex;
+use thiserror::Error;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// What the client does once a budget is used up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetExhaustedAction {
+    /// Stop building paid circuits until the budget frees up (default)
+    Pause,
+    /// Pause paid circuits and hand streams back to Tor's own free circuits meanwhile
+    FreeCircuits,
+}
+
+impl BudgetExhaustedAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim().to_lowercase().as_str() {
+            "pause" => Some(BudgetExhaustedAction::Pause),
+            "free_circuits" => Some(BudgetExhaustedAction::FreeCircuits),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetExhaustedAction::Pause => "pause",
+            BudgetExhaustedAction::FreeCircuits => "free_circuits",
+        }
+    }
+}
+
+/// Spending limits in msats over rolling windows, checked against the payments sent
+/// ledger before every payment. Routing fees already paid count toward the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    pub daily_msats: Option<i64>,
+    pub weekly_msats: Option<i64>,
+    pub monthly_msats: Option<i64>,
+    /// Most a single relay may be paid in a day
+    pub relay_daily_msats: Option<i64>,
+    pub on_exhausted: BudgetExhaustedAction,
+    /// How often a paused client checks whether the budget freed up
+    pub recheck_secs: u64,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            daily_msats: None,
+            weekly_msats: None,
+            monthly_msats: None,
+            relay_daily_msats: None,
+            on_exhausted: BudgetExhaustedAction::Pause,
+            recheck_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("BudgetExceeded: {limit} budget of {limit_msats} msats used up ({spent_msats} msats spent)")]
+pub struct BudgetExceeded {
+    /// daily, weekly, monthly or relay_daily
+    pub limit: &'static str,
+    pub limit_msats: i64,
+    pub spent_msats: i64,
+    /// When the oldest payment counted leaves the window (unix seconds)
+    pub frees_up_at: i64,
+}
+
+/// A payment counted toward the budget
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spend {
+    pub relay_fingerprint: String,
+    pub msats: i64,
+    pub at: i64,
+}
+
+impl Spend {
+    pub fn of(payment: &Payment) -> Self {
+        Spend {
+            relay_fingerprint: payment.relay_fingerprint.clone(),
+            msats: payment.amount_msat + payment.fee.unwrap_or(0),
+            at: payment.updated_at,
+        }
+    }
+}
+
+impl Budget {
+    /// Reads the limits from `BUDGET_*` environment variables, unset limits are unlimited
+    pub fn from_env() -> Self {
+        let default = Budget::default();
+        let msats = |key: &str| env::var(key).ok().and_then(|v| v.trim().parse::<i64>().ok());
+        Budget {
+            daily_msats: msats("BUDGET_DAILY_MSATS"),
+            weekly_msats: msats("BUDGET_WEEKLY_MSATS"),
+            monthly_msats: msats("BUDGET_MONTHLY_MSATS"),
+            relay_daily_msats: msats("BUDGET_RELAY_DAILY_MSATS"),
+            on_exhausted: env::var("BUDGET_EXHAUSTED_ACTION")
+                .ok()
+                .and_then(|action| BudgetExhaustedAction::parse(&action))
+                .unwrap_or(default.on_exhausted),
+            recheck_secs: env::var("BUDGET_RECHECK_SECONDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.recheck_secs),
+        }
+    }
+
+    // (name, window, limit, per relay)
+    fn limits(&self) -> Vec<(&'static str, i64, i64, bool)> {
+        vec![
+            ("daily", DAY_SECS, self.daily_msats, false),
+            ("weekly", 7 * DAY_SECS, self.weekly_msats, false),
+            ("monthly", 30 * DAY_SECS, self.monthly_msats, false),
+            ("relay_daily", DAY_SECS, self.relay_daily_msats, true),
+        ]
+        .into_iter()
+        .filter_map(|(name, window, limit, per_relay)| limit.map(|limit| (name, window, limit, per_relay)))
+        .collect()
+    }
+
+    /// Longest window a limit looks back, 0 without limits
+    pub fn lookback_secs(&self) -> i64 {
+        self.limits().iter().map(|(_, window, _, _)| *window).max().unwrap_or(0)
+    }
+
+    /// Checks that `amount_msats` more to `relay_fingerprint` fits every limit. With no
+    /// relay the per relay cap is skipped, an amount of 0 checks no limit is used up yet.
+    pub fn check(
+        &self,
+        spends: &[Spend],
+        relay_fingerprint: Option<&str>,
+        amount_msats: i64,
+        now: i64,
+    ) -> Result<(), BudgetExceeded> {
+        for (limit, window, limit_msats, per_relay) in self.limits() {
+            if per_relay && relay_fingerprint.is_none() {
+                continue;
+            }
+            let counted: Vec<&Spend> = spends
+                .iter()
+                .filter(|s| s.at > now - window)
+                .filter(|s| !per_relay || Some(s.relay_fingerprint.as_str()) == relay_fingerprint)
+                .collect();
+            let spent_msats: i64 = counted.iter().map(|s| s.msats).sum();
+            if spent_msats >= limit_msats || spent_msats + amount_msats > limit_msats {
+                let oldest = counted.iter().map(|s| s.at).min().unwrap_or(now);
+                return Err(BudgetExceeded {
+                    limit,
+                    limit_msats,
+                    spent_msats,
+                    frees_up_at: oldest + window,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The limit that is used up according to the payments sent ledger, if any
+    pub fn exhausted(&self, db: &Db, now: i64) -> Result<Option<BudgetExceeded>, DbError> {
+        let spends = ledger_spends(db, self.lookback_secs(), now)?;
+        Ok(self.check(&spends, None, 0, now).err())
+    }
+}
+
+fn ledger_spends(db: &Db, lookback_secs: i64, now: i64) -> Result<Vec<Spend>, DbError> {
+    if lookback_secs == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(db
+        .payments_paid_since(now - lookback_secs)?
+        .iter()
+        .map(Spend::of)
+        .collect())
+}
+
+/// Checks payments against the budget, counting the ones still in flight so concurrent
+/// payments can't overshoot it together
+pub struct BudgetGuard {
+    budget: Budget,
+    in_flight: Mutex<Vec<(u64, Spend)>>,
+    next_id: Mutex<u64>,
+}
+
+/// An in flight payment counted by its `BudgetGuard` until dropped
+pub struct BudgetReservation<'a> {
+    guard: &'a BudgetGuard,
+    id: u64,
+}
+
+impl Drop for BudgetReservation<'_> {
+    fn drop(&mut self) {
+        self.guard.in_flight.lock().unwrap().retain(|(id, _)| *id != self.id);
+    }
+}
+
+impl BudgetGuard {
+    pub fn new(budget: Budget) -> Self {
+        BudgetGuard {
+            budget,
+            in_flight: Mutex::new(Vec::new()),
+            next_id: Mutex::new(0),
+        }
+    }
+
+    /// Reserves `amount_msats` to `relay_fingerprint` if the budget allows it. Keep the
+    /// reservation until the payment is written to the ledger.
+    pub fn reserve(
+        &self,
+        db: &Db,
+        relay_fingerprint: &str,
+        amount_msats: i64,
+    ) -> Result<BudgetReservation<'_>, Box<dyn std::error::Error + Send + Sync>> {
+        let now = chrono::Utc::now().timestamp();
+        let mut spends = ledger_spends(db, self.budget.lookback_secs(), now)?;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        spends.extend(in_flight.iter().map(|(_, spend)| spend.clone()));
+        self.budget.check(&spends, Some(relay_fingerprint), amount_msats, now)?;
+
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        in_flight.push((
+            *next_id,
+            Spend {
+                relay_fingerprint: relay_fingerprint.to_string(),
+                msats: amount_msats,
+                at: now,
+            },
+        ));
+        Ok(BudgetReservation {
+            guard: self,
+            id: *next_id,
+        })
+    }
+}
+
+/// Structured client events, logged as one JSON line prefixed with `EVENT`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BudgetEvent {
+    BudgetExhausted {
+        limit: String,
+        limit_msats: i64,
+        spent_msats: i64,
+        frees_up_at: i64,
+        action: String,
+    },
+    BudgetAvailable,
+}
+
+impl BudgetEvent {
+    pub fn exhausted(exceeded: &BudgetExceeded, action: BudgetExhaustedAction) -> Self {
+        BudgetEvent::BudgetExhausted {
+            limit: exceeded.limit.to_string(),
+            limit_msats: exceeded.limit_msats,
+            spent_msats: exceeded.spent_msats,
+            frees_up_at: exceeded.frees_up_at,
+            action: action.as_str().to_string(),
+        }
+    }
+
+    pub fn emit(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => client_warn!("EVENT {}", json),
+            Err(e) => client_warn!("Failed to serialize event {:?}: {}", self, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spend(relay: &str, msats: i64, at: i64) -> Spend {
+        Spend {
+            relay_fingerprint: relay.to_string(),
+            msats,
+            at,
+        }
+    }
+
+    #[test]
+    fn test_budget_windows_and_relay_cap() {
+        let now = 100 * DAY_SECS;
+        let budget = Budget {
+            daily_msats: Some(5000),
+            monthly_msats: Some(8500),
+            relay_daily_msats: Some(3000),
+            ..Budget::default()
+        };
+        let spends = vec![
+            spend("A", 2000, now - 60),
+            spend("B", 1000, now - 120),
+            // Outside the daily window, inside the monthly one
+            spend("A", 4000, now - 2 * DAY_SECS),
+        ];
+        assert!(budget.check(&spends, Some("B"), 1000, now).is_ok());
+
+        let relay_cap = budget.check(&spends, Some("A"), 1500, now).unwrap_err();
+        assert_eq!(relay_cap.limit, "relay_daily");
+        assert_eq!(relay_cap.spent_msats, 2000);
+        assert_eq!(relay_cap.frees_up_at, now - 60 + DAY_SECS);
+
+        let daily = budget.check(&spends, Some("C"), 2500, now).unwrap_err();
+        assert_eq!(daily.limit, "daily");
+
+        // 7000 of the monthly 8500 spent
+        let monthly = budget.check(&spends, Some("C"), 1600, now).unwrap_err();
+        assert_eq!(monthly.limit, "monthly");
+        assert!(budget.check(&spends, None, 0, now).is_ok());
+        assert!(Budget::default().check(&spends, Some("A"), i64::MAX / 2, now).is_ok());
+    }
+
+    #[test]
+    fn test_guard_counts_payments_in_flight() {
+        let db = Db::with_store(std::sync::Arc::new(
+            crate::database::SqliteStore::open_in_memory().unwrap(),
+        ));
+        let mut paid = crate::database::test_payment("1", "5", 1);
+        paid.paid = true;
+        paid.amount_msat = 1000;
+        paid.fee = Some(10);
+        paid.updated_at = chrono::Utc::now().timestamp();
+        db.write_payment(paid).unwrap();
+
+        let guard = BudgetGuard::new(Budget {
+            daily_msats: Some(3010),
+            ..Budget::default()
+        });
+        let first = guard.reserve(&db, "A", 1000).unwrap();
+        let _second = guard.reserve(&db, "B", 1000).unwrap();
+        assert!(guard.reserve(&db, "C", 1).is_err());
+        drop(first);
+        assert!(guard.reserve(&db, "C", 1000).is_ok());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::rpc;
-use crate::types::{Relay, RpcConfig};
+use crate::types::{Relay, RoundSchedule, RpcConfig};
 use crate::utils::get_random_payhash_and_preimage;
 use log::{debug, info};
 
@@ -8,19 +8,22 @@ struct ExtendPaidCircuitRow {
     handshake_fee_payment_hash: String,
     handshake_fee_preimage: String,
     payment_ids_concatinated_10: String,
+    built_at: String,
 }
 
-// 0. loop each relay and check if handshake fee is required, is so then pay the handshake fee and record the payment hash and preimage
-// TODO skip for now since we are using a simple algo that does not require a handshake fee
-// 1. generate a dummy payment hash and preimage for the handshake fee to pad the data for privacy
+// 0. loop each relay and check if handshake fee is required, is so then pay the handshake fee and record the payment hash and preimage (see handshake_fee.rs)
+// 1. generate a dummy payment hash and preimage for relays without a handshake fee to pad the data for privacy
 // 2. generate N (10 default) payment ids hashes one for each round in the interval. These will be passed to the relay to verify the payment on their lightning node
 //  if bolt12 is being used the payment id is passed in the bolt12 offer as a payer note
 //  if bolt11 is being used then the payment id can be the pregenerated payment hash of a bolt11 invoice (make sure expiration of the invoice is bigger than the interval time)
-// 3. EXTENDPAIDCIRCUIT with the relays fingerprint and payment id hashes
+// 3. EXTENDPAIDCIRCUIT with the relays fingerprint and payment id hashes, followed by the
+//  circuit build timestamp `built_at` both sides count payment rounds from (see RoundSchedule)
+//  for relays that advertise PaymentProtocolVersion 2 or later
 // 4. return the circuit id so the client can watch it.
 pub async fn build_circuit(
     rpc_config: &RpcConfig,
     relays: &Vec<Relay>,
+    built_at: i64,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let mut extend_paid_circuit_rows = Vec::new();
 
@@ -40,6 +43,12 @@ pub async fn build_circuit(
                 .clone()
                 .unwrap_or_default()
                 .join(""),
+            // Older relays would take the timestamp for an 11th payment id
+            built_at: if relay.takes_build_timestamp() {
+                RoundSchedule::built_at_to_wire(built_at)
+            } else {
+                String::new()
+            },
         };
         extend_paid_circuit_rows.push(row);
     }
@@ -47,18 +56,17 @@ pub async fn build_circuit(
     let mut command = String::from("+EXTENDPAIDCIRCUIT 0\n");
     for row in extend_paid_circuit_rows {
         command.push_str(&format!(
-            "{} {}{}{}\n",
+            "{} {}{}{}{}\n",
             row.relay_fingerprint,
             row.handshake_fee_payment_hash,
             row.handshake_fee_preimage,
-            row.payment_ids_concatinated_10
+            row.payment_ids_concatinated_10,
+            row.built_at
         ));
     }
     command.push_str(".");
     info!("EXTENDPAIDCIRCUIT Command: {}", command);
-    let circuit_id = rpc::extend_paid_circuit(&rpc_config, command)
-        .await
-        .unwrap();
+    let circuit_id = rpc::extend_paid_circuit(&rpc_config, command).await?;
     let event_data = serde_json::json!({
         "event": "CIRCUIT_BUILT",
         "circuit_id": circuit_id,
@@ -77,12 +85,14 @@ pub fn pregen_extend_paid_circuit_hashes(
     payment_rounds: u16,
 ) -> &Vec<Relay> {
     for relay in selected_relays.iter_mut() {
-        // Generate payhash and preimage for handshake fee
-        let (handshake_payhash, handshake_preimage) = get_random_payhash_and_preimage();
-        info!("Handshake Payment Hash: {}\n", handshake_payhash);
-        info!("Handshake Payment Preimage: {}\n", handshake_preimage);
-        relay.payment_handshake_fee_payhash = Some(handshake_payhash);
-        relay.payment_handshake_fee_preimage = Some(handshake_preimage);
+        // Pad the handshake fields with a random payhash and preimage unless a handshake fee was paid
+        if relay.payment_handshake_fee_payhash.is_none() {
+            let (handshake_payhash, handshake_preimage) = get_random_payhash_and_preimage();
+            relay.payment_handshake_fee_payhash = Some(handshake_payhash);
+            relay.payment_handshake_fee_preimage = Some(handshake_preimage);
+        }
+        info!("Handshake Payment Hash: {:?}\n", relay.payment_handshake_fee_payhash);
+        info!("Handshake Payment Preimage: {:?}\n", relay.payment_handshake_fee_preimage);
 
         // Generate 10 payment id hashes for each round of payment in the circuit lifetime
         let mut payment_id_hashes_10 = Vec::new();
@@ -93,3 +103,96 @@ pub fn pregen_extend_paid_circuit_hashes(
     }
     selected_relays
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::payment_retry::RetryPolicy;
+    use crate::client::select_relay_algo::simple_relay_selection_algo;
+    use crate::lightning::payment_method::PaymentCapabilities;
+    use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+
+    #[tokio::test]
+    async fn test_select_build_and_wait_for_paid_circuit() {
+        let mock = MockControlPort::start().await;
+        let mut guard = MockRelay::new("guard", 1);
+        guard.extra_descriptor_lines.push("PaymentProtocolVersion 2".to_string());
+        mock.add_relay(guard);
+        mock.add_relay(MockRelay::new("middle", 2));
+        mock.add_relay(MockRelay::new("exit", 3));
+        mock.set_build_after_polls(2);
+        let rpc_config = mock.rpc_config();
+
+        let capabilities = PaymentCapabilities::for_node_type("phoenixd");
+        let mut relays = simple_relay_selection_algo(&rpc_config, &capabilities, &RetryPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(relays.len(), 3);
+        pregen_extend_paid_circuit_hashes(&mut relays, 10);
+
+        let built_at = chrono::Utc::now().timestamp();
+        let circuit_id = build_circuit(&rpc_config, &relays, built_at).await.unwrap();
+        rpc::wait_for_circuit_ready(&rpc_config, &circuit_id, 10)
+            .await
+            .unwrap();
+
+        let circuit = mock
+            .circuits()
+            .into_iter()
+            .find(|c| c.id == circuit_id)
+            .unwrap();
+        assert_eq!(circuit.state, "BUILT");
+        let fingerprints: Vec<String> = relays.iter().map(|r| r.fingerprint.clone()).collect();
+        assert_eq!(circuit.path, fingerprints);
+        // handshake payhash + preimage + 10 payment id hashes per hop, plus the build
+        // timestamp for the hop that advertises PaymentProtocolVersion 2
+        for (relay, payment_hashes) in relays.iter().zip(&circuit.payment_hashes) {
+            let relay_payments = crate::relay::RelayPayments::from_wire_format(payment_hashes);
+            assert_eq!(relay_payments.payhashes.len(), 10);
+            if relay.nickname == "guard" {
+                assert_eq!(payment_hashes.len(), 13 * 64);
+                assert_eq!(relay_payments.built_at, Some(built_at));
+            } else {
+                assert_eq!(payment_hashes.len(), 12 * 64);
+                assert_eq!(relay_payments.built_at, None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_circuit_through_unknown_relay_fails() {
+        let mock = MockControlPort::start().await;
+        mock.add_relay(MockRelay::new("known", 1));
+        let mut relay = MockRelay::new("unknown", 9);
+        relay.payment_rate_msats = None;
+
+        let descriptor_only = Relay {
+            nickname: relay.nickname,
+            fingerprint: relay.fingerprint,
+            contact: None,
+            bandwidth: None,
+            payment_bolt12_offer: None,
+            ip: None,
+            port: None,
+            payment_bip353: None,
+            payment_bolt11_lnurl: None,
+            payment_bolt11_lightning_address: None,
+            payment_rate_msats: None,
+            payment_interval_seconds: None,
+            payment_interval_rounds: None,
+            payment_handshake_fee: None,
+            payment_prepaid: false,
+            payment_protocol_version: 1,
+            payment_id_hashes_10: None,
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            payment_method: None,
+            relay_tag: None,
+            hop: None,
+        };
+        assert!(build_circuit(&mock.rpc_config(), &vec![descriptor_only], 0)
+            .await
+            .is_err());
+        assert!(mock.circuits().is_empty());
+    }
+}
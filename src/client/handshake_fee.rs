@@ -0,0 +1,340 @@
+use super::budget::BudgetGuard;
+use super::payment_retry::RetryPolicy;
+use crate::database::{Db, Payment};
+use crate::lightning::payment_method::PaymentCapabilities;
+use crate::lightning::{bip353, lnurl};
+use crate::types::{PaymentMethod, Relay};
+use crate::utils::preimage_matches_payhash;
+use lni::types::PayInvoiceResponse;
+use lni::{LightningNode, PayInvoiceParams};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    // Relay fingerprint => (payment hash, preimage) of a handshake fee paid for a circuit
+    // that was never built. No relay saw it, so the next circuit through the relay uses it.
+    static ref UNUSED_HANDSHAKES: Mutex<HashMap<String, (String, String)>> = Mutex::new(HashMap::new());
+}
+
+/// Pays the `PaymentHandshakeFee` of every relay that advertises one and stores the
+/// payment hash and preimage on the relay so they are sent with EXTENDPAIDCIRCUIT.
+/// Relays without a handshake fee are left alone (their handshake fields get random padding).
+/// A handshake paid earlier for a circuit that was never built is reused instead of paying
+/// again, every new payment is written to the payments sent ledger `db`. Each fee plus its
+/// routing fee limit is reserved against `budget` first, a fee over budget isn't paid.
+///
+/// With a hard routing fee cap (`PAYMENT_MAX_ROUTING_FEE_*`) the fee is paid over the
+/// relay's BOLT 11 endpoint, which takes a fee limit, and refused if it or the wallet has none.
+///
+/// Returns the total handshake fees paid in msats. On error the handshakes paid so far are
+/// stored on their relays, hand them back with `release_unused_handshakes`.
+pub async fn pay_handshake_fees(
+    wallet: &(dyn LightningNode + Send + Sync),
+    db: &Db,
+    budget: &BudgetGuard,
+    relays: &mut [Relay],
+    retry_policy: &RetryPolicy,
+    capabilities: &PaymentCapabilities,
+    socks_port: Option<u16>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut total_paid_msats = 0u64;
+    for relay in relays.iter_mut() {
+        let fee_msats = match relay.payment_handshake_fee {
+            Some(fee) if fee > 0 => fee,
+            _ => continue,
+        };
+        let unused = UNUSED_HANDSHAKES.lock().unwrap().remove(&relay.fingerprint);
+        if let Some((payhash, preimage)) = unused {
+            info!("🤝 Reusing the unused handshake fee paid to relay {}", relay.nickname);
+            relay.payment_handshake_fee_payhash = Some(payhash);
+            relay.payment_handshake_fee_preimage = Some(preimage);
+            continue;
+        }
+        let fee_cap_msats = retry_policy.max_routing_fee_msats(fee_msats as i64);
+        // Count the worst case routing fee until the handshake is in the ledger
+        let fee_limit_msats = retry_policy.fee_limit_msats(fee_msats as i64, 1);
+        let _reservation = budget
+            .reserve(db, &relay.fingerprint, fee_msats as i64 + fee_limit_msats)
+            .map_err(|e| format!("Not paying the handshake fee to relay {}: {}", relay.nickname, e))?;
+        let (method, pay_resp) = match fee_cap_msats {
+            Some(cap) if !capabilities.bolt11 => {
+                return Err(format!(
+                    "The wallet only pays BOLT 12 offers, which can't hold the {} msats handshake fee to relay {} to the {} msats routing fee cap",
+                    fee_msats, relay.nickname, cap
+                )
+                .into())
+            }
+            Some(cap) => pay_capped_handshake_fee(wallet, relay, fee_msats, cap, socks_port).await?,
+            None => pay_handshake_fee_offer(wallet, relay, fee_msats).await?,
+        };
+        if !preimage_matches_payhash(&pay_resp.payment_hash, &pay_resp.preimage) {
+            return Err(format!(
+                "Handshake fee payment to relay {} returned a preimage that does not match its payment hash",
+                relay.nickname
+            )
+            .into());
+        }
+
+        // The fee is spent whatever happens to the circuit, so it goes on the ledger now
+        let row = handshake_ledger_row(relay, fee_msats, method, &pay_resp, chrono::Utc::now().timestamp());
+        if let Err(e) = db.write_payment(row) {
+            warn!("Failed to write the handshake fee paid to relay {} to the ledger: {}", relay.nickname, e);
+        }
+
+        relay.payment_handshake_fee_payhash = Some(pay_resp.payment_hash);
+        relay.payment_handshake_fee_preimage = Some(pay_resp.preimage);
+        total_paid_msats += fee_msats as u64 + pay_resp.fee_msats.max(0) as u64;
+    }
+    Ok(total_paid_msats)
+}
+
+// Pays the handshake fee to the relay's BOLT 12 offer, the wallet's own routing fee limit applies
+async fn pay_handshake_fee_offer(
+    wallet: &(dyn LightningNode + Send + Sync),
+    relay: &Relay,
+    fee_msats: u32,
+) -> Result<(PaymentMethod, PayInvoiceResponse), Box<dyn std::error::Error + Send + Sync>> {
+    let offer = bip353::relay_bolt12_offer(relay).await?.ok_or_else(|| {
+        format!(
+            "Relay {} requires a {} msats handshake fee but has no PaymentBolt12Offer or PaymentBip353",
+            relay.nickname, fee_msats
+        )
+    })?;
+    let method = if relay.payment_bolt12_offer.is_some() {
+        PaymentMethod::Bolt12Offer
+    } else {
+        PaymentMethod::Bip353
+    };
+
+    info!("🤝 Paying {} msats handshake fee to relay {}", fee_msats, relay.nickname);
+    let pay_resp = wallet.pay_offer(offer, fee_msats as i64, None).await.map_err(|e| {
+        warn!("Handshake fee payment to relay {} failed: {:?}", relay.nickname, e);
+        format!("Handshake fee payment to relay {} failed", relay.nickname)
+    })?;
+    Ok((method, pay_resp))
+}
+
+// Pays the handshake fee over BOLT 11 with `fee_cap_msats` as the fee limit. pay_offer takes
+// no fee limit, so relays without a BOLT 11 endpoint aren't paid.
+async fn pay_capped_handshake_fee(
+    wallet: &(dyn LightningNode + Send + Sync),
+    relay: &Relay,
+    fee_msats: u32,
+    fee_cap_msats: i64,
+    socks_port: Option<u16>,
+) -> Result<(PaymentMethod, PayInvoiceResponse), Box<dyn std::error::Error + Send + Sync>> {
+    let (method, pay_url) = match (lnurl::relay_bolt11_method(relay), lnurl::relay_lnurl_pay_url(relay)) {
+        (Some(method), Some(pay_url)) => (method, pay_url?),
+        _ => {
+            return Err(format!(
+                "Relay {} only takes its {} msats handshake fee over BOLT 12, which can't be held to the {} msats routing fee cap",
+                relay.nickname, fee_msats, fee_cap_msats
+            )
+            .into())
+        }
+    };
+    let client = lnurl::lnurl_http_client(socks_port)?;
+    let invoice = lnurl::fetch_bolt11_invoice(&client, &pay_url, fee_msats as i64, "handshake").await?;
+
+    info!(
+        "🤝 Paying {} msats handshake fee to relay {} over {} with a {} msats routing fee cap",
+        fee_msats, relay.nickname, method.as_str(), fee_cap_msats
+    );
+    let params = PayInvoiceParams {
+        invoice,
+        fee_limit_msat: Some(fee_cap_msats),
+        ..Default::default()
+    };
+    let pay_resp = wallet.pay_invoice(params).await.map_err(|e| {
+        warn!("Handshake fee payment to relay {} failed: {:?}", relay.nickname, e);
+        format!("Handshake fee payment to relay {} failed", relay.nickname)
+    })?;
+    Ok((method, pay_resp))
+}
+
+/// Keeps the handshakes paid to `relays` for the next circuit through them. Only call this
+/// when no relay saw them, i.e. EXTENDPAIDCIRCUIT was never sent or Tor refused it.
+pub fn release_unused_handshakes(relays: &[Relay]) {
+    let mut unused = UNUSED_HANDSHAKES.lock().unwrap();
+    for relay in relays.iter().filter(|r| r.payment_handshake_fee.unwrap_or(0) > 0) {
+        if let (Some(payhash), Some(preimage)) = (
+            relay.payment_handshake_fee_payhash.clone(),
+            relay.payment_handshake_fee_preimage.clone(),
+        ) {
+            unused.insert(relay.fingerprint.clone(), (payhash, preimage));
+        }
+    }
+}
+
+/// The paid sent ledger row of a handshake fee. It has round 0 and no circuit until the
+/// circuit it opens is built.
+fn handshake_ledger_row(
+    relay: &Relay,
+    fee_msats: u32,
+    method: PaymentMethod,
+    pay_resp: &PayInvoiceResponse,
+    now: i64,
+) -> Payment {
+    Payment {
+        payment_id: pay_resp.payment_hash.clone(),
+        circ_id: String::new(),
+        interval_seconds: relay.payment_cadence().interval_seconds as i64,
+        round: 0,
+        relay_fingerprint: relay.fingerprint.clone(),
+        updated_at: now,
+        amount_msat: fee_msats as i64,
+        handshake_fee_payhash: Some(pay_resp.payment_hash.clone()),
+        handshake_fee_preimage: Some(pay_resp.preimage.clone()),
+        paid: true,
+        expires_at: now,
+        bolt11_invoice: None,
+        bolt12_offer: relay.payment_bolt12_offer.clone().filter(|_| method.is_bolt12()),
+        payment_hash: Some(pay_resp.payment_hash.clone()),
+        preimage: Some(pay_resp.preimage.clone()),
+        fee: Some(pay_resp.fee_msats.max(0)),
+        has_error: false,
+        payment_method: Some(method.as_str().to_string()),
+        attempts: 1,
+        last_attempt_at: Some(now),
+        last_error: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::budget::Budget;
+    use crate::lightning::mock_wallet::MockLightningNode;
+
+    fn ledger_with_budget(budget: Budget) -> (Db, BudgetGuard) {
+        let db = Db::with_store(std::sync::Arc::new(
+            crate::database::SqliteStore::open_in_memory().unwrap(),
+        ));
+        (db, BudgetGuard::new(budget))
+    }
+
+    fn phoenixd() -> PaymentCapabilities {
+        PaymentCapabilities::for_node_type("phoenixd")
+    }
+
+    fn handshake_relay(fingerprint: &str) -> Relay {
+        Relay {
+            nickname: "hop".to_string(),
+            fingerprint: fingerprint.to_string(),
+            contact: None,
+            bandwidth: None,
+            ip: None,
+            port: None,
+            payment_bolt12_offer: Some("lno1handshake".to_string()),
+            payment_bip353: None,
+            payment_bolt11_lnurl: None,
+            payment_bolt11_lightning_address: None,
+            payment_rate_msats: None,
+            payment_interval_seconds: None,
+            payment_interval_rounds: None,
+            payment_handshake_fee: Some(5000),
+            payment_prepaid: false,
+            payment_protocol_version: 1,
+            payment_handshake_fee_payhash: Some(format!("{}_hash", fingerprint)),
+            payment_handshake_fee_preimage: Some(format!("{}_preimage", fingerprint)),
+            payment_id_hashes_10: None,
+            payment_method: None,
+            relay_tag: None,
+            hop: None,
+        }
+    }
+
+    #[test]
+    fn test_handshake_ledger_row_records_amount_and_fee() {
+        let pay_resp = PayInvoiceResponse {
+            payment_hash: "hs_hash".to_string(),
+            preimage: "hs_preimage".to_string(),
+            fee_msats: 12,
+        };
+        let row = handshake_ledger_row(&handshake_relay("HS1"), 5000, PaymentMethod::Bolt12Offer, &pay_resp, 1700000000);
+        assert_eq!(row.payment_id, "hs_hash");
+        assert_eq!((row.round, row.amount_msat, row.fee), (0, 5000, Some(12)));
+        assert!(row.paid);
+        assert_eq!(row.relay_fingerprint, "HS1");
+        assert_eq!(row.payment_method.as_deref(), Some("bolt12_offer"));
+    }
+
+    #[tokio::test]
+    async fn test_unused_handshake_is_reused_without_paying() {
+        release_unused_handshakes(&[handshake_relay("HS2")]);
+        let mut relays = vec![Relay {
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            ..handshake_relay("HS2")
+        }];
+        // The wallet fails its one payment, so only a reused handshake gets through
+        let wallet = MockLightningNode::failing(1);
+        let (db, budget) = ledger_with_budget(Budget::default());
+        let retry_policy = RetryPolicy::default();
+        assert_eq!(pay_handshake_fees(&wallet, &db, &budget, &mut relays, &retry_policy, &phoenixd(), None).await.unwrap(), 0);
+        assert_eq!(relays[0].payment_handshake_fee_payhash.as_deref(), Some("HS2_hash"));
+        // Each unused handshake is reused once
+        relays[0].payment_handshake_fee_payhash = None;
+        assert!(pay_handshake_fees(&wallet, &db, &budget, &mut relays, &retry_policy, &phoenixd(), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_capped_handshake_fee_is_not_paid_to_an_offer() {
+        let mut relays = vec![Relay {
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            ..handshake_relay("HS3")
+        }];
+        let retry_policy = RetryPolicy {
+            max_routing_fee_msats: Some(10),
+            ..Default::default()
+        };
+        let wallet = MockLightningNode::default();
+        let (db, budget) = ledger_with_budget(Budget::default());
+        let err = pay_handshake_fees(&wallet, &db, &budget, &mut relays, &retry_policy, &phoenixd(), None).await.unwrap_err();
+        assert!(err.to_string().contains("routing fee cap"));
+        assert!(wallet.paid.lock().unwrap().is_empty());
+        assert!(relays[0].payment_handshake_fee_payhash.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_capped_handshake_fee_needs_a_bolt11_wallet() {
+        let mut relays = vec![Relay {
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            payment_bolt11_lightning_address: Some("hs4@relay.example".to_string()),
+            ..handshake_relay("HS4")
+        }];
+        let retry_policy = RetryPolicy {
+            max_routing_fee_msats: Some(10),
+            ..Default::default()
+        };
+        let bolt12_only = phoenixd().with_overrides(None, Some(false));
+        let wallet = MockLightningNode::default();
+        let (db, budget) = ledger_with_budget(Budget::default());
+        let err = pay_handshake_fees(&wallet, &db, &budget, &mut relays, &retry_policy, &bolt12_only, None).await.unwrap_err();
+        assert!(err.to_string().contains("wallet only pays BOLT 12"));
+        assert!(wallet.paid.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_fee_over_relay_budget_is_not_paid() {
+        let mut relays = vec![Relay {
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            ..handshake_relay("HS5")
+        }];
+        let (db, budget) = ledger_with_budget(Budget {
+            relay_daily_msats: Some(4000),
+            ..Budget::default()
+        });
+        let wallet = MockLightningNode::default();
+        let err = pay_handshake_fees(&wallet, &db, &budget, &mut relays, &RetryPolicy::default(), &phoenixd(), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("relay_daily"));
+        assert!(wallet.paid.lock().unwrap().is_empty());
+        assert!(relays[0].payment_handshake_fee_payhash.is_none());
+    }
+}
@@ -4,6 +4,10 @@ mod circuit;
 mod payments_sent_ledger;
 mod payments_loop;
 mod bandwidth_test;
+mod handshake_fee;
+mod payment_retry;
+mod budget;
+mod routing_fees;
 
 pub use start_client_flow::*;
 pub use payments_loop::*;
@@ -0,0 +1,181 @@
+use std::env;
+
+/// What the payments loop does once every retry for a round has failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalFailureAction {
+    /// Stop paying and let the client build new circuits (default)
+    Rebuild,
+    /// Stop paying this hop for the rest of the circuit and keep paying the others.
+    /// The relay will tear the circuit down, streams move to the other circuit.
+    DropHop,
+    /// Ignore `max_attempts` and keep retrying until the round's window closes,
+    /// then move on to the next round
+    KeepTrying,
+}
+
+impl FinalFailureAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim().to_lowercase().as_str() {
+            "rebuild" => Some(FinalFailureAction::Rebuild),
+            "drop_hop" => Some(FinalFailureAction::DropHop),
+            "keep_trying" => Some(FinalFailureAction::KeepTrying),
+            _ => None,
+        }
+    }
+}
+
+/// How a failed relay payment is retried within its round
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per round, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /// Routing fee limit of the first attempt, in percent of the payment amount
+    pub fee_limit_percent: f64,
+    /// Factor the fee limit is multiplied by on every retry
+    pub fee_limit_escalation: f64,
+    /// Upper bound of the escalated fee limit, in percent of the payment amount
+    pub max_fee_limit_percent: f64,
+    /// Fee limits never go below this, small payments need at least a base fee to route
+    pub min_fee_limit_msats: i64,
+    /// Hard cap on the routing fee of a payment in msats, wins over `min_fee_limit_msats`
+    pub max_routing_fee_msats: Option<i64>,
+    /// Hard cap on the routing fee of a payment in percent of the payment amount
+    pub max_routing_fee_percent: Option<f64>,
+    pub on_final_failure: FinalFailureAction,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_secs: 2,
+            max_backoff_secs: 16,
+            fee_limit_percent: 1.0,
+            fee_limit_escalation: 2.0,
+            max_fee_limit_percent: 5.0,
+            min_fee_limit_msats: 1000,
+            max_routing_fee_msats: None,
+            max_routing_fee_percent: None,
+            on_final_failure: FinalFailureAction::Rebuild,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads the policy from `PAYMENT_RETRY_*` environment variables, falling back
+    /// to the defaults for anything unset or invalid
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: env_or("PAYMENT_RETRY_MAX_ATTEMPTS", default.max_attempts).max(1),
+            initial_backoff_secs: env_or("PAYMENT_RETRY_INITIAL_BACKOFF_SECONDS", default.initial_backoff_secs),
+            max_backoff_secs: env_or("PAYMENT_RETRY_MAX_BACKOFF_SECONDS", default.max_backoff_secs),
+            fee_limit_percent: env_or("PAYMENT_RETRY_FEE_LIMIT_PERCENT", default.fee_limit_percent),
+            fee_limit_escalation: env_or("PAYMENT_RETRY_FEE_ESCALATION", default.fee_limit_escalation).max(1.0),
+            max_fee_limit_percent: env_or("PAYMENT_RETRY_MAX_FEE_LIMIT_PERCENT", default.max_fee_limit_percent),
+            min_fee_limit_msats: env_or("PAYMENT_RETRY_MIN_FEE_LIMIT_MSATS", default.min_fee_limit_msats),
+            max_routing_fee_msats: env_opt("PAYMENT_MAX_ROUTING_FEE_MSATS"),
+            max_routing_fee_percent: env_opt("PAYMENT_MAX_ROUTING_FEE_PERCENT"),
+            on_final_failure: env::var("PAYMENT_FINAL_FAILURE_ACTION")
+                .ok()
+                .and_then(|action| FinalFailureAction::parse(&action))
+                .unwrap_or(default.on_final_failure),
+        }
+    }
+
+    /// Whether another attempt may follow the 1 based `attempt` that just failed.
+    /// The round's window is checked separately.
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        self.on_final_failure == FinalFailureAction::KeepTrying || attempt < self.max_attempts
+    }
+
+    /// Seconds to wait after the 1 based `attempt` failed: doubles every attempt up to `max_backoff_secs`
+    pub fn backoff_secs(&self, attempt: u32) -> u64 {
+        let exponent = attempt.saturating_sub(1).min(32);
+        self.initial_backoff_secs
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_backoff_secs)
+    }
+
+    /// Whether payments have a hard routing fee cap (`PAYMENT_MAX_ROUTING_FEE_*`)
+    pub fn has_routing_fee_cap(&self) -> bool {
+        self.max_routing_fee_msats.is_some() || self.max_routing_fee_percent.is_some()
+    }
+
+    /// Routing fee limit in msats for the 1 based `attempt` of an `amount_msats` payment
+    pub fn fee_limit_msats(&self, amount_msats: i64, attempt: u32) -> i64 {
+        let escalation = self.fee_limit_escalation.powi(attempt.saturating_sub(1) as i32);
+        let percent = (self.fee_limit_percent * escalation).min(self.max_fee_limit_percent);
+        let fee_limit = ((amount_msats as f64 * percent / 100.0) as i64).max(self.min_fee_limit_msats);
+        match self.max_routing_fee_msats(amount_msats) {
+            Some(cap) => fee_limit.min(cap),
+            None => fee_limit,
+        }
+    }
+
+    /// The hard routing fee cap of an `amount_msats` payment, if one is configured
+    pub fn max_routing_fee_msats(&self, amount_msats: i64) -> Option<i64> {
+        let percent_cap = self
+            .max_routing_fee_percent
+            .map(|percent| (amount_msats as f64 * percent / 100.0) as i64);
+        match (self.max_routing_fee_msats, percent_cap) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (cap, None) | (None, cap) => cap,
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
+
+fn env_opt<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|value| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_fee_escalation() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<u64> = (1..=5).map(|a| policy.backoff_secs(a)).collect();
+        assert_eq!(backoffs, vec![2, 4, 8, 16, 16]);
+
+        // 1% of 1,000,000 msats, doubling to the 5% cap
+        let limits: Vec<i64> = (1..=4).map(|a| policy.fee_limit_msats(1_000_000, a)).collect();
+        assert_eq!(limits, vec![10_000, 20_000, 40_000, 50_000]);
+        // Small payments still get the minimum fee limit
+        assert_eq!(policy.fee_limit_msats(1000, 1), 1000);
+
+        // Hard caps win over the escalation and the minimum
+        let capped = RetryPolicy {
+            max_routing_fee_msats: Some(30_000),
+            max_routing_fee_percent: Some(0.5),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(capped.max_routing_fee_msats(1_000_000), Some(5_000));
+        assert_eq!(capped.fee_limit_msats(1_000_000, 4), 5_000);
+        assert_eq!(capped.fee_limit_msats(10_000_000, 4), 30_000);
+        assert_eq!(capped.fee_limit_msats(1000, 1), 5);
+        assert_eq!(policy.max_routing_fee_msats(1000), None);
+
+        assert!(policy.should_retry(2));
+        assert!(!policy.should_retry(3));
+        let keep_trying = RetryPolicy {
+            on_final_failure: FinalFailureAction::KeepTrying,
+            ..RetryPolicy::default()
+        };
+        assert!(keep_trying.should_retry(100));
+    }
+
+    #[test]
+    fn test_parse_final_failure_action() {
+        assert_eq!(FinalFailureAction::parse("drop_hop"), Some(FinalFailureAction::DropHop));
+        assert_eq!(FinalFailureAction::parse(" KEEP_TRYING "), Some(FinalFailureAction::KeepTrying));
+        assert_eq!(FinalFailureAction::parse("give_up"), None);
+    }
+}
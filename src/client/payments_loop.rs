@@ -1,113 +1,93 @@
 use super::bandwidth_test;
-use crate::database::{Db, Payment};
-use crate::types::Relay;
-use lni::{LightningNode, PayInvoiceResponse};
+use super::budget::{Budget, BudgetGuard};
+use super::payment_retry::{FinalFailureAction, RetryPolicy};
+use crate::database::{self, Db, Payment};
+use crate::lightning::payment_method::PaymentCapabilities;
+use crate::lightning::{bip353, lnurl};
+use crate::rpc::get_circuit_states;
+use crate::types::{PaymentMethod, Relay, RoundSchedule};
+use futures_util::future::join_all;
+use lni::{LightningNode, PayInvoiceParams, PayInvoiceResponse};
 use log::{error, info, warn};
+use std::collections::HashSet;
 use std::env;
+use tokio::sync::Semaphore;
 
-/// Runs payment loops on two circuits in round-robin fashion.
-/// Alternates between primary and backup circuits for each payment round.
-/// This provides load balancing and redundancy.
+/// A built circuit whose hops the payments loop pays
+pub struct PaidCircuit<'a> {
+    pub relays: &'a Vec<Relay>,
+    pub circuit_id: &'a String,
+    /// The build timestamp sent in EXTENDPAIDCIRCUIT, rounds are due counting from it
+    pub built_at: i64,
+}
+
+/// Runs the payment loops for a primary and a backup circuit.
+/// Every hop of both circuits is paid on its own advertised cadence
+/// (`PaymentInterval` / `PaymentIntervalRounds`) while streams are spread
+/// round-robin across the two circuits. This provides load balancing and redundancy.
 pub async fn start_payments_loop_round_robin(
     rpc_config: &crate::types::RpcConfig,
-    primary_relays: &Vec<Relay>,
-    primary_circuit_id: &String,
-    backup_relays: &Vec<Relay>,
-    backup_circuit_id: &String,
+    primary: PaidCircuit<'_>,
+    backup: PaidCircuit<'_>,
     wallet: std::sync::Arc<Box<dyn LightningNode + Send + Sync>>,
+    capabilities: PaymentCapabilities,
     socks_port: u16,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let db = load_or_create_db()?;
-    let rate_limit_delay = get_rate_limit_delay();
-    let max_rounds = 10;
+    let ctx = PaymentContext::new(load_or_create_db()?, &**wallet, capabilities, socks_port);
+    let circuits: [(&Vec<Relay>, &str); 2] = [(primary.relays, "PRIMARY"), (backup.relays, "BACKUP")];
+    // Hops each circuit stopped paying after their retries ran out (FinalFailureAction::DropHop)
+    let mut dropped_hops: [HashSet<String>; 2] = Default::default();
+    // Circuits Tor closed, their hops aren't paid anymore
+    let mut failed_over = [false; 2];
+    let ticks = payment_ticks(&payment_schedule(&[
+        (primary.relays, primary.built_at),
+        (backup.relays, backup.built_at),
+    ]));
     
-    info!("🔄 Starting round-robin payment loop with {} rounds", max_rounds);
-    info!("   Primary circuit: {}", primary_circuit_id);
-    info!("   Backup circuit: {}", backup_circuit_id);
+    info!("🔄 Starting round-robin payment loop with {} payment ticks", ticks.len());
+    info!("   Primary circuit: {}", primary.circuit_id);
+    info!("   Backup circuit: {}", backup.circuit_id);
     
     let mut first_bandwidth_check = true; // Track if this is the first successful bandwidth check
     let mut stream_monitor_started = false; // Track if we've started the stream attachment monitor
     
-    for round in 1..=max_rounds {
-        // Determine which circuit to use for this round (alternate between them)
-        let (current_relays, current_circuit_id, circuit_name) = if round % 2 == 1 {
-            (primary_relays, primary_circuit_id, "PRIMARY")
-        } else {
-            (backup_relays, backup_circuit_id, "BACKUP")
-        };
+    for (tick, (due_at, due)) in ticks.iter().enumerate() {
+        // Wait for the next payments to come due, with bandwidth monitoring
+        if tick > 0 && !wait_for_next_round_with_monitoring(rpc_config, socks_port, *due_at).await {
+            warn!("❌ Bandwidth lost during round wait.");
+            return Err("Bandwidth lost during round wait".into());
+        }
         
         info!(
-            "🥊 Round {}/{} - Using {} circuit {} 🥊",
-            round, max_rounds, circuit_name, current_circuit_id
+            "🥊 Payment tick {}/{} at T+{}s - {} payments due across both circuits 🥊",
+            tick + 1, ticks.len(), due_at - primary.built_at, due.len()
         );
         
         // Check stream capacity and warn if approaching limit
         check_and_warn_stream_capacity(rpc_config).await;
         
-        // Check bandwidth before paying for this round
+        // Fail over per circuit: stop paying a circuit Tor closed and keep paying the other
+        let open = circuits_open(rpc_config, &[primary.circuit_id, backup.circuit_id]).await;
+        for (circuit, (_, circuit_name)) in circuits.iter().enumerate() {
+            if !open[circuit] && !failed_over[circuit] {
+                let (_, other_name) = circuits[1 - circuit];
+                warn!("🔄 FAILOVER: {} circuit is closed, paying only the {} circuit from now on", circuit_name, other_name);
+                failed_over[circuit] = true;
+            }
+        }
+        if failed_over.iter().all(|closed| *closed) {
+            warn!("❌ FAILOVER FAILED: both circuits are closed.");
+            return Err("Both circuits are closed".into());
+        }
+
+        // Check bandwidth before paying, streams use both circuits so retry once before giving up on them
         if !bandwidth_test::has_bandwidth(socks_port).await {
-            warn!("❌ SOCKS bandwidth check failed before payment round {} on {} circuit.", round, circuit_name);
-            warn!("🔄 FAILOVER: Switching to {} circuit for this round", if circuit_name == "PRIMARY" { "BACKUP" } else { "PRIMARY" });
-            
-            // Switch to the other circuit for this round
-            let (failover_relays, failover_name) = if circuit_name == "PRIMARY" {
-                (backup_relays, "BACKUP")
-            } else {
-                (primary_relays, "PRIMARY")
-            };
-            
-            // Try the failover circuit
+            warn!("❌ SOCKS bandwidth check failed before payment tick {}. Retrying...", tick + 1);
             if !bandwidth_test::has_bandwidth(socks_port).await {
-                warn!("❌ FAILOVER FAILED: {} circuit also has no bandwidth. Both circuits down.", failover_name);
+                warn!("❌ SOCKS bandwidth check failed again. Rebuilding both circuits.");
                 return Err("Both circuits have lost bandwidth".into());
             }
-            
-            info!("✅ FAILOVER SUCCESS: {} circuit has bandwidth, continuing with it", failover_name);
-            
-            // Start stream monitor on first bandwidth check during failover path too
-            if first_bandwidth_check {
-                info!("🔄 Bootstrapping 100%");
-                first_bandwidth_check = false;
-                
-                if !stream_monitor_started {
-                    info!("🌊 Starting stream attachment monitor for round-robin stream distribution...");
-                    match crate::rpc::start_stream_attachment_monitor(
-                        rpc_config.clone(),
-                        primary_circuit_id.clone(),
-                        backup_circuit_id.clone(),
-                    )
-                    .await
-                    {
-                        Ok(_handle) => {
-                            info!("✅ Stream attachment monitor started - streams will be distributed 50/50 across both circuits");
-                            stream_monitor_started = true;
-                        }
-                        Err(e) => {
-                            warn!("⚠️  Failed to start stream attachment monitor: {}", e);
-                            warn!("⚠️  Falling back to Tor's automatic stream assignment");
-                        }
-                    }
-                }
-            }
-            
-            // Process payments on failover circuit for this round
-            process_payments_for_relays(
-                &db,
-                failover_relays,
-                round,
-                &**wallet,
-                rate_limit_delay,
-                failover_name,
-            ).await?;
-            
-            // Wait for next round with monitoring
-            if round < max_rounds {
-                if !wait_for_next_round_with_monitoring(rpc_config, socks_port, 45).await {
-                    warn!("❌ Bandwidth lost during round wait after failover.");
-                    return Err("Bandwidth lost during round wait after failover".into());
-                }
-            }
-            continue;
+            info!("✅ SOCKS bandwidth is back, continuing");
         }
         
         let (total_streams, _) = bandwidth_test::check_stream_capacity(rpc_config).await;
@@ -123,8 +103,8 @@ pub async fn start_payments_loop_round_robin(
                 info!("🌊 Starting stream attachment monitor for round-robin stream distribution...");
                 match crate::rpc::start_stream_attachment_monitor(
                     rpc_config.clone(),
-                    primary_circuit_id.clone(),
-                    backup_circuit_id.clone(),
+                    primary.circuit_id.clone(),
+                    backup.circuit_id.clone(),
                 )
                 .await
                 {
@@ -140,24 +120,23 @@ pub async fn start_payments_loop_round_robin(
             }
         }
         
-        info!("🛜  SOCKS bandwidth check passed before payment round {} on {} circuit ({} total streams)", round, circuit_name, total_streams);
-        
-        // Process payments for all relays in current circuit
-        process_payments_for_relays(
-            &db,
-            current_relays,
-            round,
-            &**wallet,
-            rate_limit_delay,
-            circuit_name,
-        ).await?;
+        info!("🛜  SOCKS bandwidth check passed before payment tick {} ({} total streams)", tick + 1, total_streams);
         
-        // Wait for next round with bandwidth monitoring
-        if round < max_rounds {
-            if !wait_for_next_round_with_monitoring(rpc_config, socks_port, 45).await {
-                warn!("❌ Bandwidth lost during round wait.");
-                return Err("Bandwidth lost during round wait".into());
-            }
+        // Pay the hops that are due on both circuits concurrently
+        let ctx = &ctx;
+        let results = join_all(circuits.iter().zip(dropped_hops.iter_mut()).enumerate().map(
+            |(circuit, ((relays, circuit_name), dropped_hops))| {
+                let due_here: Vec<(&Relay, usize)> = due
+                    .iter()
+                    .filter(|p| p.circuit == circuit && !failed_over[circuit])
+                    .map(|p| (&relays[p.relay_index], p.round))
+                    .collect();
+                async move { process_payments_for_relays(ctx, &due_here, dropped_hops, circuit_name).await }
+            },
+        ))
+        .await;
+        for result in results {
+            result?;
         }
     }
     
@@ -167,21 +146,28 @@ pub async fn start_payments_loop_round_robin(
 
 pub async fn start_payments_loop(
     rpc_config: &crate::types::RpcConfig,
-    relays: &Vec<Relay>,
-    circuit_id: &String,
+    circuit: PaidCircuit<'_>,
     wallet: std::sync::Arc<Box<dyn LightningNode + Send + Sync>>,
+    capabilities: PaymentCapabilities,
     socks_port: u16,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let db = load_or_create_db()?;
-    let rate_limit_delay = get_rate_limit_delay();
-    let max_rounds = 10;
+    let ctx = PaymentContext::new(load_or_create_db()?, &**wallet, capabilities, socks_port);
+    let mut dropped_hops = HashSet::new();
+    let (relays, circuit_id) = (circuit.relays, circuit.circuit_id);
+    let ticks = payment_ticks(&payment_schedule(&[(relays, circuit.built_at)]));
     
     let mut first_bandwidth_check = true; // Track if this is the first successful bandwidth check
     
-    for round in 1..=max_rounds {
+    for (tick, (due_at, due)) in ticks.iter().enumerate() {
+        // Wait for the next payments to come due, with bandwidth monitoring
+        if tick > 0 && !wait_for_next_round_with_monitoring(rpc_config, socks_port, *due_at).await {
+            warn!("❌ Bandwidth lost during round wait. Stopping payments and rebuilding circuit.");
+            return Err("Bandwidth lost".into());
+        }
+        
         info!(
-            "🥊 Round {:?} - Starting payments loop for circuit: {:?} 🥊",
-            round, circuit_id
+            "🥊 Payment tick {}/{} at T+{}s - {} payments due for circuit: {:?} 🥊",
+            tick + 1, ticks.len(), due_at - circuit.built_at, due.len(), circuit_id
         );
         
         // Check stream capacity and warn if approaching limit
@@ -189,7 +175,7 @@ pub async fn start_payments_loop(
         
         // Check bandwidth before paying for this round (using real SOCKS proxy test)
         if !bandwidth_test::has_bandwidth(socks_port).await {
-            warn!("❌ SOCKS bandwidth check failed before payment round {}. Stopping payments and rebuilding circuit.", round);
+            warn!("❌ SOCKS bandwidth check failed before payment tick {}. Stopping payments and rebuilding circuit.", tick + 1);
             return Err("Bandwidth lost before payment".into());
         }
         
@@ -201,50 +187,90 @@ pub async fn start_payments_loop(
             first_bandwidth_check = false;
         }
         
-        info!("🛜  SOCKS bandwidth check passed before payment round {} ({} total streams)", round, total_streams);
+        info!("🛜  SOCKS bandwidth check passed before payment tick {} ({} total streams)", tick + 1, total_streams);
         
-        // Process payments for all relays
-        process_payments_for_relays(
-            &db,
-            relays,
-            round,
-            &**wallet,
-            rate_limit_delay,
-            "SINGLE",
-        ).await?;
-        
-        // Wait for next round with bandwidth monitoring
-        if round < max_rounds {
-            if !wait_for_next_round_with_monitoring(rpc_config, socks_port, 45).await {
-                warn!("❌ Bandwidth lost during round wait. Stopping payments and rebuilding circuit.");
-                return Err("Bandwidth lost".into());
-            }
-        }
+        // Pay the hops that are due now
+        let due_here: Vec<(&Relay, usize)> = due
+            .iter()
+            .map(|p| (&relays[p.relay_index], p.round))
+            .collect();
+        process_payments_for_relays(&ctx, &due_here, &mut dropped_hops, "SINGLE").await?;
     }
     
     Ok(())
 }
 
-/// Load the payments database or create a fresh one if corrupted
-fn load_or_create_db() -> Result<Db, Box<dyn std::error::Error + Send + Sync>> {
-    match Db::new("data/payments_sent.json".to_string()) {
-        Ok(db) => Ok(db),
+/// Which of `circuit_ids` Tor still has open. All of them are taken as open when the
+/// circuit status can't be read, the SOCKS check catches circuits that stopped working.
+async fn circuits_open(rpc_config: &crate::types::RpcConfig, circuit_ids: &[&String]) -> Vec<bool> {
+    let states = match get_circuit_states(rpc_config).await {
+        Ok(states) => states,
         Err(e) => {
-            error!("Failed to load payments database: {}. Creating backup and starting fresh...", e);
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            let backup_path = format!("data/payments_sent.json.backup_{}", timestamp);
-            if let Err(backup_err) = std::fs::copy("data/payments_sent.json", &backup_path) {
-                warn!("Could not create backup: {}", backup_err);
-            } else {
-                info!("Corrupted database backed up to: {}", backup_path);
+            warn!("Failed to get circuit-status, assuming both circuits are open: {}", e);
+            return vec![true; circuit_ids.len()];
+        }
+    };
+    circuit_ids
+        .iter()
+        .map(|id| !matches!(states.get(id.as_str()).map(String::as_str), None | Some("FAILED") | Some("CLOSED")))
+        .collect()
+}
+
+/// A hop payment due at unix seconds `due_at`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ScheduledPayment {
+    due_at: i64,
+    /// Index into the circuits passed to `payment_schedule`
+    circuit: usize,
+    relay_index: usize,
+    /// 1 based payment round of this hop
+    round: usize,
+}
+
+/// Schedules every hop's rounds on its own cadence: round n is paid at
+/// (n - 1) * the relay's PaymentInterval after the circuit's build timestamp.
+/// Sorted by due time.
+fn payment_schedule(circuits: &[(&Vec<Relay>, i64)]) -> Vec<ScheduledPayment> {
+    let mut schedule = Vec::new();
+    for (circuit, (relays, built_at)) in circuits.iter().enumerate() {
+        for (relay_index, relay) in relays.iter().enumerate() {
+            let cadence = relay.payment_cadence();
+            let rounds_due = RoundSchedule::new(cadence, *built_at);
+            let hashes = relay.payment_id_hashes_10.as_ref().map_or(0, |h| h.len());
+            let rounds = (cadence.rounds as usize).min(hashes);
+            for round in 0..rounds {
+                schedule.push(ScheduledPayment {
+                    due_at: rounds_due.round_start(round as u32),
+                    circuit,
+                    relay_index,
+                    round: round + 1,
+                });
             }
-            std::fs::write("data/payments_sent.json", "[]")?;
-            Ok(Db::new("data/payments_sent.json".to_string())?)
         }
     }
+    schedule.sort_by_key(|p| (p.due_at, p.circuit, p.relay_index));
+    schedule
+}
+
+/// Groups scheduled payments that are due at the same time
+fn payment_ticks(schedule: &[ScheduledPayment]) -> Vec<(i64, Vec<ScheduledPayment>)> {
+    let mut ticks: Vec<(i64, Vec<ScheduledPayment>)> = Vec::new();
+    for payment in schedule {
+        match ticks.last_mut() {
+            Some((due_at, due)) if *due_at == payment.due_at => due.push(payment.clone()),
+            _ => ticks.push((payment.due_at, vec![payment.clone()])),
+        }
+    }
+    ticks
+}
+
+/// Open the payments sent ledger. Errors are returned rather than papered over
+/// so a broken ledger never loses track of what was already paid.
+fn load_or_create_db() -> Result<Db, Box<dyn std::error::Error + Send + Sync>> {
+    database::open_payments_sent_ledger().map_err(|e| {
+        error!("Failed to open payments ledger: {}", e);
+        e.into()
+    })
 }
 
 /// Get the rate limit delay from environment variable
@@ -255,6 +281,51 @@ fn get_rate_limit_delay() -> u64 {
         .unwrap()
 }
 
+/// Get the number of payments the wallet may have in flight at once from environment variable
+fn get_wallet_max_concurrent_payments() -> usize {
+    env::var("WALLET_MAX_CONCURRENT_PAYMENTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(3)
+}
+
+/// Everything a payments loop needs to pay its hops
+struct PaymentContext<'a> {
+    db: Db,
+    wallet: &'a (dyn LightningNode + Send + Sync),
+    /// What the wallet can pay, a hard routing fee cap needs BOLT 11
+    capabilities: PaymentCapabilities,
+    /// Limits the payments in flight on the wallet, shared by every circuit of the loop
+    wallet_permits: Semaphore,
+    /// Seconds a wallet slot stays taken after a payment attempt
+    rate_limit_delay: u64,
+    retry_policy: RetryPolicy,
+    /// Spending limits checked before every payment attempt
+    budget: BudgetGuard,
+    socks_port: u16,
+}
+
+impl<'a> PaymentContext<'a> {
+    fn new(
+        db: Db,
+        wallet: &'a (dyn LightningNode + Send + Sync),
+        capabilities: PaymentCapabilities,
+        socks_port: u16,
+    ) -> Self {
+        PaymentContext {
+            db,
+            wallet,
+            capabilities,
+            wallet_permits: Semaphore::new(get_wallet_max_concurrent_payments()),
+            rate_limit_delay: get_rate_limit_delay(),
+            retry_policy: RetryPolicy::from_env(),
+            budget: BudgetGuard::new(Budget::from_env()),
+            socks_port,
+        }
+    }
+}
+
 /// Check stream capacity and warn if approaching limit
 async fn check_and_warn_stream_capacity(rpc_config: &crate::types::RpcConfig) {
     let (total_streams, needs_more_circuits) = bandwidth_test::check_stream_capacity(rpc_config).await;
@@ -265,95 +336,254 @@ async fn check_and_warn_stream_capacity(rpc_config: &crate::types::RpcConfig) {
     }
 }
 
-/// Process payments for a set of relays in a given round
+/// Process the payments that are due, given as (relay, 1 based round) pairs.
+/// The hops are paid concurrently and each one updates its own ledger row, so a slow
+/// payment doesn't hold up the others. Failed payments are retried per the context's
+/// `RetryPolicy`; hops in `dropped_hops` are skipped.
 async fn process_payments_for_relays(
-    db: &Db,
-    relays: &Vec<Relay>,
+    ctx: &PaymentContext<'_>,
+    due: &[(&Relay, usize)],
+    dropped_hops: &mut HashSet<String>,
+    circuit_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let due: Vec<&(&Relay, usize)> = due
+        .iter()
+        .filter(|(relay, round)| {
+            let dropped = dropped_hops.contains(&relay.fingerprint);
+            if dropped {
+                info!("Skipping round {} of dropped hop {} on {} circuit", round, relay.nickname, circuit_name);
+            }
+            !dropped
+        })
+        .collect();
+    let outcomes = join_all(
+        due.iter()
+            .map(|(relay, round)| pay_due_hop(ctx, relay, *round, circuit_name)),
+    )
+    .await;
+
+    // Every hop has finished, now act on the failures
+    let mut first_err = None;
+    for ((relay, round), outcome) in due.iter().zip(outcomes) {
+        match outcome {
+            Ok(true) => {}
+            Ok(false) => match ctx.retry_policy.on_final_failure {
+                FinalFailureAction::Rebuild => {
+                    first_err.get_or_insert_with(|| {
+                        format!("Payment to relay {} failed on {} circuit, rebuilding", relay.nickname, circuit_name).into()
+                    });
+                }
+                FinalFailureAction::DropHop => {
+                    warn!("Dropping hop {} on {} circuit, its remaining rounds won't be paid", relay.nickname, circuit_name);
+                    dropped_hops.insert(relay.fingerprint.clone());
+                }
+                FinalFailureAction::KeepTrying => {
+                    warn!("Round {} window closed for relay {}, moving on to the next round", round, relay.nickname);
+                }
+            },
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    match first_err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Pays one hop's round. Returns false if the payment failed after all retries,
+/// true if it was paid or there was nothing to pay.
+async fn pay_due_hop(
+    ctx: &PaymentContext<'_>,
+    relay: &Relay,
     round: usize,
-    wallet: &(dyn LightningNode + Send + Sync),
-    rate_limit_delay: u64,
     circuit_name: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let payment_id_hash = match relay.payment_id_hashes_10.as_ref().and_then(|h| h.get(round - 1)) {
+        Some(hash) => hash.clone(),
+        None => return Err("Payment ID hashes not found".into()),
+    };
+    
+    let mut payment = match ctx.db.lookup_payment_by_id(payment_id_hash) {
+        Ok(Some(payment)) => payment,
+        Ok(None) => return Err("Payment not found in database".into()),
+        Err(_) => return Err("Payment for the circuit not found".into()),
+    };
+    
+    // Skip if already prepaid, zero amount (free or covered by a prepaid batch) or no way to pay the relay
+    let has_bolt12 = payment.bolt12_offer.is_some() || relay.payment_bip353.is_some();
+    let has_bolt11_endpoint = relay.payment_bolt11_lnurl.is_some() || relay.payment_bolt11_lightning_address.is_some();
+    let has_method = payment.payment_method.is_some() || has_bolt12 || has_bolt11_endpoint;
+    if payment.paid || payment.amount_msat == 0 || !has_method {
+        info!(
+            "Nothing to pay this round, skipping payment id: {:?}",
+            payment.payment_id
+        );
+        return Ok(true);
+    }
+    
+    // Check if round is expired
+    if is_round_expired(&payment) {
+        warn!("Round expired for {} circuit", circuit_name);
+        return Err(format!("Round expired on {} circuit", circuit_name).into());
+    }
+    
+    // Attempt payment, retrying within the round's window
+    let paid = pay_relay_with_retries(ctx, &mut payment, relay).await?;
+    if paid {
+        mark_prepaid_rounds_paid(ctx, relay, round, &payment)?;
+    } else {
+        warn!(
+            "Payment failed for payment id: {:?} on {} circuit after {} attempts",
+            payment.payment_id, circuit_name, payment.attempts
+        );
+    }
+    Ok(paid)
+}
+
+/// Marks the rounds after `round` that were written with 0 msats as paid by `batch`,
+/// the payment that prepaid them
+fn mark_prepaid_rounds_paid(
+    ctx: &PaymentContext<'_>,
+    relay: &Relay,
+    round: usize,
+    batch: &Payment,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    for relay in relays.iter() {
-        let payment_id_hash = match &relay.payment_id_hashes_10 {
-            Some(hashes) => hashes[round - 1].clone(),
-            None => return Err("Payment ID hashes not found".into()),
+    let later_rounds = relay.payment_id_hashes_10.iter().flatten().skip(round);
+    for payment_id in later_rounds {
+        let covered = match ctx.db.lookup_payment_by_id(payment_id.clone())? {
+            Some(payment) if payment.amount_msat == 0 && !payment.paid => payment,
+            // The next batch (or a just in time round) starts here
+            _ => break,
         };
-        
-        let mut payment = match db.lookup_payment_by_id(payment_id_hash) {
-            Ok(Some(payment)) => payment,
-            Ok(None) => return Err("Payment not found in database".into()),
-            Err(_) => return Err("Payment for the circuit not found".into()),
+        info!("Round {} for relay {} prepaid by payment id {:?}", covered.round, relay.nickname, batch.payment_id);
+        ctx.db.modify_payment(&covered.payment_id, |p| {
+            p.paid = true;
+            p.payment_hash = batch.payment_hash.clone();
+            p.preimage = batch.preimage.clone();
+            p.fee = Some(0);
+            p.updated_at = chrono::Utc::now().timestamp();
+        })?;
+    }
+    Ok(())
+}
+
+/// Pays one round, retrying with exponential backoff and an escalating fee limit until it
+/// succeeds, `retry_policy` gives up or the round's window closes. Every attempt is written
+/// to the ledger. Returns whether the round got paid.
+async fn pay_relay_with_retries(
+    ctx: &PaymentContext<'_>,
+    payment: &mut Payment,
+    relay: &Relay,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let retry_policy = &ctx.retry_policy;
+    let mut attempt = 1;
+    loop {
+        let fee_limit_msats = retry_policy.fee_limit_msats(payment.amount_msat, attempt);
+        let permit = ctx.wallet_permits.acquire().await?;
+        // Count the worst case fee until the payment's real fee is in the ledger
+        let reservation = match ctx.budget.reserve(&ctx.db, &relay.fingerprint, payment.amount_msat + fee_limit_msats) {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                warn!("Not paying payment id {:?} to relay {}: {}", payment.payment_id, relay.nickname, e);
+                payment.last_error = Some(e.to_string());
+                ctx.db.update_payment(payment.clone())?;
+                return Err(e);
+            }
         };
-        
-        // Skip if zero amount or no invoice
-        if payment.amount_msat == 0 || (payment.bolt12_offer.is_none() && payment.bolt11_invoice.is_none()) {
-            info!(
-                "Payment amount is zero, skipping payment id: {:?}",
-                payment.payment_id
-            );
-            continue;
-        }
-        
-        // Check if round is expired
-        if is_round_expired(&payment) {
-            warn!("Round expired for {} circuit", circuit_name);
-            return Err(format!("Round expired on {} circuit", circuit_name).into());
-        }
-        
-        // Attempt payment
-        match pay_relay(wallet, &payment).await {
+        let fee_cap_msats = retry_policy.max_routing_fee_msats(payment.amount_msat);
+        let result = pay_relay(ctx.wallet, &ctx.capabilities, payment, relay, ctx.socks_port, fee_limit_msats, fee_cap_msats).await;
+        let now = chrono::Utc::now().timestamp();
+        payment.attempts += 1;
+        payment.last_attempt_at = Some(now);
+        payment.updated_at = now;
+        match result {
             Ok(pay_resp) => {
+                // BOLT 12 payments can't pass the fee limit to the wallet (without a hard cap
+                // they are still made), the fee history makes the relay less likely to be picked again
+                if pay_resp.fee_msats > fee_limit_msats {
+                    warn!(
+                        "Routing fee of {} msats for payment id {:?} to relay {} is over the {} msats limit",
+                        pay_resp.fee_msats, payment.payment_id, relay.nickname, fee_limit_msats
+                    );
+                }
                 payment.payment_hash = Some(pay_resp.payment_hash);
                 payment.preimage = Some(pay_resp.preimage);
                 payment.fee = Some(pay_resp.fee_msats);
                 payment.paid = true;
-                db.update_payment(payment)?;
+                payment.has_error = false;
             }
-            Err(_) => {
-                warn!("Payment failed for payment id: {:?} on {} circuit", payment.payment_id, circuit_name);
+            Err(e) => {
                 payment.has_error = true;
-                db.update_payment(payment)?;
+                payment.last_error = Some(e.to_string());
             }
         }
-        
-        tokio::time::sleep(tokio::time::Duration::from_secs(rate_limit_delay)).await;
+        ctx.db.update_payment(payment.clone())?;
+        drop(reservation);
+        // Space out payments on this wallet slot
+        tokio::time::sleep(tokio::time::Duration::from_secs(ctx.rate_limit_delay)).await;
+        drop(permit);
+        if payment.paid {
+            return Ok(true);
+        }
+
+        let backoff_secs = retry_policy.backoff_secs(attempt);
+        if !retry_policy.should_retry(attempt) || !round_window_open(payment, backoff_secs as i64) {
+            return Ok(false);
+        }
+        warn!(
+            "Retrying payment id {:?} to relay {} in {}s (attempt {} failed)",
+            payment.payment_id, relay.nickname, backoff_secs, attempt
+        );
+        tokio::time::sleep(tokio::time::Duration::from_secs(backoff_secs)).await;
+        attempt += 1;
     }
-    
-    Ok(())
 }
 
 // check if the round is expired, allow a few seconds of padding to allow for slower lightning payments and route finding
 fn is_round_expired(payment: &Payment) -> bool {
+    !round_window_open(payment, 0)
+}
+
+/// Whether a payment started `delay_secs` from now still lands inside the round
+fn round_window_open(payment: &Payment, delay_secs: i64) -> bool {
     let expiry_padding: i64 = env::var("EXPIRY_PADDING_FOR_PAYMENT_ROUND")
         .unwrap_or("15".to_string())
         .parse()
         .unwrap();
-    (payment.expires_at - chrono::Utc::now().timestamp()) < expiry_padding
+    (payment.expires_at - chrono::Utc::now().timestamp() - delay_secs) >= expiry_padding
 }
 
-/// Waits for the next payment round while monitoring bandwidth every 2 seconds.
-/// Uses heartbeat checks (every 2s) and full bandwidth tests (every 60s) via SOCKS proxy.
+/// Waits until unix seconds `due_at`, when the next payments come due, while monitoring bandwidth every 2 seconds.
+/// Uses heartbeat checks (every 2s) and full bandwidth tests (every 45s) via SOCKS proxy.
+/// The wait runs by the clock, the time the checks themselves take counts toward it.
 /// Returns true if bandwidth remains good throughout the wait.
 /// Returns false if bandwidth is lost, signaling to stop payments and rebuild circuit.
 async fn wait_for_next_round_with_monitoring(
     rpc_config: &crate::types::RpcConfig,
     socks_port: u16,
-    interval_seconds: i64,
+    due_at: i64,
 ) -> bool {
-    info!("Waiting for next round with SOCKS bandwidth monitoring ({}s interval)...", interval_seconds);
+    let started = chrono::Utc::now().timestamp();
+    info!("Waiting for next round with SOCKS bandwidth monitoring ({}s until due)...", due_at - started);
     
     let heartbeat_interval = 2; // Heartbeat check every 2 seconds
     let bandwidth_test_interval = 45; // Full bandwidth test every 45 seconds (matches wait interval)
     let log_interval = 10; // Log stats every 10 seconds
-    let iterations = interval_seconds / heartbeat_interval;
     
     let mut last_bandwidth_test = -45i64; // Initialize to -45 so first test runs immediately
+    let mut last_log = 0i64;
     
-    for i in 0..iterations {
-        tokio::time::sleep(tokio::time::Duration::from_secs(heartbeat_interval as u64)).await;
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        if now >= due_at {
+            break;
+        }
+        let sleep_secs = heartbeat_interval.min(due_at - now);
+        tokio::time::sleep(tokio::time::Duration::from_secs(sleep_secs as u64)).await;
         
-        let elapsed = (i + 1) * heartbeat_interval;
+        let elapsed = chrono::Utc::now().timestamp() - started;
         
         // Check stream capacity (via RPC)
         let (total_streams, needs_more) = bandwidth_test::check_stream_capacity(rpc_config).await;
@@ -365,9 +595,8 @@ async fn wait_for_next_round_with_monitoring(
                 elapsed, total_streams
             );
             warn!(
-                "SOCKS heartbeat check failed at iteration {}/{} during round wait", 
-                i + 1, 
-                iterations
+                "SOCKS heartbeat check failed {}s before the round was due",
+                due_at - chrono::Utc::now().timestamp()
             );
             return false;
         }
@@ -393,24 +622,13 @@ async fn wait_for_next_round_with_monitoring(
         }
         
         // Log every 10 seconds
-        if elapsed % log_interval == 0 {
+        if elapsed - last_log >= log_interval {
             info!(
                 "[T+{:02}s] ✅ HEARTBEAT OK | 🌊 Streams: {}{}",
                 elapsed, total_streams,
                 if needs_more { " ⚠️ APPROACHING LIMIT!" } else { "" }
             );
-        }
-    }
-    
-    // Handle remaining time if interval_seconds is not evenly divisible by heartbeat_interval
-    let remaining = interval_seconds % heartbeat_interval;
-    if remaining > 0 {
-        tokio::time::sleep(tokio::time::Duration::from_secs(remaining as u64)).await;
-        
-        // Final heartbeat check
-        if !bandwidth_test::has_bandwidth(socks_port).await {
-            warn!("[T+{:02}s] ❌ FAILED | SOCKS heartbeat check failed during final check", interval_seconds);
-            return false;
+            last_log = elapsed;
         }
     }
     
@@ -420,26 +638,122 @@ async fn wait_for_next_round_with_monitoring(
 
 async fn pay_relay(
     wallet: &(dyn LightningNode + Send + Sync),
-    payment: &Payment,
+    capabilities: &PaymentCapabilities,
+    payment: &mut Payment,
+    relay: &Relay,
+    socks_port: u16,
+    fee_limit_msats: i64,
+    fee_cap_msats: Option<i64>,
 ) -> Result<PayInvoiceResponse, Box<dyn std::error::Error + Send + Sync>> {
     let amount_msats = payment.amount_msat;
-    info!(
-        "Paying {} sats relay: {:?} with payment id: {:?}",
-        amount_msats / 1000,
-        Some(
-            payment
-                .bolt12_offer
-                .clone()
-                .map(|offer| offer.chars().take(10).collect::<String>())
-        ),
-        payment.payment_id
-    );
-
-    let pay_resp = wallet.pay_offer(
-        payment.bolt12_offer.clone().unwrap(),
-        amount_msats,
-        Some(payment.payment_id.clone()),
-    ).await;
+    let method = payment.payment_method.as_deref().and_then(PaymentMethod::parse);
+    let offer = match method {
+        // Use the method negotiated when the circuit was built
+        Some(method) if method.is_bolt12() => match payment.bolt12_offer.clone() {
+            Some(offer) => Some(offer),
+            None => {
+                let offer = bip353::relay_bolt12_offer(relay)
+                    .await?
+                    .ok_or_else(|| format!("Relay {} has no BOLT 12 offer", relay.nickname))?;
+                payment.bolt12_offer = Some(offer.clone());
+                Some(offer)
+            }
+        },
+        Some(_) => None,
+        // Rows without a negotiated method: try the advertised offer, the relay's BIP-353 name, then BOLT 11
+        None if payment.bolt12_offer.is_some() => payment.bolt12_offer.clone(),
+        None => match bip353::relay_bolt12_offer(relay).await {
+            Ok(offer) => {
+                payment.bolt12_offer = offer.clone();
+                offer
+            }
+            Err(_) if lnurl::relay_lnurl_pay_url(relay).is_some() => {
+                info!("Falling back to BOLT 11 for relay {}", relay.nickname);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        },
+    };
+    // pay_offer takes no fee limit, so a hard routing fee cap moves the round to BOLT 11
+    let offer = match (offer, fee_cap_msats) {
+        (Some(_), Some(cap)) => match lnurl::relay_bolt11_method(relay).filter(|_| capabilities.bolt11) {
+            Some(bolt11) => {
+                info!(
+                    "Paying relay {} over {} to keep the routing fee under the {} msats cap",
+                    relay.nickname, bolt11.as_str(), cap
+                );
+                payment.payment_method = Some(bolt11.as_str().to_string());
+                None
+            }
+            None if !capabilities.bolt11 => {
+                return Err(format!(
+                    "The wallet only pays BOLT 12 offers, which can't be held to the {} msats routing fee cap",
+                    cap
+                )
+                .into())
+            }
+            None => {
+                return Err(format!(
+                    "Relay {} only takes BOLT 12 offers, which can't be held to the {} msats routing fee cap",
+                    relay.nickname, cap
+                )
+                .into())
+            }
+        },
+        (offer, _) => offer,
+    };
+    let pay_resp = match offer {
+        Some(offer) => {
+            info!(
+                "Paying {} sats relay: {:?} with payment id: {:?}",
+                amount_msats / 1000,
+                offer.chars().take(10).collect::<String>(),
+                payment.payment_id
+            );
+            // No hard cap, the wallet's own routing fee limit applies
+            wallet.pay_offer(offer, amount_msats, Some(payment.payment_id.clone())).await
+        }
+        None => {
+            // BOLT 11: fetch an invoice for this round from the relay's LNURL-pay endpoint,
+            // the payment id goes in the comment so the relay can match the invoice
+            let method = payment.payment_method.as_deref().and_then(PaymentMethod::parse);
+            let pay_url = match method {
+                Some(PaymentMethod::Lnurl) => relay.payment_bolt11_lnurl.as_deref().map(lnurl::lnurl_to_url),
+                Some(PaymentMethod::LightningAddress) => relay
+                    .payment_bolt11_lightning_address
+                    .as_deref()
+                    .map(lnurl::lightning_address_to_url),
+                _ => lnurl::relay_lnurl_pay_url(relay),
+            };
+            let pay_url = match pay_url {
+                Some(pay_url) => pay_url?,
+                None => return Err(format!("Relay {} has no BOLT 12 offer or BOLT 11 endpoint", relay.nickname).into()),
+            };
+            // Retries pay the invoice fetched by the first attempt
+            let invoice = match payment.bolt11_invoice.clone() {
+                Some(invoice) => invoice,
+                None => {
+                    let client = lnurl::lnurl_http_client(Some(socks_port))?;
+                    lnurl::fetch_bolt11_invoice(&client, &pay_url, amount_msats, &payment.payment_id).await?
+                }
+            };
+            payment.bolt11_invoice = Some(invoice.clone());
+            info!(
+                "Paying {} sats relay: {:?} BOLT 11 invoice: {:?} with payment id: {:?}",
+                amount_msats / 1000,
+                relay.nickname,
+                invoice.chars().take(10).collect::<String>(),
+                payment.payment_id
+            );
+            wallet
+                .pay_invoice(PayInvoiceParams {
+                    invoice,
+                    fee_limit_msat: Some(fee_limit_msats),
+                    ..Default::default()
+                })
+                .await
+        }
+    };
     match pay_resp {
         Ok(result) => {
             info!(
@@ -453,9 +767,339 @@ async fn pay_relay(
                 "Payment failed for payment id: {:?} with error {:?}",
                 payment.payment_id, e
             );
-            Err("Payment failed".into())
+            Err(format!("Payment failed: {:?}", e).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock_wallet::MockLightningNode;
+    use tokio::time::{Duration, Instant};
+
+    fn relay(interval_seconds: Option<u32>, rounds: Option<u32>) -> Relay {
+        Relay {
+            nickname: "hop".to_string(),
+            fingerprint: "AAAA".to_string(),
+            contact: None,
+            bandwidth: None,
+            ip: None,
+            port: None,
+            payment_bolt12_offer: None,
+            payment_bip353: None,
+            payment_bolt11_lnurl: None,
+            payment_bolt11_lightning_address: None,
+            payment_rate_msats: None,
+            payment_interval_seconds: interval_seconds,
+            payment_interval_rounds: rounds,
+            payment_handshake_fee: None,
+            payment_prepaid: false,
+            payment_protocol_version: 1,
+            payment_handshake_fee_payhash: None,
+            payment_handshake_fee_preimage: None,
+            payment_method: None,
+            payment_id_hashes_10: Some((0..10).map(|i| i.to_string()).collect()),
+            relay_tag: None,
+            hop: None,
+        }
+    }
+
+    #[test]
+    fn test_mixed_interval_schedule() {
+        // 30s hop paid 4 times, default 60s hop paid twice
+        let circuit = vec![relay(Some(30), Some(4)), relay(None, Some(2))];
+        let due: Vec<(i64, usize, usize)> = payment_schedule(&[(&circuit, 0)])
+            .into_iter()
+            .map(|p| (p.due_at, p.relay_index, p.round))
+            .collect();
+        assert_eq!(
+            due,
+            vec![(0, 0, 1), (0, 1, 1), (30, 0, 2), (60, 0, 3), (60, 1, 2), (90, 0, 4)]
+        );
+
+        // Counted from the circuit's build timestamp
+        let ticks = payment_ticks(&payment_schedule(&[(&circuit, 1000)]));
+        let due_at: Vec<i64> = ticks.iter().map(|(due_at, _)| *due_at).collect();
+        assert_eq!(due_at, vec![1000, 1030, 1060, 1090]);
+        assert_eq!(ticks[2].1.len(), 2);
+    }
+
+    #[test]
+    fn test_schedule_limited_by_hashes() {
+        let mut short = relay(Some(10), Some(10));
+        short.payment_id_hashes_10 = Some(vec!["a".to_string(), "b".to_string()]);
+        let mut unpaid = relay(None, None);
+        unpaid.payment_id_hashes_10 = None;
+        let backup = vec![relay(Some(20), Some(1))];
+
+        // The backup circuit was built 5s after the primary
+        let schedule = payment_schedule(&[(&vec![short, unpaid], 0), (&backup, 5)]);
+        let due: Vec<(i64, usize, usize)> = schedule
+            .into_iter()
+            .map(|p| (p.due_at, p.circuit, p.round))
+            .collect();
+        assert_eq!(due, vec![(0, 0, 1), (5, 1, 1), (10, 0, 2)]);
+    }
+
+    /// Hop whose round 1 is due and paid to its own offer
+    fn hop_with_payment(db: &Db, seed: u8) -> Relay {
+        let mut hop = relay(None, None);
+        hop.nickname = format!("hop{}", seed);
+        hop.fingerprint = seed.to_string();
+        hop.payment_bolt12_offer = Some(format!("lno1hop{}", seed));
+        let payment_id = format!("hop{}_round1", seed);
+        hop.payment_id_hashes_10 = Some(vec![payment_id.clone()]);
+
+        let mut payment = database::test_payment(&payment_id, "circ", 1);
+        payment.relay_fingerprint = hop.fingerprint.clone();
+        payment.amount_msat = 1000;
+        payment.bolt12_offer = hop.payment_bolt12_offer.clone();
+        payment.expires_at = chrono::Utc::now().timestamp() + 60;
+        db.write_payment(payment).unwrap();
+        hop
+    }
+
+    fn test_context(wallet: &MockLightningNode, max_concurrent: usize, retry_policy: RetryPolicy) -> PaymentContext<'_> {
+        let path = database::test_ledger_path("payments_loop.db");
+        PaymentContext {
+            db: Db::new(path.display().to_string()).unwrap(),
+            wallet,
+            capabilities: PaymentCapabilities::for_node_type("phoenixd"),
+            wallet_permits: Semaphore::new(max_concurrent),
+            rate_limit_delay: 0,
+            retry_policy,
+            budget: BudgetGuard::new(Budget::default()),
+            socks_port: 0,
         }
     }
 
-    // TODO Retry strategy
+    #[tokio::test]
+    async fn test_hops_paid_concurrently_within_wallet_limit() {
+        let wallet = MockLightningNode::with_pay_delay(Duration::from_millis(200));
+        let ctx = test_context(&wallet, 2, RetryPolicy::default());
+        let hops: Vec<Relay> = (1..=3).map(|seed| hop_with_payment(&ctx.db, seed)).collect();
+        let due: Vec<(&Relay, usize)> = hops.iter().map(|hop| (hop, 1)).collect();
+
+        let start = Instant::now();
+        process_payments_for_relays(&ctx, &due, &mut HashSet::new(), "TEST")
+            .await
+            .unwrap();
+        // Two batches of 200ms instead of three payments one after another
+        assert!(start.elapsed() < Duration::from_millis(550));
+        assert_eq!(wallet.max_in_flight(), 2);
+
+        let payments = ctx.db.lookup_payments_by_circuit("circ").unwrap();
+        assert_eq!(payments.len(), 3);
+        assert!(payments.iter().all(|p| p.paid && p.attempts == 1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_hop_does_not_hold_up_the_others() {
+        let wallet = MockLightningNode::failing(1);
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        let ctx = test_context(&wallet, 1, policy);
+        let hops: Vec<Relay> = (1..=3).map(|seed| hop_with_payment(&ctx.db, seed)).collect();
+        let due: Vec<(&Relay, usize)> = hops.iter().map(|hop| (hop, 1)).collect();
+
+        // The circuit gets rebuilt, but only after the other hops were paid and recorded
+        assert!(process_payments_for_relays(&ctx, &due, &mut HashSet::new(), "TEST")
+            .await
+            .is_err());
+        assert_eq!(wallet.paid.lock().unwrap().len(), 2);
+        let payments = ctx.db.lookup_payments_by_circuit("circ").unwrap();
+        assert_eq!(payments.iter().filter(|p| p.paid).count(), 2);
+        let failed: Vec<&Payment> = payments.iter().filter(|p| p.has_error).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 1);
+        assert!(failed[0].last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_budget_stops_payments() {
+        let wallet = MockLightningNode::default();
+        let mut ctx = test_context(&wallet, 1, RetryPolicy::default());
+        // Every payment reserves 1000 msats plus the 1000 msats minimum fee limit
+        ctx.budget = BudgetGuard::new(Budget {
+            daily_msats: Some(3500),
+            ..Budget::default()
+        });
+        let hops: Vec<Relay> = (1..=3).map(|seed| hop_with_payment(&ctx.db, seed)).collect();
+        let due: Vec<(&Relay, usize)> = hops.iter().map(|hop| (hop, 1)).collect();
+
+        let err = process_payments_for_relays(&ctx, &due, &mut HashSet::new(), "TEST")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("daily budget"));
+        assert_eq!(wallet.paid.lock().unwrap().len(), 2);
+        let unpaid = ctx.db.lookup_payment_by_id("hop3_round1".to_string()).unwrap().unwrap();
+        assert!(!unpaid.paid);
+        assert_eq!(unpaid.attempts, 0);
+        assert!(unpaid.last_error.unwrap().contains("BudgetExceeded"));
+    }
+
+    #[tokio::test]
+    async fn test_routing_fee_cap_moves_offers_to_bolt11() {
+        let wallet = MockLightningNode::default();
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            max_routing_fee_msats: Some(10),
+            ..RetryPolicy::default()
+        };
+        let ctx = test_context(&wallet, 1, policy);
+        let offer_only = hop_with_payment(&ctx.db, 1);
+        let mut with_address = hop_with_payment(&ctx.db, 2);
+        with_address.payment_bolt11_lightning_address = Some("hop2@relay.example".to_string());
+        // The invoice an earlier attempt fetched from the Lightning Address
+        ctx.db
+            .modify_payment("hop2_round1", |p| p.bolt11_invoice = Some("lnbc1hop2".to_string()))
+            .unwrap();
+        let due = vec![(&offer_only, 1), (&with_address, 1)];
+
+        let err = process_payments_for_relays(&ctx, &due, &mut HashSet::new(), "TEST")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("hop1"));
+        // pay_offer can't be held to the cap, only the BOLT 11 invoice is paid
+        assert_eq!(*wallet.paid.lock().unwrap(), vec!["lnbc1hop2"]);
+        let refused = ctx.db.lookup_payment_by_id("hop1_round1".to_string()).unwrap().unwrap();
+        assert!(!refused.paid);
+        assert!(refused.last_error.unwrap().contains("routing fee cap"));
+        let paid = ctx.db.lookup_payment_by_id("hop2_round1".to_string()).unwrap().unwrap();
+        assert!(paid.paid);
+        assert_eq!(paid.payment_method.as_deref(), Some("lightning_address"));
+    }
+
+    #[tokio::test]
+    async fn test_routing_fee_cap_needs_a_bolt11_wallet() {
+        let wallet = MockLightningNode::default();
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            max_routing_fee_msats: Some(10),
+            ..RetryPolicy::default()
+        };
+        let ctx = PaymentContext {
+            capabilities: PaymentCapabilities::for_node_type("phoenixd").with_overrides(None, Some(false)),
+            ..test_context(&wallet, 1, policy)
+        };
+        let mut with_address = hop_with_payment(&ctx.db, 1);
+        with_address.payment_bolt11_lightning_address = Some("hop1@relay.example".to_string());
+        let due = vec![(&with_address, 1)];
+
+        let err = process_payments_for_relays(&ctx, &due, &mut HashSet::new(), "TEST")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("hop1"));
+        assert!(wallet.paid.lock().unwrap().is_empty());
+        let refused = ctx.db.lookup_payment_by_id("hop1_round1".to_string()).unwrap().unwrap();
+        assert!(refused.last_error.unwrap().contains("wallet only pays BOLT 12"));
+    }
+
+    #[tokio::test]
+    async fn test_circuits_open_per_circuit() {
+        let mock = crate::rpc::mock_control_port::MockControlPort::start().await;
+        mock.add_circuit("11", "BUILT");
+        mock.add_circuit("12", "CLOSED");
+        let (built, closed, gone) = ("11".to_string(), "12".to_string(), "13".to_string());
+        assert_eq!(
+            circuits_open(&mock.rpc_config(), &[&built, &closed, &gone]).await,
+            vec![true, false, false]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_round_wait_ends_when_due() {
+        // Nothing listens on either port, so any check made would fail the wait
+        let rpc_config = crate::types::RpcConfig {
+            addr: "127.0.0.1:1".to_string(),
+            rpc_password: None,
+            command: "".to_string(),
+        };
+        let start = Instant::now();
+        assert!(wait_for_next_round_with_monitoring(&rpc_config, 1, chrono::Utc::now().timestamp()).await);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_paid_circuit_watched_until_unpaid_round() {
+        use crate::client::circuit::{build_circuit, pregen_extend_paid_circuit_hashes};
+        use crate::client::payments_sent_ledger::init_payments_sent_ledger;
+        use crate::client::select_relay_algo::simple_relay_selection_algo;
+        use crate::lightning::payment_method::PaymentCapabilities;
+        use crate::relay::{watch_payments, RelaySettings};
+        use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+        use std::sync::Arc;
+
+        // One control port serves the client and acts as the exit relay's Tor
+        let mock = MockControlPort::start().await;
+        for (nickname, seed) in [("guard", 1), ("middle", 2), ("exit", 3)] {
+            let mut relay = MockRelay::new(nickname, seed);
+            relay.payment_interval_seconds = Some(2);
+            relay.payment_interval_rounds = Some(2);
+            relay.extra_descriptor_lines.push("PaymentProtocolVersion 2".to_string());
+            mock.add_relay(relay);
+        }
+        let exit = MockRelay::new("exit", 3);
+        mock.set_getinfo("fingerprint", &exit.fingerprint);
+        mock.set_conf("PaymentRateMsats", "100");
+        mock.set_conf("PaymentInterval", "2");
+        mock.set_conf("PaymentIntervalRounds", "2");
+        mock.set_conf("PaymentBolt12Offer", exit.payment_bolt12_offer.as_deref().unwrap());
+        mock.emit_payment_events_for(&exit.fingerprint);
+        let rpc_config = mock.rpc_config();
+
+        // The exit watches its rounds with no grace period, both sides keep scratch ledgers
+        let sent_ledger = Db::new(database::test_ledger_path("payments_sent.db").display().to_string()).unwrap();
+        let received_ledger = Db::new(database::test_ledger_path("payments_received.db").display().to_string()).unwrap();
+        let relay_wallet = Arc::new(MockLightningNode::default());
+        let settings = RelaySettings {
+            grace_secs: 0,
+            clock_skew_secs: 0,
+            ..RelaySettings::default()
+        };
+        let (relay_rpc_config, watcher_wallet, watcher_ledger) =
+            (rpc_config.clone(), relay_wallet.clone(), received_ledger.clone());
+        tokio::spawn(async move {
+            let _ = watch_payments(&relay_rpc_config, watcher_wallet, settings, watcher_ledger).await;
+        });
+        assert!(mock.wait_for_subscription("PAYMENT_ID_HASH_RECEIVED", Duration::from_secs(5)).await);
+
+        // Select, build and wait for the circuit
+        let capabilities = PaymentCapabilities::for_node_type("phoenixd");
+        let mut relays = simple_relay_selection_algo(&rpc_config, &capabilities, &RetryPolicy::default())
+            .await
+            .unwrap();
+        pregen_extend_paid_circuit_hashes(&mut relays, 10);
+        let built_at = chrono::Utc::now().timestamp();
+        let circuit_id = build_circuit(&rpc_config, &relays, built_at).await.unwrap();
+        crate::rpc::wait_for_circuit_ready(&rpc_config, &circuit_id, 10).await.unwrap();
+        init_payments_sent_ledger(&sent_ledger, &relays, &circuit_id, 1, built_at).unwrap();
+
+        // Pay round 1 of every hop. The 2s rounds are inside the expiry padding
+        // process_payments_for_relays keeps, so the hops are paid directly.
+        let client_wallet = MockLightningNode::paying_into(relay_wallet.clone());
+        let ctx = PaymentContext {
+            db: sent_ledger,
+            ..test_context(&client_wallet, 3, RetryPolicy::default())
+        };
+        for relay in &relays {
+            let payment_id = relay.payment_id_hashes_10.as_ref().unwrap()[0].clone();
+            let mut payment = ctx.db.lookup_payment_by_id(payment_id).unwrap().unwrap();
+            assert!(pay_relay_with_retries(&ctx, &mut payment, relay).await.unwrap());
+        }
+
+        // The exit keeps the circuit through round 1 and tears it down once round 2 goes unpaid
+        let teardown = format!("TEARDOWNCIRCUIT {}", circuit_id);
+        assert!(mock.wait_for_command(&teardown, Duration::from_secs(10)).await);
+        assert!(chrono::Utc::now().timestamp() >= built_at + 4);
+        let exit_hop = relays.iter().find(|r| r.fingerprint == exit.fingerprint).unwrap();
+        let rounds: Vec<bool> = exit_hop.payment_id_hashes_10.as_ref().unwrap()[..2]
+            .iter()
+            .map(|id| received_ledger.lookup_payment_by_id(id.clone()).unwrap().unwrap().paid)
+            .collect();
+        assert_eq!(rounds, vec![true, false]);
+    }
 }
@@ -1,86 +1,102 @@
 use crate::database;
-use crate::types::Relay;
-use log::{error, info, warn};
+use crate::types::{PaymentMethod, Relay, RoundSchedule};
+use log::{info, warn};
 
-pub fn init_payments_sent_ledger(selected_relays: &Vec<Relay>, circuit_id: &String) {
+/// Rounds paid with one payment to `relay`: `prepaid_rounds` (`PAYMENT_PREPAID_ROUNDS`)
+/// for relays that accept prepaid rounds, otherwise every round is paid just in time
+fn prepaid_batch_rounds(relay: &Relay, prepaid_rounds: u32) -> u32 {
+    if relay.payment_prepaid {
+        prepaid_rounds.max(1)
+    } else {
+        1
+    }
+}
+
+/// Writes a row per hop and round. With prepaid rounds the first round of every batch
+/// carries the amount for the whole batch and the rounds it covers are written with 0 msats.
+/// Rounds expire on the schedule counted from the circuit's build timestamp `built_at`.
+pub fn init_payments_sent_ledger(
+    db: &database::Db,
+    selected_relays: &Vec<Relay>,
+    circuit_id: &String,
+    prepaid_rounds: u32,
+    built_at: i64,
+) -> Result<(), database::DbError> {
+    let mut rows = Vec::new();
     for relay in selected_relays.iter() {
+        // Each hop is paid on its own advertised cadence
+        let cadence = relay.payment_cadence();
+        let schedule = RoundSchedule::new(cadence, built_at);
+        let interval_seconds = cadence.interval_seconds as i64;
+        // Only record the advertised offer when the wallet negotiated to pay it
+        let bolt12_offer = match relay.payment_method {
+            Some(PaymentMethod::Bolt12Offer) | None => relay.payment_bolt12_offer.clone(),
+            Some(_) => None,
+        };
+        let batch = prepaid_batch_rounds(relay, prepaid_rounds) as i64;
+        let rounds = cadence.rounds as i64;
         let mut i = 1;
-        for payment_id_hash in relay.payment_id_hashes_10.clone().unwrap().iter() {
+        for payment_id_hash in relay
+            .payment_id_hashes_10
+            .clone()
+            .unwrap()
+            .iter()
+            .take(cadence.rounds as usize)
+        {
             let mut row = database::Payment {
                 payment_id: payment_id_hash.to_string(),
                 circ_id: circuit_id.to_string(),
-                interval_seconds: relay.payment_interval_seconds.unwrap_or(60) as i64,
+                interval_seconds,
                 round: i,
                 relay_fingerprint: relay.fingerprint.clone(),
                 updated_at: chrono::Utc::now().timestamp(),
-                amount_msat: relay.payment_rate_msats.unwrap_or(0) as i64,
+                amount_msat: batch_amount_msat(relay, i, batch, rounds),
                 handshake_fee_payhash: None,
                 handshake_fee_preimage: None,
                 paid: false,
-                expires_at: chrono::Utc::now().timestamp()
-                    + (relay.payment_interval_seconds.unwrap_or(60) as i64 * i), // expires now + 60 seconds for round 1, now + 120 seconds for round 2, etc
-                bolt11_invoice: None, // TODO implement
-                bolt12_offer: relay.payment_bolt12_offer.clone(), // TODO lookup payment preference from relay based on what capabilities your wallet has
+                expires_at: schedule.round_end(i as u32 - 1), // expires built_at + 1 interval for round 1, built_at + 2 intervals for round 2, etc
+                bolt11_invoice: None, // fetched from the relay's LNURL-pay endpoint when the round is paid
+                bolt12_offer: bolt12_offer.clone(),
                 payment_hash: None,
                 preimage: None,
                 fee: None,
                 has_error: false,
+                payment_method: relay.payment_method.map(|method| method.as_str().to_string()),
+                attempts: 0,
+                last_attempt_at: None,
+                last_error: None,
             };
             if i == 1 {
                 row.handshake_fee_payhash = relay.payment_handshake_fee_payhash.clone();
                 row.handshake_fee_preimage = relay.payment_handshake_fee_preimage.clone();
             }
-            // Create data folder if it doesn't exist
-            // TODO read from config file
-            if let Err(e) = std::fs::create_dir_all("data") {
-                error!("Failed to create data directory: {}", e);
-                continue;
-            }
-            // Create payments_sent.json file if it doesn't exist
-            let payments_sent_path = "data/payments_sent.json";
-            if !std::path::Path::new(payments_sent_path).exists() {
-                if let Err(e) = std::fs::File::create(payments_sent_path) {
-                    error!("Failed to create payments_sent.json: {}", e);
-                    continue;
-                }
-            }
-            let db = match database::Db::new(payments_sent_path.to_string()) {
-                Ok(db) => db,
-                Err(e) => {
-                    error!("Failed to load payments_sent ledger: {}. Creating backup and starting fresh...", e);
-                    // Backup the corrupted file
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs();
-                    let backup_path = format!("data/payments_sent.json.backup_{}", timestamp);
-                    if let Err(backup_err) = std::fs::copy(payments_sent_path, &backup_path) {
-                        warn!("Could not create backup: {}", backup_err);
-                    } else {
-                        info!("Corrupted database backed up to: {}", backup_path);
-                    }
-                    // Start with empty database
-                    if let Err(write_err) = std::fs::write(payments_sent_path, "[]") {
-                        error!("Failed to reset database file: {}", write_err);
-                        continue;
-                    }
-                    match database::Db::new(payments_sent_path.to_string()) {
-                        Ok(db) => db,
-                        Err(e2) => {
-                            error!("Failed to create fresh database: {}", e2);
-                            continue;
-                        }
-                    }
-                }
-            };
-            if let Err(e) = db.write_payment(row) {
-                error!("Failed to write payment to database: {}", e);
-            }
+            rows.push(row);
             i += 1;
         }
     }
+    // All rounds for the circuit are written in one transaction
+    db.write_payments(&rows)?;
+    // The handshake fees paid for the circuit were written when they were paid
+    for relay in selected_relays.iter().filter(|r| r.payment_handshake_fee.unwrap_or(0) > 0) {
+        if let Some(payhash) = relay.payment_handshake_fee_payhash.as_deref() {
+            if let Err(e) = db.modify_payment(payhash, |row| row.circ_id = circuit_id.to_string()) {
+                warn!("Failed to link the handshake fee paid to relay {} to circuit {}: {}", relay.nickname, circuit_id, e);
+            }
+        }
+    }
     info!(
         "Init row in payments sent ledger for circuit: {:?}",
         circuit_id
     );
+    Ok(())
+}
+
+/// Amount due in 1 based `round`: the rate for every round of the batch it starts
+/// (up to the last round), 0 for rounds paid by an earlier batch
+fn batch_amount_msat(relay: &Relay, round: i64, batch: i64, rounds: i64) -> i64 {
+    if (round - 1) % batch != 0 {
+        return 0;
+    }
+    let covered = batch.min(rounds - round + 1);
+    relay.payment_rate_msats.unwrap_or(0) as i64 * covered
 }
@@ -0,0 +1,68 @@
+use crate::database::{self, Payment};
+use log::warn;
+use std::collections::HashMap;
+use std::env;
+
+/// How far back the payments sent ledger is read for routing fee history
+const FEE_HISTORY_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Routing fees the client paid to reach each relay, from the payments sent ledger
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelayFeeHistory {
+    /// Relay fingerprint => (msats paid to the relay, msats paid in routing fees)
+    totals: HashMap<String, (i64, i64)>,
+}
+
+impl RelayFeeHistory {
+    pub fn from_payments(payments: &[Payment]) -> Self {
+        let mut totals = HashMap::new();
+        // Rounds covered by a prepaid batch have no amount or fee of their own
+        for payment in payments.iter().filter(|p| p.paid && p.amount_msat > 0) {
+            let (amount, fee) = totals.entry(payment.relay_fingerprint.clone()).or_insert((0, 0));
+            *amount += payment.amount_msat;
+            *fee += payment.fee.unwrap_or(0);
+        }
+        RelayFeeHistory { totals }
+    }
+
+    /// Reads the last 30 days of paid payments, no history if the ledger can't be read
+    pub fn load() -> Self {
+        let since = chrono::Utc::now().timestamp() - FEE_HISTORY_SECS;
+        match database::open_payments_sent_ledger().and_then(|db| db.payments_paid_since(since)) {
+            Ok(payments) => RelayFeeHistory::from_payments(&payments),
+            Err(e) => {
+                warn!("Failed to read routing fee history: {}", e);
+                RelayFeeHistory::default()
+            }
+        }
+    }
+
+    /// Routing fees paid to reach a relay, in percent of what it was paid
+    pub fn fee_percent(&self, fingerprint: &str) -> Option<f64> {
+        self.totals
+            .get(fingerprint)
+            .map(|(amount, fee)| *fee as f64 * 100.0 / *amount as f64)
+    }
+
+    /// Routing fee a `amount_msats` payment to the relay is expected to cost, 0 without history
+    pub fn expected_fee_msats(&self, fingerprint: &str, amount_msats: u32) -> u32 {
+        self.fee_percent(fingerprint)
+            .map(|percent| (amount_msats as f64 * percent / 100.0).ceil() as u32)
+            .unwrap_or(0)
+    }
+
+    /// Whether paying the relay has cost more than `threshold_percent` in routing fees
+    pub fn is_expensive(&self, fingerprint: &str, threshold_percent: f64) -> bool {
+        self.fee_percent(fingerprint)
+            .is_some_and(|percent| percent > threshold_percent)
+    }
+}
+
+/// Relays whose payments cost more than this percent in routing fees are only picked
+/// when there is no other choice
+pub fn get_high_routing_fee_percent() -> f64 {
+    env::var("PAYMENT_HIGH_ROUTING_FEE_PERCENT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5.0)
+}
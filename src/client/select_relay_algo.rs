@@ -1,3 +1,8 @@
+use super::payment_retry::RetryPolicy;
+use super::routing_fees::{get_high_routing_fee_percent, RelayFeeHistory};
+use crate::lightning::payment_method::{
+    can_pay_relay, can_pay_relay_within_fee_cap, negotiate_payment_method, PaymentCapabilities,
+};
 use crate::rpc;
 use crate::types::{ConsensusRelay, RelayTag};
 use crate::types::{Relay, RpcConfig};
@@ -9,26 +14,40 @@ use std::error::Error;
 use std::sync::{Arc, Mutex};
 
 // Simple Relay Selection Algo
-// 1. Pick 3 relays, 1 entry, 1 middle, 1 exit at random
-// 2. Make sure the total amount is under the PaymentCircuitMaxFee (from torrc config)
-// 3. Prefer 0 handshake fee
+// 1. Pick 3 relays, 1 entry, 1 middle, 1 exit at random, among relays the wallet can pay
+//    (over BOLT 11 when `retry_policy` has a hard routing fee cap)
+// 2. Make sure the total amount (handshake fees + every round + expected routing fees) is under the PaymentCircuitMaxFee (from torrc config)
+// 3. Relays that historically cost a lot in routing fees are only picked when there is no other choice
 // TODO optimize this algo as more relays are added (not currently optimized)
 pub async fn simple_relay_selection_algo(
     rpc_config: &RpcConfig,
+    capabilities: &PaymentCapabilities,
+    retry_policy: &RetryPolicy,
 ) -> Result<Vec<Relay>, Box<dyn Error>> {
-    let relays = rpc::get_relay_descriptors(&rpc_config).await.unwrap();
+    let mut relays = rpc::get_relay_descriptors(&rpc_config).await.unwrap();
+    for relay in relays.iter_mut() {
+        relay.payment_method = negotiate_payment_method(relay, capabilities);
+    }
     
     let payment_circuit_max_fee = rpc::get_conf_payment_circuit_max_fee(&rpc_config)
         .await
         .unwrap_or(11000);
     info!("PaymentCircuitMaxFee: {}", payment_circuit_max_fee);
+    let fee_history = RelayFeeHistory::load();
 
-    // Filter out relays with a handshake fee
-    // TODO implement handshake fee budget
+    // Skip relays that charge but offer no payment method the wallet supports, or only
+    // offers when the routing fee is capped
+    let fee_capped = retry_policy.has_routing_fee_cap();
     let filtered_relays: Vec<&Relay> = relays
         .iter()
-        .filter(|relay| relay.payment_handshake_fee.is_none())
+        .filter(|relay| can_pay_relay(relay) && (!fee_capped || can_pay_relay_within_fee_cap(relay, capabilities)))
         .collect();
+    debug!(
+        "{} of {} relays payable with wallet capabilities {:?}",
+        filtered_relays.len(),
+        relays.len(),
+        capabilities
+    );
 
     // Get consensus relays
     let consensus_relays = rpc::get_current_consensus(&rpc_config).await.unwrap();
@@ -68,6 +87,7 @@ pub async fn simple_relay_selection_algo(
     // Try to find a circuit within fee limits
     select_circuit_within_fee_limit(
         payment_circuit_max_fee as u32,
+        &fee_history,
         guard_relays,
         middle_relays,
         exit_relays,
@@ -92,7 +112,7 @@ fn categorize_relays<'a>(
     let mut exit_relays = Vec::new();
 
     for relay in consensus_relays {
-        // Check if relay is in our filtered list (no handshake fee)
+        // Check if relay is in our filtered list
         let is_available = filtered_relays
             .iter()
             .any(|r| r.fingerprint == relay.fingerprint);
@@ -122,6 +142,7 @@ fn categorize_relays<'a>(
 /// Strategy: First select random circuit, then apply EntryNodes/ExitNodes preferences
 fn select_circuit_within_fee_limit(
     max_fee: u32,
+    fee_history: &RelayFeeHistory,
     mut guard_relays: Vec<&ConsensusRelay>,
     mut middle_relays: Vec<&ConsensusRelay>,
     mut exit_relays: Vec<&ConsensusRelay>,
@@ -132,6 +153,7 @@ fn select_circuit_within_fee_limit(
 ) -> Result<Vec<Relay>, Box<dyn Error>> {
     const MAX_RETRIES: u32 = 10;
     let rng = Arc::new(Mutex::new(SmallRng::from_entropy()));
+    let high_fee_percent = get_high_routing_fee_percent();
 
     for attempt in 1..=MAX_RETRIES {
         debug!("Relay selection attempt {}/{}", attempt, MAX_RETRIES);
@@ -143,6 +165,9 @@ fn select_circuit_within_fee_limit(
             middle_relays.shuffle(&mut *rng);
             exit_relays.shuffle(&mut *rng);
         }
+        deprioritize_expensive_relays(&mut guard_relays, fee_history, high_fee_percent);
+        deprioritize_expensive_relays(&mut middle_relays, fee_history, high_fee_percent);
+        deprioritize_expensive_relays(&mut exit_relays, fee_history, high_fee_percent);
 
         // Try to pick one of each type
         let selected_consensus = match select_three_relays(
@@ -202,7 +227,7 @@ fn select_circuit_within_fee_limit(
         }
 
         // Check fee limit (after applying preferences)
-        if !is_circuit_under_max_fee(max_fee, &matched_relays) {
+        if !is_circuit_under_max_fee(max_fee, &matched_relays, fee_history) {
             debug!("Circuit exceeds maximum fee on attempt {}, retrying...", attempt);
             continue;
         }
@@ -229,6 +254,11 @@ fn select_circuit_within_fee_limit(
     Ok(Vec::new())
 }
 
+/// Moves relays with high routing fees to the back, keeping the shuffled order otherwise
+fn deprioritize_expensive_relays(relays: &mut [&ConsensusRelay], fee_history: &RelayFeeHistory, high_fee_percent: f64) {
+    relays.sort_by_key(|relay| fee_history.is_expensive(&relay.fingerprint, high_fee_percent));
+}
+
 /// Selects one guard, one middle, and one exit relay (ensuring no duplicates)
 fn select_three_relays<'a>(
     guard_relays: &[&'a ConsensusRelay],
@@ -270,28 +300,34 @@ fn tag_circuit_relays(relays: &mut [Relay]) {
     }
 }
 
-/// Checks if 10 rounds of payments for the selected relays do not exceed the max_fee
+/// Checks if paying every relay's handshake fee plus all of its advertised rounds
+/// (`PaymentIntervalRounds`, default 10) and the routing fees those rounds are expected
+/// to cost does not exceed the max_fee
 ///
 /// # Arguments
 /// * `max_fee` - Maximum fee allowed for the circuit in millisatoshis
 /// * `selected_relays` - Vector of relays in the circuit
+/// * `fee_history` - Routing fees previously paid to reach each relay
 ///
 /// # Returns
-/// * `true` if the total cost for 10 rounds is under or equal to max_fee
+/// * `true` if the total cost over the circuit lifetime is under or equal to max_fee
 /// * `false` if the total cost exceeds max_fee
-fn is_circuit_under_max_fee(max_fee: u32, selected_relays: &[Relay]) -> bool {
-    let rounds = 10;
+fn is_circuit_under_max_fee(max_fee: u32, selected_relays: &[Relay], fee_history: &RelayFeeHistory) -> bool {
     let mut total_cost = 0u32;
 
     for relay in selected_relays {
         // Get the payment rate per round for this relay
         let payment_rate = relay.payment_rate_msats.unwrap_or(0);
+        let rounds = relay.payment_cadence().rounds;
+        let routing_fee = fee_history.expected_fee_msats(&relay.fingerprint, payment_rate);
 
-        // Add the cost for 10 rounds of this relay
-        total_cost = total_cost.saturating_add(payment_rate.saturating_mul(rounds));
+        // Add the handshake fee and the cost for all rounds of this relay, routing fees included
+        total_cost = total_cost
+            .saturating_add(relay.payment_handshake_fee.unwrap_or(0))
+            .saturating_add(payment_rate.saturating_add(routing_fee).saturating_mul(rounds));
 
         // Early exit if we've already exceeded the max fee
-        if total_cost >= max_fee {
+        if total_cost > max_fee {
             debug!(
                 "Circuit exceeds max fee: {} msats > {} msats (relay: {})",
                 total_cost, max_fee, relay.nickname
@@ -301,11 +337,143 @@ fn is_circuit_under_max_fee(max_fee: u32, selected_relays: &[Relay]) -> bool {
     }
 
     debug!(
-        "Circuit total cost for {} rounds: {} msats (max: {} msats)",
-        rounds, total_cost, max_fee
+        "Circuit total cost: {} msats (max: {} msats)",
+        total_cost, max_fee
     );
 
     total_cost <= max_fee
 }
 
 // TODO: implement more complicated relay selection algos
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+
+    #[tokio::test]
+    async fn test_handshake_fee_counts_toward_max_fee() {
+        let mock = MockControlPort::start().await;
+        let mut relay = MockRelay::new("fee", 1);
+        relay.payment_rate_msats = Some(1000);
+        relay.payment_handshake_fee = Some(500);
+        mock.add_relay(relay);
+        let relays = rpc::get_relay_descriptors(&mock.rpc_config()).await.unwrap();
+
+        // 10 rounds of 1000 msats plus the 500 msats handshake fee
+        let no_history = RelayFeeHistory::default();
+        assert!(is_circuit_under_max_fee(10500, &relays, &no_history));
+        assert!(!is_circuit_under_max_fee(10499, &relays, &no_history));
+
+        // Routing fees of 2% seen before add 20 msats to every round
+        let mut paid = crate::database::test_payment("1", "1", 1);
+        paid.relay_fingerprint = relays[0].fingerprint.clone();
+        paid.amount_msat = 5000;
+        paid.fee = Some(100);
+        paid.paid = true;
+        let fee_history = RelayFeeHistory::from_payments(&[paid]);
+        assert_eq!(fee_history.fee_percent(&relays[0].fingerprint), Some(2.0));
+        assert!(is_circuit_under_max_fee(10700, &relays, &fee_history));
+        assert!(!is_circuit_under_max_fee(10699, &relays, &fee_history));
+    }
+
+    #[tokio::test]
+    async fn test_expensive_relays_picked_last() {
+        let mock = MockControlPort::start().await;
+        for (nickname, seed) in [("cheap", 1), ("pricey", 2), ("unknown", 3)].iter() {
+            mock.add_relay(MockRelay::new(nickname, *seed));
+        }
+        let relays = rpc::get_relay_descriptors(&mock.rpc_config()).await.unwrap();
+        let consensus = rpc::get_current_consensus(&mock.rpc_config()).await.unwrap();
+        let fingerprint = |nickname: &str| relays.iter().find(|r| r.nickname == nickname).unwrap().fingerprint.clone();
+
+        let payments: Vec<_> = [("cheap", 10), ("pricey", 500)]
+            .iter()
+            .map(|(nickname, fee)| {
+                let mut paid = crate::database::test_payment(nickname, "1", 1);
+                paid.relay_fingerprint = fingerprint(nickname);
+                paid.amount_msat = 1000;
+                paid.fee = Some(*fee);
+                paid.paid = true;
+                paid
+            })
+            .collect();
+        let fee_history = RelayFeeHistory::from_payments(&payments);
+        assert!(fee_history.is_expensive(&fingerprint("pricey"), 5.0));
+        assert!(!fee_history.is_expensive(&fingerprint("unknown"), 5.0));
+
+        let relay_refs: Vec<&Relay> = relays.iter().collect();
+        let pool: Vec<&ConsensusRelay> = consensus.iter().collect();
+        for _ in 0..5 {
+            let selected = select_circuit_within_fee_limit(
+                u32::MAX,
+                &fee_history,
+                pool.clone(),
+                pool.clone(),
+                pool.clone(),
+                &relay_refs,
+                &consensus,
+                None,
+                None,
+            )
+            .unwrap();
+            // Three hops out of three relays, the expensive one always gets the last pick
+            assert_eq!(selected[2].nickname, "pricey");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skips_relays_the_wallet_cannot_pay() {
+        let mock = MockControlPort::start().await;
+        mock.add_relay(MockRelay::new("guard", 1));
+        mock.add_relay(MockRelay::new("middle", 2));
+        let mut exit = MockRelay::new("exit", 3);
+        exit.extra_descriptor_lines
+            .push("PaymentBolt11LightningAddress exit@example.com".to_string());
+        mock.add_relay(exit);
+        let rpc_config = mock.rpc_config();
+
+        // A BOLT 11 only wallet can't pay the two relays that only advertise an offer
+        let lnd = PaymentCapabilities::for_node_type("lnd");
+        let no_cap = RetryPolicy::default();
+        assert!(simple_relay_selection_algo(&rpc_config, &lnd, &no_cap).await.unwrap().is_empty());
+
+        let phoenixd = PaymentCapabilities::for_node_type("phoenixd");
+        let relays = simple_relay_selection_algo(&rpc_config, &phoenixd, &no_cap).await.unwrap();
+        assert_eq!(relays.len(), 3);
+        assert!(relays
+            .iter()
+            .all(|r| r.payment_method == Some(crate::types::PaymentMethod::Bolt12Offer)));
+    }
+
+    #[tokio::test]
+    async fn test_routing_fee_cap_skips_offer_only_relays() {
+        let mock = MockControlPort::start().await;
+        for (nickname, seed) in [("guard", 1), ("middle", 2), ("exit", 3), ("offer", 4)] {
+            let mut relay = MockRelay::new(nickname, seed);
+            if nickname != "offer" {
+                relay
+                    .extra_descriptor_lines
+                    .push(format!("PaymentBolt11LightningAddress {}@example.com", nickname));
+            }
+            mock.add_relay(relay);
+        }
+        let rpc_config = mock.rpc_config();
+        let capped = RetryPolicy {
+            max_routing_fee_msats: Some(10),
+            ..RetryPolicy::default()
+        };
+
+        // The relay that only takes an offer can't be held to the cap
+        let phoenixd = PaymentCapabilities::for_node_type("phoenixd");
+        for _ in 0..10 {
+            let relays = simple_relay_selection_algo(&rpc_config, &phoenixd, &capped).await.unwrap();
+            assert_eq!(relays.len(), 3);
+            assert!(relays.iter().all(|r| r.nickname != "offer"));
+        }
+
+        // Nor can any relay when the wallet can't pay BOLT 11
+        let offers_only = phoenixd.with_overrides(None, Some(false));
+        assert!(simple_relay_selection_algo(&rpc_config, &offers_only, &capped).await.unwrap().is_empty());
+    }
+}
@@ -1,7 +1,12 @@
+use super::budget::{Budget, BudgetEvent, BudgetExhaustedAction, BudgetGuard};
 use super::circuit;
+use super::handshake_fee;
+use super::payment_retry::RetryPolicy;
 use super::payments_sent_ledger;
 use super::select_relay_algo;
 use crate::client::payments_loop;
+use crate::database;
+use crate::lightning::bip353;
 use crate::rpc::{wait_for_tor_bootstrap, wait_for_circuit_ready};
 use crate::types::RpcConfig;
 use crate::{client_info, client_warn};
@@ -12,7 +17,7 @@ use std::env;
 /// This function performs the following steps:
 /// 1. Wait for Tor Bootstrap
 /// 2. Relay Descriptor Lookup
-/// 3. Handshake Fee (currently skipped)
+/// 3. Pay the Handshake Fee of relays that require one
 /// 4. Pre-generate payment ID hashes for the circuit
 /// 5. Circuit build
 /// 6. Initialize Payments Ledger
@@ -31,6 +36,8 @@ use std::env;
 /// - Bootstrap detection uses the Tor control protocol's `GETINFO status/bootstrap-phase` command
 /// - Tor automatically refreshes consensus hourly in the background (no user impact)
 /// - The number of payment rounds is determined by the `PAYMENT_INTERVAL_ROUNDS` environment variable, defaulting to 10 if not set.
+/// - Relays advertising prepaid rounds (see `rpc::advertise_in_contact_info`) are paid `PAYMENT_PREPAID_ROUNDS` rounds at a time (default 1, just in time).
+/// - No circuit is built while a `BUDGET_*` spending limit is used up, see `wait_for_budget`.
 /// - The function selects relays using a simple relay selection algorithm and builds a circuit with the selected relays.
 /// - A backup circuit is planned but not yet implemented.
 /// - Bandwidth testing and client bandwidth watcher are placeholders for future implementation.
@@ -39,7 +46,9 @@ pub async fn start_client_flow(rpc_config: &RpcConfig) -> tokio::task::JoinHandl
     let rpc_config = rpc_config.clone();
     
     tokio::spawn(async move {
+        let budget = Budget::from_env();
         loop {
+            wait_for_budget(&rpc_config, &budget).await;
             let next = client_flow_impl(&rpc_config).await;
             if next {
                 client_info!("Next Circuit...");
@@ -52,6 +61,51 @@ pub async fn start_client_flow(rpc_config: &RpcConfig) -> tokio::task::JoinHandl
     })
 }
 
+/// Pauses paid circuits while a spending budget is used up, checking again every
+/// `recheck_secs` or when the oldest payment counted leaves its window. Emits a
+/// `budget_exhausted` event when the pause starts and `budget_available` when it ends.
+async fn wait_for_budget(rpc_config: &RpcConfig, budget: &Budget) {
+    let mut paused = false;
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        let exceeded = match database::open_payments_sent_ledger().and_then(|db| budget.exhausted(&db, now)) {
+            Ok(exceeded) => exceeded,
+            Err(e) => {
+                client_warn!("Failed to check the spending budget: {}", e);
+                None
+            }
+        };
+        let exceeded = match exceeded {
+            Some(exceeded) => exceeded,
+            None => {
+                if paused {
+                    client_info!("💰 Spending budget available again, resuming paid circuits");
+                    BudgetEvent::BudgetAvailable.emit();
+                }
+                return;
+            }
+        };
+
+        if !paused {
+            client_warn!(
+                "💸 {}. Pausing paid circuits for up to {}s",
+                exceeded,
+                exceeded.frees_up_at - now
+            );
+            BudgetEvent::exhausted(&exceeded, budget.on_exhausted).emit();
+            if budget.on_exhausted == BudgetExhaustedAction::FreeCircuits {
+                match crate::rpc::disable_manual_stream_attachment(rpc_config).await {
+                    Ok(()) => client_info!("Streams fall back to free Tor circuits until the budget frees up"),
+                    Err(e) => client_warn!("Failed to fall back to free Tor circuits: {}", e),
+                }
+            }
+            paused = true;
+        }
+        let wait_secs = (exceeded.frees_up_at - now).clamp(1, budget.recheck_secs.max(1) as i64);
+        tokio::time::sleep(tokio::time::Duration::from_secs(wait_secs as u64)).await;
+    }
+}
+
 async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     // loop {
     
@@ -82,22 +136,49 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     }
     client_info!("Tor ready to build circuits.");
 
-    let lightning_wallet = match crate::lightning::load_wallet(&rpc_config).await {
-        Ok(wallet) => std::sync::Arc::new(wallet),
+    let wallet_pool = match crate::lightning::load_wallet_pool(&rpc_config).await {
+        // maxSpendMsats counts what every wallet paid before, restarts included
+        Ok(pool) => match database::open_payments_sent_ledger() {
+            Ok(db) => pool.with_spend_ledger(db),
+            Err(e) => {
+                client_warn!("Failed to open payments sent ledger, wallet spending caps start from 0: {}", e);
+                pool
+            }
+        },
         Err(e) => {
             client_warn!("Failed to load Lightning wallet: {}. Client will continue without Lightning functionality.", e);
             client_warn!("To fix this, update the PaymentLightningNodeConfig in your torrc file with valid Lightning node credentials");
             return false; // Retry immediately
         }
     };
+    // Only relays offering a payment method one of the wallets supports are selected
+    let capabilities = wallet_pool.capabilities();
+    client_info!("Wallet payment capabilities: {:?}", capabilities);
+    let lightning_wallet: std::sync::Arc<Box<dyn lni::LightningNode + Send + Sync>> =
+        std::sync::Arc::new(Box::new(wallet_pool));
 
     let payment_rounds: u16 = env::var("PAYMENT_INTERVAL_ROUNDS")
         .unwrap_or(10.to_string())
         .parse()
         .unwrap();
 
+    // Rounds paid at once to relays that accept prepaid rounds, 1 pays every round just in time
+    let prepaid_rounds: u32 = env::var("PAYMENT_PREPAID_ROUNDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+
+    // Resolve relays' BIP-353 payment names over Tor
+    let socks_port = crate::rpc::get_socks_port(rpc_config).await;
+    match bip353::DohTxtResolver::new(bip353::DEFAULT_DOH_URL, Some(socks_port)) {
+        Ok(backend) => bip353::set_bip353_dns_backend(std::sync::Arc::new(backend)),
+        Err(e) => client_warn!("Failed to set up BIP-353 DNS over Tor: {}", e),
+    }
+
     // 2. Relay Descriptor Lookup
-    let mut selected_relays = match select_relay_algo::simple_relay_selection_algo(&rpc_config).await {
+    // With a hard routing fee cap only relays payable over BOLT 11 are selected
+    let retry_policy = RetryPolicy::from_env();
+    let mut selected_relays = match select_relay_algo::simple_relay_selection_algo(&rpc_config, &capabilities, &retry_policy).await {
         Ok(relays) => relays,
         Err(e) => {
             client_warn!("Failed to select relays: {}. Retrying...", e);
@@ -117,7 +198,7 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
 
     // 2b. Build backup circuit with different relays
     client_info!("Selecting relays for backup circuit...");
-    let mut backup_selected_relays = match select_relay_algo::simple_relay_selection_algo(&rpc_config).await {
+    let mut backup_selected_relays = match select_relay_algo::simple_relay_selection_algo(&rpc_config, &capabilities, &retry_policy).await {
         Ok(relays) => relays,
         Err(e) => {
             client_warn!("Failed to select backup relays: {}. Continuing with primary circuit only.", e);
@@ -131,7 +212,37 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
         client_info!("Backup circuit relays: {:?}", &backup_selected_relays);
     }
 
-    // 3. Handshake Fee (simple algo is 0, so skip for now)
+    // 3. Handshake Fee: pay relays that advertise a PaymentHandshakeFee before EXTENDPAIDCIRCUIT
+    // The fees count toward the BUDGET_* spending limits like every round
+    let sent_ledger = match database::open_payments_sent_ledger() {
+        Ok(db) => db,
+        Err(e) => {
+            client_warn!("Failed to open payments ledger: {}. Retrying...", e);
+            return false;
+        }
+    };
+    let handshake_budget = BudgetGuard::new(Budget::from_env());
+    match handshake_fee::pay_handshake_fees(&**lightning_wallet, &sent_ledger, &handshake_budget, &mut selected_relays, &retry_policy, &capabilities, Some(socks_port)).await {
+        Ok(0) => {}
+        Ok(paid_msats) => client_info!("Paid {} msats in handshake fees for the primary circuit", paid_msats),
+        Err(e) => {
+            client_warn!("Failed to pay handshake fees: {}. Retrying...", e);
+            // The handshakes paid before the failure are reused by the next attempt
+            handshake_fee::release_unused_handshakes(&selected_relays);
+            return false; // Retry immediately
+        }
+    }
+    if !backup_selected_relays.is_empty() {
+        match handshake_fee::pay_handshake_fees(&**lightning_wallet, &sent_ledger, &handshake_budget, &mut backup_selected_relays, &retry_policy, &capabilities, Some(socks_port)).await {
+            Ok(0) => {}
+            Ok(paid_msats) => client_info!("Paid {} msats in handshake fees for the backup circuit", paid_msats),
+            Err(e) => {
+                client_warn!("Failed to pay backup handshake fees: {}. Continuing with primary circuit only.", e);
+                handshake_fee::release_unused_handshakes(&backup_selected_relays);
+                backup_selected_relays.clear();
+            }
+        }
+    }
 
     // 4. Pregenerate payment id hashes for the circuit
     // TODO for bolt11 get a real payment hash from the invoice via the lightning node, like LND
@@ -143,12 +254,16 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     }
 
     // 5. Circuit build
-    // EXTENDPAIDCIRCUIT
-    let circuit_id = circuit::build_circuit(&rpc_config, &selected_relays).await;
+    // EXTENDPAIDCIRCUIT, the relays count payment rounds from our build timestamp
+    let built_at = chrono::Utc::now().timestamp();
+    let circuit_id = circuit::build_circuit(&rpc_config, &selected_relays, built_at).await;
     let circuit_id = match circuit_id {
         Ok(id) => id,
         Err(e) => {
             client_warn!("Failed to build primary circuit: {}. Retrying...", e);
+            // Tor refused EXTENDPAIDCIRCUIT, no relay saw the handshakes
+            handshake_fee::release_unused_handshakes(&selected_relays);
+            handshake_fee::release_unused_handshakes(&backup_selected_relays);
             return false; // Retry immediately
         }
     };
@@ -162,13 +277,16 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     client_info!("Waiting for circuit {} to be fully built...", circuit_id);
     if let Err(e) = wait_for_circuit_ready(&rpc_config, &circuit_id, 30).await {
         client_warn!("Primary circuit {} failed to build: {}. Retrying...", circuit_id, e);
+        // Relays on the path may have used their handshakes, the backup circuit's are unused
+        handshake_fee::release_unused_handshakes(&backup_selected_relays);
         return false; // Retry immediately
     }
 
     // 5b. Build backup circuit if we have backup relays selected
+    let backup_built_at = chrono::Utc::now().timestamp();
     let backup_circuit_id = if !backup_selected_relays.is_empty() {
         client_info!("Building backup circuit...");
-        match circuit::build_circuit(&rpc_config, &backup_selected_relays).await {
+        match circuit::build_circuit(&rpc_config, &backup_selected_relays, backup_built_at).await {
             Ok(backup_id) => {
                 client_info!("Created backup Circuit with ID: {}", backup_id);
                 client_info!("Waiting for backup circuit {} to be fully built...", backup_id);
@@ -185,6 +303,7 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
             }
             Err(e) => {
                 client_warn!("Failed to build backup circuit: {}. Continuing with primary only.", e);
+                handshake_fee::release_unused_handshakes(&backup_selected_relays);
                 None
             }
         }
@@ -193,13 +312,22 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     };
 
     // 6. Init Payments Ledger for both circuits
-    payments_sent_ledger::init_payments_sent_ledger(&selected_relays, &circuit_id);
-    if let Some(ref backup_id) = backup_circuit_id {
-        payments_sent_ledger::init_payments_sent_ledger(&backup_selected_relays, backup_id);
+    if let Err(e) = payments_sent_ledger::init_payments_sent_ledger(&sent_ledger, &selected_relays, &circuit_id, prepaid_rounds, built_at) {
+        client_warn!("Failed to write payments ledger for circuit {}: {}. Retrying...", circuit_id, e);
+        return false;
     }
+    let backup_circuit_id = match backup_circuit_id {
+        Some(backup_id) => match payments_sent_ledger::init_payments_sent_ledger(&sent_ledger, &backup_selected_relays, &backup_id, prepaid_rounds, backup_built_at) {
+            Ok(()) => Some(backup_id),
+            Err(e) => {
+                client_warn!("Failed to write payments ledger for backup circuit {}: {}. Continuing with primary only.", backup_id, e);
+                None
+            }
+        },
+        None => None,
+    };
 
     // 7. Start Payments Loop with Round-Robin Load Balancing
-    let socks_port = crate::rpc::get_socks_port(rpc_config).await;
     client_info!("Using SOCKS port {} for bandwidth testing", socks_port);
     client_info!("✅ Primary circuit {} is BUILT and ready for traffic!", circuit_id);
     if backup_circuit_id.is_some() {
@@ -215,11 +343,18 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
         // Pass circuit IDs to payment loop - it will start stream monitor AFTER first bandwidth check
         let result = payments_loop::start_payments_loop_round_robin(
             rpc_config,
-            &selected_relays,
-            &circuit_id,
-            &backup_selected_relays,
-            &backup_id,
+            payments_loop::PaidCircuit {
+                relays: &selected_relays,
+                circuit_id: &circuit_id,
+                built_at,
+            },
+            payments_loop::PaidCircuit {
+                relays: &backup_selected_relays,
+                circuit_id: &backup_id,
+                built_at: backup_built_at,
+            },
             lightning_wallet,
+            capabilities,
             socks_port,
         )
         .await;
@@ -240,9 +375,13 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
         
         let payment_loop_result = payments_loop::start_payments_loop(
             rpc_config,
-            &selected_relays,
-            &circuit_id,
+            payments_loop::PaidCircuit {
+                relays: &selected_relays,
+                circuit_id: &circuit_id,
+                built_at,
+            },
             lightning_wallet,
+            capabilities,
             socks_port,
         )
         .await;
@@ -266,3 +405,24 @@ async fn client_flow_impl(rpc_config: &RpcConfig) -> bool {
     // }
     //}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+
+    #[tokio::test]
+    async fn test_client_flow_retries_without_wallet_config() {
+        let mock = MockControlPort::start().await;
+        mock.add_relay(MockRelay::new("relay1", 1));
+        // Bootstrap also waits for a general purpose circuit
+        mock.add_circuit("100", "BUILT");
+
+        assert!(!client_flow_impl(&mock.rpc_config()).await);
+
+        let commands = mock.commands();
+        assert!(commands.iter().any(|c| c == "GETINFO status/bootstrap-phase"));
+        assert!(commands.iter().any(|c| c == "GETCONF PaymentLightningNodeConfig"));
+        assert!(!commands.iter().any(|c| c.starts_with("+EXTENDPAIDCIRCUIT")));
+    }
+}
@@ -0,0 +1,77 @@
+use super::{Db, DbError, Payment};
+use std::path::{Path, PathBuf};
+
+/// Imports a ledger written by older releases (a JSON array of `Payment`s)
+/// into `db`. Once imported the file is renamed to `<name>.imported` so the
+/// import only ever runs once. Payments already present in `db` are kept.
+///
+/// A JSON ledger that can't be parsed is left untouched and reported as an
+/// error instead of being reset.
+pub fn import_json_ledger(db: &Db, json_path: &Path) -> Result<usize, DbError> {
+    if !json_path.exists() {
+        return Ok(0);
+    }
+    let contents = std::fs::read_to_string(json_path).map_err(|e| DbError::IoErr {
+        reason: format!("{}: {}", json_path.display(), e),
+    })?;
+    let payments: Vec<Payment> = if contents.trim().is_empty() {
+        Vec::new()
+    } else {
+        serde_json::from_str(&contents).map_err(|e| DbError::DeserializationErr {
+            reason: format!("{}: {}", json_path.display(), e),
+        })?
+    };
+
+    let imported = db.import_payments(&payments)?;
+    std::fs::rename(json_path, imported_path(json_path)).map_err(|e| DbError::IoErr {
+        reason: format!("{}: {}", json_path.display(), e),
+    })?;
+    Ok(imported)
+}
+
+fn imported_path(json_path: &Path) -> PathBuf {
+    let mut name = json_path.as_os_str().to_owned();
+    name.push(".imported");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{test_ledger_path, test_payment, SqliteStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_import_json_ledger_once() {
+        let db = Db::with_store(Arc::new(SqliteStore::open_in_memory().unwrap()));
+        let json_path = test_ledger_path("payments_sent.json");
+        // Older builds could write the same payment twice, the first copy wins
+        let mut duplicate = test_payment("1", "9", 1);
+        duplicate.paid = true;
+        let legacy = vec![test_payment("1", "9", 1), test_payment("2", "9", 2), duplicate];
+        std::fs::write(&json_path, serde_json::to_string_pretty(&legacy).unwrap()).unwrap();
+
+        assert_eq!(import_json_ledger(&db, &json_path).unwrap(), 2);
+        assert!(!json_path.exists());
+        assert!(imported_path(&json_path).exists());
+        assert!(!db.lookup_payment_by_id("1".to_string()).unwrap().unwrap().paid);
+
+        // Nothing left to import on the next start
+        assert_eq!(import_json_ledger(&db, &json_path).unwrap(), 0);
+        let _ = std::fs::remove_file(imported_path(&json_path));
+    }
+
+    #[test]
+    fn test_corrupt_json_ledger_is_not_reset() {
+        let db = Db::with_store(Arc::new(SqliteStore::open_in_memory().unwrap()));
+        let json_path = test_ledger_path("payments_received.json");
+        std::fs::write(&json_path, "[{\"payment_id\":").unwrap();
+
+        assert!(matches!(
+            import_json_ledger(&db, &json_path),
+            Err(DbError::DeserializationErr { .. })
+        ));
+        assert_eq!(std::fs::read_to_string(&json_path).unwrap(), "[{\"payment_id\":");
+        let _ = std::fs::remove_file(json_path);
+    }
+}
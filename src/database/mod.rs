@@ -0,0 +1,337 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use thiserror::Error;
+
+mod json_import;
+mod report;
+mod sqlite_store;
+
+pub use json_import::*;
+pub use report::*;
+pub use sqlite_store::*;
+
+/// Data directory used when neither `--data-dir`, `ELTOR_DATA_DIR` nor
+/// Tor's `DataDirectory` is configured (relative to the working directory)
+pub const DEFAULT_DATA_DIR: &str = "data";
+/// Ledger of payments the client sends to relays
+pub const PAYMENTS_SENT_LEDGER: &str = "payments_sent.db";
+/// Ledger of payments a relay expects to receive from clients
+pub const PAYMENTS_RECEIVED_LEDGER: &str = "payments_received.db";
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("IoError: {reason}")]
+    IoErr { reason: String },
+    #[error("SerializationError: {reason}")]
+    SerializationErr { reason: String },
+    #[error("DeserializationError: {reason}")]
+    DeserializationErr { reason: String },
+    #[error("StoreError: {reason}")]
+    StoreErr { reason: String },
+    #[error("MigrationError: {reason}")]
+    MigrationErr { reason: String },
+    #[error("NotFound: {reason}")]
+    NotFoundErr { reason: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Payment {
+    pub payment_id: String,
+    pub circ_id: String,
+    pub interval_seconds: i64,
+    pub round: i64,
+    pub relay_fingerprint: String,
+    pub updated_at: i64,
+    pub amount_msat: i64,
+    pub handshake_fee_payhash: Option<String>,
+    pub handshake_fee_preimage: Option<String>,
+    pub paid: bool,
+    pub expires_at: i64,
+    pub bolt11_invoice: Option<String>,
+    pub bolt12_offer: Option<String>,
+    pub payment_hash: Option<String>,
+    pub preimage: Option<String>,
+    pub fee: Option<i64>,
+    pub has_error: bool,
+    /// `PaymentMethod` negotiated for the relay, e.g. `bolt12_offer` or `lnurl`
+    #[serde(default)]
+    pub payment_method: Option<String>,
+    /// Number of payment attempts made for this round
+    #[serde(default)]
+    pub attempts: i64,
+    #[serde(default)]
+    pub last_attempt_at: Option<i64>,
+    /// Error of the most recent failed attempt
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// Storage backend for a payments ledger. Every write is atomic: a batch of
+/// payments is either stored completely or not at all.
+pub trait LedgerStore: Send + Sync {
+    /// Inserts all `payments` in one transaction. Fails if any payment_id already exists.
+    fn insert_payments(&self, payments: &[Payment]) -> Result<(), DbError>;
+    /// Inserts `payments` in one transaction, skipping payment_ids that already
+    /// exist. Returns the number of rows inserted.
+    fn import_payments(&self, payments: &[Payment]) -> Result<usize, DbError>;
+    /// Replaces the row with the same payment_id
+    fn update_payment(&self, payment: &Payment) -> Result<(), DbError>;
+    /// Read-modify-write of a single row inside one transaction
+    fn modify_payment(
+        &self,
+        payment_id: &str,
+        modify: &mut dyn FnMut(&mut Payment),
+    ) -> Result<Payment, DbError>;
+    fn lookup_payment_by_id(&self, payment_id: &str) -> Result<Option<Payment>, DbError>;
+    fn lookup_payments(&self, circ_id: &str, round: i64) -> Result<Vec<Payment>, DbError>;
+    fn lookup_payments_by_circuit(&self, circ_id: &str) -> Result<Vec<Payment>, DbError>;
+    /// Paid payments last updated at or after `since` (unix seconds)
+    fn payments_paid_since(&self, since: i64) -> Result<Vec<Payment>, DbError>;
+    /// Payments expiring at or after `since` (unix seconds), paid or not
+    fn payments_expiring_since(&self, since: i64) -> Result<Vec<Payment>, DbError>;
+    fn all_payments(&self) -> Result<Vec<Payment>, DbError>;
+    /// Records that circuit `circ_id` used the handshake fee paid with `payment_hash`.
+    /// Returns false if another circuit used it first.
+    fn claim_handshake(&self, payment_hash: &str, circ_id: &str, used_at: i64) -> Result<bool, DbError>;
+    /// Records `msats` (fees included) paid by the pool wallet `wallet`
+    fn record_wallet_spend(&self, wallet: &str, payment_hash: &str, msats: i64, paid_at: i64) -> Result<(), DbError>;
+    /// Total msats recorded for `wallet`
+    fn wallet_spent_msats(&self, wallet: &str) -> Result<i64, DbError>;
+}
+
+lazy_static::lazy_static! {
+    // One store per ledger file so every part of the process shares a single connection
+    static ref OPEN_LEDGERS: Mutex<HashMap<PathBuf, Db>> = Mutex::new(HashMap::new());
+    static ref DATA_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::from(DEFAULT_DATA_DIR));
+}
+
+/// Sets the directory all ledgers, state and caches are stored in. Call this once
+/// at startup before anything is opened; give each eltord instance on a host its own.
+pub fn set_data_dir(dir: impl Into<PathBuf>) {
+    *DATA_DIR.write().unwrap() = dir.into();
+}
+
+pub fn data_dir() -> PathBuf {
+    DATA_DIR.read().unwrap().clone()
+}
+
+/// Resolves `name` inside the data directory
+pub fn data_path(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+#[derive(Clone)]
+pub struct Db {
+    store: Arc<dyn LedgerStore>,
+}
+
+impl Db {
+    /// Opens the SQLite ledger at `path`, creating the file and its parent
+    /// directory if needed and running any pending schema migrations.
+    pub fn new(path: String) -> Result<Self, DbError> {
+        let path = PathBuf::from(path);
+        let mut ledgers = OPEN_LEDGERS.lock().unwrap();
+        if let Some(db) = ledgers.get(&path) {
+            return Ok(db.clone());
+        }
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| DbError::IoErr {
+                reason: format!("{}: {}", parent.display(), e),
+            })?;
+        }
+        let db = Self::with_store(Arc::new(SqliteStore::open(&path)?));
+        ledgers.insert(path, db.clone());
+        Ok(db)
+    }
+
+    pub fn with_store(store: Arc<dyn LedgerStore>) -> Self {
+        Self { store }
+    }
+
+    /// Opens the SQLite ledger at `path` and moves a legacy JSON ledger with the
+    /// same name (e.g. `payments_sent.json` next to `payments_sent.db`) into it.
+    pub fn open_ledger(path: &Path) -> Result<Self, DbError> {
+        let db = Self::new(path.display().to_string())?;
+        let legacy_json = path.with_extension("json");
+        let imported = import_json_ledger(&db, &legacy_json)?;
+        if imported > 0 {
+            log::info!(
+                "Imported {} payments from {} into {}",
+                imported,
+                legacy_json.display(),
+                path.display()
+            );
+        }
+        Ok(db)
+    }
+
+    /// Opens the SQLite ledger at `path` for reading only. Nothing is created,
+    /// migrated or imported; a missing ledger is an error.
+    pub fn open_read_only(path: &Path) -> Result<Self, DbError> {
+        if !path.is_file() {
+            return Err(DbError::NotFoundErr {
+                reason: format!("no ledger at {}", path.display()),
+            });
+        }
+        Ok(Self::with_store(Arc::new(SqliteStore::open_read_only(path)?)))
+    }
+
+    pub fn write_payment(&self, payment: Payment) -> Result<(), DbError> {
+        self.store.insert_payments(std::slice::from_ref(&payment))
+    }
+
+    pub fn write_payments(&self, payments: &[Payment]) -> Result<(), DbError> {
+        self.store.insert_payments(payments)
+    }
+
+    pub fn import_payments(&self, payments: &[Payment]) -> Result<usize, DbError> {
+        self.store.import_payments(payments)
+    }
+
+    pub fn update_payment(&self, payment: Payment) -> Result<(), DbError> {
+        self.store.update_payment(&payment)
+    }
+
+    /// Atomically loads the payment, applies `modify` and writes it back
+    pub fn modify_payment<F: FnMut(&mut Payment)>(
+        &self,
+        payment_id: &str,
+        mut modify: F,
+    ) -> Result<Payment, DbError> {
+        self.store.modify_payment(payment_id, &mut modify)
+    }
+
+    pub fn lookup_payment_by_id(&self, payment_id: String) -> Result<Option<Payment>, DbError> {
+        self.store.lookup_payment_by_id(&payment_id)
+    }
+
+    pub fn lookup_payments(&self, circuit_id: String, round: i64) -> Result<Vec<Payment>, DbError> {
+        self.store.lookup_payments(&circuit_id, round)
+    }
+
+    pub fn lookup_payments_by_circuit(&self, circuit_id: &str) -> Result<Vec<Payment>, DbError> {
+        self.store.lookup_payments_by_circuit(circuit_id)
+    }
+
+    pub fn payments_paid_since(&self, since: i64) -> Result<Vec<Payment>, DbError> {
+        self.store.payments_paid_since(since)
+    }
+
+    pub fn payments_expiring_since(&self, since: i64) -> Result<Vec<Payment>, DbError> {
+        self.store.payments_expiring_since(since)
+    }
+
+    pub fn all_payments(&self) -> Result<Vec<Payment>, DbError> {
+        self.store.all_payments()
+    }
+
+    pub fn claim_handshake(&self, payment_hash: &str, circuit_id: &str) -> Result<bool, DbError> {
+        self.store
+            .claim_handshake(payment_hash, circuit_id, chrono::Utc::now().timestamp())
+    }
+
+    pub fn record_wallet_spend(&self, wallet: &str, payment_hash: &str, msats: i64) -> Result<(), DbError> {
+        self.store
+            .record_wallet_spend(wallet, payment_hash, msats, chrono::Utc::now().timestamp())
+    }
+
+    pub fn wallet_spent_msats(&self, wallet: &str) -> Result<i64, DbError> {
+        self.store.wallet_spent_msats(wallet)
+    }
+}
+
+/// Opens the client's payments sent ledger
+pub fn open_payments_sent_ledger() -> Result<Db, DbError> {
+    Db::open_ledger(&data_path(PAYMENTS_SENT_LEDGER))
+}
+
+/// Opens the relay's payments received ledger
+pub fn open_payments_received_ledger() -> Result<Db, DbError> {
+    Db::open_ledger(&data_path(PAYMENTS_RECEIVED_LEDGER))
+}
+
+/// Unique scratch path for a test ledger
+#[cfg(test)]
+pub(crate) fn test_ledger_path(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "eltor_ledger_{}_{}_{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst),
+        name
+    ))
+}
+
+#[cfg(test)]
+pub(crate) fn test_payment(payment_id: &str, circ_id: &str, round: i64) -> Payment {
+    Payment {
+        payment_id: payment_id.to_string(),
+        circ_id: circ_id.to_string(),
+        interval_seconds: 60,
+        round,
+        relay_fingerprint: "1".to_string(),
+        updated_at: 1,
+        amount_msat: 1,
+        handshake_fee_payhash: Some("1".to_string()),
+        handshake_fee_preimage: Some("1".to_string()),
+        paid: false,
+        expires_at: 1,
+        bolt11_invoice: None,
+        bolt12_offer: None,
+        payment_hash: None,
+        preimage: None,
+        fee: None,
+        has_error: false,
+        payment_method: None,
+        attempts: 0,
+        last_attempt_at: None,
+        last_error: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db() {
+        let payment = test_payment("1", "1", 1);
+        let payment2 = test_payment("2", "1", 2);
+
+        let path = test_ledger_path("payments_sent.db");
+        let db = Db::new(path.display().to_string()).unwrap();
+
+        db.write_payment(payment).unwrap();
+        db.write_payment(payment2).unwrap();
+
+        let payment_lookup = db.lookup_payment_by_id("1".to_string()).unwrap();
+        assert_eq!(payment_lookup.unwrap().payment_id, "1".to_string());
+
+        let relays_to_pay = db.lookup_payments("1".to_string(), 2).unwrap();
+        assert_eq!(relays_to_pay[0].payment_id, "2".to_string());
+
+        // Reopening the same ledger shares the store
+        let reopened = Db::new(path.display().to_string()).unwrap();
+        assert_eq!(reopened.lookup_payments_by_circuit("1").unwrap().len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_open_ledger_imports_legacy_json() {
+        let path = test_ledger_path("payments_received.db");
+        let json_path = path.with_extension("json");
+        let legacy = vec![test_payment("a", "7", 1), test_payment("b", "7", 2)];
+        std::fs::write(&json_path, serde_json::to_string(&legacy).unwrap()).unwrap();
+
+        let db = Db::open_ledger(&path).unwrap();
+        assert_eq!(db.lookup_payments("7".to_string(), 2).unwrap(), vec![legacy[1].clone()]);
+        assert!(!json_path.exists());
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(json_path.with_extension("json.imported"));
+    }
+}
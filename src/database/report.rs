@@ -0,0 +1,306 @@
+use super::Payment;
+use serde::Serialize;
+
+/// Which side of a payment a ledger records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerKind {
+    /// Payments the client sent to relays
+    Sent,
+    /// Payments a relay expects from clients
+    Received,
+}
+
+impl LedgerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedgerKind::Sent => "sent",
+            LedgerKind::Received => "received",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentStatus {
+    Paid,
+    /// The payment attempt failed
+    Error,
+    /// Not paid yet but the round hasn't expired
+    Pending,
+    /// The round expired without being paid
+    Unpaid,
+}
+
+impl PaymentStatus {
+    pub fn of(payment: &Payment, now: i64) -> Self {
+        if payment.has_error {
+            PaymentStatus::Error
+        } else if payment.paid {
+            PaymentStatus::Paid
+        } else if payment.expires_at > now {
+            PaymentStatus::Pending
+        } else {
+            PaymentStatus::Unpaid
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Paid => "paid",
+            PaymentStatus::Error => "error",
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Unpaid => "unpaid",
+        }
+    }
+}
+
+/// Selects payments for the ledger reports. Empty fields match everything,
+/// `since`/`until` are unix timestamps compared against `updated_at`.
+#[derive(Debug, Clone, Default)]
+pub struct PaymentFilter {
+    pub circ_id: Option<String>,
+    pub relay_fingerprint: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    /// Only unpaid (expired) and errored rounds
+    pub problems_only: bool,
+}
+
+impl PaymentFilter {
+    pub fn matches(&self, payment: &Payment, now: i64) -> bool {
+        if self.circ_id.as_ref().is_some_and(|c| *c != payment.circ_id) {
+            return false;
+        }
+        if self
+            .relay_fingerprint
+            .as_ref()
+            .is_some_and(|f| !f.eq_ignore_ascii_case(&payment.relay_fingerprint))
+        {
+            return false;
+        }
+        if self.since.is_some_and(|since| payment.updated_at < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| payment.updated_at > until) {
+            return false;
+        }
+        if self.problems_only {
+            let status = PaymentStatus::of(payment, now);
+            return status == PaymentStatus::Error || status == PaymentStatus::Unpaid;
+        }
+        true
+    }
+}
+
+/// One ledger row as shown or exported by the `eltor ledger` commands
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerRecord {
+    pub ledger: LedgerKind,
+    pub status: PaymentStatus,
+    #[serde(flatten)]
+    pub payment: Payment,
+}
+
+impl LedgerRecord {
+    /// Filters `payments` from the `ledger` ledger into records, ordered by time, circuit and round
+    pub fn collect(
+        ledger: LedgerKind,
+        payments: Vec<Payment>,
+        filter: &PaymentFilter,
+        now: i64,
+    ) -> Vec<LedgerRecord> {
+        let mut records: Vec<LedgerRecord> = payments
+            .into_iter()
+            .filter(|p| filter.matches(p, now))
+            .map(|payment| LedgerRecord {
+                ledger,
+                status: PaymentStatus::of(&payment, now),
+                payment,
+            })
+            .collect();
+        records.sort_by(|a, b| {
+            (a.payment.updated_at, &a.payment.circ_id, a.payment.round)
+                .cmp(&(b.payment.updated_at, &b.payment.circ_id, b.payment.round))
+        });
+        records
+    }
+}
+
+/// Totals for one ledger
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LedgerSummary {
+    pub payments: usize,
+    pub paid: usize,
+    pub pending: usize,
+    pub unpaid: usize,
+    pub errored: usize,
+    /// msats spent (sent ledger) or earned (received ledger)
+    pub paid_msat: i64,
+    /// msats owed for rounds that expired unpaid
+    pub unpaid_msat: i64,
+    /// Lightning routing fees paid in msats
+    pub fees_msat: i64,
+}
+
+impl LedgerSummary {
+    pub fn from_records(records: &[LedgerRecord]) -> Self {
+        let mut summary = LedgerSummary::default();
+        for record in records {
+            summary.payments += 1;
+            match record.status {
+                PaymentStatus::Paid => {
+                    summary.paid += 1;
+                    summary.paid_msat += record.payment.amount_msat;
+                }
+                PaymentStatus::Pending => summary.pending += 1,
+                PaymentStatus::Unpaid => {
+                    summary.unpaid += 1;
+                    summary.unpaid_msat += record.payment.amount_msat;
+                }
+                PaymentStatus::Error => summary.errored += 1,
+            }
+            summary.fees_msat += record.payment.fee.unwrap_or(0);
+        }
+        summary
+    }
+}
+
+const CSV_COLUMNS: &[&str] = &[
+    "ledger",
+    "status",
+    "payment_id",
+    "circ_id",
+    "round",
+    "relay_fingerprint",
+    "amount_msat",
+    "fee_msat",
+    "paid",
+    "has_error",
+    "updated_at",
+    "expires_at",
+    "payment_hash",
+    "preimage",
+    "payment_method",
+    "attempts",
+    "last_error",
+];
+
+/// Renders `records` as CSV with a header row
+pub fn records_to_csv(records: &[LedgerRecord]) -> String {
+    let mut out = CSV_COLUMNS.join(",");
+    out.push('\n');
+    for r in records {
+        let p = &r.payment;
+        let fields = [
+            r.ledger.as_str().to_string(),
+            r.status.as_str().to_string(),
+            p.payment_id.clone(),
+            p.circ_id.clone(),
+            p.round.to_string(),
+            p.relay_fingerprint.clone(),
+            p.amount_msat.to_string(),
+            p.fee.map(|f| f.to_string()).unwrap_or_default(),
+            p.paid.to_string(),
+            p.has_error.to_string(),
+            p.updated_at.to_string(),
+            p.expires_at.to_string(),
+            p.payment_hash.clone().unwrap_or_default(),
+            p.preimage.clone().unwrap_or_default(),
+            p.payment_method.clone().unwrap_or_default(),
+            p.attempts.to_string(),
+            p.last_error.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_payment;
+
+    fn payment(id: &str, circ_id: &str, fingerprint: &str, updated_at: i64) -> Payment {
+        let mut p = test_payment(id, circ_id, 1);
+        p.relay_fingerprint = fingerprint.to_string();
+        p.updated_at = updated_at;
+        p.amount_msat = 1000;
+        p.expires_at = 100;
+        p
+    }
+
+    #[test]
+    fn test_filter_and_summarize() {
+        let mut paid = payment("1", "10", "AAAA", 50);
+        paid.paid = true;
+        paid.fee = Some(7);
+        let mut errored = payment("2", "10", "BBBB", 60);
+        errored.has_error = true;
+        let unpaid = payment("3", "11", "AAAA", 70);
+        let mut pending = payment("4", "11", "AAAA", 80);
+        pending.expires_at = 500;
+        let all = vec![paid, errored, unpaid, pending];
+
+        let records = LedgerRecord::collect(LedgerKind::Sent, all.clone(), &PaymentFilter::default(), 200);
+        assert_eq!(
+            LedgerSummary::from_records(&records),
+            LedgerSummary {
+                payments: 4,
+                paid: 1,
+                pending: 1,
+                unpaid: 1,
+                errored: 1,
+                paid_msat: 1000,
+                unpaid_msat: 1000,
+                fees_msat: 7,
+            }
+        );
+
+        let by_relay = PaymentFilter {
+            relay_fingerprint: Some("aaaa".to_string()),
+            since: Some(60),
+            ..Default::default()
+        };
+        let ids: Vec<String> = LedgerRecord::collect(LedgerKind::Sent, all.clone(), &by_relay, 200)
+            .into_iter()
+            .map(|r| r.payment.payment_id)
+            .collect();
+        assert_eq!(ids, vec!["3", "4"]);
+
+        let problems = PaymentFilter {
+            problems_only: true,
+            ..Default::default()
+        };
+        let statuses: Vec<PaymentStatus> = LedgerRecord::collect(LedgerKind::Sent, all, &problems, 200)
+            .into_iter()
+            .map(|r| r.status)
+            .collect();
+        assert_eq!(statuses, vec![PaymentStatus::Error, PaymentStatus::Unpaid]);
+    }
+
+    #[test]
+    fn test_records_to_csv() {
+        let mut p = payment("1", "10", "AAAA", 50);
+        p.payment_hash = Some("a,\"b\"".to_string());
+        p.payment_method = Some("bolt12_offer".to_string());
+        let records = LedgerRecord::collect(LedgerKind::Received, vec![p], &PaymentFilter::default(), 0);
+        let csv = records_to_csv(&records);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next().unwrap(), CSV_COLUMNS.join(","));
+        assert_eq!(
+            lines.next().unwrap(),
+            "received,pending,1,10,1,AAAA,1000,,false,false,50,100,\"a,\"\"b\"\"\",,bolt12_offer,0,"
+        );
+    }
+}
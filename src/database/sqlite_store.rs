@@ -0,0 +1,496 @@
+use super::{DbError, LedgerStore, Payment};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, Transaction};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Schema migrations, applied in order. The index of the last applied
+/// migration + 1 is stored in `PRAGMA user_version`. Never edit an entry that
+/// has shipped, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial payments table
+    "CREATE TABLE payments (
+        payment_id TEXT PRIMARY KEY NOT NULL,
+        circ_id TEXT NOT NULL,
+        interval_seconds INTEGER NOT NULL,
+        round INTEGER NOT NULL,
+        relay_fingerprint TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        amount_msat INTEGER NOT NULL,
+        handshake_fee_payhash TEXT,
+        handshake_fee_preimage TEXT,
+        paid INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        bolt11_invoice TEXT,
+        bolt12_offer TEXT,
+        payment_hash TEXT,
+        preimage TEXT,
+        fee INTEGER,
+        has_error INTEGER NOT NULL
+    );
+    CREATE INDEX idx_payments_circ_round ON payments (circ_id, round);",
+    // 2: payment method negotiated with the relay
+    "ALTER TABLE payments ADD COLUMN payment_method TEXT;",
+    // 3: payment attempts within a round
+    "ALTER TABLE payments ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE payments ADD COLUMN last_attempt_at INTEGER;
+    ALTER TABLE payments ADD COLUMN last_error TEXT;",
+    // 4: handshake fees a relay has accepted, so a paid handshake opens one circuit only
+    "CREATE TABLE handshakes (
+        payment_hash TEXT PRIMARY KEY NOT NULL,
+        circ_id TEXT NOT NULL,
+        used_at INTEGER NOT NULL
+    );",
+    // 5: what each wallet of the client's pool paid, for its maxSpendMsats cap
+    "CREATE TABLE wallet_spends (
+        wallet TEXT NOT NULL,
+        payment_hash TEXT NOT NULL,
+        msats INTEGER NOT NULL,
+        paid_at INTEGER NOT NULL
+    );
+    CREATE INDEX idx_wallet_spends_wallet ON wallet_spends (wallet);",
+];
+
+const COLUMNS: &str = "payment_id, circ_id, interval_seconds, round, relay_fingerprint, updated_at, \
+    amount_msat, handshake_fee_payhash, handshake_fee_preimage, paid, expires_at, bolt11_invoice, \
+    bolt12_offer, payment_hash, preimage, fee, has_error, payment_method, attempts, last_attempt_at, \
+    last_error";
+
+const INSERT: &str = "INSERT INTO payments (payment_id, circ_id, interval_seconds, round, \
+    relay_fingerprint, updated_at, amount_msat, handshake_fee_payhash, handshake_fee_preimage, paid, \
+    expires_at, bolt11_invoice, bolt12_offer, payment_hash, preimage, fee, has_error, payment_method, \
+    attempts, last_attempt_at, last_error) \
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)";
+
+const UPDATE: &str = "UPDATE payments SET circ_id = ?2, interval_seconds = ?3, round = ?4, \
+    relay_fingerprint = ?5, updated_at = ?6, amount_msat = ?7, handshake_fee_payhash = ?8, \
+    handshake_fee_preimage = ?9, paid = ?10, expires_at = ?11, bolt11_invoice = ?12, \
+    bolt12_offer = ?13, payment_hash = ?14, preimage = ?15, fee = ?16, has_error = ?17, \
+    payment_method = ?18, attempts = ?19, last_attempt_at = ?20, last_error = ?21 WHERE payment_id = ?1";
+
+fn store_err(e: rusqlite::Error) -> DbError {
+    DbError::StoreErr {
+        reason: e.to_string(),
+    }
+}
+
+/// SQLite backed `LedgerStore`
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, DbError> {
+        let conn = Connection::open(path).map_err(|e| DbError::StoreErr {
+            reason: format!("{}: {}", path.display(), e),
+        })?;
+        // WAL keeps readers from blocking the payment loop's writes
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(store_err)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(store_err)?;
+        Self::from_connection(conn)
+    }
+
+    /// Opens an existing ledger without ever writing to it: no file is created and
+    /// no migrations run, so the ledger has to be on the current schema already.
+    pub fn open_read_only(path: &Path) -> Result<Self, DbError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(path, flags).map_err(|e| DbError::StoreErr {
+            reason: format!("{}: {}", path.display(), e),
+        })?;
+        let version = schema_version(&conn)?;
+        if version != MIGRATIONS.len() {
+            return Err(DbError::MigrationErr {
+                reason: format!(
+                    "{} is on schema version {}, this build reads version {}. Start eltord once to migrate it",
+                    path.display(),
+                    version,
+                    MIGRATIONS.len()
+                ),
+            });
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn open_in_memory() -> Result<Self, DbError> {
+        Self::from_connection(Connection::open_in_memory().map_err(store_err)?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, DbError> {
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn schema_version(&self) -> Result<usize, DbError> {
+        schema_version(&self.conn.lock().unwrap())
+    }
+
+    fn query(&self, filter: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Payment>, DbError> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!("SELECT {} FROM payments {} ORDER BY round, rowid", COLUMNS, filter);
+        let mut stmt = conn.prepare_cached(&sql).map_err(store_err)?;
+        let rows = stmt.query_map(params, payment_from_row).map_err(store_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(store_err)
+    }
+}
+
+fn schema_version(conn: &Connection) -> Result<usize, DbError> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+        .map(|v| v as usize)
+        .map_err(store_err)
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), DbError> {
+    let current = schema_version(conn)?;
+    if current > MIGRATIONS.len() {
+        return Err(DbError::MigrationErr {
+            reason: format!(
+                "ledger schema version {} is newer than this build supports ({})",
+                current,
+                MIGRATIONS.len()
+            ),
+        });
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = i + 1;
+        let tx = conn.transaction().map_err(store_err)?;
+        tx.execute_batch(migration)
+            .and_then(|_| tx.pragma_update(None, "user_version", version as i64))
+            .and_then(|_| tx.commit())
+            .map_err(|e| DbError::MigrationErr {
+                reason: format!("migration {} failed: {}", version, e),
+            })?;
+    }
+    Ok(())
+}
+
+fn payment_from_row(row: &Row) -> rusqlite::Result<Payment> {
+    Ok(Payment {
+        payment_id: row.get(0)?,
+        circ_id: row.get(1)?,
+        interval_seconds: row.get(2)?,
+        round: row.get(3)?,
+        relay_fingerprint: row.get(4)?,
+        updated_at: row.get(5)?,
+        amount_msat: row.get(6)?,
+        handshake_fee_payhash: row.get(7)?,
+        handshake_fee_preimage: row.get(8)?,
+        paid: row.get(9)?,
+        expires_at: row.get(10)?,
+        bolt11_invoice: row.get(11)?,
+        bolt12_offer: row.get(12)?,
+        payment_hash: row.get(13)?,
+        preimage: row.get(14)?,
+        fee: row.get(15)?,
+        has_error: row.get(16)?,
+        payment_method: row.get(17)?,
+        attempts: row.get(18)?,
+        last_attempt_at: row.get(19)?,
+        last_error: row.get(20)?,
+    })
+}
+
+fn write_row(tx: &Transaction, sql: &str, p: &Payment) -> rusqlite::Result<usize> {
+    tx.prepare_cached(sql)?.execute(params![
+        p.payment_id,
+        p.circ_id,
+        p.interval_seconds,
+        p.round,
+        p.relay_fingerprint,
+        p.updated_at,
+        p.amount_msat,
+        p.handshake_fee_payhash,
+        p.handshake_fee_preimage,
+        p.paid,
+        p.expires_at,
+        p.bolt11_invoice,
+        p.bolt12_offer,
+        p.payment_hash,
+        p.preimage,
+        p.fee,
+        p.has_error,
+        p.payment_method,
+        p.attempts,
+        p.last_attempt_at,
+        p.last_error,
+    ])
+}
+
+fn not_found(payment_id: &str) -> DbError {
+    DbError::NotFoundErr {
+        reason: format!("Payment {} not found", payment_id),
+    }
+}
+
+impl LedgerStore for SqliteStore {
+    fn insert_payments(&self, payments: &[Payment]) -> Result<(), DbError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(store_err)?;
+        for payment in payments {
+            write_row(&tx, INSERT, payment).map_err(store_err)?;
+        }
+        tx.commit().map_err(store_err)
+    }
+
+    fn import_payments(&self, payments: &[Payment]) -> Result<usize, DbError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(store_err)?;
+        let insert_or_ignore = INSERT.replacen("INSERT", "INSERT OR IGNORE", 1);
+        let mut inserted = 0;
+        for payment in payments {
+            inserted += write_row(&tx, &insert_or_ignore, payment).map_err(store_err)?;
+        }
+        tx.commit().map_err(store_err)?;
+        Ok(inserted)
+    }
+
+    fn update_payment(&self, payment: &Payment) -> Result<(), DbError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(store_err)?;
+        if write_row(&tx, UPDATE, payment).map_err(store_err)? == 0 {
+            return Err(not_found(&payment.payment_id));
+        }
+        tx.commit().map_err(store_err)
+    }
+
+    fn modify_payment(
+        &self,
+        payment_id: &str,
+        modify: &mut dyn FnMut(&mut Payment),
+    ) -> Result<Payment, DbError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(store_err)?;
+        let sql = format!("SELECT {} FROM payments WHERE payment_id = ?1", COLUMNS);
+        let mut payment = tx
+            .query_row(&sql, [payment_id], payment_from_row)
+            .optional()
+            .map_err(store_err)?
+            .ok_or_else(|| not_found(payment_id))?;
+        modify(&mut payment);
+        // The primary key is fixed, a changed payment_id would silently update nothing
+        payment.payment_id = payment_id.to_string();
+        write_row(&tx, UPDATE, &payment).map_err(store_err)?;
+        tx.commit().map_err(store_err)?;
+        Ok(payment)
+    }
+
+    fn lookup_payment_by_id(&self, payment_id: &str) -> Result<Option<Payment>, DbError> {
+        Ok(self
+            .query("WHERE payment_id = ?1", &[&payment_id])?
+            .into_iter()
+            .next())
+    }
+
+    fn lookup_payments(&self, circ_id: &str, round: i64) -> Result<Vec<Payment>, DbError> {
+        self.query("WHERE circ_id = ?1 AND round = ?2", &[&circ_id, &round])
+    }
+
+    fn lookup_payments_by_circuit(&self, circ_id: &str) -> Result<Vec<Payment>, DbError> {
+        self.query("WHERE circ_id = ?1", &[&circ_id])
+    }
+
+    fn payments_paid_since(&self, since: i64) -> Result<Vec<Payment>, DbError> {
+        self.query("WHERE paid = 1 AND updated_at >= ?1", &[&since])
+    }
+
+    fn payments_expiring_since(&self, since: i64) -> Result<Vec<Payment>, DbError> {
+        self.query("WHERE expires_at >= ?1", &[&since])
+    }
+
+    fn all_payments(&self) -> Result<Vec<Payment>, DbError> {
+        self.query("", &[])
+    }
+
+    fn claim_handshake(&self, payment_hash: &str, circ_id: &str, used_at: i64) -> Result<bool, DbError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(store_err)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO handshakes (payment_hash, circ_id, used_at) VALUES (?1, ?2, ?3)",
+            params![payment_hash, circ_id, used_at],
+        )
+        .map_err(store_err)?;
+        let owner: String = tx
+            .query_row(
+                "SELECT circ_id FROM handshakes WHERE payment_hash = ?1",
+                [payment_hash],
+                |row| row.get(0),
+            )
+            .map_err(store_err)?;
+        tx.commit().map_err(store_err)?;
+        Ok(owner == circ_id)
+    }
+
+    fn record_wallet_spend(&self, wallet: &str, payment_hash: &str, msats: i64, paid_at: i64) -> Result<(), DbError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO wallet_spends (wallet, payment_hash, msats, paid_at) VALUES (?1, ?2, ?3, ?4)",
+            params![wallet, payment_hash, msats, paid_at],
+        )
+        .map_err(store_err)?;
+        Ok(())
+    }
+
+    fn wallet_spent_msats(&self, wallet: &str) -> Result<i64, DbError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COALESCE(SUM(msats), 0) FROM wallet_spends WHERE wallet = ?1",
+            [wallet],
+            |row| row.get(0),
+        )
+        .map_err(store_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_payment;
+
+    #[test]
+    fn test_migrates_new_store_to_latest_schema() {
+        let path = crate::database::test_ledger_path("migrate.db");
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        drop(store);
+
+        // Reopening an up to date ledger is a no-op
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        drop(store);
+
+        // A ledger written by a newer build is refused rather than clobbered
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64)
+            .unwrap();
+        drop(conn);
+        assert!(matches!(
+            SqliteStore::open(&path),
+            Err(DbError::MigrationErr { .. })
+        ));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_only_ledger() {
+        let path = crate::database::test_ledger_path("read_only.db");
+        assert!(SqliteStore::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        SqliteStore::open(&path)
+            .unwrap()
+            .insert_payments(&[test_payment("1", "5", 1)])
+            .unwrap();
+        let store = SqliteStore::open_read_only(&path).unwrap();
+        assert_eq!(store.all_payments().unwrap().len(), 1);
+        assert!(store.insert_payments(&[test_payment("2", "5", 2)]).is_err());
+        drop(store);
+
+        // Migrating is up to eltord, not to readers
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        drop(conn);
+        assert!(matches!(
+            SqliteStore::open_read_only(&path),
+            Err(DbError::MigrationErr { .. })
+        ));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_upgrades_v1_ledger() {
+        let path = crate::database::test_ledger_path("migrate_v1.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO payments (payment_id, circ_id, interval_seconds, round, relay_fingerprint, \
+            updated_at, amount_msat, paid, expires_at, has_error) VALUES ('1', '5', 60, 1, 'AAAA', 1, 1000, 0, 1, 0)",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        let mut payment = store.lookup_payment_by_id("1").unwrap().unwrap();
+        assert_eq!(payment.payment_method, None);
+        assert_eq!(payment.attempts, 0);
+        payment.payment_method = Some("lnurl".to_string());
+        store.update_payment(&payment).unwrap();
+        assert_eq!(store.lookup_payment_by_id("1").unwrap(), Some(payment));
+        drop(store);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_insert_batch_is_atomic() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.insert_payments(&[test_payment("1", "5", 1)]).unwrap();
+
+        // "1" already exists so the whole batch is rolled back
+        let batch = [test_payment("2", "5", 2), test_payment("1", "5", 1)];
+        assert!(store.insert_payments(&batch).is_err());
+        assert!(store.lookup_payment_by_id("2").unwrap().is_none());
+
+        assert_eq!(store.import_payments(&batch).unwrap(), 1);
+        assert_eq!(store.lookup_payments_by_circuit("5").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_claim_handshake_once() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert!(store.claim_handshake("hs", "5", 1).unwrap());
+        // Claiming again for the same circuit is idempotent
+        assert!(store.claim_handshake("hs", "5", 2).unwrap());
+        assert!(!store.claim_handshake("hs", "6", 3).unwrap());
+        assert!(store.claim_handshake("other", "6", 3).unwrap());
+    }
+
+    #[test]
+    fn test_wallet_spends() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert_eq!(store.wallet_spent_msats("phoenixd-1").unwrap(), 0);
+        store.record_wallet_spend("phoenixd-1", "a", 1000, 1).unwrap();
+        store.record_wallet_spend("phoenixd-1", "b", 1003, 2).unwrap();
+        store.record_wallet_spend("lnd-2", "c", 500, 2).unwrap();
+        assert_eq!(store.wallet_spent_msats("phoenixd-1").unwrap(), 2003);
+        assert_eq!(store.wallet_spent_msats("lnd-2").unwrap(), 500);
+    }
+
+    #[test]
+    fn test_update_and_modify_payment() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store
+            .insert_payments(&[test_payment("1", "5", 1), test_payment("2", "6", 1)])
+            .unwrap();
+
+        let mut paid = test_payment("1", "5", 1);
+        paid.paid = true;
+        paid.fee = Some(3);
+        store.update_payment(&paid).unwrap();
+        assert_eq!(store.payments_paid_since(1).unwrap(), vec![paid.clone()]);
+        assert!(store.payments_paid_since(2).unwrap().is_empty());
+        assert_eq!(store.payments_expiring_since(1).unwrap().len(), 2);
+        assert!(store.payments_expiring_since(2).unwrap().is_empty());
+        assert_eq!(store.lookup_payment_by_id("1").unwrap(), Some(paid));
+
+        let modified = store
+            .modify_payment("2", &mut |p| p.has_error = true)
+            .unwrap();
+        assert!(modified.has_error);
+        assert_eq!(store.lookup_payments("6", 1).unwrap(), vec![modified]);
+
+        assert!(matches!(
+            store.update_payment(&test_payment("missing", "5", 1)),
+            Err(DbError::NotFoundErr { .. })
+        ));
+        assert!(matches!(
+            store.modify_payment("missing", &mut |_| {}),
+            Err(DbError::NotFoundErr { .. })
+        ));
+    }
+}
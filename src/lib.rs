@@ -468,8 +468,10 @@ where
     //let mut input = String::new();
     // std::io::stdin().read_line(&mut input).unwrap();
 
-    let (mode, torrc_path, control_port_password) = parse_args(args.into_iter().map(Into::into));
+    let args: Vec<String> = args.into_iter().map(Into::into).collect();
+    let (mode, torrc_path, control_port_password) = parse_args(args.clone());
     info!("Mode: {:?}", mode);
+    init_data_dir(&torrc_path, parse_data_dir_arg(&args)).await;
     let rpc_config = self::get_rpc_config_from_torrc(&torrc_path, control_port_password).await;
     info!("RPC Config: {:?}", rpc_config);
     if rpc_config.is_none() {
@@ -604,6 +606,7 @@ pub async fn init_and_run() {
 /// let (mode, torrc_path, password) = parse_args(args);
 /// assert_eq!(mode, "client");
 /// assert_eq!(torrc_path, "torrc.client.dev");
+/// assert_eq!(password, None); // cookie auth unless --pw is given
 /// ```
 pub fn parse_args<I>(args: I) -> (String, String, Option<String>)
 where
@@ -612,7 +615,7 @@ where
     let mut args = args.into_iter().skip(1); // Skip program name such as eltord
     let mut mode = "client".to_string(); // default to client mode
     let mut torrc_path = "torrc".to_string(); // Default torrc path is in same folder as eltord binary named torrc
-    let mut control_port_password: Option<String> = Some("password1234_".to_string()); // Default password for control port
+    let mut control_port_password: Option<String> = None; // Without --pw we authenticate via cookie (PROTOCOLINFO)
 
     // Check if first argument is "client" or "relay" or "both"
     if let Some(arg1) = args.next() {
@@ -648,6 +651,42 @@ where
     (mode, torrc_path, control_port_password)
 }
 
+/// Returns the value of the `--data-dir` flag, if given
+///
+/// # Examples
+///
+/// ```rust
+/// use eltor::parse_data_dir_arg;
+///
+/// let args = vec!["eltor".to_string(), "relay".to_string(), "--data-dir".to_string(), "/srv/eltor/relay1".to_string()];
+/// assert_eq!(parse_data_dir_arg(&args), Some("/srv/eltor/relay1".to_string()));
+/// ```
+pub fn parse_data_dir_arg(args: &[String]) -> Option<String> {
+    args.iter()
+        .skip(1)
+        .skip_while(|arg| *arg != "--data-dir")
+        .nth(1)
+        .cloned()
+}
+
+/// Sets the directory ledgers, state and caches are kept in. In order of precedence:
+/// the `--data-dir` flag, the `ELTOR_DATA_DIR` environment variable, an `eltor` folder
+/// inside the torrc's `DataDirectory`, and finally `./data`.
+pub async fn init_data_dir(torrc_path: &str, cli_data_dir: Option<String>) -> std::path::PathBuf {
+    let env_data_dir = std::env::var("ELTOR_DATA_DIR").ok().filter(|dir| !dir.trim().is_empty());
+    let data_dir = match cli_data_dir.or(env_data_dir) {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => rpc::parse_raw_torrc_file(torrc_path)
+            .await
+            .ok()
+            .and_then(|entries| rpc::data_dir_from_torrc_entries(&entries))
+            .unwrap_or_else(|| database::DEFAULT_DATA_DIR.into()),
+    };
+    info!("Using data directory: {}", data_dir.display());
+    database::set_data_dir(data_dir.clone());
+    data_dir
+}
+
 /// Start the client flow with the given RPC configuration
 /// 
 /// # Arguments
@@ -785,7 +824,9 @@ impl EltordTasks {
 /// }
 /// ```
 pub async fn initialize_eltord(args: impl Iterator<Item = impl Into<String>>) -> Result<(RpcConfig, String), Box<dyn std::error::Error>> {
-    let (mode, torrc_path, control_port_password) = parse_args(args.into_iter().map(Into::into));
+    let args: Vec<String> = args.map(Into::into).collect();
+    let (mode, torrc_path, control_port_password) = parse_args(args.clone());
+    init_data_dir(&torrc_path, parse_data_dir_arg(&args)).await;
     let rpc_config = self::get_rpc_config_from_torrc(&torrc_path, control_port_password).await;
     info!("RPC Config: {:?}", rpc_config);
     if rpc_config.is_none() {
@@ -798,7 +839,7 @@ pub async fn initialize_eltord(args: impl Iterator<Item = impl Into<String>>) ->
     info!("Checking if Tor is already running on {}...", addr);
     
     // Try to connect to see if Tor is already running
-    if let Ok(_) = tokio::net::TcpStream::connect(&addr).await {
+    if let Ok(_) = rpc::connect_control_port(&addr).await {
         info!("Tor appears to already be running on {}, skipping Tor startup", addr);
         return Ok((rpc_config, mode));
     }
@@ -829,7 +870,7 @@ pub async fn initialize_eltord(args: impl Iterator<Item = impl Into<String>>) ->
     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
 
     // Verify Tor started successfully
-    if let Err(_) = tokio::net::TcpStream::connect(&addr).await {
+    if let Err(_) = rpc::connect_control_port(&addr).await {
         return Err(format!("Failed to connect to Tor on {} after startup", addr).into());
     }
     
@@ -0,0 +1,356 @@
+use crate::types::Relay;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Public DNS-over-HTTPS resolver used when no other backend is configured
+pub const DEFAULT_DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+/// Upper bound for caching a resolved offer, whatever TTL the record has
+const MAX_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Error)]
+pub enum Bip353Error {
+    #[error("InvalidName: {reason}")]
+    InvalidName { reason: String },
+    #[error("DnsError: {reason}")]
+    DnsErr { reason: String },
+    #[error("NotFound: {reason}")]
+    NotFoundErr { reason: String },
+    #[error("InvalidRecord: {reason}")]
+    InvalidRecord { reason: String },
+}
+
+/// One TXT record, with its character-strings already concatenated
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxtRecord {
+    pub text: String,
+    pub ttl: u32,
+}
+
+/// DNS backend for BIP-353 lookups. Implementations must only return records
+/// that passed DNSSEC validation.
+#[async_trait::async_trait]
+pub trait DnsTxtResolver: Send + Sync {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<TxtRecord>, Bip353Error>;
+}
+
+/// DNS-over-HTTPS backend using the JSON API (RFC 8427 style, as served by Cloudflare
+/// and Google). Queries go through Tor's SOCKS port when one is given.
+pub struct DohTxtResolver {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    /// Authenticated Data: the resolver validated the answer with DNSSEC
+    #[serde(rename = "AD", default)]
+    ad: bool,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    data: String,
+}
+
+const DNS_TYPE_TXT: u16 = 16;
+const DNS_RCODE_NXDOMAIN: u32 = 3;
+
+impl DohTxtResolver {
+    pub fn new(url: &str, socks_port: Option<u16>) -> Result<Self, Bip353Error> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(port) = socks_port {
+            let proxy = reqwest::Proxy::all(format!("socks5h://127.0.0.1:{}", port))
+                .map_err(|e| Bip353Error::DnsErr { reason: e.to_string() })?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| Bip353Error::DnsErr { reason: e.to_string() })?;
+        Ok(DohTxtResolver {
+            url: url.to_string(),
+            client,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsTxtResolver for DohTxtResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<TxtRecord>, Bip353Error> {
+        let dns_err = |e: reqwest::Error| Bip353Error::DnsErr {
+            reason: format!("{}: {}", name, e),
+        };
+        let body = self
+            .client
+            .get(&self.url)
+            .query(&[("name", name), ("type", "TXT"), ("do", "1")])
+            .header("accept", "application/dns-json")
+            .send()
+            .await
+            .map_err(dns_err)?
+            .text()
+            .await
+            .map_err(dns_err)?;
+        parse_doh_response(name, &body)
+    }
+}
+
+fn parse_doh_response(name: &str, body: &str) -> Result<Vec<TxtRecord>, Bip353Error> {
+    let response: DohResponse = serde_json::from_str(body).map_err(|e| Bip353Error::DnsErr {
+        reason: format!("{}: invalid DNS-over-HTTPS response: {}", name, e),
+    })?;
+    if response.status == DNS_RCODE_NXDOMAIN {
+        return Ok(Vec::new());
+    }
+    if response.status != 0 {
+        return Err(Bip353Error::DnsErr {
+            reason: format!("{}: DNS error code {}", name, response.status),
+        });
+    }
+    if !response.ad {
+        return Err(Bip353Error::DnsErr {
+            reason: format!("{}: answer is not DNSSEC validated", name),
+        });
+    }
+    Ok(response
+        .answer
+        .into_iter()
+        .filter(|a| a.record_type == DNS_TYPE_TXT)
+        .map(|a| TxtRecord {
+            text: join_character_strings(&a.data),
+            ttl: a.ttl,
+        })
+        .collect())
+}
+
+/// Long TXT records are split into quoted character-strings (`"abc" "def"`), join them
+fn join_character_strings(data: &str) -> String {
+    let data = data.trim();
+    if !data.starts_with('"') {
+        return data.to_string();
+    }
+    data.split('"')
+        .enumerate()
+        .filter(|(i, _)| i % 2 == 1)
+        .map(|(_, s)| s)
+        .collect()
+}
+
+/// Resolves BIP-353 names (`user@domain`) to the BOLT 12 offer in their DNS TXT record.
+/// Results are cached for the record's TTL (at most an hour).
+pub struct Bip353Resolver {
+    backend: RwLock<Arc<dyn DnsTxtResolver>>,
+    cache: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Bip353Resolver {
+    pub fn new(backend: Arc<dyn DnsTxtResolver>) -> Self {
+        Bip353Resolver {
+            backend: RwLock::new(backend),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Swaps the DNS backend, cached offers are kept
+    pub fn set_backend(&self, backend: Arc<dyn DnsTxtResolver>) {
+        *self.backend.write().unwrap() = backend;
+    }
+
+    pub async fn resolve_offer(&self, name: &str) -> Result<String, Bip353Error> {
+        let dns_name = bip353_dns_name(name)?;
+        if let Some((offer, expires)) = self.cache.lock().unwrap().get(&dns_name) {
+            if *expires > Instant::now() {
+                return Ok(offer.clone());
+            }
+        }
+
+        let backend = self.backend.read().unwrap().clone();
+        let records = backend.lookup_txt(&dns_name).await?;
+        let (offer, ttl) = offer_from_txt_records(&dns_name, &records)?;
+        info!("Resolved BIP-353 name {} to offer {}...", name, offer.chars().take(10).collect::<String>());
+        let ttl = Duration::from_secs(ttl as u64).min(MAX_CACHE_TTL);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(dns_name, (offer.clone(), Instant::now() + ttl));
+        Ok(offer)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BIP353_RESOLVER: Bip353Resolver = Bip353Resolver::new(Arc::new(
+        DohTxtResolver::new(DEFAULT_DOH_URL, None).expect("default DNS-over-HTTPS client")
+    ));
+}
+
+/// Replaces the DNS backend of the process wide resolver (e.g. to query over Tor)
+pub fn set_bip353_dns_backend(backend: Arc<dyn DnsTxtResolver>) {
+    BIP353_RESOLVER.set_backend(backend);
+}
+
+/// Resolves `name` with the process wide resolver
+pub async fn resolve_bip353_offer(name: &str) -> Result<String, Bip353Error> {
+    BIP353_RESOLVER.resolve_offer(name).await
+}
+
+/// The BOLT 12 offer to pay a relay with: its `PaymentBolt12Offer`, or else the offer
+/// its `PaymentBip353` name resolves to. `None` if the relay advertises neither.
+pub async fn relay_bolt12_offer(relay: &Relay) -> Result<Option<String>, Bip353Error> {
+    if let Some(offer) = relay.payment_bolt12_offer.as_ref() {
+        return Ok(Some(offer.clone()));
+    }
+    match relay.payment_bip353.as_ref() {
+        Some(name) => resolve_bip353_offer(name).await.map(Some).map_err(|e| {
+            warn!("Failed to resolve BIP-353 name {} of relay {}: {}", name, relay.nickname, e);
+            e
+        }),
+        None => Ok(None),
+    }
+}
+
+/// `user@domain` (optionally prefixed with ₿) to `user.user._bitcoin-payment.domain.`
+pub fn bip353_dns_name(name: &str) -> Result<String, Bip353Error> {
+    let name = name.trim();
+    let name = name.strip_prefix('₿').unwrap_or(name);
+    let invalid = || Bip353Error::InvalidName {
+        reason: name.to_string(),
+    };
+    let (user, domain) = name.split_once('@').ok_or_else(invalid)?;
+    let domain = domain.trim_end_matches('.');
+    if user.is_empty() || domain.is_empty() || domain.contains('@') || !domain.contains('.') {
+        return Err(invalid());
+    }
+    Ok(format!("{}.user._bitcoin-payment.{}.", user, domain).to_lowercase())
+}
+
+/// Finds the single `bitcoin:` URI among the TXT records and returns its `lno` offer
+fn offer_from_txt_records(
+    dns_name: &str,
+    records: &[TxtRecord],
+) -> Result<(String, u32), Bip353Error> {
+    let mut uris = records
+        .iter()
+        .filter(|r| r.text.get(..8).is_some_and(|prefix| prefix.eq_ignore_ascii_case("bitcoin:")));
+    let record = uris.next().ok_or_else(|| Bip353Error::NotFoundErr {
+        reason: format!("no bitcoin: TXT record at {}", dns_name),
+    })?;
+    if uris.next().is_some() {
+        return Err(Bip353Error::InvalidRecord {
+            reason: format!("more than one bitcoin: TXT record at {}", dns_name),
+        });
+    }
+
+    let query = record.text.split_once('?').map(|(_, q)| q).unwrap_or_default();
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("lno"))
+        .map(|(_, offer)| (offer.to_string(), record.ttl))
+        .ok_or_else(|| Bip353Error::NotFoundErr {
+            reason: format!("no BOLT 12 offer (lno) in the payment instructions at {}", dns_name),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Local stub resolver that answers from a fixed zone and counts lookups
+    struct StubResolver {
+        zone: HashMap<String, Vec<TxtRecord>>,
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl DnsTxtResolver for StubResolver {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<TxtRecord>, Bip353Error> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(self.zone.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn txt(text: &str) -> TxtRecord {
+        TxtRecord {
+            text: text.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_offer_with_stub_resolver() {
+        let mut zone = HashMap::new();
+        zone.insert(
+            "relay.user._bitcoin-payment.example.com.".to_string(),
+            vec![
+                txt("v=spf1 -all"),
+                // Byte 8 falls inside the multi-byte ₿
+                txt("v=spf1 ₿ -all"),
+                txt("bitcoin:?lno=lno1qgsyxjtl6luzd9t3pr62xr7eemp6awnejusgf6gw45q75vcfqqqqqqq&sp=sp1qq"),
+            ],
+        );
+        zone.insert(
+            "twice.user._bitcoin-payment.example.com.".to_string(),
+            vec![txt("bitcoin:?lno=lno1a"), txt("BITCOIN:?lno=lno1b")],
+        );
+        zone.insert(
+            "onchain.user._bitcoin-payment.example.com.".to_string(),
+            vec![txt("bitcoin:bc1qexample")],
+        );
+        let stub = Arc::new(StubResolver {
+            zone,
+            lookups: AtomicUsize::new(0),
+        });
+        let resolver = Bip353Resolver::new(stub.clone());
+
+        let offer = resolver.resolve_offer("₿Relay@Example.com").await.unwrap();
+        assert_eq!(offer, "lno1qgsyxjtl6luzd9t3pr62xr7eemp6awnejusgf6gw45q75vcfqqqqqqq");
+        // Served from the cache the second time
+        assert_eq!(resolver.resolve_offer("relay@example.com").await.unwrap(), offer);
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+
+        assert!(matches!(
+            resolver.resolve_offer("twice@example.com").await,
+            Err(Bip353Error::InvalidRecord { .. })
+        ));
+        assert!(matches!(
+            resolver.resolve_offer("onchain@example.com").await,
+            Err(Bip353Error::NotFoundErr { .. })
+        ));
+        assert!(matches!(
+            resolver.resolve_offer("nobody@example.com").await,
+            Err(Bip353Error::NotFoundErr { .. })
+        ));
+        assert!(matches!(
+            resolver.resolve_offer("example.com").await,
+            Err(Bip353Error::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_doh_response() {
+        let body = r#"{"Status":0,"AD":true,"Answer":[{"name":"relay.user._bitcoin-payment.example.com.","type":16,"TTL":3600,"data":"\"bitcoin:?lno=lno1ab\" \"cd\""}]}"#;
+        assert_eq!(
+            parse_doh_response("relay", body).unwrap(),
+            vec![TxtRecord {
+                text: "bitcoin:?lno=lno1abcd".to_string(),
+                ttl: 3600
+            }]
+        );
+
+        let unsigned = body.replace("\"AD\":true", "\"AD\":false");
+        assert!(matches!(parse_doh_response("relay", &unsigned), Err(Bip353Error::DnsErr { .. })));
+        assert!(parse_doh_response("relay", r#"{"Status":3,"AD":true}"#).unwrap().is_empty());
+    }
+}
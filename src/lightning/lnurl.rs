@@ -0,0 +1,273 @@
+use crate::types::{PaymentMethod, Relay};
+use log::info;
+use serde::Deserialize;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LnurlError {
+    #[error("InvalidLnurl: {reason}")]
+    InvalidLnurl { reason: String },
+    #[error("InvalidLightningAddress: {reason}")]
+    InvalidLightningAddress { reason: String },
+    #[error("HttpError: {reason}")]
+    HttpErr { reason: String },
+    #[error("ServiceError: {reason}")]
+    ServiceErr { reason: String },
+    #[error("AmountOutOfRange: {reason}")]
+    AmountOutOfRangeErr { reason: String },
+}
+
+/// First response of an LNURL-pay service (LUD-06)
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LnurlPayParams {
+    pub tag: String,
+    pub callback: String,
+    pub min_sendable: i64,
+    pub max_sendable: i64,
+    /// Max comment length the service accepts (LUD-12), 0 if comments aren't supported
+    #[serde(default)]
+    pub comment_allowed: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct LnurlInvoice {
+    pr: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LnurlStatus {
+    status: Option<String>,
+    reason: Option<String>,
+}
+
+/// Decodes a bech32 `lnurl1...` string (optionally prefixed with `lightning:`) or a
+/// LUD-17 `lnurlp://` URL into the https URL of the LNURL-pay service
+pub fn lnurl_to_url(lnurl: &str) -> Result<String, LnurlError> {
+    let lnurl = lnurl.trim();
+    let lnurl = lnurl
+        .strip_prefix("lightning:")
+        .or_else(|| lnurl.strip_prefix("LIGHTNING:"))
+        .unwrap_or(lnurl);
+    if let Some(rest) = lnurl.strip_prefix("lnurlp://") {
+        let scheme = if is_onion(rest) { "http" } else { "https" };
+        return Ok(format!("{}://{}", scheme, rest));
+    }
+
+    let (hrp, data) = bech32::decode(lnurl).map_err(|e| LnurlError::InvalidLnurl {
+        reason: format!("{}: {}", lnurl, e),
+    })?;
+    if !hrp.as_str().eq_ignore_ascii_case("lnurl") {
+        return Err(LnurlError::InvalidLnurl {
+            reason: format!("unexpected prefix {}", hrp),
+        });
+    }
+    String::from_utf8(data).map_err(|e| LnurlError::InvalidLnurl {
+        reason: format!("{}: {}", lnurl, e),
+    })
+}
+
+/// Maps a Lightning Address `name@domain` to its LNURL-pay URL (LUD-16)
+pub fn lightning_address_to_url(address: &str) -> Result<String, LnurlError> {
+    let invalid = || LnurlError::InvalidLightningAddress {
+        reason: address.to_string(),
+    };
+    let (name, domain) = address.trim().split_once('@').ok_or_else(invalid)?;
+    if name.is_empty() || domain.is_empty() || domain.contains(['/', '@']) {
+        return Err(invalid());
+    }
+    let scheme = if is_onion(domain) { "http" } else { "https" };
+    Ok(format!(
+        "{}://{}/.well-known/lnurlp/{}",
+        scheme,
+        domain,
+        name.to_lowercase()
+    ))
+}
+
+fn is_onion(host: &str) -> bool {
+    host.split(['/', ':']).next().unwrap_or_default().ends_with(".onion")
+}
+
+/// The LNURL-pay URL a relay can be paid through over BOLT 11. `PaymentBolt11Lnurl`
+/// wins over `PaymentBolt11LightningAddress` when a relay advertises both.
+pub fn relay_lnurl_pay_url(relay: &Relay) -> Option<Result<String, LnurlError>> {
+    if let Some(lnurl) = relay.payment_bolt11_lnurl.as_ref() {
+        Some(lnurl_to_url(lnurl))
+    } else {
+        relay
+            .payment_bolt11_lightning_address
+            .as_ref()
+            .map(|address| lightning_address_to_url(address))
+    }
+}
+
+/// The BOLT 11 method `relay_lnurl_pay_url` pays the relay through, if it has one
+pub fn relay_bolt11_method(relay: &Relay) -> Option<PaymentMethod> {
+    if relay.payment_bolt11_lnurl.is_some() {
+        Some(PaymentMethod::Lnurl)
+    } else if relay.payment_bolt11_lightning_address.is_some() {
+        Some(PaymentMethod::LightningAddress)
+    } else {
+        None
+    }
+}
+
+/// HTTP client for LNURL requests. Requests go through Tor's SOCKS port when one
+/// is given so relays can't link the client's IP to its payments.
+pub fn lnurl_http_client(socks_port: Option<u16>) -> Result<reqwest::Client, LnurlError> {
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    if let Some(port) = socks_port {
+        let proxy = reqwest::Proxy::all(format!("socks5h://127.0.0.1:{}", port))
+            .map_err(|e| LnurlError::HttpErr { reason: e.to_string() })?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| LnurlError::HttpErr { reason: e.to_string() })
+}
+
+/// Fetches a BOLT 11 invoice for `amount_msats` from the LNURL-pay service at `pay_url`,
+/// sending `comment` (the round's payment id) so the relay can match the invoice
+pub async fn fetch_bolt11_invoice(
+    client: &reqwest::Client,
+    pay_url: &str,
+    amount_msats: i64,
+    comment: &str,
+) -> Result<String, LnurlError> {
+    let params = parse_pay_params(&http_get(client, pay_url, &[]).await?)?;
+    if amount_msats < params.min_sendable || amount_msats > params.max_sendable {
+        return Err(LnurlError::AmountOutOfRangeErr {
+            reason: format!(
+                "{} msats is outside {}-{} msats accepted by {}",
+                amount_msats, params.min_sendable, params.max_sendable, pay_url
+            ),
+        });
+    }
+    if params.comment_allowed < comment.len() {
+        return Err(LnurlError::ServiceErr {
+            reason: format!(
+                "{} accepts comments up to {} chars, the payment id needs {}",
+                pay_url,
+                params.comment_allowed,
+                comment.len()
+            ),
+        });
+    }
+
+    info!("Fetching BOLT 11 invoice for {} msats from {}", amount_msats, params.callback);
+    let amount = amount_msats.to_string();
+    let body = http_get(
+        client,
+        &params.callback,
+        &[("amount", amount.as_str()), ("comment", comment)],
+    )
+    .await?;
+    parse_invoice_response(&body)
+}
+
+async fn http_get(
+    client: &reqwest::Client,
+    url: &str,
+    query: &[(&str, &str)],
+) -> Result<String, LnurlError> {
+    let http_err = |e: reqwest::Error| LnurlError::HttpErr {
+        reason: format!("{}: {}", url, e),
+    };
+    client
+        .get(url)
+        .query(query)
+        .send()
+        .await
+        .map_err(http_err)?
+        .text()
+        .await
+        .map_err(http_err)
+}
+
+/// LNURL services answer errors with HTTP 200 and `{"status":"ERROR","reason":"..."}`
+fn check_status(body: &str) -> Result<(), LnurlError> {
+    if let Ok(LnurlStatus {
+        status: Some(status),
+        reason,
+    }) = serde_json::from_str::<LnurlStatus>(body)
+    {
+        if status.eq_ignore_ascii_case("ERROR") {
+            return Err(LnurlError::ServiceErr {
+                reason: reason.unwrap_or(status),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse_pay_params(body: &str) -> Result<LnurlPayParams, LnurlError> {
+    check_status(body)?;
+    let params: LnurlPayParams =
+        serde_json::from_str(body).map_err(|e| LnurlError::ServiceErr {
+            reason: format!("invalid payRequest response: {}", e),
+        })?;
+    if params.tag != "payRequest" {
+        return Err(LnurlError::ServiceErr {
+            reason: format!("expected a payRequest, got {}", params.tag),
+        });
+    }
+    Ok(params)
+}
+
+fn parse_invoice_response(body: &str) -> Result<String, LnurlError> {
+    check_status(body)?;
+    let invoice: LnurlInvoice = serde_json::from_str(body).map_err(|e| LnurlError::ServiceErr {
+        reason: format!("invalid invoice response: {}", e),
+    })?;
+    Ok(invoice.pr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lnurl_to_url() {
+        // Example from LUD-01
+        let lnurl = "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS";
+        let url = "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+        assert_eq!(lnurl_to_url(lnurl).unwrap(), url);
+        assert_eq!(lnurl_to_url(&format!("lightning:{}", lnurl.to_lowercase())).unwrap(), url);
+        assert_eq!(
+            lnurl_to_url("lnurlp://relay.example.com/pay/1").unwrap(),
+            "https://relay.example.com/pay/1"
+        );
+        assert!(matches!(lnurl_to_url("lnurl1notbech32"), Err(LnurlError::InvalidLnurl { .. })));
+    }
+
+    #[test]
+    fn test_lightning_address_to_url() {
+        assert_eq!(
+            lightning_address_to_url("Relay@example.com").unwrap(),
+            "https://example.com/.well-known/lnurlp/relay"
+        );
+        assert_eq!(
+            lightning_address_to_url("relay@abc.onion").unwrap(),
+            "http://abc.onion/.well-known/lnurlp/relay"
+        );
+        assert!(lightning_address_to_url("example.com").is_err());
+        assert!(lightning_address_to_url("@example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_lnurl_responses() {
+        let params = parse_pay_params(
+            r#"{"tag":"payRequest","callback":"https://example.com/cb","minSendable":1000,"maxSendable":100000,"metadata":"[]","commentAllowed":255}"#,
+        )
+        .unwrap();
+        assert_eq!(params.callback, "https://example.com/cb");
+        assert_eq!(params.comment_allowed, 255);
+        assert!(matches!(
+            parse_pay_params(r#"{"status":"ERROR","reason":"unknown user"}"#),
+            Err(LnurlError::ServiceErr { reason }) if reason == "unknown user"
+        ));
+        assert_eq!(parse_invoice_response(r#"{"pr":"lnbc10n1xyz","routes":[]}"#).unwrap(), "lnbc10n1xyz");
+    }
+}
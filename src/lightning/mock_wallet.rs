@@ -0,0 +1,206 @@
+//! In-memory `LightningNode` for tests.
+//!
+//! Payments succeed with a fixed payment hash and preimage after an optional delay
+//! and an optional number of scripted failures. The mock records every payment and
+//! the highest number of payments it had in flight at once. A payer mock can settle
+//! its offer payments into a payee mock, standing in for the relay's wallet.
+
+use lni::types::{
+    CreateInvoiceParams, ListTransactionsParams, LookupInvoiceParams, NodeInfo, Offer,
+    OnInvoiceEventCallback, OnInvoiceEventParams, PayInvoiceParams, PayInvoiceResponse,
+};
+use lni::{ApiError, LightningNode};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+
+#[derive(Default)]
+pub(crate) struct MockLightningNode {
+    /// How long every pay_offer / pay_invoice call takes
+    pub pay_delay: Duration,
+    /// Number of payments that fail before payments start succeeding
+    pub failures: AtomicUsize,
+    /// Why the failing payments fail, "mock payment failure" if empty
+    pub failure_reason: String,
+    /// Offers and invoices paid, in the order the payments completed
+    pub paid: Mutex<Vec<String>>,
+    /// Returned by list_transactions
+    pub transactions: Mutex<Vec<lni::Transaction>>,
+    /// Makes get_info fail like a node that can't be reached
+    pub unreachable: AtomicBool,
+    /// Receives every paid offer as a settled incoming transaction with the payer note
+    pub payee: Option<Arc<MockLightningNode>>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl MockLightningNode {
+    pub fn with_pay_delay(pay_delay: Duration) -> Self {
+        MockLightningNode {
+            pay_delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn failing(failures: usize) -> Self {
+        MockLightningNode {
+            failures: AtomicUsize::new(failures),
+            ..Default::default()
+        }
+    }
+
+    pub fn failing_with(failures: usize, reason: &str) -> Self {
+        MockLightningNode {
+            failures: AtomicUsize::new(failures),
+            failure_reason: reason.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn paying_into(payee: Arc<MockLightningNode>) -> Self {
+        MockLightningNode {
+            payee: Some(payee),
+            ..Default::default()
+        }
+    }
+
+    /// Highest number of payments that were in flight at the same time
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    async fn pay(&self, target: String) -> Result<PayInvoiceResponse, ApiError> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(self.pay_delay).await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        let failed = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |f| f.checked_sub(1))
+            .is_ok();
+        if failed {
+            let reason = match self.failure_reason.as_str() {
+                "" => "mock payment failure",
+                reason => reason,
+            };
+            return Err(ApiError::Api {
+                reason: reason.to_string(),
+            });
+        }
+        self.paid.lock().unwrap().push(target);
+        Ok(PayInvoiceResponse {
+            payment_hash: "test_hash".to_string(),
+            preimage: "test_preimage".to_string(),
+            fee_msats: 0,
+        })
+    }
+}
+
+fn test_transaction() -> lni::Transaction {
+    lni::Transaction {
+        payment_hash: "test_hash".to_string(),
+        preimage: "test_preimage".to_string(),
+        type_: "incoming".to_string(),
+        amount_msats: 1000000,
+        fees_paid: 0,
+        payer_note: Some("test".to_string()),
+        external_id: Some("test".to_string()),
+        invoice: "test_invoice".to_string(),
+        description: "test".to_string(),
+        description_hash: "".to_string(),
+        settled_at: 0,
+        created_at: 0,
+        expires_at: 0,
+    }
+}
+
+#[async_trait::async_trait]
+impl LightningNode for MockLightningNode {
+    async fn get_info(&self) -> Result<NodeInfo, ApiError> {
+        if self.unreachable.load(Ordering::SeqCst) {
+            return Err(ApiError::Http {
+                reason: "mock node unreachable".to_string(),
+            });
+        }
+        Ok(NodeInfo::default())
+    }
+
+    async fn create_invoice(&self, _params: CreateInvoiceParams) -> Result<lni::Transaction, ApiError> {
+        Ok(test_transaction())
+    }
+
+    async fn pay_invoice(&self, params: PayInvoiceParams) -> Result<PayInvoiceResponse, ApiError> {
+        self.pay(params.invoice).await
+    }
+
+    async fn create_offer(&self, params: lni::CreateOfferParams) -> Result<Offer, ApiError> {
+        Ok(Offer {
+            bolt12: "test_offer".to_string(),
+            offer_id: "test_offer_id".to_string(),
+            label: params.description,
+            active: Some(true),
+            single_use: Some(false),
+            used: Some(false),
+            amount_msats: params.amount_msats,
+        })
+    }
+
+    async fn get_offer(&self, _offer_id: Option<String>) -> Result<Offer, ApiError> {
+        Ok(Offer {
+            bolt12: "test_offer".to_string(),
+            offer_id: "test_offer_id".to_string(),
+            label: Some("test_label".to_string()),
+            active: Some(true),
+            single_use: Some(false),
+            used: Some(false),
+            amount_msats: None,
+        })
+    }
+
+    async fn list_offers(&self, _offer_id: Option<String>) -> Result<Vec<Offer>, ApiError> {
+        Ok(vec![])
+    }
+
+    async fn pay_offer(&self, offer: String, amount_msats: i64, comment: Option<String>) -> Result<PayInvoiceResponse, ApiError> {
+        let response = self.pay(offer.clone()).await?;
+        if let Some(payee) = self.payee.as_ref() {
+            let mut transactions = payee.transactions.lock().unwrap();
+            let now = chrono::Utc::now().timestamp();
+            let payment_hash = format!("mock_hash_{}", transactions.len());
+            transactions.push(lni::Transaction {
+                payment_hash,
+                amount_msats,
+                payer_note: comment,
+                invoice: offer,
+                description: String::new(),
+                settled_at: now,
+                created_at: now,
+                ..test_transaction()
+            });
+        }
+        Ok(response)
+    }
+
+    async fn lookup_invoice(&self, params: LookupInvoiceParams) -> Result<lni::Transaction, ApiError> {
+        let search = params.payment_hash.or(params.search).unwrap_or_default();
+        let transactions = self.transactions.lock().unwrap();
+        let found = transactions.iter().find(|txn| {
+            !search.is_empty()
+                && (txn.payment_hash == search || txn.payer_note.as_deref().is_some_and(|note| note.contains(&search)))
+        });
+        Ok(found.cloned().unwrap_or_else(test_transaction))
+    }
+
+    async fn list_transactions(&self, _params: ListTransactionsParams) -> Result<Vec<lni::Transaction>, ApiError> {
+        Ok(self.transactions.lock().unwrap().clone())
+    }
+
+    async fn decode(&self, _input: String) -> Result<String, ApiError> {
+        Ok("decoded".to_string())
+    }
+
+    async fn on_invoice_events(&self, _params: OnInvoiceEventParams, _callback: Box<dyn OnInvoiceEventCallback>) {
+        // Mock implementation - do nothing
+    }
+}
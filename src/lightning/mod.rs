@@ -1,3 +1,10 @@
+pub mod bip353;
+pub mod lnurl;
+#[cfg(test)]
+pub(crate) mod mock_wallet;
+pub mod payment_method;
 pub mod wallet;
+pub mod wallet_pool;
 
 pub use wallet::*;
+pub use wallet_pool::*;
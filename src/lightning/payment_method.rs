@@ -0,0 +1,132 @@
+use crate::types::{PaymentMethod, Relay};
+
+/// What the client's Lightning backend can pay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentCapabilities {
+    /// Can pay BOLT 12 offers (`PaymentBolt12Offer`, `PaymentBip353`)
+    pub bolt12: bool,
+    /// Can pay BOLT 11 invoices (`PaymentBolt11Lnurl`, `PaymentBolt11LightningAddress`)
+    pub bolt11: bool,
+}
+
+impl PaymentCapabilities {
+    /// Default capabilities of a `PaymentLightningNodeConfig` node type, a config can
+    /// override them (see `WalletConfig::capabilities`).
+    /// Unknown backends are assumed to only pay BOLT 11 invoices.
+    pub fn for_node_type(node_type: &str) -> Self {
+        match node_type {
+            "phoenixd" | "cln" => PaymentCapabilities {
+                bolt12: true,
+                bolt11: true,
+            },
+            _ => PaymentCapabilities {
+                bolt12: false,
+                bolt11: true,
+            },
+        }
+    }
+
+    /// These capabilities with the methods set to `Some` turned on or off
+    pub fn with_overrides(self, bolt12: Option<bool>, bolt11: Option<bool>) -> Self {
+        PaymentCapabilities {
+            bolt12: bolt12.unwrap_or(self.bolt12),
+            bolt11: bolt11.unwrap_or(self.bolt11),
+        }
+    }
+
+    pub fn supports(&self, method: PaymentMethod) -> bool {
+        if method.is_bolt12() {
+            self.bolt12
+        } else {
+            self.bolt11
+        }
+    }
+}
+
+/// Picks the first method the relay advertises that the wallet can pay.
+/// Preference order is BOLT 12 offer, BIP-353, LNURL, then Lightning Address.
+pub fn negotiate_payment_method(
+    relay: &Relay,
+    capabilities: &PaymentCapabilities,
+) -> Option<PaymentMethod> {
+    relay
+        .advertised_payment_methods()
+        .into_iter()
+        .find(|method| capabilities.supports(*method))
+}
+
+/// Whether the client can pay everything the relay charges with the negotiated method.
+/// Handshake fees are only paid to BOLT 12 offers.
+pub fn can_pay_relay(relay: &Relay) -> bool {
+    if relay.is_free() {
+        return true;
+    }
+    match relay.payment_method {
+        Some(method) => relay.payment_handshake_fee.unwrap_or(0) == 0 || method.is_bolt12(),
+        None => false,
+    }
+}
+
+/// Whether the client can pay everything the relay charges with a hard routing fee cap.
+/// Offers take no fee limit, so relays that charge have to be paid over their BOLT 11 endpoint.
+pub fn can_pay_relay_within_fee_cap(relay: &Relay, capabilities: &PaymentCapabilities) -> bool {
+    relay.is_free() || (capabilities.bolt11 && super::lnurl::relay_bolt11_method(relay).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+    use crate::rpc::get_relay_descriptors;
+
+    async fn mock_relay(configure: impl FnOnce(&mut MockRelay)) -> Relay {
+        let mock = MockControlPort::start().await;
+        let mut relay = MockRelay::new("method", 1);
+        relay.payment_bolt12_offer = None;
+        configure(&mut relay);
+        mock.add_relay(relay);
+        get_relay_descriptors(&mock.rpc_config()).await.unwrap().remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_payment_method() {
+        let phoenixd = PaymentCapabilities::for_node_type("phoenixd");
+        let lnd = PaymentCapabilities::for_node_type("lnd");
+
+        let relay = mock_relay(|r| {
+            r.payment_bolt12_offer = Some("lno1relay".to_string());
+            r.extra_descriptor_lines
+                .push("PaymentBolt11LightningAddress relay@example.com".to_string());
+        })
+        .await;
+        assert_eq!(negotiate_payment_method(&relay, &phoenixd), Some(PaymentMethod::Bolt12Offer));
+        assert_eq!(negotiate_payment_method(&relay, &lnd), Some(PaymentMethod::LightningAddress));
+
+        let relay = mock_relay(|r| {
+            r.extra_descriptor_lines.push("PaymentBip353 relay@example.com".to_string());
+        })
+        .await;
+        assert_eq!(negotiate_payment_method(&relay, &phoenixd), Some(PaymentMethod::Bip353));
+        assert_eq!(negotiate_payment_method(&relay, &lnd), None);
+    }
+
+    #[tokio::test]
+    async fn test_can_pay_relay() {
+        let mut relay = mock_relay(|r| {
+            r.payment_handshake_fee = Some(500);
+            r.extra_descriptor_lines
+                .push("PaymentBolt11LightningAddress relay@example.com".to_string());
+        })
+        .await;
+        assert!(!can_pay_relay(&relay));
+
+        relay.payment_method = Some(PaymentMethod::LightningAddress);
+        assert!(!can_pay_relay(&relay), "handshake fees need a BOLT 12 offer");
+        relay.payment_handshake_fee = None;
+        assert!(can_pay_relay(&relay));
+
+        relay.payment_method = None;
+        relay.payment_rate_msats = Some(0);
+        assert!(can_pay_relay(&relay), "free relays need no payment method");
+    }
+}
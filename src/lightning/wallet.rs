@@ -1,149 +1,445 @@
-use log::info;
+use log::{info, warn};
 
 use lni::cln::{ClnConfig, ClnNode};
 use lni::lnd::{LndConfig, LndNode};
 use lni::nwc::{NwcConfig, NwcNode};
 use lni::phoenixd::{PhoenixdConfig, PhoenixdNode};
 use lni::strike::{StrikeConfig, StrikeNode};
-use lni::{LightningNode};
+use lni::types::NodeInfo;
+use lni::LightningNode;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 
-use crate::rpc::get_conf;
+use super::payment_method::PaymentCapabilities;
+use super::wallet_pool::{PoolWallet, WalletPool};
+use crate::rpc::{get_conf, parse_raw_torrc_file};
 use crate::types::RpcConfig;
 
+#[derive(Debug, Error, PartialEq)]
+pub enum WalletConfigError {
+    #[error("NotConfigured: {reason}")]
+    NotConfigured { reason: String },
+    #[error("UnsupportedType: {reason}")]
+    UnsupportedType { reason: String },
+    #[error("MissingField: {reason}")]
+    MissingField { reason: String },
+    #[error("InvalidField: {reason}")]
+    InvalidField { reason: String },
+    #[error("Unreachable: {reason}")]
+    Unreachable { reason: String },
+}
+
+/// Wallet backends a `PaymentLightningNodeConfig` can use
+pub const SUPPORTED_NODE_TYPES: [&str; 5] = ["phoenixd", "lnd", "cln", "nwc", "strike"];
+
+/// Loads every `PaymentLightningNodeConfig` wallet into a `WalletPool`
 pub async fn load_wallet(
     rpc_config: &RpcConfig,
 ) -> Result<Box<dyn LightningNode + Send + Sync>, Box<dyn std::error::Error>> {
+    let pool = load_wallet_pool(rpc_config).await?;
+    Ok(Box::new(pool))
+}
+
+/// Loads every valid `PaymentLightningNodeConfig` wallet, in the order payments try them.
+/// Invalid configs are skipped. Unreachable wallets stay in the pool and are used again
+/// once their health check passes, it is an error if none is reachable.
+pub async fn load_wallet_pool(rpc_config: &RpcConfig) -> Result<WalletPool, WalletConfigError> {
     info!("Loading wallet...");
-    let node_torrc_config = lookup_default_lightning_node_from_torrc(&rpc_config).await?;
-    let lightning_node = get_lightning_node(node_torrc_config).await?;
-    Ok(lightning_node)
+    let configs = lookup_lightning_nodes_from_torrc(rpc_config).await?;
+    let (pool, errors) = wallet_pool_from_configs(&configs);
+    for e in &errors {
+        warn!("Skipping wallet: {}", e);
+    }
+    if pool.wallets().is_empty() {
+        return Err(errors.into_iter().next().unwrap_or(WalletConfigError::NotConfigured {
+            reason: "No PaymentLightningNodeConfig in torrc".to_string(),
+        }));
+    }
+    if pool.check_health().await == 0 {
+        return Err(WalletConfigError::Unreachable {
+            reason: "None of the PaymentLightningNodeConfig wallets is reachable".to_string(),
+        });
+    }
+    Ok(pool)
 }
 
-pub async fn lookup_default_lightning_node_from_torrc(
+/// Builds a pool of every valid config without contacting the nodes (offline mode).
+/// Returns the pool and the errors of the configs left out.
+pub fn wallet_pool_from_configs(configs: &[WalletConfig]) -> (WalletPool, Vec<WalletConfigError>) {
+    let mut wallets = Vec::new();
+    let mut errors = Vec::new();
+    for config in configs {
+        match build_lightning_node(config) {
+            Ok(node) => {
+                info!(
+                    "Loaded wallet {} (priority {}, spending cap {:?} msats)",
+                    config.name, config.priority, config.max_spend_msats
+                );
+                let capabilities = config.capabilities();
+                wallets.push(PoolWallet::new(config.name.clone(), node, capabilities, config.max_spend_msats));
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    (WalletPool::new(wallets), errors)
+}
+
+/// Payment methods at least one `PaymentLightningNodeConfig` backend can pay
+pub async fn load_wallet_capabilities(
     rpc_config: &RpcConfig,
-) -> Result<(String, String), Box<dyn std::error::Error>> {
+) -> Result<PaymentCapabilities, Box<dyn std::error::Error>> {
+    let configs = lookup_lightning_nodes_from_torrc(rpc_config).await?;
+    let capabilities = PaymentCapabilities {
+        bolt12: configs.iter().any(|c| c.capabilities().bolt12),
+        bolt11: configs.iter().any(|c| c.capabilities().bolt11),
+    };
+    info!("Wallet payment capabilities: {:?}", capabilities);
+    Ok(capabilities)
+}
+
+/// One `PaymentLightningNodeConfig` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletConfig {
+    /// `name=`, or e.g. "phoenixd-1a2b3c4d", the node type and a hash of its url (or uri),
+    /// so the wallet keeps its spending record when torrc lines move
+    pub name: String,
+    /// Empty when the line has no `type=`
+    pub node_type: String,
+    /// e.g. "type=phoenixd url=http://url.com password=pass1234 default=true"
+    pub config_line: String,
+    /// `priority=` (default 0), lower priorities are tried first and `default=true` wins ties
+    pub priority: u32,
+    /// `maxSpendMsats=`, the most the wallet may spend, counted in the payments sent ledger
+    pub max_spend_msats: Option<i64>,
+    pub is_default: bool,
+}
+
+impl WalletConfig {
+    /// Value of `key=` on the config line, `None` if it is missing or empty
+    pub fn value(&self, key: &str) -> Option<String> {
+        get_config_value(&self.config_line, key)
+    }
+
+    /// What the wallet can pay: the defaults of its node type, with `bolt12=` / `bolt11=`
+    /// (true or false) overriding them for backends that can pay more or less than usual
+    pub fn capabilities(&self) -> PaymentCapabilities {
+        let flag = |key: &str| self.value(key).and_then(|v| v.parse::<bool>().ok());
+        PaymentCapabilities::for_node_type(&self.node_type).with_overrides(flag("bolt12"), flag("bolt11"))
+    }
+
+    fn required(&self, key: &str) -> Result<String, WalletConfigError> {
+        self.value(key).ok_or_else(|| WalletConfigError::MissingField {
+            reason: format!("{} wallet {} needs {}=", self.node_type, self.name, key),
+        })
+    }
+
+    fn required_url(&self, key: &str) -> Result<String, WalletConfigError> {
+        let url = self.required(key)?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(WalletConfigError::InvalidField {
+                reason: format!("{} of wallet {} is not an http(s) url: {}", key, self.name, url),
+            });
+        }
+        Ok(url)
+    }
+}
+
+pub async fn lookup_lightning_nodes_from_torrc(
+    rpc_config: &RpcConfig,
+) -> Result<Vec<WalletConfig>, WalletConfigError> {
     info!(
-        "Looking up default lightning node from torrc with config: {:?}",
+        "Looking up lightning nodes from torrc with config: {:?}",
         rpc_config
     );
     let lightning_conf_str = get_conf(rpc_config, "PaymentLightningNodeConfig".to_string())
         .await
-        .map_err(|e| format!("Failed to get PaymentLightningNodeConfig from torrc: {}", e))?;
-    info!("Lightning config string: {}", lightning_conf_str);
-    // parse the string "PaymentLightningNodeConfig type=phoenixd url=http://url.com password=pass1234 default=true"
-    // TODO handle mutliple configs for PaymentLightningNodeConfig and choose default
-    let node_type = get_default_value(lightning_conf_str.clone(), "type".to_string())
-        .ok_or("No 'type' found in PaymentLightningNodeConfig")?;
-    Ok((node_type.to_string(), lightning_conf_str))
+        .map_err(|e| WalletConfigError::NotConfigured {
+            reason: format!("Failed to get PaymentLightningNodeConfig from torrc: {}", e),
+        })?;
+    let configs = parse_wallet_configs(&lightning_conf_str);
+    if configs.is_empty() {
+        return Err(WalletConfigError::NotConfigured {
+            reason: "No PaymentLightningNodeConfig in torrc".to_string(),
+        });
+    }
+    Ok(configs)
 }
 
-pub async fn get_lightning_node(
-    (node_type, lightning_conf_str): (String, String),
-) -> Result<Box<dyn LightningNode + Send + Sync>, Box<dyn std::error::Error>> {
-    let node_type_str = node_type.as_str();
-    match node_type_str {
-        "phoenixd" => {
-            let url = get_default_value(lightning_conf_str.clone(), "url".to_string())
-                .ok_or("url not found in torrc config")?;
-            let password = get_default_value(lightning_conf_str.clone(), "password".to_string())
-                .ok_or("password not found in torrc config")?;
-            let config = PhoenixdConfig {
-                url: url.clone(),
-                password: password.clone(),
-                ..Default::default()
-            };
-            let node: Box<dyn LightningNode + Send + Sync> = Box::new(PhoenixdNode::new(config));
-            let info = node.get_info().await?;
-            info!("Phoenixd Node info: {:?}", info);
-            Ok(node)
+/// Reads the `PaymentLightningNodeConfig` lines straight from a torrc file, without Tor
+pub async fn wallet_configs_from_torrc_file(torrc_path: &str) -> Result<Vec<WalletConfig>, WalletConfigError> {
+    let entries = parse_raw_torrc_file(torrc_path)
+        .await
+        .map_err(|e| WalletConfigError::NotConfigured {
+            reason: format!("Failed to read {}: {}", torrc_path, e),
+        })?;
+    let lines: Vec<String> = entries
+        .into_iter()
+        .filter(|e| e.key == "PaymentLightningNodeConfig")
+        .map(|e| e.value)
+        .collect();
+    Ok(parse_wallet_configs(&lines.join("\r\n")))
+}
+
+/// Parses the CRLF separated `PaymentLightningNodeConfig=...` lines of a GETCONF reply,
+/// sorted in the order payments try the wallets
+pub fn parse_wallet_configs(lightning_conf_str: &str) -> Vec<WalletConfig> {
+    let mut configs = Vec::new();
+    for line in lightning_conf_str.split("\r\n") {
+        let config_line = line.replace("PaymentLightningNodeConfig=", "").trim().to_string();
+        // Unset keys come back as a bare "PaymentLightningNodeConfig"
+        if config_line.is_empty() || config_line == "PaymentLightningNodeConfig" {
+            continue;
         }
-        "lnd" => {
-            let url = get_default_value(lightning_conf_str.clone(), "url".to_string())
-                .ok_or("url not found in torrc config")?;
-            let macaroon = get_default_value(lightning_conf_str.clone(), "macaroon".to_string())
-                .ok_or("macaroon not found in torrc config")?;
-            let config = LndConfig {
-                url: url.clone(),
-                macaroon: macaroon.clone(),
-                ..Default::default()
-            };
-            let node: Box<dyn LightningNode + Send + Sync> = Box::new(LndNode::new(config));
-            let info = node.get_info().await?;
-            info!("LND Node info: {:?}", info);
-            Ok(node)
+        let node_type = get_config_value(&config_line, "type").unwrap_or_default();
+        configs.push(WalletConfig {
+            name: wallet_name(&config_line, &node_type),
+            priority: get_config_value(&config_line, "priority")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(0),
+            max_spend_msats: get_config_value(&config_line, "maxSpendMsats").and_then(|m| m.parse().ok()),
+            is_default: get_config_value(&config_line, "default").as_deref() == Some("true"),
+            node_type,
+            config_line,
+        });
+    }
+    configs.sort_by_key(|c| (c.priority, !c.is_default));
+    configs
+}
+
+// `name=` if set, otherwise the node type and a hash of the endpoint the wallet pays from
+fn wallet_name(config_line: &str, node_type: &str) -> String {
+    if let Some(name) = get_config_value(config_line, "name") {
+        return name;
+    }
+    let endpoint = ["url", "uri", "apiKey"]
+        .iter()
+        .find_map(|key| get_config_value(config_line, key))
+        .unwrap_or_default();
+    let digest = Sha256::digest(format!("{}|{}", node_type, endpoint).as_bytes());
+    let node_type = if node_type.is_empty() { "wallet" } else { node_type };
+    format!("{}-{}", node_type, &hex::encode(digest)[..8])
+}
+
+/// Checks a config has everything its backend needs, without contacting the node
+pub fn validate_wallet_config(config: &WalletConfig) -> Result<(), WalletConfigError> {
+    build_lightning_node(config).map(|_| ())
+}
+
+/// Builds the wallet of one `PaymentLightningNodeConfig` line without contacting the node
+pub fn build_lightning_node(
+    config: &WalletConfig,
+) -> Result<Box<dyn LightningNode + Send + Sync>, WalletConfigError> {
+    for key in ["priority", "maxSpendMsats"] {
+        if let Some(value) = config.value(key) {
+            if value.parse::<u64>().is_err() {
+                return Err(WalletConfigError::InvalidField {
+                    reason: format!("{} of wallet {} is not a positive number: {}", key, config.name, value),
+                });
+            }
         }
-        "cln" => {
-            let url = get_default_value(lightning_conf_str.clone(), "url".to_string())
-                .ok_or("url not found in torrc config")?;
-            let rune = get_default_value(lightning_conf_str.clone(), "rune".to_string())
-                .ok_or("rune not found in torrc config")?;
-            let config = ClnConfig {
-                url: url.clone(),
-                rune: rune.clone(),
-                ..Default::default()
-            };
-            let node: Box<dyn LightningNode + Send + Sync> = Box::new(ClnNode::new(config));
-            let info = node.get_info().await?;
-            info!("CLN Node info: {:?}", info);
-            Ok(node)
+    }
+    for key in ["bolt12", "bolt11"] {
+        if let Some(value) = config.value(key) {
+            if value.parse::<bool>().is_err() {
+                return Err(WalletConfigError::InvalidField {
+                    reason: format!("{} of wallet {} is not true or false: {}", key, config.name, value),
+                });
+            }
         }
+    }
+    let capabilities = config.capabilities();
+    if !capabilities.bolt12 && !capabilities.bolt11 {
+        return Err(WalletConfigError::InvalidField {
+            reason: format!("wallet {} can pay neither BOLT 12 offers nor BOLT 11 invoices", config.name),
+        });
+    }
+    let node: Box<dyn LightningNode + Send + Sync> = match config.node_type.as_str() {
+        "phoenixd" => Box::new(PhoenixdNode::new(PhoenixdConfig {
+            url: config.required_url("url")?,
+            password: config.required("password")?,
+            ..Default::default()
+        })),
+        "lnd" => Box::new(LndNode::new(LndConfig {
+            url: config.required_url("url")?,
+            macaroon: config.required("macaroon")?,
+            ..Default::default()
+        })),
+        "cln" => Box::new(ClnNode::new(ClnConfig {
+            url: config.required_url("url")?,
+            rune: config.required("rune")?,
+            ..Default::default()
+        })),
         "nwc" => {
             // PaymentLightningNodeConfig type=nwc uri=nostr+walletconnect://pubkey?relay=...&secret=... default=true
-            let uri = get_default_value(lightning_conf_str.clone(), "uri".to_string())
-                .ok_or("uri not found in torrc config")?;
-            let config = NwcConfig {
-                nwc_uri: uri.clone(),
+            let uri = config.required("uri")?;
+            if !uri.starts_with("nostr+walletconnect://") {
+                return Err(WalletConfigError::InvalidField {
+                    reason: format!("uri of wallet {} is not a nostr+walletconnect:// uri", config.name),
+                });
+            }
+            Box::new(NwcNode::new(NwcConfig {
+                nwc_uri: uri,
                 ..Default::default()
-            };
-            let node: Box<dyn LightningNode + Send + Sync> = Box::new(NwcNode::new(config));
-            let info = node.get_info().await?;
-            info!("NWC Node info: {:?}", info);
-            Ok(node)
+            }))
         }
         "strike" => {
             // PaymentLightningNodeConfig type=strike apiKey=1234abc
-            let url = get_default_value(lightning_conf_str.clone(), "url".to_string())
-                .unwrap_or_else(|| "https://api.strike.me/v1".to_string());
-            let api_key = get_default_value(lightning_conf_str.clone(), "apiKey".to_string())
-                .ok_or("apiKey not found in torrc config")?;
-            let config = StrikeConfig {
-                base_url: Some(url.clone()),
-                api_key: api_key.clone(),
-                 ..Default::default()
+            let url = match config.value("url") {
+                Some(_) => config.required_url("url")?,
+                None => "https://api.strike.me/v1".to_string(),
             };
-            let node: Box<dyn LightningNode + Send + Sync> = Box::new(StrikeNode::new(config));
-            let info = node.get_info().await?;
-            info!("Strike Node info: {:?}", info);
-            Ok(node)
+            Box::new(StrikeNode::new(StrikeConfig {
+                base_url: Some(url),
+                api_key: config.required("apiKey")?,
+                ..Default::default()
+            }))
         }
-        _ => panic!("Unsupported node type: {}", node_type),
-    }
-}
-
-fn get_default_value(lightning_conf_str: String, key: String) -> Option<String> {
-    let config_array = lightning_conf_str.split("\r\n").collect::<Vec<&str>>();
-
-    for config in config_array {
-        if config.contains("default=true") {
-            let binding =
-                config.replace(&"PaymentLightningNodeConfig=".to_string(), &"".to_string());
-            let parts: Vec<&str> = binding.split_whitespace().collect();
-            info!("Config parts: {:?}", parts);
-            let mut val: Option<&str> = None;
-            for part in parts {
-                let formatted_key = format!("{}=", key);
-                if part.contains(&formatted_key) {
-                    // For URI values, we need to get everything after the first '='
-                    // not just split on '=' and take [1]
-                    if let Some(eq_idx) = part.find('=') {
-                        val = Some(&part[eq_idx + 1..]);
-                        break;
-                    }
-                }
-            }
-            info!("Extracted value: {:?}", val);
-            return Some(val.unwrap_or_default().to_string());
+        "" => {
+            return Err(WalletConfigError::MissingField {
+                reason: format!("wallet {} needs type=, one of {}", config.name, SUPPORTED_NODE_TYPES.join(", ")),
+            })
         }
+        node_type => {
+            return Err(WalletConfigError::UnsupportedType {
+                reason: format!(
+                    "wallet {} has type={}, use one of {}",
+                    config.name,
+                    node_type,
+                    SUPPORTED_NODE_TYPES.join(", ")
+                ),
+            })
+        }
+    };
+    Ok(node)
+}
+
+/// Connects to the wallet of one `PaymentLightningNodeConfig` line
+pub async fn get_lightning_node(
+    config: &WalletConfig,
+) -> Result<Box<dyn LightningNode + Send + Sync>, WalletConfigError> {
+    let node = build_lightning_node(config)?;
+    let info = get_node_info(config, &*node).await?;
+    info!("{} Node info: {:?}", config.name, info);
+    Ok(node)
+}
+
+async fn get_node_info(
+    config: &WalletConfig,
+    node: &(dyn LightningNode + Send + Sync),
+) -> Result<NodeInfo, WalletConfigError> {
+    node.get_info().await.map_err(|e| WalletConfigError::Unreachable {
+        reason: format!("wallet {}: {:?}", config.name, e),
+    })
+}
+
+/// Validates a config and, unless `offline`, checks the node answers `get_info`
+pub async fn check_wallet_config(config: &WalletConfig, offline: bool) -> Result<Option<NodeInfo>, WalletConfigError> {
+    let node = build_lightning_node(config)?;
+    if offline {
+        return Ok(None);
+    }
+    get_node_info(config, &*node).await.map(Some)
+}
+
+fn get_config_value(config_line: &str, key: &str) -> Option<String> {
+    let formatted_key = format!("{}=", key);
+    config_line
+        .split_whitespace()
+        // For URI values, we need to get everything after the first '='
+        // not just split on '=' and take [1]
+        .find_map(|part| part.strip_prefix(&formatted_key))
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_wallet_configs() {
+        let conf = [
+            "PaymentLightningNodeConfig=type=lnd url=https://lnd.local macaroon=abc priority=2",
+            "PaymentLightningNodeConfig=type=cln url=https://cln.local rune=xyz maxSpendMsats=50000",
+            "PaymentLightningNodeConfig=type=phoenixd url=http://localhost:9740 password=pw default=true",
+            "PaymentLightningNodeConfig=url=http://no-type.local",
+        ]
+        .join("\r\n");
+        let configs = parse_wallet_configs(&conf);
+        let types: Vec<&str> = configs.iter().map(|c| c.node_type.as_str()).collect();
+        assert_eq!(types, vec!["phoenixd", "cln", "", "lnd"]);
+        assert!(configs[1].name.starts_with("cln-"));
+        assert!(configs[2].name.starts_with("wallet-"));
+        assert_eq!(configs[1].max_spend_msats, Some(50000));
+        assert_eq!(configs[1].config_line, "type=cln url=https://cln.local rune=xyz maxSpendMsats=50000");
+        assert_eq!(configs[3].priority, 2);
+        assert!(configs[0].is_default);
+
+        assert!(parse_wallet_configs("PaymentLightningNodeConfig").is_empty());
+    }
+
+    fn config(line: &str) -> WalletConfig {
+        parse_wallet_configs(line).remove(0)
+    }
+
+    #[test]
+    fn test_wallet_names_survive_torrc_edits() {
+        let lnd = "PaymentLightningNodeConfig=type=lnd url=https://lnd.local macaroon=abc maxSpendMsats=1000";
+        let cln = "PaymentLightningNodeConfig=type=cln url=https://cln.local rune=xyz";
+        let names = |conf: &[&str]| {
+            let mut names: Vec<String> = parse_wallet_configs(&conf.join("\r\n")).into_iter().map(|c| c.name).collect();
+            names.sort();
+            names
+        };
+        // Moving or removing a line doesn't hand its spending record to another wallet
+        assert_eq!(names(&[lnd, cln]), names(&[cln, lnd]));
+        assert_eq!(names(&[lnd])[0], config("type=lnd url=https://lnd.local macaroon=new").name);
+        assert_ne!(names(&[lnd])[0], config("type=lnd url=https://lnd2.local macaroon=abc").name);
+        assert_ne!(
+            config("type=nwc uri=nostr+walletconnect://a").name,
+            config("type=nwc uri=nostr+walletconnect://b").name
+        );
+        assert_eq!(config("type=lnd url=https://lnd.local macaroon=abc name=savings").name, "savings");
+    }
+
+    #[test]
+    fn test_validate_wallet_config() {
+        assert!(validate_wallet_config(&config("type=phoenixd url=http://localhost:9740 password=pw")).is_ok());
+        assert!(validate_wallet_config(&config("type=strike apiKey=abc")).is_ok());
+
+        let err = |line: &str| validate_wallet_config(&config(line)).unwrap_err();
+        assert!(matches!(err("type=eclair url=http://x"), WalletConfigError::UnsupportedType { .. }));
+        assert!(matches!(err("url=http://x"), WalletConfigError::MissingField { .. }));
+        // An empty value is as good as a missing one
+        assert!(matches!(err("type=lnd url=https://lnd.local macaroon="), WalletConfigError::MissingField { .. }));
+        assert!(matches!(err("type=cln url=cln.local rune=xyz"), WalletConfigError::InvalidField { .. }));
+        assert!(matches!(err("type=nwc uri=https://not-nwc"), WalletConfigError::InvalidField { .. }));
+        assert!(matches!(
+            err("type=phoenixd url=http://localhost:9740 password=pw priority=high"),
+            WalletConfigError::InvalidField { .. }
+        ));
+        assert!(matches!(
+            err("type=lnd url=https://lnd.local macaroon=abc bolt12=maybe"),
+            WalletConfigError::InvalidField { .. }
+        ));
+        assert!(matches!(
+            err("type=lnd url=https://lnd.local macaroon=abc bolt11=false"),
+            WalletConfigError::InvalidField { .. }
+        ));
+    }
+
+    #[test]
+    fn test_wallet_capabilities() {
+        let lnd = config("type=lnd url=https://lnd.local macaroon=abc");
+        assert_eq!(lnd.capabilities(), PaymentCapabilities { bolt12: false, bolt11: true });
+        // An LND with an offers plugin, a phoenixd only used for offers
+        let lnd = config("type=lnd url=https://lnd.local macaroon=abc bolt12=true");
+        assert_eq!(lnd.capabilities(), PaymentCapabilities { bolt12: true, bolt11: true });
+        let phoenixd = config("type=phoenixd url=http://localhost:9740 password=pw bolt11=false");
+        assert_eq!(phoenixd.capabilities(), PaymentCapabilities { bolt12: true, bolt11: false });
+
+        let (pool, errors) = wallet_pool_from_configs(&[lnd, phoenixd]);
+        assert!(errors.is_empty());
+        assert_eq!(pool.capabilities(), PaymentCapabilities { bolt12: true, bolt11: true });
     }
-    None
 }
@@ -0,0 +1,565 @@
+//! Every `PaymentLightningNodeConfig` wallet behind one `LightningNode`.
+//!
+//! Payments go to the first healthy wallet, in priority order, that can pay the request
+//! and has spending cap left. When a payment fails before it was sent the pool fails over
+//! to the next wallet. Invoice lookups search every wallet, so a relay sees payments made
+//! to any of its nodes.
+
+use super::payment_method::PaymentCapabilities;
+use crate::database::Db;
+use futures_util::future::BoxFuture;
+use lni::types::{
+    CreateInvoiceParams, ListTransactionsParams, LookupInvoiceParams, NodeInfo, Offer,
+    OnInvoiceEventCallback, OnInvoiceEventParams, PayInvoiceParams, PayInvoiceResponse,
+    Transaction,
+};
+use lni::{ApiError, CreateOfferParams, LightningNode};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Wallets are health checked with `get_info` at most this often, and again after a failed call
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Payment errors that mean the wallet never sent the payment, so another wallet can pay
+/// the same request without paying twice. Anything else (a timeout, a failure reported
+/// while the HTLC was in flight) may still settle.
+const PRE_SEND_ERRORS: [&str; 10] = [
+    "no route",
+    "route not found",
+    "unable to find a path",
+    "insufficient balance",
+    "insufficient funds",
+    "not enough balance",
+    "connection refused",
+    "error trying to connect",
+    "dns error",
+    "unauthorized",
+];
+
+/// A wallet of the pool with its spending cap and health
+pub struct PoolWallet {
+    /// `WalletConfig::name`, what the payments sent ledger records its spending under
+    pub name: String,
+    pub capabilities: PaymentCapabilities,
+    node: Box<dyn LightningNode + Send + Sync>,
+    /// Most this wallet may spend, fees included, as counted in the payments sent ledger.
+    /// `None` is unlimited.
+    max_spend_msats: Option<i64>,
+    spent_msats: AtomicI64,
+    healthy: AtomicBool,
+    last_health_check: Mutex<Option<Instant>>,
+}
+
+impl PoolWallet {
+    pub fn new(
+        name: String,
+        node: Box<dyn LightningNode + Send + Sync>,
+        capabilities: PaymentCapabilities,
+        max_spend_msats: Option<i64>,
+    ) -> Self {
+        PoolWallet {
+            name,
+            capabilities,
+            node,
+            max_spend_msats,
+            spent_msats: AtomicI64::new(0),
+            healthy: AtomicBool::new(true),
+            last_health_check: Mutex::new(None),
+        }
+    }
+
+    /// Msats paid by this wallet so far, fees included
+    pub fn spent_msats(&self) -> i64 {
+        self.spent_msats.load(Ordering::SeqCst)
+    }
+
+    // Counts `msats` toward the spending cap if it fits, in one step so concurrent
+    // payments can't overshoot the cap together
+    fn reserve(&self, msats: i64) -> bool {
+        let max_spend_msats = self.max_spend_msats.unwrap_or(i64::MAX);
+        self.spent_msats
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |spent| {
+                spent.checked_add(msats).filter(|total| *total <= max_spend_msats)
+            })
+            .is_ok()
+    }
+
+    // Gives back the part of a reservation the payment didn't use
+    fn release(&self, msats: i64) {
+        self.spent_msats.fetch_sub(msats, Ordering::SeqCst);
+    }
+
+    /// Runs a `get_info` health check when the last one is older than `HEALTH_CHECK_INTERVAL`
+    async fn is_healthy(&self) -> bool {
+        let check_due = match *self.last_health_check.lock().unwrap() {
+            Some(checked_at) => checked_at.elapsed() >= HEALTH_CHECK_INTERVAL,
+            None => true,
+        };
+        if check_due {
+            let healthy = match self.node.get_info().await {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("⚠️  Wallet {} is unreachable: {:?}", self.name, e);
+                    false
+                }
+            };
+            if healthy && !self.healthy.load(Ordering::SeqCst) {
+                info!("✅ Wallet {} is reachable again", self.name);
+            }
+            self.healthy.store(healthy, Ordering::SeqCst);
+            *self.last_health_check.lock().unwrap() = Some(Instant::now());
+        }
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    // Health check the wallet again before it is used next
+    fn recheck_health(&self) {
+        *self.last_health_check.lock().unwrap() = None;
+    }
+}
+
+enum PayRequest {
+    Invoice(PayInvoiceParams),
+    Offer {
+        offer: String,
+        amount_msats: i64,
+        payer_note: Option<String>,
+    },
+}
+
+impl PayRequest {
+    fn amount_msats(&self) -> i64 {
+        match self {
+            PayRequest::Invoice(params) => params
+                .amount_msats
+                .or_else(|| bolt11_amount_msats(&params.invoice))
+                .unwrap_or(0),
+            PayRequest::Offer { amount_msats, .. } => *amount_msats,
+        }
+    }
+
+    /// Most the routing fee may add, 0 for offers which take no fee limit
+    fn fee_limit_msats(&self) -> i64 {
+        match self {
+            PayRequest::Invoice(params) => params.fee_limit_msat.unwrap_or(0).max(0),
+            PayRequest::Offer { .. } => 0,
+        }
+    }
+
+    fn can_be_paid_by(&self, capabilities: &PaymentCapabilities) -> bool {
+        match self {
+            PayRequest::Invoice(_) => capabilities.bolt11,
+            PayRequest::Offer { .. } => capabilities.bolt12,
+        }
+    }
+}
+
+/// Whether a failed payment was never sent by the wallet
+fn failed_before_sending(e: &ApiError) -> bool {
+    let reason = format!("{:?}", e).to_lowercase();
+    PRE_SEND_ERRORS.iter().any(|pre_send| reason.contains(pre_send))
+}
+
+/// The wallets of every `PaymentLightningNodeConfig`, in the order they are tried
+pub struct WalletPool {
+    wallets: Vec<PoolWallet>,
+    /// Where every payment is recorded against the wallet that made it
+    spend_ledger: Option<Db>,
+}
+
+impl WalletPool {
+    pub fn new(wallets: Vec<PoolWallet>) -> Self {
+        WalletPool {
+            wallets,
+            spend_ledger: None,
+        }
+    }
+
+    /// Counts what every wallet already paid according to `db` (the payments sent ledger)
+    /// toward its spending cap, and records every payment made from now on there
+    pub fn with_spend_ledger(mut self, db: Db) -> Self {
+        for wallet in &self.wallets {
+            match db.wallet_spent_msats(&wallet.name) {
+                Ok(spent_msats) => wallet.spent_msats.store(spent_msats, Ordering::SeqCst),
+                Err(e) => warn!("Failed to read what wallet {} spent so far: {}", wallet.name, e),
+            }
+        }
+        self.spend_ledger = Some(db);
+        self
+    }
+
+    pub fn wallets(&self) -> &[PoolWallet] {
+        &self.wallets
+    }
+
+    /// Payment methods at least one wallet of the pool can pay
+    pub fn capabilities(&self) -> PaymentCapabilities {
+        self.wallets.iter().fold(
+            PaymentCapabilities {
+                bolt12: false,
+                bolt11: false,
+            },
+            |all, wallet| PaymentCapabilities {
+                bolt12: all.bolt12 || wallet.capabilities.bolt12,
+                bolt11: all.bolt11 || wallet.capabilities.bolt11,
+            },
+        )
+    }
+
+    /// Health checks every wallet now and returns how many are reachable
+    pub async fn check_health(&self) -> usize {
+        let mut healthy = 0;
+        for wallet in &self.wallets {
+            wallet.recheck_health();
+            if wallet.is_healthy().await {
+                healthy += 1;
+            }
+        }
+        info!("{}/{} wallets reachable", healthy, self.wallets.len());
+        healthy
+    }
+
+    async fn pay(&self, request: PayRequest) -> Result<PayInvoiceResponse, ApiError> {
+        let amount_msats = request.amount_msats();
+        // The worst case stays counted toward the cap until the real fee is known
+        let reserved_msats = amount_msats + request.fee_limit_msats();
+        let mut last_error = None;
+        for wallet in &self.wallets {
+            if !request.can_be_paid_by(&wallet.capabilities) {
+                continue;
+            }
+            if !wallet.is_healthy().await {
+                continue;
+            }
+            if !wallet.reserve(reserved_msats) {
+                info!(
+                    "Wallet {} reached its spending cap ({} msats spent), skipping",
+                    wallet.name,
+                    wallet.spent_msats()
+                );
+                continue;
+            }
+            let result = match &request {
+                PayRequest::Invoice(params) => wallet.node.pay_invoice(params.clone()).await,
+                PayRequest::Offer {
+                    offer,
+                    amount_msats,
+                    payer_note,
+                } => {
+                    wallet
+                        .node
+                        .pay_offer(offer.clone(), *amount_msats, payer_note.clone())
+                        .await
+                }
+            };
+            match result {
+                Ok(response) => {
+                    let spent_msats = amount_msats + response.fee_msats;
+                    wallet.release(reserved_msats - spent_msats);
+                    if let Some(db) = &self.spend_ledger {
+                        if let Err(e) = db.record_wallet_spend(&wallet.name, &response.payment_hash, spent_msats) {
+                            warn!("Failed to record the payment of wallet {}: {}", wallet.name, e);
+                        }
+                    }
+                    return Ok(response);
+                }
+                Err(e) if failed_before_sending(&e) => {
+                    warn!("Payment with wallet {} failed: {:?}. Failing over to the next wallet", wallet.name, e);
+                    wallet.release(reserved_msats);
+                    wallet.recheck_health();
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    // Paying with another wallet could pay the relay twice. The reservation
+                    // stays counted as the payment may still settle.
+                    warn!("Payment with wallet {} failed and may still be in flight: {:?}. Not failing over", wallet.name, e);
+                    wallet.recheck_health();
+                    return Err(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ApiError::Api {
+            reason: "No reachable wallet with spending cap left can pay this request".to_string(),
+        }))
+    }
+
+    // Runs `call` on the first reachable wallet it succeeds on
+    async fn with_failover<'a, T>(
+        &'a self,
+        call: impl Fn(&'a (dyn LightningNode + Send + Sync)) -> BoxFuture<'a, Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        let mut last_error = None;
+        for wallet in &self.wallets {
+            if !wallet.is_healthy().await {
+                continue;
+            }
+            match call(&*wallet.node).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    warn!("Wallet {} failed: {:?}. Failing over to the next wallet", wallet.name, e);
+                    wallet.recheck_health();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ApiError::Api {
+            reason: "No reachable wallet".to_string(),
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl LightningNode for WalletPool {
+    async fn get_info(&self) -> Result<NodeInfo, ApiError> {
+        self.with_failover(|node| node.get_info()).await
+    }
+
+    async fn create_invoice(&self, params: CreateInvoiceParams) -> Result<Transaction, ApiError> {
+        self.with_failover(|node| node.create_invoice(params.clone())).await
+    }
+
+    async fn pay_invoice(&self, params: PayInvoiceParams) -> Result<PayInvoiceResponse, ApiError> {
+        self.pay(PayRequest::Invoice(params)).await
+    }
+
+    async fn create_offer(&self, params: CreateOfferParams) -> Result<Offer, ApiError> {
+        self.with_failover(|node| node.create_offer(params.clone())).await
+    }
+
+    async fn get_offer(&self, offer_id: Option<String>) -> Result<Offer, ApiError> {
+        self.with_failover(|node| node.get_offer(offer_id.clone())).await
+    }
+
+    async fn list_offers(&self, offer_id: Option<String>) -> Result<Vec<Offer>, ApiError> {
+        self.with_failover(|node| node.list_offers(offer_id.clone())).await
+    }
+
+    async fn pay_offer(
+        &self,
+        offer: String,
+        amount_msats: i64,
+        payer_note: Option<String>,
+    ) -> Result<PayInvoiceResponse, ApiError> {
+        self.pay(PayRequest::Offer {
+            offer,
+            amount_msats,
+            payer_note,
+        })
+        .await
+    }
+
+    /// Searches every wallet, a settled invoice wins over an unpaid one
+    async fn lookup_invoice(&self, params: LookupInvoiceParams) -> Result<Transaction, ApiError> {
+        let mut found = None;
+        let mut last_error = None;
+        for wallet in &self.wallets {
+            match wallet.node.lookup_invoice(params.clone()).await {
+                Ok(txn) if txn.settled_at > 0 => return Ok(txn),
+                Ok(txn) => {
+                    found.get_or_insert(txn);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match (found, last_error) {
+            (Some(txn), _) => Ok(txn),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(ApiError::Api {
+                reason: "No wallet configured".to_string(),
+            }),
+        }
+    }
+
+    /// Transactions of every reachable wallet
+    async fn list_transactions(&self, params: ListTransactionsParams) -> Result<Vec<Transaction>, ApiError> {
+        let mut transactions = Vec::new();
+        let mut last_error = None;
+        let mut any_ok = false;
+        for wallet in &self.wallets {
+            match wallet.node.list_transactions(params.clone()).await {
+                Ok(txns) => {
+                    any_ok = true;
+                    transactions.extend(txns);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) if !any_ok => Err(e),
+            _ => Ok(transactions),
+        }
+    }
+
+    async fn decode(&self, input: String) -> Result<String, ApiError> {
+        self.with_failover(|node| node.decode(input.clone())).await
+    }
+
+    /// With more than one wallet the pool polls `lookup_invoice` itself so an invoice
+    /// settled on any wallet is seen
+    async fn on_invoice_events(&self, params: OnInvoiceEventParams, callback: Box<dyn OnInvoiceEventCallback>) {
+        if let [wallet] = self.wallets.as_slice() {
+            return wallet.node.on_invoice_events(params, callback).await;
+        }
+        let started = Instant::now();
+        let max_polling = Duration::from_secs(params.max_polling_sec.max(0) as u64);
+        let polling_delay = Duration::from_secs(params.polling_delay_sec.max(1) as u64);
+        loop {
+            let lookup = LookupInvoiceParams {
+                payment_hash: None,
+                search: params.search.clone(),
+            };
+            match self.lookup_invoice(lookup).await {
+                Ok(txn) if txn.settled_at > 0 => return callback.success(Some(txn)),
+                Ok(txn) => callback.pending(Some(txn)),
+                Err(_) => callback.pending(None),
+            }
+            if started.elapsed() >= max_polling {
+                return callback.failure(None);
+            }
+            tokio::time::sleep(polling_delay).await;
+        }
+    }
+}
+
+/// Amount of a BOLT 11 invoice from its human readable part, e.g. "lnbc2500u1...".
+/// `None` for invoices without an amount.
+fn bolt11_amount_msats(invoice: &str) -> Option<i64> {
+    let invoice = invoice.trim().to_lowercase();
+    let hrp = &invoice[..invoice.rfind('1')?];
+    // Skip the currency prefix: bc, tb, tbs, bcrt
+    let amount = hrp.strip_prefix("ln")?.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let (digits, multiplier) = match amount.char_indices().last()? {
+        (idx, c) if c.is_ascii_alphabetic() => (&amount[..idx], Some(c)),
+        _ => (amount, None),
+    };
+    let value: i64 = digits.parse().ok()?;
+    match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') => Some(value / 10),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock_wallet::MockLightningNode;
+
+    const BOTH: PaymentCapabilities = PaymentCapabilities {
+        bolt12: true,
+        bolt11: true,
+    };
+
+    fn pool_wallet(name: &str, node: MockLightningNode, capabilities: PaymentCapabilities, max_spend_msats: Option<i64>) -> PoolWallet {
+        PoolWallet::new(name.to_string(), Box::new(node), capabilities, max_spend_msats)
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_to_the_next_wallet() {
+        let pool = WalletPool::new(vec![
+            pool_wallet("first", MockLightningNode::failing_with(1, "no route found"), BOTH, None),
+            pool_wallet("second", MockLightningNode::default(), BOTH, None),
+        ]);
+        pool.pay_offer("lno1relay".to_string(), 1000, None).await.unwrap();
+        assert_eq!(pool.wallets()[0].spent_msats(), 0);
+        assert_eq!(pool.wallets()[1].spent_msats(), 1000);
+
+        // The first wallet pays again once it works
+        pool.pay_offer("lno1relay".to_string(), 1000, None).await.unwrap();
+        assert_eq!(pool.wallets()[0].spent_msats(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_no_failover_when_the_payment_may_be_in_flight() {
+        let pool = WalletPool::new(vec![
+            pool_wallet("first", MockLightningNode::failing_with(1, "request timed out"), BOTH, None),
+            pool_wallet("second", MockLightningNode::default(), BOTH, None),
+        ]);
+        assert!(pool.pay_offer("lno1relay".to_string(), 1000, None).await.is_err());
+        assert_eq!(pool.wallets()[1].spent_msats(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spending_caps_survive_restarts() {
+        let db = Db::with_store(std::sync::Arc::new(crate::database::SqliteStore::open_in_memory().unwrap()));
+        let pool = WalletPool::new(vec![pool_wallet("capped", MockLightningNode::default(), BOTH, Some(1500))])
+            .with_spend_ledger(db.clone());
+        pool.pay_offer("lno1relay".to_string(), 1000, None).await.unwrap();
+
+        // A new pool, e.g. after a restart, starts from what the ledger recorded
+        let pool = WalletPool::new(vec![pool_wallet("capped", MockLightningNode::default(), BOTH, Some(1500))])
+            .with_spend_ledger(db);
+        assert_eq!(pool.wallets()[0].spent_msats(), 1000);
+        assert!(pool.pay_offer("lno1relay".to_string(), 1000, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_skips_unreachable_capped_and_incapable_wallets() {
+        let unreachable = MockLightningNode::default();
+        unreachable.unreachable.store(true, Ordering::SeqCst);
+        let bolt11_only = PaymentCapabilities {
+            bolt12: false,
+            bolt11: true,
+        };
+        let pool = WalletPool::new(vec![
+            pool_wallet("unreachable", unreachable, BOTH, None),
+            pool_wallet("bolt11", MockLightningNode::default(), bolt11_only, None),
+            pool_wallet("capped", MockLightningNode::default(), BOTH, Some(1500)),
+        ]);
+        assert_eq!(pool.check_health().await, 2);
+        assert_eq!(pool.capabilities(), BOTH);
+
+        pool.pay_offer("lno1relay".to_string(), 1000, None).await.unwrap();
+        assert_eq!(pool.wallets()[2].spent_msats(), 1000);
+        // The only BOLT 12 wallet left has no cap left for another round
+        assert!(pool.pay_offer("lno1relay".to_string(), 1000, None).await.is_err());
+
+        let invoice = PayInvoiceParams {
+            invoice: "lnbc10n1pjtest".to_string(),
+            ..Default::default()
+        };
+        pool.pay_invoice(invoice).await.unwrap();
+        assert_eq!(pool.wallets()[1].spent_msats(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_payments_reserve_the_cap() {
+        let pool = WalletPool::new(vec![pool_wallet(
+            "capped",
+            MockLightningNode::with_pay_delay(Duration::from_millis(50)),
+            BOTH,
+            Some(1500),
+        )]);
+        let (first, second) = tokio::join!(
+            pool.pay_offer("lno1relay".to_string(), 1000, None),
+            pool.pay_offer("lno1relay".to_string(), 1000, None)
+        );
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(pool.wallets()[0].spent_msats(), 1000);
+
+        // The fee limit is reserved too and released once the real fee (0) is known
+        let pool = WalletPool::new(vec![pool_wallet("capped", MockLightningNode::default(), BOTH, Some(1500))]);
+        let invoice = |fee_limit_msat| PayInvoiceParams {
+            invoice: "lnbc10n1pjtest".to_string(),
+            fee_limit_msat: Some(fee_limit_msat),
+            ..Default::default()
+        };
+        assert!(pool.pay_invoice(invoice(600)).await.is_err());
+        pool.pay_invoice(invoice(500)).await.unwrap();
+        assert_eq!(pool.wallets()[0].spent_msats(), 1000);
+    }
+
+    #[test]
+    fn test_bolt11_amount_msats() {
+        assert_eq!(bolt11_amount_msats("lnbc2500u1pvjluez"), Some(250_000_000));
+        assert_eq!(bolt11_amount_msats("LNTB10N1PJTEST"), Some(1000));
+        assert_eq!(bolt11_amount_msats("lnbcrt1m1pjtest"), Some(100_000_000));
+        assert_eq!(bolt11_amount_msats("lntbs20p1pjtest"), Some(2));
+        assert_eq!(bolt11_amount_msats("lnbc1pvjluez"), None);
+    }
+}
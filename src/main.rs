@@ -1,6 +1,8 @@
+use eltor::database::{self, LedgerKind, LedgerRecord, LedgerSummary, PaymentFilter};
 use eltor::init_and_run;
+use eltor::lightning;
 use eltor::logging::setup_logging;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
 use std::env;
 
@@ -19,6 +21,10 @@ struct Args {
     #[arg(short = 'p', long = "pw")]
     password: Option<String>,
     
+    /// Directory for ledgers and other state (default: ELTOR_DATA_DIR or <DataDirectory>/eltor from the torrc)
+    #[arg(short = 'd', long = "data-dir")]
+    data_dir: Option<String>,
+    
     /// Optional log file path for output (non-blocking file logging)
     #[arg(short = 'l', long = "log-file")]
     log_file: Option<String>,
@@ -36,11 +42,155 @@ struct Args {
     torrc_path: Option<String>,
 }
 
+/// eltor ledger: query and export the payment ledgers
+#[derive(Parser, Debug)]
+#[command(name = "eltor ledger", about = "Query and export the payment ledgers")]
+struct LedgerArgs {
+    /// Torrc configuration file path (used to find the data directory)
+    #[arg(short = 'f', long = "config", default_value = "torrc", global = true)]
+    config: String,
+    
+    /// Directory holding the ledgers (overrides ELTOR_DATA_DIR)
+    #[arg(short = 'd', long = "data-dir", global = true)]
+    data_dir: Option<String>,
+    
+    /// Which ledger to read
+    #[arg(long = "ledger", value_enum, default_value_t = LedgerChoice::Both, global = true)]
+    ledger: LedgerChoice,
+    
+    #[command(subcommand)]
+    command: LedgerCommand,
+}
+
+/// eltor wallet: check the PaymentLightningNodeConfig wallets
+#[derive(Parser, Debug)]
+#[command(name = "eltor wallet", about = "Check the PaymentLightningNodeConfig wallets")]
+struct WalletArgs {
+    /// Torrc configuration file path
+    #[arg(short = 'f', long = "config", default_value = "torrc", global = true)]
+    config: String,
+    
+    #[command(subcommand)]
+    command: WalletCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum WalletCommand {
+    /// Validate every wallet config and check the nodes are reachable, without starting Tor
+    Check {
+        /// Only validate the configs, don't contact the nodes
+        #[arg(long = "offline")]
+        offline: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LedgerCommand {
+    /// List payments
+    List(FilterArgs),
+    /// Show unpaid or errored rounds
+    Unpaid(FilterArgs),
+    /// Sum msats spent and earned, and routing fees paid
+    Summary(FilterArgs),
+    /// Export payments as CSV or JSON
+    Export {
+        #[arg(long = "format", value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        
+        /// Write to this file instead of stdout
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+        
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// Only payments for this circuit id
+    #[arg(long = "circuit")]
+    circuit: Option<String>,
+    
+    /// Only payments to/from this relay fingerprint
+    #[arg(long = "relay")]
+    relay: Option<String>,
+    
+    /// Only payments updated at or after this time (unix seconds, YYYY-MM-DD or RFC 3339)
+    #[arg(long = "since", value_parser = parse_time)]
+    since: Option<i64>,
+    
+    /// Only payments updated at or before this time (unix seconds, YYYY-MM-DD or RFC 3339)
+    #[arg(long = "until", value_parser = parse_time)]
+    until: Option<i64>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self, problems_only: bool) -> PaymentFilter {
+        PaymentFilter {
+            circ_id: self.circuit.clone(),
+            relay_fingerprint: self.relay.clone(),
+            since: self.since,
+            until: self.until,
+            problems_only,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LedgerChoice {
+    Sent,
+    Received,
+    Both,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(ts) = value.parse::<i64>() {
+        return Ok(ts);
+    }
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.timestamp());
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+        .map_err(|_| format!("invalid time '{}', use unix seconds, YYYY-MM-DD or RFC 3339", value))
+}
+
 #[tokio::main]
 async fn main() {
     // Load .env file first
     dotenv().ok();
     
+    // `eltor ledger ...` is a one-shot query tool, not the daemon
+    if env::args().nth(1).as_deref() == Some("ledger") {
+        let ledger_args = LedgerArgs::parse_from(env::args().skip(1));
+        if let Err(e) = run_ledger_command(ledger_args).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    // `eltor wallet check` reports wallet config problems without starting Tor
+    if env::args().nth(1).as_deref() == Some("wallet") {
+        let wallet_args = WalletArgs::parse_from(env::args().skip(1));
+        match run_wallet_command(wallet_args).await {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    
     // Check if ARGS env variable is set (takes precedence over CLI args)
     let args = if let Ok(env_args) = env::var("ARGS") {
         // Parse ARGS environment variable
@@ -81,6 +231,11 @@ async fn main() {
             lib_args.push(password);
         }
         
+        if let Some(data_dir) = args.data_dir {
+            lib_args.push("--data-dir".to_string());
+            lib_args.push(data_dir);
+        }
+        
         // Use shell_words to properly quote arguments with spaces
         let args_string = shell_words::join(&lib_args);
         env::set_var("ARGS", args_string);
@@ -97,6 +252,140 @@ async fn main() {
     }
 }
 
+async fn run_ledger_command(args: LedgerArgs) -> Result<(), Box<dyn std::error::Error>> {
+    eltor::init_data_dir(&args.config, args.data_dir.clone()).await;
+    let now = chrono::Utc::now().timestamp();
+    
+    let (filter_args, problems_only) = match &args.command {
+        LedgerCommand::List(f) | LedgerCommand::Summary(f) => (f, false),
+        LedgerCommand::Unpaid(f) => (f, true),
+        LedgerCommand::Export { filter, .. } => (filter, false),
+    };
+    let filter = filter_args.to_filter(problems_only);
+    
+    // Read only: a query never creates, migrates or imports a ledger. With both
+    // ledgers picked, a host that only runs a client (or a relay) has just one.
+    let mut ledgers = Vec::new();
+    let mut missing = Vec::new();
+    for (kind, name, picked) in [
+        (LedgerKind::Sent, database::PAYMENTS_SENT_LEDGER, args.ledger != LedgerChoice::Received),
+        (LedgerKind::Received, database::PAYMENTS_RECEIVED_LEDGER, args.ledger != LedgerChoice::Sent),
+    ] {
+        if !picked {
+            continue;
+        }
+        match database::Db::open_read_only(&database::data_path(name)) {
+            Ok(db) => ledgers.push((kind, db)),
+            Err(e @ database::DbError::NotFoundErr { .. }) if args.ledger == LedgerChoice::Both => missing.push(e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if ledgers.is_empty() {
+        let reasons: Vec<String> = missing.iter().map(|e| e.to_string()).collect();
+        return Err(reasons.join(", ").into());
+    }
+    let mut records = Vec::new();
+    for (kind, db) in &ledgers {
+        records.extend(LedgerRecord::collect(*kind, db.all_payments()?, &filter, now));
+    }
+    
+    match args.command {
+        LedgerCommand::List(_) | LedgerCommand::Unpaid(_) => print_records(&records),
+        LedgerCommand::Summary(_) => {
+            for (kind, _) in &ledgers {
+                let ledger_records: Vec<LedgerRecord> =
+                    records.iter().filter(|r| r.ledger == *kind).cloned().collect();
+                print_summary(*kind, &LedgerSummary::from_records(&ledger_records));
+            }
+        }
+        LedgerCommand::Export { format, output, .. } => {
+            let rendered = match format {
+                ExportFormat::Csv => database::records_to_csv(&records),
+                ExportFormat::Json => serde_json::to_string_pretty(&records)? + "\n",
+            };
+            match output {
+                Some(path) => std::fs::write(&path, rendered)?,
+                None => print!("{}", rendered),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether every wallet passed the check
+async fn run_wallet_command(args: WalletArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let WalletCommand::Check { offline } = args.command;
+    let configs = lightning::wallet_configs_from_torrc_file(&args.config).await?;
+    if configs.is_empty() {
+        println!("No PaymentLightningNodeConfig found in {}", args.config);
+        return Ok(false);
+    }
+    let mut all_ok = true;
+    for config in &configs {
+        let label = format!(
+            "{} (priority {}{})",
+            config.name,
+            config.priority,
+            if config.is_default { ", default" } else { "" }
+        );
+        match lightning::check_wallet_config(config, offline).await {
+            Ok(Some(info)) => println!("✅ {}: reachable, alias {:?}", label, info.alias),
+            Ok(None) => println!("✅ {}: config valid", label),
+            Err(e) => {
+                all_ok = false;
+                println!("❌ {}: {}", label, e);
+            }
+        }
+    }
+    Ok(all_ok)
+}
+
+fn print_records(records: &[LedgerRecord]) {
+    if records.is_empty() {
+        println!("No payments found");
+        return;
+    }
+    println!(
+        "{:<8} {:<7} {:<10} {:>5} {:<40} {:>10} {:>8} {:<17}  UPDATED",
+        "LEDGER", "STATUS", "CIRCUIT", "ROUND", "RELAY", "MSATS", "FEE", "METHOD"
+    );
+    for r in records {
+        let p = &r.payment;
+        let updated = chrono::DateTime::from_timestamp(p.updated_at, 0)
+            .map(|d| d.to_rfc3339())
+            .unwrap_or_else(|| p.updated_at.to_string());
+        println!(
+            "{:<8} {:<7} {:<10} {:>5} {:<40} {:>10} {:>8} {:<17}  {}",
+            r.ledger.as_str(),
+            r.status.as_str(),
+            p.circ_id,
+            p.round,
+            p.relay_fingerprint,
+            p.amount_msat,
+            p.fee.map(|f| f.to_string()).unwrap_or_else(|| "-".to_string()),
+            p.payment_method.as_deref().unwrap_or("-"),
+            updated
+        );
+    }
+}
+
+fn print_summary(kind: LedgerKind, summary: &LedgerSummary) {
+    let paid_label = match kind {
+        LedgerKind::Sent => "spent",
+        LedgerKind::Received => "earned",
+    };
+    println!("{} ledger", kind.as_str());
+    println!(
+        "  payments: {} ({} paid, {} pending, {} unpaid, {} errored)",
+        summary.payments, summary.paid, summary.pending, summary.unpaid, summary.errored
+    );
+    println!("  {}: {} msats", paid_label, summary.paid_msat);
+    println!("  unpaid: {} msats", summary.unpaid_msat);
+    if kind == LedgerKind::Sent {
+        println!("  routing fees: {} msats", summary.fees_msat);
+    }
+}
+
 #[cfg(windows)]
 async fn run_tor_subprocess(torrc_path: String) {
     use libtor::{Tor, TorFlag};
@@ -0,0 +1,153 @@
+use crate::database::Db;
+use crate::utils::preimage_matches_payhash;
+use lni::types::{LookupInvoiceParams, Transaction};
+use lni::LightningNode;
+use thiserror::Error;
+
+use super::RelayPayments;
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("InvalidPreimage: {reason}")]
+    InvalidPreimage { reason: String },
+    #[error("NotPaid: {reason}")]
+    NotPaid { reason: String },
+    #[error("Underpaid: {reason}")]
+    Underpaid { reason: String },
+    #[error("Reused: {reason}")]
+    Reused { reason: String },
+}
+
+/// Verifies the handshake fee a client paid before extending a circuit through this relay.
+/// The preimage from EXTENDPAIDCIRCUIT has to hash to the handshake payment hash and the
+/// invoice with that hash has to be settled on our wallet for at least `fee_msats`.
+pub async fn verify_handshake_fee(
+    wallet: &(dyn LightningNode + Send + Sync),
+    relay_payments: &RelayPayments,
+    fee_msats: u32,
+) -> Result<(), HandshakeError> {
+    if !preimage_matches_payhash(
+        &relay_payments.handshake_payment_hash,
+        &relay_payments.handshake_preimage,
+    ) {
+        return Err(HandshakeError::InvalidPreimage {
+            reason: format!(
+                "preimage does not match handshake payment hash {}",
+                relay_payments.handshake_payment_hash
+            ),
+        });
+    }
+
+    let invoice = wallet
+        .lookup_invoice(LookupInvoiceParams {
+            payment_hash: Some(relay_payments.handshake_payment_hash.clone()),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| HandshakeError::NotPaid {
+            reason: format!(
+                "no invoice for handshake payment hash {}: {:?}",
+                relay_payments.handshake_payment_hash, e
+            ),
+        })?;
+    check_handshake_invoice(&invoice, fee_msats)
+}
+
+/// Claims a verified handshake fee for `circuit_id` in the received ledger. A handshake
+/// pays for one circuit, so a payment hash another circuit already used is rejected.
+pub fn claim_handshake_fee(
+    db: &Db,
+    relay_payments: &RelayPayments,
+    circuit_id: &str,
+) -> Result<(), HandshakeError> {
+    let claimed = db
+        .claim_handshake(&relay_payments.handshake_payment_hash, circuit_id)
+        .map_err(|e| HandshakeError::NotPaid {
+            reason: format!(
+                "failed to record handshake payment hash {}: {}",
+                relay_payments.handshake_payment_hash, e
+            ),
+        })?;
+    if !claimed {
+        return Err(HandshakeError::Reused {
+            reason: format!(
+                "handshake payment hash {} was already used by another circuit",
+                relay_payments.handshake_payment_hash
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Checks the wallet's invoice for the handshake payment hash
+fn check_handshake_invoice(invoice: &Transaction, fee_msats: u32) -> Result<(), HandshakeError> {
+    if invoice.settled_at <= 0 {
+        return Err(HandshakeError::NotPaid {
+            reason: format!("handshake invoice {} is not settled", invoice.payment_hash),
+        });
+    }
+    if invoice.amount_msats < fee_msats as i64 {
+        return Err(HandshakeError::Underpaid {
+            reason: format!(
+                "handshake invoice {} paid {} msats, fee is {} msats",
+                invoice.payment_hash, invoice.amount_msats, fee_msats
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice(amount_msats: i64, settled_at: i64) -> Transaction {
+        Transaction {
+            payment_hash: "handshake_hash".to_string(),
+            preimage: "handshake_preimage".to_string(),
+            type_: "incoming".to_string(),
+            amount_msats,
+            fees_paid: 0,
+            payer_note: None,
+            external_id: None,
+            invoice: "test_invoice".to_string(),
+            description: "".to_string(),
+            description_hash: "".to_string(),
+            settled_at,
+            created_at: 0,
+            expires_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_check_handshake_invoice() {
+        assert!(check_handshake_invoice(&invoice(5000, 1700000000), 5000).is_ok());
+        assert!(matches!(
+            check_handshake_invoice(&invoice(5000, 0), 5000),
+            Err(HandshakeError::NotPaid { .. })
+        ));
+        assert!(matches!(
+            check_handshake_invoice(&invoice(4999, 1700000000), 5000),
+            Err(HandshakeError::Underpaid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_claim_handshake_fee_rejects_reuse() {
+        let db = Db::with_store(std::sync::Arc::new(
+            crate::database::SqliteStore::open_in_memory().unwrap(),
+        ));
+        let handshake = RelayPayments {
+            handshake_payment_hash: "handshake_hash".to_string(),
+            handshake_preimage: "handshake_preimage".to_string(),
+            payhashes: Vec::new(),
+            built_at: None,
+        };
+        assert!(claim_handshake_fee(&db, &handshake, "5").is_ok());
+        assert!(claim_handshake_fee(&db, &handshake, "5").is_ok());
+        assert!(matches!(
+            claim_handshake_fee(&db, &handshake, "6"),
+            Err(HandshakeError::Reused { .. })
+        ));
+    }
+}
@@ -0,0 +1,408 @@
+use super::TimerWheel;
+use lni::types::{ListTransactionsParams, LookupInvoiceParams, OnInvoiceEventCallback, Transaction};
+use lni::LightningNode;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{Duration, Instant};
+
+/// Length of one timer wheel tick
+const TICK: Duration = Duration::from_secs(1);
+/// Enough slots that a circuit's rounds rarely share one
+const WHEEL_SLOTS: usize = 1024;
+/// The wallet is polled for settled invoices every this many ticks while rounds are waiting
+/// or payment ids are tracked
+const POLL_EVERY_TICKS: u64 = 3;
+/// Most recent transactions fetched per poll
+const POLL_TRANSACTIONS_LIMIT: i64 = 100;
+/// Transactions fetched when catching up on invoices that settled before being tracked
+const CATCH_UP_TRANSACTIONS_LIMIT: i64 = 1000;
+
+enum Timer {
+    /// A round's payment window closed
+    Round(String),
+    /// A task waiting in `sleep_until`
+    Wake(oneshot::Sender<()>),
+}
+
+struct Waiter {
+    payment_id: String,
+    deadline_tick: u64,
+    callback: Box<dyn OnInvoiceEventCallback>,
+    cancellation_receiver: broadcast::Receiver<()>,
+}
+
+impl Waiter {
+    // Whether the circuit was torn down while the round was waiting
+    fn is_cancelled(&mut self) -> bool {
+        !matches!(
+            self.cancellation_receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        )
+    }
+}
+
+struct DispatchState {
+    /// Rounds waiting for their invoice, by lowercase payment id
+    waiting: HashMap<String, Waiter>,
+    /// Settled invoices of the tracked payment ids, by lowercase payment id
+    settled: HashMap<String, Vec<Transaction>>,
+    timers: TimerWheel<Timer>,
+}
+
+impl DispatchState {
+    // Adds a settled invoice to every tracked payment id it carries, each invoice once
+    fn index(&mut self, txn: &Transaction) {
+        for key in candidate_payment_ids(txn) {
+            if let Some(settled) = self.settled.get_mut(&key) {
+                if !settled.iter().any(|known| known.payment_hash == txn.payment_hash) {
+                    settled.push(txn.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The one invoice subscription of a wallet. Instead of every round polling the node
+/// for its own invoice, rounds register their payment id here: a single task polls the
+/// wallet's recent transactions and hands each settled invoice to the round waiting
+/// for the payment id it carries. Round deadlines all live on one timer wheel, a round
+/// still unpaid when its deadline passes gets `failure`.
+///
+/// Checks that add up several invoices of one payment id (top ups, prepaid credit)
+/// `track` it instead: the same poll indexes every settled invoice carrying it.
+pub struct InvoiceDispatcher {
+    wallet: Arc<dyn LightningNode + Send + Sync>,
+    started: Instant,
+    state: Mutex<DispatchState>,
+}
+
+impl InvoiceDispatcher {
+    /// A dispatcher that doesn't run yet, see `start`
+    pub fn new(wallet: Arc<dyn LightningNode + Send + Sync>) -> Arc<Self> {
+        Arc::new(InvoiceDispatcher {
+            wallet,
+            started: Instant::now(),
+            state: Mutex::new(DispatchState {
+                waiting: HashMap::new(),
+                settled: HashMap::new(),
+                timers: TimerWheel::new(WHEEL_SLOTS),
+            }),
+        })
+    }
+
+    /// Starts polling the wallet and ticking the timer wheel
+    pub fn start(wallet: Arc<dyn LightningNode + Send + Sync>) -> Arc<Self> {
+        let dispatcher = InvoiceDispatcher::new(wallet);
+        tokio::spawn(dispatcher.clone().run());
+        dispatcher
+    }
+
+    // Rounded up so nothing fires early
+    fn tick_of(&self, deadline: Instant) -> u64 {
+        let since = deadline.saturating_duration_since(self.started).as_millis() as u64;
+        since.div_ceil(TICK.as_millis() as u64)
+    }
+
+    /// Hands the first settled invoice carrying `payment_id` to `callback.success`, or
+    /// calls `callback.failure` once `deadline` passes without one. Nothing is called
+    /// once the circuit behind `cancellation_receiver` is torn down.
+    pub fn watch(
+        &self,
+        payment_id: &str,
+        deadline: Instant,
+        callback: Box<dyn OnInvoiceEventCallback>,
+        cancellation_receiver: broadcast::Receiver<()>,
+    ) {
+        let key = payment_id.to_lowercase();
+        let deadline_tick = self.tick_of(deadline);
+        let mut state = self.state.lock().unwrap();
+        state.timers.insert(deadline_tick, Timer::Round(key.clone()));
+        let waiter = Waiter {
+            payment_id: payment_id.to_string(),
+            deadline_tick,
+            callback,
+            cancellation_receiver,
+        };
+        if state.waiting.insert(key, waiter).is_some() {
+            warn!("Payment id {} was already being watched, replacing its round", payment_id);
+        }
+    }
+
+    /// Indexes every settled invoice carrying `payment_id` from the next poll on, see
+    /// `settled_invoices`. `already_settled` is an invoice of it seen before.
+    pub fn track(&self, payment_id: &str, already_settled: Option<&Transaction>) {
+        let mut state = self.state.lock().unwrap();
+        state.settled.entry(payment_id.to_lowercase()).or_default();
+        if let Some(txn) = already_settled.filter(|txn| txn.settled_at > 0) {
+            state.index(txn);
+        }
+    }
+
+    /// Stops indexing the invoices of `payment_id` and forgets them
+    pub fn untrack(&self, payment_id: &str) {
+        self.state.lock().unwrap().settled.remove(&payment_id.to_lowercase());
+    }
+
+    /// The settled invoices of a tracked `payment_id` indexed so far
+    pub fn settled_invoices(&self, payment_id: &str) -> Vec<Transaction> {
+        let state = self.state.lock().unwrap();
+        state.settled.get(&payment_id.to_lowercase()).cloned().unwrap_or_default()
+    }
+
+    /// Looks further back than the polls for invoices of the tracked `payment_ids` that
+    /// settled before they were tracked, e.g. while eltord was down
+    pub async fn catch_up(&self, payment_ids: &[String]) {
+        let params = ListTransactionsParams {
+            from: 0,
+            limit: CATCH_UP_TRANSACTIONS_LIMIT,
+            ..Default::default()
+        };
+        let mut txns = match self.wallet.list_transactions(params).await {
+            Ok(txns) => txns,
+            Err(e) => {
+                warn!("Failed to list the wallet's transactions to catch up on: {:?}", e);
+                Vec::new()
+            }
+        };
+        for payment_id in payment_ids {
+            let params = LookupInvoiceParams {
+                payment_hash: None,
+                search: Some(payment_id.clone()),
+            };
+            if let Ok(txn) = self.wallet.lookup_invoice(params).await {
+                txns.push(txn);
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        for txn in txns.iter().filter(|txn| txn.settled_at > 0 && txn.type_ != "outgoing") {
+            state.index(txn);
+        }
+    }
+
+    /// Sleeps until `deadline` on the timer wheel
+    pub async fn sleep_until(&self, deadline: Instant) {
+        let (sender, receiver) = oneshot::channel();
+        let tick = self.tick_of(deadline);
+        self.state.lock().unwrap().timers.insert(tick, Timer::Wake(sender));
+        let _ = receiver.await;
+    }
+
+    /// Number of rounds waiting for their invoice
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+
+    // Whether a poll has anyone to hand settled invoices to
+    fn has_watchers(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.waiting.is_empty() || !state.settled.is_empty()
+    }
+
+    async fn run(self: Arc<Self>) {
+        info!("🧾 Invoice dispatcher polling the wallet every {}s while invoices are watched", POLL_EVERY_TICKS * TICK.as_secs());
+        let mut ticker = tokio::time::interval_at(self.started + TICK, TICK);
+        loop {
+            let now = ticker.tick().await;
+            // Nobody can register rounds anymore
+            if Arc::strong_count(&self) == 1 {
+                return;
+            }
+            let tick = self.tick_of(now);
+            if tick.is_multiple_of(POLL_EVERY_TICKS) && self.has_watchers() {
+                self.poll().await;
+            }
+            self.fire(tick);
+        }
+    }
+
+    // Dispatches the settled invoices among the wallet's recent transactions
+    async fn poll(&self) {
+        let params = ListTransactionsParams {
+            from: 0,
+            limit: POLL_TRANSACTIONS_LIMIT,
+            ..Default::default()
+        };
+        let txns = match self.wallet.list_transactions(params).await {
+            Ok(txns) => txns,
+            Err(e) => {
+                warn!("Failed to poll the wallet for settled invoices: {:?}", e);
+                return;
+            }
+        };
+        let mut ready = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for txn in txns.iter().filter(|txn| txn.settled_at > 0 && txn.type_ != "outgoing") {
+                state.index(txn);
+                for key in candidate_payment_ids(txn) {
+                    if let Some(waiter) = state.waiting.remove(&key) {
+                        ready.push((waiter, txn.clone()));
+                    }
+                }
+            }
+        }
+        for (mut waiter, txn) in ready {
+            if !waiter.is_cancelled() {
+                waiter.callback.success(Some(txn));
+            }
+        }
+    }
+
+    fn fire(self: &Arc<Self>, tick: u64) {
+        let mut expired = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for timer in state.timers.advance(tick) {
+                match timer {
+                    Timer::Wake(sender) => {
+                        let _ = sender.send(());
+                    }
+                    Timer::Round(key) => {
+                        // A replaced round has a later deadline of its own
+                        if state.waiting.get(&key).is_some_and(|w| w.deadline_tick <= tick) {
+                            expired.extend(state.waiting.remove(&key));
+                        }
+                    }
+                }
+            }
+        }
+        if !expired.is_empty() {
+            tokio::spawn(self.clone().expire(expired));
+        }
+    }
+
+    // Rounds whose deadline passed get one direct lookup, in case their invoice settled
+    // outside the recent transactions polled, before they fail
+    async fn expire(self: Arc<Self>, expired: Vec<Waiter>) {
+        for mut waiter in expired {
+            if waiter.is_cancelled() {
+                continue;
+            }
+            let params = LookupInvoiceParams {
+                payment_hash: None,
+                search: Some(waiter.payment_id.clone()),
+            };
+            match self.wallet.lookup_invoice(params).await {
+                Ok(txn)
+                    if txn.settled_at > 0
+                        && candidate_payment_ids(&txn).contains(&waiter.payment_id.to_lowercase()) =>
+                {
+                    waiter.callback.success(Some(txn))
+                }
+                _ => waiter.callback.failure(None),
+            }
+        }
+    }
+}
+
+// Payment ids an invoice may carry: its payment hash and every word of its payer note
+// and description, lowercased like the index
+fn candidate_payment_ids(txn: &Transaction) -> Vec<String> {
+    let notes = txn.payer_note.iter().chain(std::iter::once(&txn.description));
+    std::iter::once(txn.payment_hash.as_str())
+        .chain(notes.flat_map(|note| note.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock_wallet::MockLightningNode;
+
+    #[derive(Clone, Default)]
+    struct RecordingCallback(Arc<Mutex<Vec<String>>>);
+
+    impl OnInvoiceEventCallback for RecordingCallback {
+        fn success(&self, transaction: Option<Transaction>) {
+            let hash = transaction.map(|txn| txn.payment_hash).unwrap_or_default();
+            self.0.lock().unwrap().push(format!("success {}", hash));
+        }
+        fn pending(&self, _transaction: Option<Transaction>) {}
+        fn failure(&self, _transaction: Option<Transaction>) {
+            self.0.lock().unwrap().push("failure".to_string());
+        }
+    }
+
+    fn settled(hash: &str, description: &str) -> Transaction {
+        Transaction {
+            payment_hash: hash.to_string(),
+            preimage: "preimage".to_string(),
+            type_: "incoming".to_string(),
+            amount_msats: 1000,
+            fees_paid: 0,
+            payer_note: None,
+            external_id: None,
+            invoice: "".to_string(),
+            description: description.to_string(),
+            description_hash: "".to_string(),
+            settled_at: 1,
+            created_at: 1,
+            expires_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_candidate_payment_ids() {
+        let mut txn = settled("ABC123", "eltor round:deadbeef");
+        txn.payer_note = Some("cafe_01".to_string());
+        assert_eq!(
+            candidate_payment_ids(&txn),
+            vec!["abc123", "cafe_01", "eltor", "round", "deadbeef"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatches_settled_invoices_and_deadlines() {
+        let wallet = Arc::new(MockLightningNode::default());
+        *wallet.transactions.lock().unwrap() = vec![settled("hash_1", "deadbeef")];
+        let dispatcher = InvoiceDispatcher::start(wallet.clone());
+        let (sender, _) = broadcast::channel(1);
+        let paid = RecordingCallback::default();
+        let unpaid = RecordingCallback::default();
+        let cancelled = RecordingCallback::default();
+        let far = Instant::now() + Duration::from_secs(60);
+        dispatcher.watch("DEADBEEF", far, Box::new(paid.clone()), sender.subscribe());
+        dispatcher.watch("never_paid", Instant::now(), Box::new(unpaid.clone()), sender.subscribe());
+        let (cancel, cancellation_receiver) = broadcast::channel(1);
+        dispatcher.watch("torn_down", Instant::now(), Box::new(cancelled.clone()), cancellation_receiver);
+        cancel.send(()).unwrap();
+        assert_eq!(dispatcher.waiting(), 3);
+
+        dispatcher.sleep_until(Instant::now() + TICK * POLL_EVERY_TICKS as u32).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*paid.0.lock().unwrap(), vec!["success hash_1"]);
+        assert_eq!(*unpaid.0.lock().unwrap(), vec!["failure"]);
+        assert!(cancelled.0.lock().unwrap().is_empty());
+        assert_eq!(dispatcher.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_indexes_settled_invoices_of_tracked_payment_ids() {
+        let wallet = Arc::new(MockLightningNode::default());
+        *wallet.transactions.lock().unwrap() = vec![
+            settled("top_up", "deadbeef"),
+            // Polled twice, indexed once
+            settled("top_up", "deadbeef"),
+            settled("other", "cafe"),
+        ];
+        let dispatcher = InvoiceDispatcher::start(wallet.clone());
+        dispatcher.track("DEADBEEF", Some(&settled("first", "deadbeef")));
+        assert_eq!(dispatcher.settled_invoices("deadbeef").len(), 1);
+
+        dispatcher.sleep_until(Instant::now() + TICK * POLL_EVERY_TICKS as u32).await;
+        let hashes: Vec<String> = dispatcher
+            .settled_invoices("deadbeef")
+            .into_iter()
+            .map(|txn| txn.payment_hash)
+            .collect();
+        assert_eq!(hashes, vec!["first", "top_up"]);
+        assert!(dispatcher.settled_invoices("cafe").is_empty());
+
+        dispatcher.untrack("deadbeef");
+        assert!(dispatcher.settled_invoices("deadbeef").is_empty());
+    }
+}
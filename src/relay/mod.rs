@@ -2,8 +2,18 @@ mod start_relay_flow;
 mod payments_watcher;
 mod relay_payments;
 mod payments_received_ledger;
+mod handshake_fee;
+mod payment_amount;
+mod relay_settings;
+mod invoice_dispatcher;
+mod timer_wheel;
 
 pub use start_relay_flow::{start_relay_flow};
 pub use payments_watcher::*;
 pub use relay_payments::*;
-pub use payments_received_ledger::*;
\ No newline at end of file
+pub use payments_received_ledger::*;
+pub use handshake_fee::*;
+pub use payment_amount::*;
+pub use relay_settings::*;
+pub use invoice_dispatcher::*;
+pub use timer_wheel::*;
\ No newline at end of file
@@ -0,0 +1,83 @@
+/// What the relay does when a round's settled invoice pays less than `PaymentRateMsats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnderpaymentAction {
+    /// Tear the circuit down right away (default)
+    Teardown,
+    /// Give the client until the end of the round's window (plus grace period) to pay
+    /// the rest with more invoices carrying the same payment id
+    TopUp,
+    /// Keep the circuit, only record the underpayment
+    Accept,
+}
+
+impl UnderpaymentAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.trim().to_lowercase().as_str() {
+            "teardown" => Some(UnderpaymentAction::Teardown),
+            "top_up" => Some(UnderpaymentAction::TopUp),
+            "accept" => Some(UnderpaymentAction::Accept),
+            _ => None,
+        }
+    }
+}
+
+/// How the amounts clients pay are checked against what the relay advertises
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmountPolicy {
+    /// Percent of the expected amount a payment may fall short, for rounding
+    pub tolerance_percent: f64,
+    pub on_underpayment: UnderpaymentAction,
+}
+
+impl Default for AmountPolicy {
+    fn default() -> Self {
+        AmountPolicy {
+            tolerance_percent: 0.0,
+            on_underpayment: UnderpaymentAction::Teardown,
+        }
+    }
+}
+
+impl AmountPolicy {
+    /// Policy from a tolerance in percent and an `UnderpaymentAction`, the defaults for
+    /// anything missing or invalid
+    pub fn parse(tolerance_percent: Option<&str>, on_underpayment: Option<&str>) -> Self {
+        let default = AmountPolicy::default();
+        AmountPolicy {
+            tolerance_percent: tolerance_percent
+                .and_then(|percent| percent.trim().parse::<f64>().ok())
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .unwrap_or(default.tolerance_percent),
+            on_underpayment: on_underpayment
+                .and_then(UnderpaymentAction::parse)
+                .unwrap_or(default.on_underpayment),
+        }
+    }
+
+    /// Least a payment expected to be `expected_msats` may settle for
+    pub fn min_acceptable_msats(&self, expected_msats: i64) -> i64 {
+        expected_msats - (expected_msats as f64 * self.tolerance_percent / 100.0) as i64
+    }
+
+    pub fn is_enough(&self, received_msats: i64, expected_msats: i64) -> bool {
+        received_msats >= self.min_acceptable_msats(expected_msats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_policy() {
+        assert_eq!(AmountPolicy::parse(None, None), AmountPolicy::default());
+        assert_eq!(AmountPolicy::parse(Some("-1"), Some("refund")), AmountPolicy::default());
+
+        let policy = AmountPolicy::parse(Some("2.5"), Some("top_up"));
+        assert_eq!(policy.on_underpayment, UnderpaymentAction::TopUp);
+        assert_eq!(policy.min_acceptable_msats(1000), 975);
+        assert!(policy.is_enough(975, 1000));
+        assert!(!policy.is_enough(974, 1000));
+        assert!(!AmountPolicy::default().is_enough(1, 1000));
+    }
+}
@@ -1,84 +1,213 @@
-use crate::types::Relay;
-use crate::{database, relay};
-use log::{error, info, warn};
+use crate::database::{self, Db, Payment};
+use crate::rpc::{
+    get_conf_payment_bolt12_offer, get_conf_payment_cadence, get_conf_payment_rate_msats,
+    get_relay_fingerprint,
+};
+use crate::types::{PaymentCadence, RoundSchedule, RpcConfig};
+use lni::types::Transaction;
+use log::{info, warn};
 
-use super::{relay_payments, RelayPayments};
+use super::RelayPayments;
 
-pub fn init_payments_received_ledger(relay_payments: &RelayPayments, circuit_id: &String) {
-    let mut i = 1;
-    for payment_id_hash in relay_payments.payhashes.clone().iter() {
-        let mut row = database::Payment {
+/// What this relay charges, from its own torrc, written to every received ledger row
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayPaymentTerms {
+    /// Our fingerprint from `GETINFO fingerprint`
+    pub fingerprint: String,
+    /// `PaymentRateMsats`, the amount expected per round
+    pub rate_msats: u32,
+    /// `PaymentBolt12Offer`
+    pub bolt12_offer: Option<String>,
+    /// `PaymentInterval` / `PaymentIntervalRounds`
+    pub cadence: PaymentCadence,
+}
+
+impl RelayPaymentTerms {
+    pub async fn load(config: &RpcConfig) -> Self {
+        let fingerprint = match get_relay_fingerprint(config).await {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                warn!("Failed to get our relay fingerprint, the received ledger won't have it: {}", e);
+                String::new()
+            }
+        };
+        RelayPaymentTerms {
+            fingerprint,
+            rate_msats: get_conf_payment_rate_msats(config).await,
+            bolt12_offer: get_conf_payment_bolt12_offer(config).await,
+            cadence: get_conf_payment_cadence(config).await,
+        }
+    }
+}
+
+pub fn init_payments_received_ledger(
+    db: &Db,
+    relay_payments: &RelayPayments,
+    circuit_id: &String,
+    terms: &RelayPaymentTerms,
+    schedule: &RoundSchedule,
+) -> Result<(), database::DbError> {
+    db.write_payments(&received_ledger_rows(relay_payments, circuit_id, terms, schedule))?;
+    info!(
+        "Init row in payments received ledger for circuit: {:?}",
+        circuit_id
+    );
+    Ok(())
+}
+
+/// One unpaid row per advertised round, expecting `rate_msats` each and expiring when
+/// the round ends on `schedule`
+fn received_ledger_rows(
+    relay_payments: &RelayPayments,
+    circuit_id: &str,
+    terms: &RelayPaymentTerms,
+    schedule: &RoundSchedule,
+) -> Vec<Payment> {
+    let now = chrono::Utc::now().timestamp();
+    let interval_seconds = terms.cadence.interval_seconds as i64;
+    // Only the advertised number of rounds gets paid
+    relay_payments
+        .payhashes
+        .iter()
+        .take(terms.cadence.rounds as usize)
+        .zip(1..)
+        .map(|(payment_id_hash, round)| Payment {
             payment_id: payment_id_hash.to_string(),
             circ_id: circuit_id.to_string(),
-            interval_seconds: 60, //relay.payment_interval_seconds.unwrap_or(60) as i64,
-            round: i,
-            relay_fingerprint: "ME".to_string(), //relay_payments.fingerprint.clone(),
-            updated_at: chrono::Utc::now().timestamp(),
-            amount_msat: 0, //relay.payment_rate_msats.unwrap_or(0) as i64,
+            interval_seconds,
+            round,
+            relay_fingerprint: terms.fingerprint.clone(),
+            updated_at: now,
+            amount_msat: terms.rate_msats as i64,
             handshake_fee_payhash: Some(relay_payments.handshake_payment_hash.clone()),
             handshake_fee_preimage: Some(relay_payments.handshake_preimage.clone()),
             paid: false,
-            expires_at: chrono::Utc::now().timestamp() + 60,
-            bolt11_invoice: None,                               // TODO implement
-            bolt12_offer: Some("MY_BOLT_12_OFFER".to_string()), // TODO lookup
+            expires_at: schedule.round_end(round as u32 - 1),
+            bolt11_invoice: None,
+            bolt12_offer: terms.bolt12_offer.clone(),
             payment_hash: None,
             preimage: None,
             fee: None,
             has_error: false,
-        };
+            payment_method: None,
+            attempts: 0,
+            last_attempt_at: None,
+            last_error: None,
+        })
+        .collect()
+}
 
-        // Create data folder if it doesn't exist
-        // TODO read from config file
-        if let Err(e) = std::fs::create_dir_all("data") {
-            error!("Failed to create data directory: {}", e);
-            continue;
-        }
-        // Create payments_received.json file if it doesn't exist
-        let payments_received_path = "data/payments_received.json";
-        if !std::path::Path::new(payments_received_path).exists() {
-            if let Err(e) = std::fs::File::create(payments_received_path) {
-                error!("Failed to create payments_received.json: {}", e);
-                continue;
-            }
+/// Records the settled invoice `txn` on the row of `payment_id`: the amount actually
+/// received, its payment hash and preimage
+pub fn record_received_payment(db: &Db, payment_id: &str, txn: &Transaction) -> Result<Payment, database::DbError> {
+    db.modify_payment(payment_id, |row| {
+        row.amount_msat = txn.amount_msats;
+        row.payment_hash = Some(txn.payment_hash.clone());
+        row.preimage = Some(txn.preimage.clone());
+        if !txn.invoice.is_empty() && !txn.invoice.starts_with("lno") {
+            row.bolt11_invoice = Some(txn.invoice.clone());
         }
+        row.paid = true;
+        row.updated_at = chrono::Utc::now().timestamp();
+    })
+}
 
-        let db = match database::Db::new(payments_received_path.to_string()) {
-            Ok(db) => db,
-            Err(e) => {
-                error!("Failed to load payments_received ledger: {}. Creating backup and starting fresh...", e);
-                // Backup the corrupted file
-                let timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs();
-                let backup_path = format!("data/payments_received.json.backup_{}", timestamp);
-                if let Err(backup_err) = std::fs::copy(payments_received_path, &backup_path) {
-                    warn!("Could not create backup: {}", backup_err);
-                } else {
-                    info!("Corrupted database backed up to: {}", backup_path);
-                }
-                // Start with empty database
-                if let Err(write_err) = std::fs::write(payments_received_path, "[]") {
-                    error!("Failed to reset database file: {}", write_err);
-                    continue;
-                }
-                match database::Db::new(payments_received_path.to_string()) {
-                    Ok(db) => db,
-                    Err(e2) => {
-                        error!("Failed to create fresh database: {}", e2);
-                        continue;
-                    }
-                }
-            }
+/// Records that the round of `payment_id` was paid `received_msats` in total, less than
+/// the `expected_msats` it is due. The row stays unpaid.
+pub fn record_underpayment(
+    db: &Db,
+    payment_id: &str,
+    received_msats: i64,
+    expected_msats: i64,
+) -> Result<Payment, database::DbError> {
+    db.modify_payment(payment_id, |row| {
+        row.amount_msat = received_msats;
+        row.paid = false;
+        row.has_error = true;
+        row.last_error = Some(format!(
+            "Underpaid: received {} of {} msats",
+            received_msats, expected_msats
+        ));
+        row.updated_at = chrono::Utc::now().timestamp();
+    })
+}
+
+/// Marks the round of `payment_id` paid once its invoices add up to `received_msats`,
+/// clearing the underpayment recorded before
+pub fn record_top_up(db: &Db, payment_id: &str, received_msats: i64) -> Result<Payment, database::DbError> {
+    db.modify_payment(payment_id, |row| {
+        row.amount_msat = received_msats;
+        row.paid = true;
+        row.has_error = false;
+        row.last_error = None;
+        row.updated_at = chrono::Utc::now().timestamp();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SqliteStore;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rows_record_terms_and_received_payment() {
+        let relay_payments = RelayPayments {
+            handshake_payment_hash: "hs_hash".to_string(),
+            handshake_preimage: "hs_preimage".to_string(),
+            payhashes: (1..=12).map(|i| format!("payment_id_{}", i)).collect(),
+            built_at: Some(1000),
         };
-        if let Err(e) = db.write_payment(row) {
-            error!("Failed to write payment to database: {}", e);
-        }
-        i += 1;
-    }
+        let terms = RelayPaymentTerms {
+            fingerprint: "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333".to_string(),
+            rate_msats: 2500,
+            bolt12_offer: Some("lno1relay".to_string()),
+            cadence: PaymentCadence::new(Some(30), Some(4)),
+        };
+        let schedule = RoundSchedule::new(terms.cadence, 1000);
+        let rows = received_ledger_rows(&relay_payments, "42", &terms, &schedule);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[3].round, 4);
+        assert_eq!(rows[3].expires_at, 1120);
+        assert!(rows.iter().all(|row| row.relay_fingerprint == terms.fingerprint
+            && row.amount_msat == 2500
+            && row.interval_seconds == 30
+            && row.bolt12_offer.as_deref() == Some("lno1relay")
+            && !row.paid));
 
-    info!(
-        "Init row in payments received ledger for circuit: {:?}",
-        circuit_id
-    );
+        let db = Db::with_store(Arc::new(SqliteStore::open_in_memory().unwrap()));
+        db.write_payments(&rows).unwrap();
+        let txn = Transaction {
+            payment_hash: "settled_hash".to_string(),
+            preimage: "settled_preimage".to_string(),
+            type_: "incoming".to_string(),
+            amount_msats: 2600,
+            fees_paid: 0,
+            payer_note: None,
+            external_id: None,
+            invoice: "lnbc26n1settled".to_string(),
+            description: "payment_id_2".to_string(),
+            description_hash: "".to_string(),
+            settled_at: 1,
+            created_at: 1,
+            expires_at: 0,
+        };
+        let row = record_received_payment(&db, "payment_id_2", &txn).unwrap();
+        assert!(row.paid);
+        assert_eq!(row.amount_msat, 2600);
+        assert_eq!(row.payment_hash.as_deref(), Some("settled_hash"));
+        assert_eq!(row.preimage.as_deref(), Some("settled_preimage"));
+        assert_eq!(row.bolt11_invoice.as_deref(), Some("lnbc26n1settled"));
+        assert_eq!(db.lookup_payment_by_id("payment_id_2".to_string()).unwrap(), Some(row));
+        assert!(record_received_payment(&db, "unknown", &txn).is_err());
+
+        let row = record_underpayment(&db, "payment_id_2", 1, 2500).unwrap();
+        assert!(!row.paid && row.has_error);
+        assert_eq!(row.amount_msat, 1);
+        assert_eq!(row.last_error.as_deref(), Some("Underpaid: received 1 of 2500 msats"));
+
+        let row = record_top_up(&db, "payment_id_2", 2500).unwrap();
+        assert!(row.paid && !row.has_error && row.last_error.is_none());
+        assert_eq!(row.amount_msat, 2500);
+    }
 }
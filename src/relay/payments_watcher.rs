@@ -1,17 +1,26 @@
 use crate::{
-    relay::{init_payments_received_ledger, RelayPayments},
-    rpc::{rpc_event_listener, teardown_circuit},
-    types::{EventCallback, RpcConfig},
+    database::{self, Db, Payment},
+    relay::{
+        claim_handshake_fee, init_payments_received_ledger, record_received_payment, record_top_up,
+        record_underpayment, verify_handshake_fee, AmountPolicy, InvoiceDispatcher,
+        RelayPaymentTerms, RelayPayments, RelaySettings, UnderpaymentAction,
+    },
+    rpc::{
+        advertise_in_contact_info, get_circuit_states, get_conf_payment_handshake_fee, rpc_event_listener,
+        teardown_circuit, PREPAID_CONTACT_FIELD, PROTOCOL_VERSION_CONTACT_FIELD,
+    },
+    types::{EventCallback, PaymentCadence, RoundSchedule, RoundTiming, RpcConfig, PAYMENT_PROTOCOL_BUILD_TIMESTAMP},
 };
 use lni::{LightningNode, types::Transaction};
-use log::{info, warn};
-use tokio::time::{sleep, Duration, Instant};
+use log::{error, info, warn};
+use tokio::time::{Duration, Instant};
 use tokio::sync::broadcast;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
 
-// Payment window padding - grace period in seconds added to each round's payment window
-const GRACE_PERIOD_SEC: u64 = 15;
+// How long after their rounds expired circuits in the received ledger are still
+// checked for lapsed payments when the watcher restarts
+const RECOVERY_LOOKBACK_SECS: i64 = 60 * 60;
 
 // Global registry to track circuit cancellation tokens
 type CircuitCancellationRegistry = Arc<Mutex<HashMap<String, broadcast::Sender<()>>>>;
@@ -51,13 +60,76 @@ pub async fn start_payments_watcher(
     config: &RpcConfig,
     wallet: std::sync::Arc<dyn LightningNode + Send + Sync>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let received_ledger = database::open_payments_received_ledger()?;
+    watch_payments(config, wallet, RelaySettings::from_env(), received_ledger).await
+}
+
+/// Runs the payment watcher with `settings` instead of the ones in eltord's environment,
+/// recording circuits and settled rounds in `received_ledger`
+pub(crate) async fn watch_payments(
+    config: &RpcConfig,
+    wallet: std::sync::Arc<dyn LightningNode + Send + Sync>,
+    settings: RelaySettings,
+    received_ledger: Db,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // What this relay advertises, clients pay each round on its cadence
+    let terms = RelayPaymentTerms::load(config).await;
+    info!(
+        "Watching payments of {} msats every {}s for {} rounds per circuit",
+        terms.rate_msats, terms.cadence.interval_seconds, terms.cadence.rounds
+    );
+    let handshake_fee_msats = get_conf_payment_handshake_fee(config).await;
+    if let Some(fee) = handshake_fee_msats {
+        info!("Requiring a {} msats handshake fee to extend circuits", fee);
+    }
+    let RelaySettings {
+        prepaid,
+        amount_policy,
+        grace_secs,
+        clock_skew_secs,
+    } = settings;
+    // Clients only send the circuit build timestamp to relays that advertise they read it
+    let version = PAYMENT_PROTOCOL_BUILD_TIMESTAMP.to_string();
+    if let Err(e) = advertise_in_contact_info(config, PROTOCOL_VERSION_CONTACT_FIELD, &version).await {
+        warn!("Failed to advertise payment protocol version {} in ContactInfo: {}", version, e);
+    }
+    if prepaid {
+        info!("Accepting prepaid rounds, checking each circuit's credit against {} msats per round", terms.rate_msats);
+        // Clients only prepay relays whose descriptor says they may
+        if let Err(e) = advertise_in_contact_info(config, PREPAID_CONTACT_FIELD, "1").await {
+            warn!("Failed to advertise prepaid rounds in ContactInfo, clients will pay just in time: {}", e);
+        }
+    }
+    info!(
+        "Accepting payments down to {}% under the rate, on underpayment: {:?}",
+        amount_policy.tolerance_percent, amount_policy.on_underpayment
+    );
+    info!(
+        "Rounds may be paid up to {}s after they end, allowing clocks {}s apart",
+        grace_secs, clock_skew_secs
+    );
+
     // 3. Listen for the Event PAYMENT_ID_HASH_RECEIVED
     let event = "PAYMENT_ID_HASH_RECEIVED";
     let on_event_payment_id_hash_received_callback =
         Box::new(OnTorEventPaymentIdHashReceivedCallback {
             wallet: wallet.clone(),
             rpc_config: config.clone(),
+            terms,
+            handshake_fee_msats,
+            prepaid,
+            amount_policy,
+            grace_secs,
+            clock_skew_secs,
+            invoices: InvoiceDispatcher::start(wallet.clone()),
+            received_ledger: received_ledger.clone(),
         });
+
+    // Circuits paid for before a restart get their watchers back first
+    on_event_payment_id_hash_received_callback
+        .recover_watchers(&received_ledger)
+        .await;
+
     rpc_event_listener(
         config.clone(),
         event.to_string(),
@@ -76,6 +148,22 @@ pub async fn start_payments_watcher(
 struct OnTorEventPaymentIdHashReceivedCallback {
     wallet: std::sync::Arc<dyn LightningNode + Send + Sync>,
     rpc_config: RpcConfig,
+    /// Our cadence, rate, offer and fingerprint
+    terms: RelayPaymentTerms,
+    /// Our PaymentHandshakeFee, if clients have to pay one
+    handshake_fee_msats: Option<u32>,
+    /// Whether we accept prepaid rounds (`PAYMENT_PREPAID`)
+    prepaid: bool,
+    /// How settled amounts are checked against our rate
+    amount_policy: AmountPolicy,
+    /// Our PAYMENT_GRACE_PERIOD_SECONDS
+    grace_secs: u64,
+    /// Our PAYMENT_CLOCK_SKEW_SECONDS
+    clock_skew_secs: u64,
+    /// The wallet's invoice subscription and round deadlines
+    invoices: Arc<InvoiceDispatcher>,
+    /// Where circuits' rounds and their settled invoices are recorded
+    received_ledger: Db,
 }
 impl EventCallback for OnTorEventPaymentIdHashReceivedCallback {
     fn success(&self, response: Option<String>, _wallet: &(dyn LightningNode + Send + Sync)) {
@@ -123,102 +211,259 @@ impl EventCallback for OnTorEventPaymentIdHashReceivedCallback {
             let relay_payments = RelayPayments::from_wire_format(&payment_hashes.clone().unwrap());
 
             // 3c. If you require a handshake fee check the handshake_payment_hash + handshake_preimage
-            // TODO verify handshake
+            if let Some(fee_msats) = self.handshake_fee_msats {
+                let wallet = self.wallet.clone();
+                let rpc_config = self.rpc_config.clone();
+                let circuit_id = circ_id.clone();
+                let received_ledger = self.received_ledger.clone();
+                let handshake = RelayPayments {
+                    handshake_payment_hash: relay_payments.handshake_payment_hash.clone(),
+                    handshake_preimage: relay_payments.handshake_preimage.clone(),
+                    payhashes: Vec::new(),
+                    built_at: None,
+                };
+                tokio::spawn(async move {
+                    let verified = match verify_handshake_fee(&*wallet, &handshake, fee_msats).await {
+                        // A paid handshake opens one circuit, replaying it for another is refused
+                        Ok(()) => claim_handshake_fee(&received_ledger, &handshake, &circuit_id),
+                        Err(e) => Err(e),
+                    };
+                    match verified {
+                        Ok(()) => info!("🤝 Handshake fee of {} msats verified for circuit {}", fee_msats, circuit_id),
+                        Err(e) => {
+                            warn!("❌ Handshake fee check failed for circuit {}: {} - TEARDOWN circuit", circuit_id, e);
+                            teardown_unpaid_circuit(&rpc_config, &circuit_id, "invalid or reused handshake fee").await;
+                        }
+                    }
+                });
+            }
+
+            // 3d. Rounds count from the client's circuit build timestamp, if it is believable
+            let cadence = self.terms.cadence;
+            let arrived_at = chrono::Utc::now().timestamp();
+            let built_at = RoundSchedule::anchor(cadence, relay_payments.built_at, arrived_at, self.clock_skew_secs);
+            if built_at != arrived_at {
+                info!("Circuit {} was built {}s before its payment ids arrived", circ_id, arrived_at - built_at);
+            } else if let Some(claimed) = relay_payments.built_at.filter(|claimed| *claimed != arrived_at) {
+                warn!(
+                    "Circuit {} claims to be built at {} but its payment ids arrived at {}, counting rounds from their arrival",
+                    circ_id, claimed, arrived_at
+                );
+            }
+            let schedule = RoundSchedule::new(cadence, built_at).with_tolerance(self.grace_secs, self.clock_skew_secs);
 
-            // 3d. Write the payment_id_hash_round1 thru payment_id_hash_round10 to the ledger
-            init_payments_received_ledger(&relay_payments, &circ_id);
+            // 3e. Write the payment id hash of every advertised round to the ledger
+            if let Err(e) = init_payments_received_ledger(&self.received_ledger, &relay_payments, &circ_id, &self.terms, &schedule) {
+                error!("Failed to write payments received ledger for circuit {}: {}", circ_id, e);
+            }
+            // Settled invoices are recorded on the rows written above
+            let received_ledger = Some(self.received_ledger.clone());
 
             // 4. Then kick off OnInvoiceEvents (Auditor Loop)
+            // Clients only pay the first PaymentIntervalRounds hashes, the rest are padding
+            let rounds = relay_payments.payhashes.len().min(cadence.rounds as usize);
+            let payment_ids: Vec<String> = relay_payments.payhashes.iter().take(rounds).cloned().collect();
+
+            // 4a. With prepaid rounds one watcher checks the circuit's credit every round
+            if self.prepaid {
+                self.watch_prepaid_credit(&circ_id, payment_ids, schedule, received_ledger);
+                return;
+            }
+
             info!("Payment hashes received for circuit {}, starting {} invoice watchers", 
-                  circ_id, relay_payments.payhashes.len());
+                  circ_id, rounds);
             info!("Decoded payment hashes: {:?}", relay_payments.payhashes);
             
-            // Capture the circuit start time for timing validation
-            let circuit_start_time = Instant::now();
+            let rounds = payment_ids.into_iter().enumerate().collect();
+            self.watch_rounds(&circ_id, rounds, schedule, received_ledger);
+        }
+    }
+    fn failure(&self, error: Option<String>) {
+        warn!("epic fail {}", error.unwrap_or_default());
+    }
+}
+
+impl OnTorEventPaymentIdHashReceivedCallback {
+    // One watcher checking the circuit's prepaid credit at the end of every round
+    fn watch_prepaid_credit(
+        &self,
+        circ_id: &str,
+        payment_ids: Vec<String>,
+        schedule: RoundSchedule,
+        received_ledger: Option<Db>,
+    ) {
+        let (_sender, cancellation_receiver) = get_circuit_cancellation_channel(circ_id);
+        info!("Watching prepaid credit of circuit {} for {} rounds", circ_id, payment_ids.len());
+        let watcher = PrepaidCreditWatcher {
+            rpc_config: self.rpc_config.clone(),
+            circuit_id: circ_id.to_string(),
+            payment_ids,
+            schedule,
+            rate_msats: self.terms.rate_msats,
+            amount_policy: self.amount_policy,
+            invoices: self.invoices.clone(),
+            received_ledger,
+        };
+        tokio::spawn(watcher.run(cancellation_receiver));
+    }
+
+    // Registers each (round, payment id) with the wallet's invoice dispatcher, waiting
+    // until the round's deadline on `schedule`
+    fn watch_rounds(
+        &self,
+        circ_id: &str,
+        rounds: Vec<(usize, String)>,
+        schedule: RoundSchedule,
+        received_ledger: Option<Db>,
+    ) {
+        let now = chrono::Utc::now().timestamp_millis();
+        for (i, payment_hash) in rounds {
+            // Round 0: 0s, Round 1: 1 interval, etc. after the circuit was built
+            let round_start_time = schedule.round_start(i as u32) - schedule.built_at;
+            let round_end_time = schedule.round_deadline(i as u32) - schedule.built_at;
             
-            // Start invoice event monitoring for each payment hash with staggered timing
-            for (i, payment_hash) in relay_payments.payhashes.iter().enumerate() {
-                let round_start_time = i as u64 * 60; // Round 0: 0s, Round 1: 60s, Round 2: 120s, etc.
-                let round_end_time = round_start_time + 60;
-                
-                info!(
-                    "Round {}: Scheduling invoice watcher for payment hash {} on circuit {} (active from {}s to {}s)",
-                    i, payment_hash, circ_id, round_start_time, round_end_time
-                );
-                
-                let params = lni::types::OnInvoiceEventParams {
-                    search: Some(payment_hash.clone()),
-                    polling_delay_sec: 3,
-                    max_polling_sec: 60,
-                    ..Default::default()
-                };
-                
-                // Get cancellation receiver for this circuit
-                let (_sender, cancellation_receiver) = get_circuit_cancellation_channel(&circ_id);
-                
-                let callback = OnLnInvoiceEventCallback {
-                    payment_hash: payment_hash.clone(),
-                    circuit_id: circ_id.clone(),
-                    round: i,
-                    circuit_start_time,
-                    rpc_config: self.rpc_config.clone(),
-                    cancellation_receiver,
-                };
-                
-                // Log that we're scheduling the task (this will appear in main thread logs)
-                info!("⏰ Scheduling async invoice monitoring task #{} for payment hash: {} on circuit: {}", 
-                      i + 1, payment_hash, circ_id);
-                info!("   → Will start monitoring at {}s and poll every {}s for max {}s", 
-                      round_start_time, params.polling_delay_sec, params.max_polling_sec);
-                
-                // Spawn async task to handle invoice event watching with delay
-                let wallet_clone = self.wallet.clone();
-                let payment_hash_clone = payment_hash.clone();
-                let circuit_id_clone = circ_id.clone();
-                let mut cancellation_receiver_clone = callback.cancellation_receiver.resubscribe();
-                
-                let _task_handle = tokio::spawn(async move {
-                    // Wait for the round's start time or cancellation
-                    if round_start_time > 0 {
-                        info!(
-                            "⏳ Waiting {}s before starting Round {} monitoring for payment hash: {} on circuit {}",
-                            round_start_time, i, payment_hash_clone, circuit_id_clone
-                        );
-                        
-                        tokio::select! {
-                            _ = sleep(Duration::from_secs(round_start_time)) => {},
-                            _ = cancellation_receiver_clone.recv() => {
-                                info!("🛑 Round {} monitoring cancelled during wait phase for payment hash: {} on circuit {}", 
-                                      i, payment_hash_clone, circuit_id_clone);
-                                return;
-                            }
-                        }
-                    }
-                    
-                    // Check for cancellation before starting monitoring
-                    if cancellation_receiver_clone.try_recv().is_ok() {
-                        info!("🛑 Round {} monitoring cancelled before start for payment hash: {} on circuit {}", 
-                              i, payment_hash_clone, circuit_id_clone);
-                        return;
-                    }
-                    
-                    info!(
-                        "🚀 Starting Round {} invoice monitoring for payment hash: {} (polling every {}s for max {}s) on circuit {}",
-                        i, params.search.as_ref().unwrap(), params.polling_delay_sec, params.max_polling_sec, circuit_id_clone
-                    );
-                    
-                    // Start the invoice event watcher
-                    wallet_clone.on_invoice_events(params, Box::new(callback)).await;
+            info!(
+                "⏰ Round {}: Watching for payment hash {} on circuit {} (window from {}s to {}s)",
+                i, payment_hash, circ_id, round_start_time, round_end_time
+            );
+            
+            // Get cancellation receiver for this circuit
+            let (_sender, cancellation_receiver) = get_circuit_cancellation_channel(circ_id);
+            
+            let callback = OnLnInvoiceEventCallback {
+                payment_hash: payment_hash.clone(),
+                circuit_id: circ_id.to_string(),
+                round: i,
+                schedule,
+                rpc_config: self.rpc_config.clone(),
+                invoices: self.invoices.clone(),
+                expected_msats: self.terms.rate_msats as i64,
+                amount_policy: self.amount_policy,
+                received_ledger: received_ledger.clone(),
+                cancellation_receiver,
+            };
+            let cancellation_receiver = callback.cancellation_receiver.resubscribe();
+            let deadline = instant_at(schedule.round_deadline(i as u32), now);
+            self.invoices.watch(&payment_hash, deadline, Box::new(callback), cancellation_receiver);
+        }
+    }
 
-                    info!("✅ Finished Round {} invoice monitoring for payment hash: {} on circuit {}", 
-                          i, payment_hash_clone, circuit_id_clone);
-                });
+    // Rebuilds the watchers lost when eltord restarted mid-circuit from the received
+    // ledger and the circuits Tor still has open. Open rounds keep their original
+    // deadlines, circuits with a round that lapsed unpaid meanwhile are torn down.
+    async fn recover_watchers(&self, received_ledger: &Db) {
+        let now = chrono::Utc::now().timestamp();
+        let rows = match received_ledger.payments_expiring_since(now - RECOVERY_LOOKBACK_SECS) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Failed to read the payments received ledger, no watchers recovered: {}", e);
+                return;
+            }
+        };
+        let circuit_ids: BTreeSet<String> = rows.into_iter().map(|row| row.circ_id).collect();
+        if circuit_ids.is_empty() {
+            return;
+        }
+        let circuit_states = match get_circuit_states(&self.rpc_config).await {
+            Ok(states) => states,
+            Err(e) => {
+                error!("Failed to get circuit-status, no watchers recovered: {}", e);
+                return;
+            }
+        };
+        for circ_id in circuit_ids {
+            match circuit_states.get(&circ_id).map(String::as_str) {
+                None | Some("FAILED") | Some("CLOSED") => {
+                    info!("Circuit {} closed while the relay was down, nothing to watch", circ_id);
+                    continue;
+                }
+                Some(_) => {}
+            }
+            match received_ledger.lookup_payments_by_circuit(&circ_id) {
+                Ok(rows) => self.recover_circuit(&circ_id, rows, now, received_ledger).await,
+                Err(e) => error!("Failed to read the received ledger rows of circuit {}: {}", circ_id, e),
             }
         }
     }
-    fn failure(&self, error: Option<String>) {
-        warn!("epic fail {}", error.unwrap_or_default());
+
+    async fn recover_circuit(&self, circ_id: &str, mut rows: Vec<Payment>, now: i64, received_ledger: &Db) {
+        rows.sort_by_key(|row| row.round);
+        let schedule = match rows.first() {
+            // Round N (1 based) expires N intervals after the circuit was built
+            Some(first) => RoundSchedule::new(
+                PaymentCadence::new(Some(first.interval_seconds as u32), Some(rows.len() as u32)),
+                first.expires_at - first.interval_seconds * first.round,
+            )
+            .with_tolerance(self.grace_secs, self.clock_skew_secs),
+            None => return,
+        };
+        let started_at = schedule.built_at;
+        if self.prepaid {
+            info!("♻️ Resuming prepaid credit watcher of circuit {} started {}s ago", circ_id, now - started_at);
+            let payment_ids: Vec<String> = rows.into_iter().map(|row| row.payment_id).collect();
+            // The credit may have been paid while we were down
+            for payment_id in &payment_ids {
+                self.invoices.track(payment_id, None);
+            }
+            self.invoices.catch_up(&payment_ids).await;
+            self.watch_prepaid_credit(circ_id, payment_ids, schedule, Some(received_ledger.clone()));
+            return;
+        }
+
+        let mut open_rounds = Vec::new();
+        for row in rows.iter().filter(|row| !row.paid) {
+            let round = (row.round - 1) as usize;
+            if schedule.round_deadline(round as u32) >= now {
+                open_rounds.push((round, row.payment_id.clone()));
+            } else if !self.settled_while_down(row, received_ledger).await {
+                warn!(
+                    "❌ Round {} of circuit {} lapsed unpaid while the relay was down - TEARDOWN circuit",
+                    round, circ_id
+                );
+                teardown_unpaid_circuit(&self.rpc_config, circ_id, "payment lapsed while the relay was down").await;
+                return;
+            }
+        }
+        if !open_rounds.is_empty() {
+            info!(
+                "♻️ Resuming {} invoice watchers of circuit {} started {}s ago",
+                open_rounds.len(), circ_id, now - started_at
+            );
+            self.watch_rounds(circ_id, open_rounds, schedule, Some(received_ledger.clone()));
+        }
+    }
+
+    // Whether a round whose window closed while we were down got paid anyway, recording it
+    async fn settled_while_down(&self, row: &Payment, received_ledger: &Db) -> bool {
+        self.invoices.track(&row.payment_id, None);
+        self.invoices.catch_up(std::slice::from_ref(&row.payment_id)).await;
+        let received_msats = settled_msats_for(&self.invoices, &row.payment_id);
+        self.invoices.untrack(&row.payment_id);
+        let expected_msats = self.terms.rate_msats as i64;
+        let recorded = if self.amount_policy.is_enough(received_msats, expected_msats) {
+            record_top_up(received_ledger, &row.payment_id, received_msats)
+        } else if received_msats > 0 {
+            record_underpayment(received_ledger, &row.payment_id, received_msats, expected_msats)
+        } else {
+            return false;
+        };
+        if let Err(e) = recorded {
+            error!("Failed to record payment id {} in the received ledger: {}", row.payment_id, e);
+        }
+        self.amount_policy.is_enough(received_msats, expected_msats)
+            || self.amount_policy.on_underpayment == UnderpaymentAction::Accept
+    }
+}
+
+// The Instant `unix_secs` corresponds to at unix time `now_millis`, now if the clock
+// can't go back that far. Milliseconds keep deadlines from firing up to a second early.
+fn instant_at(unix_secs: i64, now_millis: i64) -> Instant {
+    let target_millis = unix_secs * 1000;
+    if target_millis >= now_millis {
+        return Instant::now() + Duration::from_millis((target_millis - now_millis) as u64);
     }
+    let elapsed = Duration::from_millis((now_millis - target_millis) as u64);
+    Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now)
 }
 
 // Invoice event callback for monitoring individual payment hashes
@@ -226,62 +471,246 @@ struct OnLnInvoiceEventCallback {
     payment_hash: String,
     circuit_id: String,
     round: usize,
-    circuit_start_time: Instant,
+    /// When each round of the circuit is due
+    schedule: RoundSchedule,
     rpc_config: RpcConfig,
+    invoices: Arc<InvoiceDispatcher>,
+    /// What the round is due, our PaymentRateMsats
+    expected_msats: i64,
+    amount_policy: AmountPolicy,
+    /// Where the settled invoice is recorded against its round
+    received_ledger: Option<Db>,
     cancellation_receiver: broadcast::Receiver<()>,
 }
 
-impl lni::types::OnInvoiceEventCallback for OnLnInvoiceEventCallback {
-    fn success(&self, transaction: Option<Transaction>) {
-        let elapsed_secs = self.circuit_start_time.elapsed().as_secs();
-        let expected_window_start = self.round as u64 * 60;
-        let expected_window_end = expected_window_start + 60 + GRACE_PERIOD_SEC;
-        
-        info!(
-            "🎉 INVOICE PAID! Payment hash: {} for circuit: {} (round {}) after {}s",
-            self.payment_hash, self.circuit_id, self.round, elapsed_secs
+impl OnLnInvoiceEventCallback {
+    // Checks the settled amount against the round's rate and records any underpayment.
+    // Returns false when the circuit is torn down for it.
+    fn verify_amount(&self, txn: &Transaction) -> bool {
+        if self.amount_policy.is_enough(txn.amount_msats, self.expected_msats) {
+            return true;
+        }
+        warn!(
+            "💸 Round {} on circuit {} UNDERPAID: received {} of {} msats for payment id {}",
+            self.round, self.circuit_id, txn.amount_msats, self.expected_msats, self.payment_hash
         );
-        
-        // Check if payment was made within the acceptable time window (including padding)
-        // Each round can be paid from circuit start (0s) up to the end of its designated window + padding
-        let base_window_end = expected_window_start + 60;
-        if elapsed_secs <= expected_window_end {
-            if elapsed_secs >= expected_window_start {
-                info!(
-                    "✅ Payment made ON TIME! Round {} payment received at {}s (window: 0s-{}s, ideal: {}s-{}s, grace: {}s) - KEEP circuit {} ALIVE",
-                    self.round, elapsed_secs, expected_window_end, expected_window_start, base_window_end, GRACE_PERIOD_SEC, self.circuit_id
-                );
-            } else {
+        if let Some(db) = self.received_ledger.as_ref() {
+            if let Err(e) = record_underpayment(db, &self.payment_hash, txn.amount_msats, self.expected_msats) {
+                error!("Failed to record underpayment of payment id {}: {}", self.payment_hash, e);
+            }
+        }
+        match self.amount_policy.on_underpayment {
+            UnderpaymentAction::Teardown => {
+                let circuit_id = self.circuit_id.clone();
+                let rpc_config = self.rpc_config.clone();
+                tokio::spawn(async move {
+                    teardown_unpaid_circuit(&rpc_config, &circuit_id, "underpayment").await;
+                });
+                false
+            }
+            UnderpaymentAction::Accept => {
+                warn!("Accepting the underpayment - KEEP circuit {} ALIVE", self.circuit_id);
+                true
+            }
+            UnderpaymentAction::TopUp => {
+                let deadline = self.schedule.round_deadline(self.round as u32);
                 info!(
-                    "⚡ Payment made EARLY! Round {} payment received at {}s (window: 0s-{}s, ideal: {}s-{}s, grace: {}s) - KEEP circuit {} ALIVE",
-                    self.round, elapsed_secs, expected_window_end, expected_window_start, base_window_end, GRACE_PERIOD_SEC, self.circuit_id
+                    "Waiting until {}s for the rest of round {} on circuit {}",
+                    deadline - self.schedule.built_at, self.round, self.circuit_id
                 );
+                self.invoices.track(&self.payment_hash, Some(txn));
+                let top_up = TopUpCheck {
+                    invoices: self.invoices.clone(),
+                    rpc_config: self.rpc_config.clone(),
+                    circuit_id: self.circuit_id.clone(),
+                    payment_id: self.payment_hash.clone(),
+                    expected_msats: self.expected_msats,
+                    amount_policy: self.amount_policy,
+                    received_ledger: self.received_ledger.clone(),
+                };
+                let deadline = instant_at(deadline, chrono::Utc::now().timestamp_millis());
+                tokio::spawn(top_up.run(deadline, self.cancellation_receiver.resubscribe()));
+                true
             }
-        } else {
-            warn!(
-                "⚠️ Payment made LATE! Round {} payment received at {}s (window: 0s-{}s, ideal: {}s-{}s, grace: {}s) - TEARDOWN circuit {}",
-                self.round, elapsed_secs, expected_window_end, expected_window_start, base_window_end, GRACE_PERIOD_SEC, self.circuit_id
+        }
+    }
+}
+
+// Gives an underpaid round until its deadline to be paid in full by more invoices
+// carrying the same payment id
+struct TopUpCheck {
+    /// Tracks the payment id, indexing the top ups
+    invoices: Arc<InvoiceDispatcher>,
+    rpc_config: RpcConfig,
+    circuit_id: String,
+    payment_id: String,
+    expected_msats: i64,
+    amount_policy: AmountPolicy,
+    received_ledger: Option<Db>,
+}
+
+impl TopUpCheck {
+    async fn run(self, deadline: Instant, mut cancellation_receiver: broadcast::Receiver<()>) {
+        tokio::select! {
+            _ = self.invoices.sleep_until(deadline) => {},
+            _ = cancellation_receiver.recv() => {
+                self.invoices.untrack(&self.payment_id);
+                return;
+            }
+        }
+        let received_msats = settled_msats_for(&self.invoices, &self.payment_id);
+        self.invoices.untrack(&self.payment_id);
+        if self.amount_policy.is_enough(received_msats, self.expected_msats) {
+            info!(
+                "✅ Payment id {} topped up to {} of {} msats - KEEP circuit {} ALIVE",
+                self.payment_id, received_msats, self.expected_msats, self.circuit_id
             );
-            
-            // Call teardown RPC logic for late payment
-            let circuit_id = self.circuit_id.clone();
-            let rpc_config = self.rpc_config.clone();
-            tokio::spawn(async move {
-                match teardown_circuit(&rpc_config, &circuit_id).await {
-                    Ok(success) => {
-                        if success {
-                            warn!("🔥 Successfully tore down circuit {} due to late payment", circuit_id);
-                            // Signal all payment monitoring tasks for this circuit to stop
-                            signal_circuit_teardown(&circuit_id);
-                        } else {
-                            warn!("⚠️ Failed to teardown circuit {} - unexpected response", circuit_id);
+            if let Some(db) = self.received_ledger.as_ref() {
+                if let Err(e) = record_top_up(db, &self.payment_id, received_msats) {
+                    error!("Failed to record top up of payment id {}: {}", self.payment_id, e);
+                }
+            }
+            return;
+        }
+        warn!(
+            "❌ Payment id {} still UNDERPAID at {} of {} msats - TEARDOWN circuit {}",
+            self.payment_id, received_msats, self.expected_msats, self.circuit_id
+        );
+        if let Some(db) = self.received_ledger.as_ref() {
+            if let Err(e) = record_underpayment(db, &self.payment_id, received_msats, self.expected_msats) {
+                error!("Failed to record underpayment of payment id {}: {}", self.payment_id, e);
+            }
+        }
+        teardown_unpaid_circuit(&self.rpc_config, &self.circuit_id, "underpayment").await;
+    }
+}
+
+// Total of the settled invoices the dispatcher indexed for the tracked `payment_id`
+fn settled_msats_for(invoices: &InvoiceDispatcher, payment_id: &str) -> i64 {
+    invoices
+        .settled_invoices(payment_id)
+        .iter()
+        .map(|txn| settled_credit_msats(txn, payment_id))
+        .sum()
+}
+
+// How a settled invoice was tied to the payment id of a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InvoiceMatch {
+    PaymentHash,
+    // BOLT 12: the payment id is the payer note
+    PayerNote,
+    // BOLT 11 via LNURL-pay / Lightning Address: the payment id is sent as the comment,
+    // which ends up in the invoice description
+    Description,
+}
+
+fn match_invoice_to_payment_id(txn: &Transaction, payment_id: &str) -> Option<InvoiceMatch> {
+    if txn.payment_hash.eq_ignore_ascii_case(payment_id) {
+        Some(InvoiceMatch::PaymentHash)
+    } else if txn.payer_note.as_deref().is_some_and(|note| note.contains(payment_id)) {
+        Some(InvoiceMatch::PayerNote)
+    } else if txn.description.contains(payment_id) {
+        Some(InvoiceMatch::Description)
+    } else {
+        None
+    }
+}
+
+// Where a round's window lies, in seconds since the circuit was built, for the logs
+struct RoundWindow {
+    now: i64,
+    elapsed_secs: i64,
+    start_secs: i64,
+    end_secs: i64,
+    deadline_secs: i64,
+    schedule: RoundSchedule,
+}
+
+impl RoundWindow {
+    fn of(schedule: &RoundSchedule, round: usize) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        let since_built = |at: i64| at - schedule.built_at;
+        RoundWindow {
+            now,
+            elapsed_secs: since_built(now),
+            start_secs: since_built(schedule.round_start(round as u32)),
+            end_secs: since_built(schedule.round_end(round as u32)),
+            deadline_secs: since_built(schedule.round_deadline(round as u32)),
+            schedule: *schedule,
+        }
+    }
+}
+
+impl std::fmt::Display for RoundWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}s (window: 0s-{}s, ideal: {}s-{}s, grace: {}s, clock skew: {}s)",
+            self.elapsed_secs, self.deadline_secs, self.start_secs, self.end_secs,
+            self.schedule.grace_secs, self.schedule.clock_skew_secs
+        )
+    }
+}
+
+impl lni::types::OnInvoiceEventCallback for OnLnInvoiceEventCallback {
+    fn success(&self, transaction: Option<Transaction>) {
+        if let Some(txn) = transaction.as_ref() {
+            match match_invoice_to_payment_id(txn, &self.payment_hash) {
+                Some(matched) => {
+                    info!(
+                        "Invoice {} matched payment id {} by {:?}",
+                        txn.payment_hash, self.payment_hash, matched
+                    );
+                    if let Some(db) = self.received_ledger.as_ref() {
+                        if let Err(e) = record_received_payment(db, &self.payment_hash, txn) {
+                            error!("Failed to record payment id {} in the received ledger: {}", self.payment_hash, e);
                         }
                     }
-                    Err(e) => {
-                        warn!("❌ Error tearing down circuit {}: {}", circuit_id, e);
+                    if !self.verify_amount(txn) {
+                        return;
                     }
                 }
-            });
+                None => {
+                    warn!(
+                        "Ignoring settled invoice {} on circuit {} (round {}): it does not carry payment id {}",
+                        txn.payment_hash, self.circuit_id, self.round, self.payment_hash
+                    );
+                    return;
+                }
+            }
+        }
+        let window = RoundWindow::of(&self.schedule, self.round);
+        
+        info!(
+            "🎉 INVOICE PAID! Payment hash: {} for circuit: {} (round {}) after {}s",
+            self.payment_hash, self.circuit_id, self.round, window.elapsed_secs
+        );
+        
+        // Each round can be paid from circuit build (0s) up to its deadline: the end of
+        // its designated window plus the grace period and clock skew
+        match self.schedule.timing(self.round as u32, window.now) {
+            RoundTiming::OnTime => info!(
+                "✅ Payment made ON TIME! Round {} payment received at {} - KEEP circuit {} ALIVE",
+                self.round, window, self.circuit_id
+            ),
+            RoundTiming::Early => info!(
+                "⚡ Payment made EARLY! Round {} payment received at {} - KEEP circuit {} ALIVE",
+                self.round, window, self.circuit_id
+            ),
+            RoundTiming::Late => {
+                warn!(
+                    "⚠️ Payment made LATE! Round {} payment received at {} - TEARDOWN circuit {}",
+                    self.round, window, self.circuit_id
+                );
+            
+                // Call teardown RPC logic for late payment
+                let circuit_id = self.circuit_id.clone();
+                let rpc_config = self.rpc_config.clone();
+                tokio::spawn(async move {
+                    teardown_unpaid_circuit(&rpc_config, &circuit_id, "late payment").await;
+                });
+            }
         }
         
         if let Some(txn) = transaction {
@@ -303,26 +732,23 @@ impl lni::types::OnInvoiceEventCallback for OnLnInvoiceEventCallback {
     }
 
     fn failure(&self, transaction: Option<Transaction>) {
-        let elapsed_secs = self.circuit_start_time.elapsed().as_secs();
-        let expected_window_start = self.round as u64 * 60;
-        let expected_window_end = expected_window_start + 60 + GRACE_PERIOD_SEC;
+        let window = RoundWindow::of(&self.schedule, self.round);
         
         warn!(
             "❌ Invoice payment failed for payment hash: {} on circuit: {} (round {}) after {}s",
-            self.payment_hash, self.circuit_id, self.round, elapsed_secs
+            self.payment_hash, self.circuit_id, self.round, window.elapsed_secs
         );
         
         // Check if failure happened within or after the acceptable time window (including padding)
-        let base_window_end = expected_window_start + 60;
-        if elapsed_secs <= expected_window_end {
+        if self.schedule.timing(self.round as u32, window.now) != RoundTiming::Late {
             warn!(
-                "⏰ Payment failed within acceptable window (0s-{}s, ideal: {}s-{}s, grace: {}s) at {}s - TEARDOWN circuit {}",
-                expected_window_end, expected_window_start, base_window_end, GRACE_PERIOD_SEC, elapsed_secs, self.circuit_id
+                "⏰ Payment failed within acceptable window at {} - TEARDOWN circuit {}",
+                window, self.circuit_id
             );
         } else {
             warn!(
-                "🕑 Payment failed after acceptable window (0s-{}s, ideal: {}s-{}s, grace: {}s) at {}s - TEARDOWN circuit {}",
-                expected_window_end, expected_window_start, base_window_end, GRACE_PERIOD_SEC, elapsed_secs, self.circuit_id
+                "🕑 Payment failed after acceptable window at {} - TEARDOWN circuit {}",
+                window, self.circuit_id
             );
         }
         
@@ -330,20 +756,7 @@ impl lni::types::OnInvoiceEventCallback for OnLnInvoiceEventCallback {
         let circuit_id = self.circuit_id.clone();
         let rpc_config = self.rpc_config.clone();
         tokio::spawn(async move {
-            match teardown_circuit(&rpc_config, &circuit_id).await {
-                Ok(success) => {
-                    if success {
-                        warn!("🔥 Successfully tore down circuit {} due to payment failure", circuit_id);
-                        // Signal all payment monitoring tasks for this circuit to stop
-                        signal_circuit_teardown(&circuit_id);
-                    } else {
-                        warn!("⚠️ Failed to teardown circuit {} - unexpected response", circuit_id);
-                    }
-                }
-                Err(e) => {
-                    warn!("❌ Error tearing down circuit {}: {}", circuit_id, e);
-                }
-            }
+            teardown_unpaid_circuit(&rpc_config, &circuit_id, "payment failure").await;
         });
         
         if let Some(txn) = transaction {
@@ -352,126 +765,150 @@ impl lni::types::OnInvoiceEventCallback for OnLnInvoiceEventCallback {
     }
 }
 
+// Checks that the payments a circuit made so far cover every elapsed round. Clients
+// paying several rounds at once tag the payment with the payment id of the first round
+// it covers, just in time clients tag each round with its own id.
+struct PrepaidCreditWatcher {
+    rpc_config: RpcConfig,
+    circuit_id: String,
+    payment_ids: Vec<String>,
+    schedule: RoundSchedule,
+    rate_msats: u32,
+    amount_policy: AmountPolicy,
+    invoices: Arc<InvoiceDispatcher>,
+    received_ledger: Option<Db>,
+}
+
+impl PrepaidCreditWatcher {
+    async fn run(self, cancellation_receiver: broadcast::Receiver<()>) {
+        for payment_id in &self.payment_ids {
+            self.invoices.track(payment_id, None);
+        }
+        self.check_rounds(cancellation_receiver).await;
+        for payment_id in &self.payment_ids {
+            self.invoices.untrack(payment_id);
+        }
+    }
+
+    async fn check_rounds(&self, mut cancellation_receiver: broadcast::Receiver<()>) {
+        let now = chrono::Utc::now().timestamp_millis();
+        for round in 0..self.payment_ids.len() {
+            // Round N has to be covered by its deadline
+            let deadline = instant_at(self.schedule.round_deadline(round as u32), now);
+            tokio::select! {
+                _ = self.invoices.sleep_until(deadline) => {},
+                _ = cancellation_receiver.recv() => {
+                    info!("🛑 Prepaid credit monitoring cancelled for circuit {}", self.circuit_id);
+                    return;
+                }
+            }
+
+            let credit_msats = self.credit_msats();
+            let covered = rounds_covered(credit_msats, self.rate_msats);
+            let due_msats = self.rate_msats as i64 * (round as i64 + 1);
+            if covered < round as u64 + 1 && !self.amount_policy.is_enough(credit_msats, due_msats) {
+                warn!(
+                    "❌ Prepaid credit of {} msats covers {} rounds, round {} is due on circuit {} - TEARDOWN",
+                    credit_msats, covered, round, self.circuit_id
+                );
+                // What is left of the credit after the rounds it covered goes to the due round
+                if let Some(db) = self.received_ledger.as_ref() {
+                    let paid_msats = (credit_msats - self.rate_msats as i64 * round as i64).max(0);
+                    if let Err(e) = record_underpayment(db, &self.payment_ids[round], paid_msats, self.rate_msats as i64) {
+                        error!("Failed to record underpayment of payment id {}: {}", self.payment_ids[round], e);
+                    }
+                }
+                teardown_unpaid_circuit(&self.rpc_config, &self.circuit_id, "exhausted prepaid credit").await;
+                return;
+            }
+            info!(
+                "✅ Prepaid credit of {} msats covers round {} on circuit {} - KEEP circuit ALIVE",
+                credit_msats, round, self.circuit_id
+            );
+        }
+    }
+
+    // Sum of the settled invoices the dispatcher indexed for the circuit's payment ids
+    fn credit_msats(&self) -> i64 {
+        let mut credit_msats = 0;
+        for payment_id in &self.payment_ids {
+            for txn in self.invoices.settled_invoices(payment_id) {
+                let credit = settled_credit_msats(&txn, payment_id);
+                if credit > 0 {
+                    if let Some(db) = self.received_ledger.as_ref() {
+                        if let Err(e) = record_received_payment(db, payment_id, &txn) {
+                            error!("Failed to record payment id {} in the received ledger: {}", payment_id, e);
+                        }
+                    }
+                }
+                credit_msats += credit;
+            }
+        }
+        credit_msats
+    }
+}
+
+// Amount a settled invoice for `payment_id` adds to the circuit's credit
+fn settled_credit_msats(txn: &Transaction, payment_id: &str) -> i64 {
+    if txn.settled_at > 0 && match_invoice_to_payment_id(txn, payment_id).is_some() {
+        txn.amount_msats.max(0)
+    } else {
+        0
+    }
+}
+
+// Number of rounds `credit_msats` pays for at `rate_msats` per round
+fn rounds_covered(credit_msats: i64, rate_msats: u32) -> u64 {
+    if rate_msats == 0 {
+        return u64::MAX;
+    }
+    credit_msats.max(0) as u64 / rate_msats as u64
+}
+
+// Tears down a circuit that wasn't paid for and stops its payment monitors
+async fn teardown_unpaid_circuit(rpc_config: &RpcConfig, circuit_id: &str, reason: &str) {
+    match teardown_circuit(rpc_config, circuit_id).await {
+        Ok(success) => {
+            if success {
+                warn!("🔥 Successfully tore down circuit {} due to {}", circuit_id, reason);
+                // Signal all payment monitoring tasks for this circuit to stop
+                signal_circuit_teardown(circuit_id);
+            } else {
+                warn!("⚠️ Failed to teardown circuit {} - unexpected response", circuit_id);
+            }
+        }
+        Err(e) => {
+            warn!("❌ Error tearing down circuit {}: {}", circuit_id, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lni::{LightningNode, ApiError};
-    use lni::types::{OnInvoiceEventCallback, CreateInvoiceParams, PayInvoiceParams, OnInvoiceEventParams, NodeInfo, PayInvoiceResponse, LookupInvoiceParams, ListTransactionsParams, Offer};
+    use lni::types::OnInvoiceEventCallback;
+    use crate::lightning::mock_wallet::MockLightningNode;
+    use crate::rpc::mock_control_port::MockControlPort;
     use tokio::time::{Duration, Instant};
 
-    // Mock LightningNode for testing
-    struct MockLightningNode;
-
-    #[async_trait::async_trait]
-    impl LightningNode for MockLightningNode {
-        async fn get_info(&self) -> Result<NodeInfo, ApiError> {
-            Ok(NodeInfo::default())
-        }
-
-        async fn create_invoice(&self, _params: CreateInvoiceParams) -> Result<lni::Transaction, ApiError> {
-            Ok(lni::Transaction {
-                payment_hash: "test_hash".to_string(),
-                preimage: "test_preimage".to_string(),
-                type_: "incoming".to_string(),
-                amount_msats: 1000000,
-                fees_paid: 0,
-                payer_note: Some("test".to_string()),
-                external_id: Some("test".to_string()),
-                invoice: "test_invoice".to_string(),
-                description: "test".to_string(),
-                description_hash: "".to_string(),
-                settled_at: 0,
-                created_at: 0,
-                expires_at: 0,
-            })
-        }
-
-        async fn pay_invoice(&self, _params: PayInvoiceParams) -> Result<PayInvoiceResponse, ApiError> {
-            Ok(PayInvoiceResponse {
-                payment_hash: "test_hash".to_string(),
-                preimage: "test_preimage".to_string(),
-                fee_msats: 0,
-            })
-        }
-
-        async fn create_offer(&self, params: lni::CreateOfferParams) -> Result<Offer, ApiError> {
-            Ok(Offer {
-                bolt12: "test_offer".to_string(),
-                offer_id: "test_offer_id".to_string(),
-                label: params.description,
-                active: Some(true),
-                single_use: Some(false),
-                used: Some(false),
-                amount_msats: params.amount_msats,
-            })
-        }
-
-        async fn get_offer(&self, _offer_id: Option<String>) -> Result<Offer, ApiError> {
-            Ok(Offer {
-                bolt12: "test_offer".to_string(),
-                offer_id: "test_offer_id".to_string(),
-                label: Some("test_label".to_string()),
-                active: Some(true),
-                single_use: Some(false),
-                used: Some(false),
-                amount_msats: None,
-            })
-        }
-
-        async fn list_offers(&self, _offer_id: Option<String>) -> Result<Vec<Offer>, ApiError> {
-            Ok(vec![])
-        }
-
-        async fn pay_offer(&self, _offer: String, _amount_sats: i64, _comment: Option<String>) -> Result<lni::PayInvoiceResponse, ApiError> {
-            Ok(lni::PayInvoiceResponse {
-                payment_hash: "test_hash".to_string(),
-                preimage: "test_preimage".to_string(),
-                fee_msats: 0,
-            })
-        }
-
-        async fn lookup_invoice(&self, _params: LookupInvoiceParams) -> Result<lni::Transaction, ApiError> {
-            Ok(lni::Transaction {
-                payment_hash: "test_hash".to_string(),
-                preimage: "test_preimage".to_string(),
-                type_: "incoming".to_string(),
-                amount_msats: 1000000,
-                fees_paid: 0,
-                payer_note: Some("test".to_string()),
-                external_id: Some("test".to_string()),
-                invoice: "test_invoice".to_string(),
-                description: "test".to_string(),
-                description_hash: "".to_string(),
-                settled_at: 0,
-                created_at: 0,
-                expires_at: 0,
-            })
-        }
-
-        async fn list_transactions(&self, _params: ListTransactionsParams) -> Result<Vec<lni::Transaction>, ApiError> {
-            Ok(vec![])
-        }
-
-        async fn decode(&self, _input: String) -> Result<String, ApiError> {
-            Ok("decoded".to_string())
-        }
-
-        async fn on_invoice_events(&self, _params: OnInvoiceEventParams, _callback: Box<dyn OnInvoiceEventCallback>) {
-            // Mock implementation - do nothing
-        }
-    }    // Helper function to create a test callback with a specific start time
+    // Helper function to create a test callback for a circuit built at a specific time
     fn create_test_callback(round: usize, circuit_start_time: Instant) -> OnLnInvoiceEventCallback {
         let (_, cancellation_receiver) = broadcast::channel(1);
+        let built_at = chrono::Utc::now().timestamp() - circuit_start_time.elapsed().as_secs() as i64;
         OnLnInvoiceEventCallback {
             payment_hash: format!("test_hash_{}", round),
             circuit_id: "test_circuit_123".to_string(),
             round,
-            circuit_start_time,
+            schedule: RoundSchedule::new(PaymentCadence::new(Some(60), Some(10)), built_at),
             rpc_config: RpcConfig {
                 addr: "127.0.0.1:9051".to_string(),
                 rpc_password: Some("test_password".to_string()),
                 command: "".to_string(),
             },
+            invoices: InvoiceDispatcher::new(Arc::new(MockLightningNode::default())),
+            expected_msats: 1000,
+            amount_policy: AmountPolicy::default(),
+            received_ledger: None,
             cancellation_receiver,
         }
     }
@@ -495,207 +932,309 @@ mod tests {
         }
     }
     
+    // Points the callback at a mock control port that has its circuit built
+    async fn with_mock_control_port(callback: &mut OnLnInvoiceEventCallback) -> MockControlPort {
+        let mock = MockControlPort::start().await;
+        mock.add_circuit(&callback.circuit_id, "BUILT");
+        callback.rpc_config = mock.rpc_config();
+        mock
+    }
+
+    // Whether the circuit got torn down on the mock control port
+    async fn torn_down(mock: &MockControlPort) -> bool {
+        mock.wait_for_command("TEARDOWNCIRCUIT", Duration::from_millis(500)).await
+    }
+
+    // How a payment for the callback's round made now is timed
+    fn timing_now(callback: &OnLnInvoiceEventCallback) -> RoundTiming {
+        callback.schedule.timing(callback.round as u32, chrono::Utc::now().timestamp())
+    }
+
     #[tokio::test]
     async fn test_round_0_on_time_payment() {
         // Round 0: expected window 0-60s, payment at 30s should be ON TIME
         let start_time = Instant::now() - Duration::from_secs(30);
-        let callback = create_test_callback(0, start_time);
+        let mut callback = create_test_callback(0, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_0"));
-        
-        // This should log as "ON TIME" and "KEEP ALIVE"
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.success(transaction);
-        
-        // Test passes if no panic occurs and logs show correct behavior
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_0_early_payment() {
         // Round 0: expected window 0-60s, payment at 5s should be ON TIME (not early since window starts at 0)
         let start_time = Instant::now() - Duration::from_secs(5);
-        let callback = create_test_callback(0, start_time);
+        let mut callback = create_test_callback(0, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_0"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_0_late_payment() {
         // Round 0: expected window 0-75s (60s + 15s padding), payment at 80s should be LATE
         let start_time = Instant::now() - Duration::from_secs(80);
-        let callback = create_test_callback(0, start_time);
+        let mut callback = create_test_callback(0, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_0"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+
         callback.success(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_1_early_payment() {
         // Round 1: acceptable window 0-120s, ideal 60-120s, payment at 30s should be EARLY
         let start_time = Instant::now() - Duration::from_secs(30);
-        let callback = create_test_callback(1, start_time);
+        let mut callback = create_test_callback(1, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_1"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Early);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_1_on_time_payment() {
         // Round 1: ideal window 60-120s, payment at 90s should be ON TIME
         let start_time = Instant::now() - Duration::from_secs(90);
-        let callback = create_test_callback(1, start_time);
+        let mut callback = create_test_callback(1, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_1"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_1_late_payment() {
         // Round 1: acceptable window 0-135s (120s + 15s padding), payment at 140s should be LATE
         let start_time = Instant::now() - Duration::from_secs(140);
-        let callback = create_test_callback(1, start_time);
+        let mut callback = create_test_callback(1, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_1"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+
         callback.success(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_2_early_payment() {
         // Round 2: acceptable window 0-180s, ideal 120-180s, payment at 60s should be EARLY
         let start_time = Instant::now() - Duration::from_secs(60);
-        let callback = create_test_callback(2, start_time);
+        let mut callback = create_test_callback(2, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_2"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Early);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_2_on_time_payment() {
         // Round 2: ideal window 120-180s, payment at 150s should be ON TIME
         let start_time = Instant::now() - Duration::from_secs(150);
-        let callback = create_test_callback(2, start_time);
+        let mut callback = create_test_callback(2, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_2"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_2_late_payment() {
         // Round 2: acceptable window 0-180s, payment at 200s should be LATE
         let start_time = Instant::now() - Duration::from_secs(200);
-        let callback = create_test_callback(2, start_time);
+        let mut callback = create_test_callback(2, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_2"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+
         callback.success(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_9_early_payment() {
         // Round 9: acceptable window 0-600s, ideal 540-600s, payment at 300s should be EARLY
         let start_time = Instant::now() - Duration::from_secs(300);
-        let callback = create_test_callback(9, start_time);
+        let mut callback = create_test_callback(9, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_9"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Early);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_9_on_time_payment() {
         // Round 9: ideal window 540-600s, payment at 570s should be ON TIME
         let start_time = Instant::now() - Duration::from_secs(570);
-        let callback = create_test_callback(9, start_time);
+        let mut callback = create_test_callback(9, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_9"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.success(transaction);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_round_9_late_payment() {
         // Round 9: acceptable window 0-600s, payment at 650s should be LATE
         let start_time = Instant::now() - Duration::from_secs(650);
-        let callback = create_test_callback(9, start_time);
+        let mut callback = create_test_callback(9, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_9"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+
         callback.success(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_payment_failure_within_window() {
         // Round 1: acceptable window 0-120s, failure at 90s should trigger TEARDOWN
         let start_time = Instant::now() - Duration::from_secs(90);
-        let callback = create_test_callback(1, start_time);
+        let mut callback = create_test_callback(1, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_1"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::OnTime);
+
         callback.failure(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_payment_failure_after_window() {
         // Round 1: acceptable window 0-120s, failure at 150s should trigger TEARDOWN
         let start_time = Instant::now() - Duration::from_secs(150);
-        let callback = create_test_callback(1, start_time);
+        let mut callback = create_test_callback(1, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
         let transaction = Some(create_test_transaction("test_hash_1"));
-        
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+
         callback.failure(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_payment_without_transaction() {
         // Test success callback with None transaction
         let start_time = Instant::now() - Duration::from_secs(30);
-        let callback = create_test_callback(0, start_time);
-        
+        let mut callback = create_test_callback(0, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
+
         callback.success(None);
+        assert!(!torn_down(&mock).await);
     }
-    
+
     #[tokio::test]
     async fn test_failure_without_transaction() {
         // Test failure callback with None transaction
         let start_time = Instant::now() - Duration::from_secs(30);
-        let callback = create_test_callback(0, start_time);
-        
+        let mut callback = create_test_callback(0, start_time);
+        let mock = with_mock_control_port(&mut callback).await;
+
         callback.failure(None);
+        assert!(torn_down(&mock).await);
     }
-    
-    #[tokio::test]
-    async fn test_window_boundary_conditions() {
-        // Test exact boundary conditions with padding
-        
+
+    #[test]
+    fn test_window_boundary_conditions() {
+        // Test exact boundary conditions with padding, counted from the circuit's build
+        let callback = create_test_callback(1, Instant::now());
+        let timing_at = |secs: i64| callback.schedule.timing(1, callback.schedule.built_at + secs);
+
         // Round 1: Payment exactly at base window end (120s) should be ON TIME
-        let start_time = Instant::now() - Duration::from_secs(120);
-        let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("boundary_test"));
-        callback.success(transaction);
-        
-        // Round 1: Payment exactly at padded window end (135s) should be ON TIME  
-        let start_time = Instant::now() - Duration::from_secs(135);
-        let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("boundary_test"));
-        callback.success(transaction);
-        
+        assert_eq!(timing_at(120), RoundTiming::OnTime);
+        // Round 1: Payment exactly at padded window end (135s) should be ON TIME
+        assert_eq!(timing_at(135), RoundTiming::OnTime);
         // Round 1: Payment exactly at ideal window start (60s) should be ON TIME
-        let start_time = Instant::now() - Duration::from_secs(60);
-        let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("boundary_test"));
-        callback.success(transaction);
-        
+        assert_eq!(timing_at(60), RoundTiming::OnTime);
+        // Round 1: Payment one second before the ideal window (59s) is EARLY
+        assert_eq!(timing_at(59), RoundTiming::Early);
         // Round 1: Payment one second after padded window (136s) should be LATE
-        let start_time = Instant::now() - Duration::from_secs(136);
-        let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("boundary_test"));
+        assert_eq!(timing_at(136), RoundTiming::Late);
+    }
+
+    #[tokio::test]
+    async fn test_round_1_late_payment_with_30s_interval() {
+        // PaymentInterval 30: Round 1 window 30-60s, with padding 0-75s, payment at 80s should be LATE
+        let start_time = Instant::now() - Duration::from_secs(80);
+        let mut callback = create_test_callback(1, start_time);
+        callback.schedule.cadence.interval_seconds = 30;
+        let mock = with_mock_control_port(&mut callback).await;
+        assert_eq!(callback.schedule.round_deadline(1) - callback.schedule.built_at, 75);
+        assert_eq!(timing_now(&callback), RoundTiming::Late);
+        // The same payment is on time at the default 60s interval
+        let mut default_interval = callback.schedule;
+        default_interval.cadence.interval_seconds = 60;
+        assert_eq!(default_interval.timing(1, chrono::Utc::now().timestamp()), RoundTiming::OnTime);
+
+        let transaction = Some(create_test_transaction("test_hash_1"));
         callback.success(transaction);
+        assert!(torn_down(&mock).await);
     }
-    
+
+    #[test]
+    fn test_match_invoice_to_payment_id() {
+        let payment_id = "ab".repeat(32);
+
+        let mut bolt12 = create_test_transaction("other_hash");
+        bolt12.payer_note = Some(payment_id.clone());
+        assert_eq!(match_invoice_to_payment_id(&bolt12, &payment_id), Some(InvoiceMatch::PayerNote));
+
+        let mut bolt11 = create_test_transaction("other_hash");
+        bolt11.description = format!("eltor payment {}", payment_id);
+        assert_eq!(match_invoice_to_payment_id(&bolt11, &payment_id), Some(InvoiceMatch::Description));
+
+        let by_hash = create_test_transaction(&payment_id.to_uppercase());
+        assert_eq!(match_invoice_to_payment_id(&by_hash, &payment_id), Some(InvoiceMatch::PaymentHash));
+
+        assert_eq!(match_invoice_to_payment_id(&create_test_transaction("other_hash"), &payment_id), None);
+    }
+
+    #[test]
+    fn test_prepaid_credit() {
+        let payment_id = "cd".repeat(32);
+        let mut batch = create_test_transaction("other_hash");
+        batch.payer_note = Some(payment_id.clone());
+        batch.amount_msats = 3000;
+        // Unsettled invoices add no credit
+        assert_eq!(settled_credit_msats(&batch, &payment_id), 0);
+        batch.settled_at = 1;
+        assert_eq!(settled_credit_msats(&batch, &payment_id), 3000);
+        assert_eq!(settled_credit_msats(&batch, &"ef".repeat(32)), 0);
+
+        assert_eq!(rounds_covered(3000, 1000), 3);
+        assert_eq!(rounds_covered(2999, 1000), 2);
+        assert_eq!(rounds_covered(0, 0), u64::MAX);
+    }
+
     // Test the timing calculations directly
     #[test]
     fn test_timing_calculations() {
         // Verify our timing math is correct with grace period
+        let schedule = RoundSchedule::new(PaymentCadence::new(Some(60), Some(10)), 0);
         for round in 0..10 {
-            let expected_window_start = round as u64 * 60;
-            let expected_window_end = expected_window_start + 60 + GRACE_PERIOD_SEC;
-            
             // Round 0: 0-75, Round 1: 60-135, Round 2: 120-195, etc. (with 15s grace period)
-            assert_eq!(expected_window_start, round as u64 * 60);
-            assert_eq!(expected_window_end, (round as u64 + 1) * 60 + GRACE_PERIOD_SEC);
+            assert_eq!(schedule.round_start(round), round as i64 * 60);
+            assert_eq!(schedule.round_deadline(round), (round as i64 + 1) * 60 + 15);
         }
     }
     
@@ -753,7 +1292,7 @@ mod tests {
         // Payment at 65s should be accepted (within padding)
         let start_time = Instant::now() - Duration::from_secs(65);
         let callback = create_test_callback(0, start_time);
-        let transaction = Some(create_test_transaction("padding_test"));
+        let transaction = Some(create_test_transaction("test_hash_0"));
         
         callback.success(transaction); // Should log as ON TIME, not LATE
         
@@ -761,7 +1300,7 @@ mod tests {
         // Payment at 125s should be accepted (within padding)
         let start_time = Instant::now() - Duration::from_secs(125);
         let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("padding_test"));
+        let transaction = Some(create_test_transaction("test_hash_1"));
         
         callback.success(transaction); // Should log as ON TIME, not LATE
     }
@@ -774,7 +1313,7 @@ mod tests {
         // Payment at 80s should be LATE (beyond padding)
         let start_time = Instant::now() - Duration::from_secs(80);
         let callback = create_test_callback(0, start_time);
-        let transaction = Some(create_test_transaction("late_test"));
+        let transaction = Some(create_test_transaction("test_hash_0"));
         
         callback.success(transaction); // Should log as LATE and trigger teardown
         
@@ -782,8 +1321,244 @@ mod tests {
         // Payment at 140s should be LATE (beyond padding)
         let start_time = Instant::now() - Duration::from_secs(140);
         let callback = create_test_callback(1, start_time);
-        let transaction = Some(create_test_transaction("late_test"));
+        let transaction = Some(create_test_transaction("test_hash_1"));
         
         callback.success(transaction); // Should log as LATE and trigger teardown
     }
+
+    #[tokio::test]
+    async fn test_late_payment_tears_down_circuit_on_control_port() {
+        let mock = MockControlPort::start().await;
+        mock.add_circuit("test_circuit_123", "BUILT");
+
+        let (_, cancellation_receiver) = broadcast::channel(1);
+        let callback = OnLnInvoiceEventCallback {
+            payment_hash: "test_hash_0".to_string(),
+            circuit_id: "test_circuit_123".to_string(),
+            round: 0,
+            schedule: RoundSchedule::new(PaymentCadence::new(Some(60), Some(10)), chrono::Utc::now().timestamp() - 80),
+            rpc_config: mock.rpc_config(),
+            invoices: InvoiceDispatcher::new(Arc::new(MockLightningNode::default())),
+            expected_msats: 1000,
+            amount_policy: AmountPolicy::default(),
+            received_ledger: None,
+            cancellation_receiver,
+        };
+        callback.success(Some(create_test_transaction("test_hash_0")));
+
+        assert!(
+            mock.wait_for_command("TEARDOWNCIRCUIT test_circuit_123", Duration::from_secs(5))
+                .await
+        );
+        assert!(mock.circuits().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_settled_invoice_recorded_in_received_ledger() {
+        let db = Db::with_store(Arc::new(database::SqliteStore::open_in_memory().unwrap()));
+        let mut row = database::test_payment("test_hash_0", "test_circuit_123", 1);
+        row.amount_msat = 1000;
+        db.write_payment(row).unwrap();
+
+        let mut callback = create_test_callback(0, Instant::now());
+        callback.received_ledger = Some(db.clone());
+        // An invoice for another payment id isn't recorded
+        callback.success(Some(create_test_transaction("other_hash")));
+        assert!(!db.lookup_payment_by_id("test_hash_0".to_string()).unwrap().unwrap().paid);
+
+        callback.success(Some(create_test_transaction("test_hash_0")));
+        let row = db.lookup_payment_by_id("test_hash_0".to_string()).unwrap().unwrap();
+        assert!(row.paid);
+        assert_eq!(row.amount_msat, 1000000);
+        assert_eq!(row.payment_hash.as_deref(), Some("test_hash_0"));
+        assert_eq!(row.preimage.as_deref(), Some("test_preimage"));
+    }
+
+    fn underpaid_callback(mock: &MockControlPort, db: &Db, policy: AmountPolicy) -> OnLnInvoiceEventCallback {
+        mock.add_circuit("test_circuit_123", "BUILT");
+        db.write_payment(database::test_payment("test_hash_0", "test_circuit_123", 1)).unwrap();
+        let mut callback = create_test_callback(0, Instant::now() - Duration::from_secs(30));
+        callback.rpc_config = mock.rpc_config();
+        callback.expected_msats = 2_000_000;
+        callback.amount_policy = policy;
+        callback.received_ledger = Some(db.clone());
+        callback
+    }
+
+    #[tokio::test]
+    async fn test_underpayment_tears_down_circuit() {
+        let mock = MockControlPort::start().await;
+        let db = Db::with_store(Arc::new(database::SqliteStore::open_in_memory().unwrap()));
+        let callback = underpaid_callback(&mock, &db, AmountPolicy::default());
+        callback.success(Some(create_test_transaction("test_hash_0")));
+
+        assert!(
+            mock.wait_for_command("TEARDOWNCIRCUIT test_circuit_123", Duration::from_secs(5))
+                .await
+        );
+        let row = db.lookup_payment_by_id("test_hash_0".to_string()).unwrap().unwrap();
+        assert!(!row.paid && row.has_error);
+        assert_eq!(row.amount_msat, 1000000);
+        assert_eq!(row.last_error.as_deref(), Some("Underpaid: received 1000000 of 2000000 msats"));
+    }
+
+    #[tokio::test]
+    async fn test_underpayment_within_tolerance_or_accepted_keeps_circuit() {
+        let mock = MockControlPort::start().await;
+        let db = Db::with_store(Arc::new(database::SqliteStore::open_in_memory().unwrap()));
+        let tolerant = AmountPolicy {
+            tolerance_percent: 50.0,
+            ..AmountPolicy::default()
+        };
+        underpaid_callback(&mock, &db, tolerant).success(Some(create_test_transaction("test_hash_0")));
+        assert!(db.lookup_payment_by_id("test_hash_0".to_string()).unwrap().unwrap().paid);
+
+        let accept = AmountPolicy {
+            on_underpayment: UnderpaymentAction::Accept,
+            ..AmountPolicy::default()
+        };
+        let db = Db::with_store(Arc::new(database::SqliteStore::open_in_memory().unwrap()));
+        underpaid_callback(&mock, &db, accept).success(Some(create_test_transaction("test_hash_0")));
+        let row = db.lookup_payment_by_id("test_hash_0".to_string()).unwrap().unwrap();
+        assert!(row.has_error);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!mock.commands().iter().any(|c| c.starts_with("TEARDOWNCIRCUIT")));
+        assert!(!mock.circuits().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_settled_msats_add_up_top_ups() {
+        let wallet = MockLightningNode::default();
+        let settled = |hash: &str, amount_msats: i64| lni::types::Transaction {
+            amount_msats,
+            description: "test_hash_0".to_string(),
+            settled_at: 1,
+            ..create_test_transaction(hash)
+        };
+        *wallet.transactions.lock().unwrap() = vec![
+            settled("first", 600),
+            settled("top_up", 400),
+            // Listed twice, counted once
+            settled("top_up", 400),
+            settled("other", 1000),
+            create_test_transaction("unsettled"),
+        ];
+        // "other" carries a different payment id
+        wallet.transactions.lock().unwrap()[3].description = "test_hash_1".to_string();
+        let invoices = InvoiceDispatcher::new(Arc::new(wallet));
+        invoices.track("test_hash_0", None);
+        invoices.catch_up(&["test_hash_0".to_string()]).await;
+        assert_eq!(settled_msats_for(&invoices, "test_hash_0"), 1000);
+        invoices.untrack("test_hash_0");
+        assert_eq!(settled_msats_for(&invoices, "test_hash_0"), 0);
+    }
+
+    fn ledger_row(payment_id: &str, circ_id: &str, round: i64, started_at: i64) -> database::Payment {
+        let mut row = database::test_payment(payment_id, circ_id, round);
+        row.expires_at = started_at + 60 * round;
+        row
+    }
+
+    #[tokio::test]
+    async fn test_recover_watchers_after_restart() {
+        let mock = MockControlPort::start().await;
+        mock.add_circuit("7001", "BUILT");
+        mock.add_circuit("7002", "BUILT");
+        mock.add_circuit("7003", "BUILT");
+        let now = chrono::Utc::now().timestamp();
+        let db = Db::with_store(Arc::new(database::SqliteStore::open_in_memory().unwrap()));
+        db.write_payments(&[
+            // Round 2 closed unpaid while we were down
+            ledger_row("lapsed_1", "7001", 1, now - 150),
+            ledger_row("lapsed_2", "7001", 2, now - 150),
+            // Round 1 paid, round 2 still open
+            ledger_row("open_1", "7002", 1, now - 70),
+            ledger_row("open_2", "7002", 2, now - 70),
+            // Paid while we were down
+            ledger_row("settled_1", "7003", 1, now - 100),
+            // Tor closed the circuit meanwhile
+            ledger_row("closed_1", "7004", 1, now - 150),
+        ])
+        .unwrap();
+        for paid in ["lapsed_1", "open_1"].iter() {
+            db.modify_payment(paid, |row| row.paid = true).unwrap();
+        }
+
+        let wallet = MockLightningNode::default();
+        *wallet.transactions.lock().unwrap() = vec![lni::types::Transaction {
+            description: "settled_1".to_string(),
+            settled_at: 1,
+            ..create_test_transaction("settled_hash")
+        }];
+        let wallet = Arc::new(wallet);
+        let callback = OnTorEventPaymentIdHashReceivedCallback {
+            wallet: wallet.clone(),
+            rpc_config: mock.rpc_config(),
+            terms: RelayPaymentTerms {
+                fingerprint: "relay".to_string(),
+                rate_msats: 1000,
+                bolt12_offer: None,
+                cadence: PaymentCadence::new(Some(60), Some(2)),
+            },
+            handshake_fee_msats: None,
+            prepaid: false,
+            amount_policy: AmountPolicy::default(),
+            grace_secs: 15,
+            clock_skew_secs: 0,
+            invoices: InvoiceDispatcher::new(wallet),
+            received_ledger: db.clone(),
+        };
+        callback.recover_watchers(&db).await;
+
+        assert!(mock.wait_for_command("TEARDOWNCIRCUIT 7001", Duration::from_secs(5)).await);
+        let commands = mock.commands();
+        assert!(!commands.iter().any(|c| c == "TEARDOWNCIRCUIT 7002" || c == "TEARDOWNCIRCUIT 7003"));
+        assert!(CIRCUIT_CANCELLATION_REGISTRY.lock().unwrap().contains_key("7002"));
+        // Only round 2 of circuit 7002 is still open
+        assert_eq!(callback.invoices.waiting(), 1);
+        assert!(!CIRCUIT_CANCELLATION_REGISTRY.lock().unwrap().contains_key("7004"));
+        let settled = db.lookup_payment_by_id("settled_1".to_string()).unwrap().unwrap();
+        assert!(settled.paid);
+        assert_eq!(settled.amount_msat, 1000000);
+    }
+
+    struct RecordingCallback(Arc<Mutex<Vec<String>>>);
+
+    impl EventCallback for RecordingCallback {
+        fn success(&self, response: Option<String>, _wallet: &(dyn LightningNode + Send + Sync)) {
+            if let Some(line) = response {
+                self.0.lock().unwrap().push(line);
+            }
+        }
+        fn failure(&self, _error: Option<String>) {}
+    }
+
+    #[tokio::test]
+    async fn test_event_listener_receives_payment_id_hash_events() {
+        let mock = MockControlPort::start().await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let callback = Box::new(RecordingCallback(received.clone()));
+        let wallet = MockLightningNode::default();
+
+        tokio::select! {
+            result = rpc_event_listener(mock.rpc_config(), "PAYMENT_ID_HASH_RECEIVED".to_string(), callback, &wallet) => {
+                panic!("event listener exited early: {:?}", result.err().map(|e| e.to_string()));
+            }
+            _ = async {
+                assert!(mock.wait_for_subscription("PAYMENT_ID_HASH_RECEIVED", Duration::from_secs(5)).await);
+                mock.emit_payment_id_hash_received("42", "deadbeef");
+                for _ in 0..100 {
+                    if received.lock().unwrap().iter().any(|l| l.starts_with("650 ")) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            } => {}
+        }
+
+        assert!(received.lock().unwrap().contains(
+            &"650 EVENT_PAYMENT_ID_HASH_RECEIVED P_CIRC_ID=42 N_CIRC_ID=0 PAYMENT_HASH=deadbeef".to_string()
+        ));
+    }
 }
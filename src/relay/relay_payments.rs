@@ -1,12 +1,17 @@
+use crate::types::RoundSchedule;
+
 pub struct RelayPayments {
     pub handshake_payment_hash: String,
     pub handshake_preimage: String,
     pub payhashes: Vec<String>,
+    /// Circuit build timestamp the client sent after the payment ids, if any
+    pub built_at: Option<i64>,
 }
 impl RelayPayments {
     // Parser for the wire_format to RelayPayments
     // Relay Payment hash wire_format is 12 (64 char) hashes concatenated together
     // "handshake_payment_hash + handshake_preimage + payment_id_hash_round1 + payment_id_hash_round2 + ...payment_id_hash_round10"
+    // optionally followed by the circuit build timestamp chunk (see RoundSchedule::built_at_to_wire)
     pub fn from_wire_format(wire_format: &str) -> Self {
         let chunks: Vec<String> = wire_format
             .as_bytes()
@@ -15,15 +20,41 @@ impl RelayPayments {
             .collect();
         let handshake_payment_hash = chunks.get(0).cloned().unwrap_or_default();
         let handshake_preimage = chunks.get(1).cloned().unwrap_or_default();
-        let payhashes = if chunks.len() > 2 {
+        let mut payhashes = if chunks.len() > 2 {
             chunks[2..].to_vec()
         } else {
             Vec::new()
         };
+        let built_at = payhashes.last().and_then(|chunk| RoundSchedule::built_at_from_wire(chunk));
+        if built_at.is_some() {
+            payhashes.pop();
+        }
         RelayPayments {
             handshake_payment_hash,
             handshake_preimage,
             payhashes,
+            built_at,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_wire_format_with_build_timestamp() {
+        let rounds: String = (0..10).map(|i| format!("{:02x}", i + 0x10).repeat(32)).collect();
+        let wire_format = format!("{}{}{}", "aa".repeat(32), "bb".repeat(32), rounds);
+        let relay_payments = RelayPayments::from_wire_format(&wire_format);
+        assert_eq!(relay_payments.payhashes.len(), 10);
+        assert_eq!(relay_payments.built_at, None);
+
+        let with_built_at = format!("{}{}", wire_format, RoundSchedule::built_at_to_wire(1_700_000_000));
+        let relay_payments = RelayPayments::from_wire_format(&with_built_at);
+        assert_eq!(relay_payments.handshake_preimage, "bb".repeat(32));
+        assert_eq!(relay_payments.payhashes.len(), 10);
+        assert_eq!(relay_payments.payhashes[9], "19".repeat(32));
+        assert_eq!(relay_payments.built_at, Some(1_700_000_000));
+    }
+}
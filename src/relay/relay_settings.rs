@@ -0,0 +1,90 @@
+use super::AmountPolicy;
+use crate::types::{DEFAULT_PAYMENT_CLOCK_SKEW_SECONDS, DEFAULT_PAYMENT_GRACE_SECONDS};
+use std::env;
+
+/// Relay settings Tor has no torrc option for, read from eltord's environment
+/// (Tor refuses to start with options it doesn't know in the torrc)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelaySettings {
+    /// `PAYMENT_PREPAID`: accept several rounds paid at once and check each circuit's
+    /// prepaid credit instead of one payment per round, advertised in ContactInfo
+    pub prepaid: bool,
+    /// `PAYMENT_AMOUNT_TOLERANCE_PERCENT` and `PAYMENT_UNDERPAYMENT_ACTION`
+    pub amount_policy: AmountPolicy,
+    /// `PAYMENT_GRACE_PERIOD_SECONDS`, seconds a round's payment may still settle after
+    /// the round ends
+    pub grace_secs: u64,
+    /// `PAYMENT_CLOCK_SKEW_SECONDS`, seconds a client's clock may be off from ours when
+    /// it sends the circuit build timestamp
+    pub clock_skew_secs: u64,
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        RelaySettings {
+            prepaid: false,
+            amount_policy: AmountPolicy::default(),
+            grace_secs: DEFAULT_PAYMENT_GRACE_SECONDS,
+            clock_skew_secs: DEFAULT_PAYMENT_CLOCK_SKEW_SECONDS,
+        }
+    }
+}
+
+impl RelaySettings {
+    /// Reads the settings from environment variables, falling back to the defaults for
+    /// anything unset or invalid
+    pub fn from_env() -> Self {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = RelaySettings::default();
+        let secs = |key: &str| var(key).and_then(|v| v.trim().parse::<u64>().ok());
+        RelaySettings {
+            prepaid: var("PAYMENT_PREPAID").is_some_and(|v| crate::rpc::parse_flag(&v)),
+            amount_policy: AmountPolicy::parse(
+                var("PAYMENT_AMOUNT_TOLERANCE_PERCENT").as_deref(),
+                var("PAYMENT_UNDERPAYMENT_ACTION").as_deref(),
+            ),
+            grace_secs: secs("PAYMENT_GRACE_PERIOD_SECONDS").unwrap_or(default.grace_secs),
+            clock_skew_secs: secs("PAYMENT_CLOCK_SKEW_SECONDS").unwrap_or(default.clock_skew_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::UnderpaymentAction;
+    use std::collections::HashMap;
+
+    fn settings(vars: &[(&str, &str)]) -> RelaySettings {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        RelaySettings::from_vars(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_relay_settings_from_vars() {
+        assert_eq!(settings(&[]), RelaySettings::default());
+
+        let relay = settings(&[
+            ("PAYMENT_PREPAID", "1"),
+            ("PAYMENT_AMOUNT_TOLERANCE_PERCENT", "2.5"),
+            ("PAYMENT_UNDERPAYMENT_ACTION", "top_up"),
+            ("PAYMENT_GRACE_PERIOD_SECONDS", "30"),
+            ("PAYMENT_CLOCK_SKEW_SECONDS", "5"),
+        ]);
+        assert!(relay.prepaid);
+        assert_eq!(relay.amount_policy.on_underpayment, UnderpaymentAction::TopUp);
+        assert_eq!(relay.amount_policy.min_acceptable_msats(1000), 975);
+        assert_eq!((relay.grace_secs, relay.clock_skew_secs), (30, 5));
+
+        // Invalid values fall back to the defaults
+        let relay = settings(&[
+            ("PAYMENT_PREPAID", "yes please"),
+            ("PAYMENT_AMOUNT_TOLERANCE_PERCENT", "150"),
+            ("PAYMENT_GRACE_PERIOD_SECONDS", "-1"),
+        ]);
+        assert_eq!(relay, RelaySettings::default());
+    }
+}
@@ -1,5 +1,5 @@
 use super::payments_watcher::start_payments_watcher;
-use crate::{rpc::get_torrc_value, types::RpcConfig, relay_info, relay_warn, relay_error};
+use crate::{rpc::get_conf_payment_bolt12_offer, types::RpcConfig, relay_info, relay_warn, relay_error};
 use log::debug;
 use std::sync::Arc;
 
@@ -35,11 +35,7 @@ async fn relay_flow_impl(rpc_config: &RpcConfig) {
 
     // 1. Torrc Config 
     //    Did you (the relay) set your BOLT12 offer in the torrc?
-    let conf = get_torrc_value(&rpc_config, &["PaymentBolt12Offer".to_string()]).await;
-    let bolt12 = conf
-        .iter()
-        .find(|e| e.key == "PaymentBolt12Offer")
-        .map(|entry| entry.value.clone());
+    let bolt12 = get_conf_payment_bolt12_offer(&rpc_config).await;
     relay_info!("BOLT12 offer from torrc: {:?}", bolt12);
     if !bolt12.is_some() {
         relay_info!("BOLT12 offer not found in torrc config. Running in free mode.");
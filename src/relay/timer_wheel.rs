@@ -0,0 +1,95 @@
+/// Hashed timer wheel: items are kept in one of `slots` buckets by the tick they are
+/// due at, so advancing a tick only looks at a single bucket. Ticks are abstract, the
+/// owner decides how long one lasts.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    current_tick: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(slots: usize) -> Self {
+        TimerWheel {
+            slots: (0..slots.max(1)).map(|_| Vec::new()).collect(),
+            current_tick: 0,
+            len: 0,
+        }
+    }
+
+    /// Last tick advanced to
+    pub fn current_tick(&self) -> u64 {
+        self.current_tick
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Schedules `item` for `tick`. Ticks already passed fire on the next advance.
+    pub fn insert(&mut self, tick: u64, item: T) {
+        let tick = tick.max(self.current_tick + 1);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((tick, item));
+        self.len += 1;
+    }
+
+    /// Moves the wheel to `tick` and returns every item due by then, earliest first
+    pub fn advance(&mut self, tick: u64) -> Vec<T> {
+        if tick <= self.current_tick {
+            return Vec::new();
+        }
+        let slot_count = self.slots.len() as u64;
+        // Past a full turn every slot has been looked at once
+        let last = tick.min(self.current_tick + slot_count);
+        let mut due = Vec::new();
+        for t in self.current_tick + 1..=last {
+            let slot = &mut self.slots[(t % slot_count) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= tick {
+                    due.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current_tick = tick;
+        self.len -= due.len();
+        due.sort_by_key(|(due_tick, _)| *due_tick);
+        due.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new(4);
+        wheel.insert(3, "c");
+        wheel.insert(1, "a");
+        // Wraps around the wheel twice before it is due
+        wheel.insert(9, "late");
+        wheel.insert(2, "b");
+        assert_eq!(wheel.len(), 4);
+
+        assert_eq!(wheel.advance(2), vec!["a", "b"]);
+        assert!(wheel.advance(2).is_empty());
+        assert_eq!(wheel.advance(5), vec!["c"]);
+
+        // Already passed, fires on the next tick
+        wheel.insert(1, "overdue");
+        assert_eq!(wheel.advance(6), vec!["overdue"]);
+        assert_eq!(wheel.len(), 1);
+
+        // Jumping several turns at once still finds it
+        assert_eq!(wheel.advance(100), vec!["late"]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.current_tick(), 100);
+    }
+}
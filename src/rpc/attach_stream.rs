@@ -1,9 +1,8 @@
-use crate::rpc::rpc_client;
+use crate::rpc::{control_session, ControlSession};
 use crate::types::RpcConfig;
 use log::{info, warn};
 use std::sync::atomic::{AtomicU64, Ordering};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
 
 /// Enables manual stream attachment mode and starts monitoring for new streams.
 /// Returns a handle that continuously attaches incoming streams to circuits in round-robin fashion.
@@ -12,12 +11,14 @@ pub async fn start_stream_attachment_monitor(
     primary_circuit_id: String,
     backup_circuit_id: String,
 ) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error + Send + Sync>> {
+    let session = control_session(&rpc_config);
+
     // Enable manual stream attachment
-    enable_manual_stream_attachment(&rpc_config).await?;
+    enable_manual_stream_attachment(&session).await?;
     
     // Subscribe to stream events
     let handle = tokio::spawn(async move {
-        if let Err(e) = stream_attachment_loop(&rpc_config, &primary_circuit_id, &backup_circuit_id).await {
+        if let Err(e) = stream_attachment_loop(&session, &primary_circuit_id, &backup_circuit_id).await {
             warn!("Stream attachment monitor stopped: {}", e);
         }
     });
@@ -27,74 +28,42 @@ pub async fn start_stream_attachment_monitor(
 
 /// Enables manual stream attachment by setting __LeaveStreamsUnattached=1
 async fn enable_manual_stream_attachment(
+    session: &ControlSession,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    session
+        .request("SETCONF __LeaveStreamsUnattached=1")
+        .await
+        .map_err(|e| format!("Failed to enable manual stream attachment: {}", e))?;
+
+    info!("✅ Manual stream attachment enabled");
+    Ok(())
+}
+
+/// Hands stream attachment back to Tor by setting __LeaveStreamsUnattached=0,
+/// new streams then use Tor's own (free) circuits
+pub async fn disable_manual_stream_attachment(
     rpc_config: &RpcConfig,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let config = RpcConfig {
-        addr: rpc_config.addr.clone(),
-        rpc_password: rpc_config.rpc_password.clone(),
-        command: "SETCONF __LeaveStreamsUnattached=1".to_string(),
-    };
-    
-    let response = match rpc_client(config).await {
-        Ok(r) => r,
-        Err(e) => return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("RPC call failed: {}", e)
-        ))),
-    };
-    
-    if response.contains("250 OK") {
-        info!("✅ Manual stream attachment enabled");
-        Ok(())
-    } else {
-        Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to enable manual stream attachment: {}", response)
-        )))
-    }
+    control_session(rpc_config)
+        .request("SETCONF __LeaveStreamsUnattached=0")
+        .await
+        .map_err(|e| format!("Failed to disable manual stream attachment: {}", e))?;
+
+    info!("✅ Manual stream attachment disabled, Tor attaches streams itself");
+    Ok(())
 }
 
 /// Main loop that monitors for STREAM NEW events and attaches them to circuits
 async fn stream_attachment_loop(
-    rpc_config: &RpcConfig,
+    session: &ControlSession,
     primary_circuit_id: &str,
     backup_circuit_id: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Connect to control port
-    let mut stream = TcpStream::connect(&rpc_config.addr).await?;
-    
-    // Authenticate (always required by Tor control protocol)
-    let auth_command = if let Some(password) = &rpc_config.rpc_password {
-        format!("AUTHENTICATE \"{}\"\r\n", password)
-    } else {
-        "AUTHENTICATE\r\n".to_string()
-    };
-    
-    stream.write_all(auth_command.as_bytes()).await?;
-    
-    // Create reader and check authentication response
-    let mut reader = BufReader::new(stream);
-    let mut auth_response = String::new();
-    reader.read_line(&mut auth_response).await?;
-    
-    if !auth_response.contains("250 OK") {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::PermissionDenied,
-            format!("Authentication failed: {}", auth_response)
-        )));
-    }
-    
-    // Subscribe to STREAM events
-    reader.get_mut().write_all(b"SETEVENTS STREAM\r\n").await?;
-    let mut event_response = String::new();
-    reader.read_line(&mut event_response).await?;
-    
-    if !event_response.contains("250 OK") {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to subscribe to STREAM events: {}", event_response)
-        )));
-    }
+    // Subscribe to STREAM events on the shared control session
+    let mut events = session
+        .subscribe(&["STREAM"])
+        .await
+        .map_err(|e| format!("Failed to subscribe to STREAM events: {}", e))?;
     
     info!("🔄 Stream attachment monitor active - distributing streams across circuits {} and {}", 
           primary_circuit_id, backup_circuit_id);
@@ -104,13 +73,17 @@ async fn stream_attachment_loop(
     
     // Read stream events and attach them
     loop {
-        let mut line = String::new();
-        let bytes_read = reader.read_line(&mut line).await?;
-        
-        if bytes_read == 0 {
-            warn!("Control connection closed");
-            break;
-        }
+        let line = match events.recv().await {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Stream attachment monitor fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => {
+                warn!("Control session event channel closed");
+                break;
+            }
+        };
         
         // Parse STREAM NEW events
         // Format: 650 STREAM <StreamID> NEW 0 <Target> [...]
@@ -126,7 +99,7 @@ async fn stream_attachment_loop(
                 };
                 
                 // Attach stream to selected circuit
-                if let Err(e) = attach_stream_to_circuit(rpc_config, &stream_id, target_circuit).await {
+                if let Err(e) = attach_stream_to_circuit(session, &stream_id, target_circuit).await {
                     warn!("⚠️ Failed to attach stream {} to circuit {}: {}", stream_id, target_circuit, e);
                 } else {
                     // info!("✅ Stream {} → Circuit {} (round-robin #{}/2)", stream_id, target_circuit, (count % 2) + 1);
@@ -151,30 +124,61 @@ fn parse_stream_id(line: &str) -> Option<String> {
 
 /// Attaches a specific stream to a specific circuit using ATTACHSTREAM
 async fn attach_stream_to_circuit(
-    rpc_config: &RpcConfig,
+    session: &ControlSession,
     stream_id: &str,
     circuit_id: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let config = RpcConfig {
-        addr: rpc_config.addr.clone(),
-        rpc_password: rpc_config.rpc_password.clone(),
-        command: format!("ATTACHSTREAM {} {}", stream_id, circuit_id),
-    };
-    
-    let response = match rpc_client(config).await {
-        Ok(r) => r,
-        Err(e) => return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("RPC call failed: {}", e)
-        ))),
-    };
+    let command = format!("ATTACHSTREAM {} {}", stream_id, circuit_id);
     
-    if response.contains("250 OK") {
-        Ok(())
-    } else {
-        Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("ATTACHSTREAM failed: {}", response)
-        )))
+    session
+        .request(&command)
+        .await
+        .map_err(|e| format!("ATTACHSTREAM failed: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::MockControlPort;
+    use tokio::time::{sleep, Duration};
+
+    #[test]
+    fn test_parse_stream_id() {
+        assert_eq!(
+            parse_stream_id("650 STREAM 42 NEW 0 example.com:443").as_deref(),
+            Some("42")
+        );
+        assert_eq!(parse_stream_id("650 CIRC 1 BUILT"), None);
+    }
+
+    #[tokio::test]
+    async fn test_new_streams_are_attached_round_robin() {
+        let mock = MockControlPort::start().await;
+        mock.add_circuit("1", "BUILT");
+        mock.add_circuit("2", "BUILT");
+
+        let handle = start_stream_attachment_monitor(mock.rpc_config(), "1".to_string(), "2".to_string())
+            .await
+            .unwrap();
+        assert_eq!(mock.conf("__LeaveStreamsUnattached"), vec!["1".to_string()]);
+        assert!(mock.wait_for_subscription("STREAM", Duration::from_secs(5)).await);
+
+        mock.emit_stream_new("10", "example.com:443");
+        mock.emit_stream_new("11", "example.org:443");
+        for _ in 0..200 {
+            if mock.streams().iter().all(|s| s.status == "SUCCEEDED") {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut circuits: Vec<String> = mock.streams().into_iter().map(|s| s.circuit_id).collect();
+        circuits.sort();
+        assert_eq!(circuits, vec!["1".to_string(), "2".to_string()]);
+        handle.abort();
+
+        disable_manual_stream_attachment(&mock.rpc_config()).await.unwrap();
+        assert_eq!(mock.conf("__LeaveStreamsUnattached"), vec!["0".to_string()]);
     }
 }
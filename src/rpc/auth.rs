@@ -0,0 +1,395 @@
+use super::control_session::{read_reply, write_all_flush};
+use super::{parse_key_values, ControlError, ControlReply};
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::Sha256;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+
+// Control port authentication driven by PROTOCOLINFO
+// https://spec.torproject.org/control-spec/commands.html#protocolinfo
+// https://spec.torproject.org/control-spec/commands.html#authchallenge
+
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+const COOKIE_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Null,
+    HashedPassword,
+    Cookie,
+    SafeCookie,
+}
+
+/// Parsed `PROTOCOLINFO` reply
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    pub methods: Vec<String>,
+    pub cookie_file: Option<String>,
+    pub tor_version: Option<String>,
+}
+
+impl ProtocolInfo {
+    pub fn from_reply(reply: &ControlReply) -> ProtocolInfo {
+        let mut info = ProtocolInfo::default();
+        for line in &reply.lines {
+            if let Some(rest) = line.text.strip_prefix("AUTH ") {
+                for (key, value) in parse_key_values(rest) {
+                    match key.as_str() {
+                        "METHODS" => {
+                            info.methods = value.split(',').map(|m| m.to_string()).collect()
+                        }
+                        "COOKIEFILE" => info.cookie_file = Some(value),
+                        _ => {}
+                    }
+                }
+            } else if let Some(rest) = line.text.strip_prefix("VERSION ") {
+                info.tor_version = parse_key_values(rest)
+                    .into_iter()
+                    .find(|(key, _)| key == "Tor")
+                    .map(|(_, value)| value);
+            }
+        }
+        info
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// Picks how to authenticate. A configured password is used when Tor accepts
+    /// one, otherwise we prefer SAFECOOKIE over plain COOKIE over no auth.
+    pub fn choose_method(&self, has_password: bool) -> Result<AuthMethod, ControlError> {
+        let has_cookie = self.cookie_file.is_some();
+        if has_password && self.supports("HASHEDPASSWORD") {
+            Ok(AuthMethod::HashedPassword)
+        } else if has_cookie && self.supports("SAFECOOKIE") {
+            Ok(AuthMethod::SafeCookie)
+        } else if has_cookie && self.supports("COOKIE") {
+            Ok(AuthMethod::Cookie)
+        } else if self.supports("NULL") {
+            Ok(AuthMethod::Null)
+        } else if self.supports("HASHEDPASSWORD") {
+            Err(ControlError::AuthRequired {
+                reason: "Tor requires a control port password (--pw)".to_string(),
+            })
+        } else {
+            Err(ControlError::AuthRequired {
+                reason: format!("no supported authentication method in {:?}", self.methods),
+            })
+        }
+    }
+}
+
+/// Authenticates a freshly opened control connection. Sends `PROTOCOLINFO`,
+/// picks a method and completes the handshake (including the SAFECOOKIE
+/// challenge-response). Returns the method that was used.
+pub async fn authenticate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    password: Option<&str>,
+) -> Result<AuthMethod, ControlError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let password = password.filter(|p| !p.is_empty());
+    let info = ProtocolInfo::from_reply(&send_command(reader, writer, "PROTOCOLINFO 1").await?);
+    let method = info.choose_method(password.is_some())?;
+    debug!(
+        "Control port offers {:?}, authenticating with {:?}",
+        info.methods, method
+    );
+
+    let command = match method {
+        AuthMethod::Null => "AUTHENTICATE".to_string(),
+        AuthMethod::HashedPassword => {
+            format!(
+                "AUTHENTICATE {}",
+                quote_string(password.unwrap_or_default())
+            )
+        }
+        AuthMethod::Cookie => {
+            let cookie = read_cookie(info.cookie_file.as_deref().unwrap_or_default()).await?;
+            format!("AUTHENTICATE {}", hex::encode(cookie))
+        }
+        AuthMethod::SafeCookie => {
+            let cookie = read_cookie(info.cookie_file.as_deref().unwrap_or_default()).await?;
+            safecookie_command(reader, writer, &cookie).await?
+        }
+    };
+    send_command(reader, writer, &command).await?;
+    Ok(method)
+}
+
+async fn send_command<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    command: &str,
+) -> Result<ControlReply, ControlError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_all_flush(writer, format!("{}\r\n", command).as_bytes()).await?;
+    let reply = read_reply(reader).await?;
+    ControlReply::parse(&reply)?.into_result()
+}
+
+// Runs AUTHCHALLENGE, verifies the server proved it knows the cookie and
+// returns the AUTHENTICATE command carrying our half of the proof.
+async fn safecookie_command<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    cookie: &[u8],
+) -> Result<String, ControlError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let client_nonce: [u8; 32] = rand::random();
+    let reply = send_command(
+        reader,
+        writer,
+        &format!("AUTHCHALLENGE SAFECOOKIE {}", hex::encode(client_nonce)),
+    )
+    .await?;
+
+    let (server_hash, server_nonce) = parse_authchallenge(reply.message())?;
+    let server_mac = safecookie_mac(SAFECOOKIE_SERVER_KEY, cookie, &client_nonce, &server_nonce);
+    if server_mac.verify_slice(&server_hash).is_err() {
+        return Err(ControlError::AuthFailed {
+            reason: "SAFECOOKIE server hash mismatch, refusing to authenticate".to_string(),
+        });
+    }
+    let client_mac = safecookie_mac(SAFECOOKIE_CLIENT_KEY, cookie, &client_nonce, &server_nonce);
+    Ok(format!(
+        "AUTHENTICATE {}",
+        hex::encode(client_mac.finalize().into_bytes())
+    ))
+}
+
+// "AUTHCHALLENGE SERVERHASH=<hex> SERVERNONCE=<hex>"
+fn parse_authchallenge(message: &str) -> Result<(Vec<u8>, Vec<u8>), ControlError> {
+    let malformed = || ControlError::Malformed {
+        reason: format!("unexpected AUTHCHALLENGE reply: {:?}", message),
+    };
+    let rest = message
+        .strip_prefix("AUTHCHALLENGE ")
+        .ok_or_else(malformed)?;
+    let pairs = parse_key_values(rest);
+    let field = |name: &str| {
+        pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .and_then(|(_, value)| hex::decode(value).ok())
+            .ok_or_else(malformed)
+    };
+    Ok((field("SERVERHASH")?, field("SERVERNONCE")?))
+}
+
+fn safecookie_mac(
+    key: &[u8],
+    cookie: &[u8],
+    client_nonce: &[u8],
+    server_nonce: &[u8],
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(cookie);
+    mac.update(client_nonce);
+    mac.update(server_nonce);
+    mac
+}
+
+async fn read_cookie(path: &str) -> Result<Vec<u8>, ControlError> {
+    let cookie = tokio::fs::read(path)
+        .await
+        .map_err(|e| ControlError::AuthFailed {
+            reason: format!("failed to read cookie file {}: {}", path, e),
+        })?;
+    if cookie.len() != COOKIE_LEN {
+        return Err(ControlError::AuthFailed {
+            reason: format!(
+                "cookie file {} is {} bytes, expected {}",
+                path,
+                cookie.len(),
+                COOKIE_LEN
+            ),
+        });
+    }
+    Ok(cookie)
+}
+
+pub(crate) fn quote_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const COOKIE: [u8; 32] = [7u8; 32];
+
+    fn write_cookie_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("eltor_test_{}_{}", name, std::process::id()));
+        std::fs::write(&path, COOKIE).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    // Mock control port that speaks PROTOCOLINFO and verifies SAFECOOKIE/COOKIE
+    // the same way Tor does. Replies 250 OK to AUTHENTICATE only if it checks out.
+    async fn spawn_auth_server(methods: &'static str, cookie_file: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            let server_nonce = [9u8; 32];
+            let mut client_nonce = Vec::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = if line.starts_with("PROTOCOLINFO") {
+                    format!(
+                        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={} COOKIEFILE=\"{}\"\r\n250-VERSION Tor=\"0.4.8.10\"\r\n250 OK\r\n",
+                        methods, cookie_file
+                    )
+                } else if let Some(nonce) = line.strip_prefix("AUTHCHALLENGE SAFECOOKIE ") {
+                    client_nonce = hex::decode(nonce).unwrap();
+                    let mac = safecookie_mac(
+                        SAFECOOKIE_SERVER_KEY,
+                        &COOKIE,
+                        &client_nonce,
+                        &server_nonce,
+                    );
+                    format!(
+                        "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                        hex::encode(mac.finalize().into_bytes()),
+                        hex::encode(server_nonce)
+                    )
+                } else if let Some(proof) = line.strip_prefix("AUTHENTICATE ") {
+                    let expected = if client_nonce.is_empty() {
+                        hex::encode(COOKIE)
+                    } else {
+                        let mac = safecookie_mac(
+                            SAFECOOKIE_CLIENT_KEY,
+                            &COOKIE,
+                            &client_nonce,
+                            &server_nonce,
+                        );
+                        hex::encode(mac.finalize().into_bytes())
+                    };
+                    if proof == expected || proof == "\"secret\"" {
+                        "250 OK\r\n".to_string()
+                    } else {
+                        "515 Authentication failed\r\n".to_string()
+                    }
+                } else {
+                    "514 Authentication required.\r\n".to_string()
+                };
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        });
+        addr
+    }
+
+    async fn auth_against(addr: &str, password: Option<&str>) -> Result<AuthMethod, ControlError> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        authenticate(&mut reader, &mut writer, password).await
+    }
+
+    #[test]
+    fn test_protocol_info_parsing() {
+        let reply = ControlReply::parse(
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE=\"/var/lib/tor/control_auth_cookie\"\r\n250-VERSION Tor=\"0.4.8.10\"\r\n250 OK\r\n",
+        )
+        .unwrap();
+        let info = ProtocolInfo::from_reply(&reply);
+        assert_eq!(info.methods, vec!["COOKIE", "SAFECOOKIE", "HASHEDPASSWORD"]);
+        assert_eq!(
+            info.cookie_file.as_deref(),
+            Some("/var/lib/tor/control_auth_cookie")
+        );
+        assert_eq!(info.tor_version.as_deref(), Some("0.4.8.10"));
+
+        assert_eq!(
+            info.choose_method(true).unwrap(),
+            AuthMethod::HashedPassword
+        );
+        assert_eq!(info.choose_method(false).unwrap(), AuthMethod::SafeCookie);
+    }
+
+    #[test]
+    fn test_choose_method_without_usable_method() {
+        let info = ProtocolInfo {
+            methods: vec!["HASHEDPASSWORD".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            info.choose_method(false),
+            Err(ControlError::AuthRequired { .. })
+        ));
+
+        let info = ProtocolInfo {
+            methods: vec!["NULL".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(info.choose_method(true).unwrap(), AuthMethod::Null);
+    }
+
+    #[tokio::test]
+    async fn test_safecookie_handshake() {
+        let cookie_file = write_cookie_file("safecookie");
+        let addr = spawn_auth_server("COOKIE,SAFECOOKIE", cookie_file).await;
+        assert_eq!(
+            auth_against(&addr, None).await.unwrap(),
+            AuthMethod::SafeCookie
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cookie_handshake() {
+        let cookie_file = write_cookie_file("cookie");
+        let addr = spawn_auth_server("COOKIE", cookie_file).await;
+        assert_eq!(auth_against(&addr, None).await.unwrap(), AuthMethod::Cookie);
+    }
+
+    #[tokio::test]
+    async fn test_password_handshake() {
+        let addr = spawn_auth_server("HASHEDPASSWORD", "unused".to_string()).await;
+        assert_eq!(
+            auth_against(&addr, Some("secret")).await.unwrap(),
+            AuthMethod::HashedPassword
+        );
+
+        let addr = spawn_auth_server("HASHEDPASSWORD", "unused".to_string()).await;
+        assert!(matches!(
+            auth_against(&addr, Some("wrong")).await,
+            Err(ControlError::AuthFailed { .. })
+        ));
+
+        // Without --pw no password is sent
+        let addr = spawn_auth_server("HASHEDPASSWORD", "unused".to_string()).await;
+        assert!(matches!(
+            auth_against(&addr, None).await,
+            Err(ControlError::AuthRequired { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_cookie_file_fails() {
+        let addr =
+            spawn_auth_server("SAFECOOKIE", "/nonexistent/control_auth_cookie".to_string()).await;
+        assert!(matches!(
+            auth_against(&addr, None).await,
+            Err(ControlError::AuthFailed { .. })
+        ));
+    }
+}
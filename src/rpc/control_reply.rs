@@ -0,0 +1,388 @@
+use thiserror::Error;
+
+// TOR control protocol replies
+// https://spec.torproject.org/control-spec/protocol-outline.html#replies
+//
+//   250-key=value        mid reply line
+//   250+key=             data reply line, followed by a dot-escaped data block ending in "."
+//   250 OK               end reply line (the reply's status code)
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    #[error("AuthRequired: {reason}")]
+    AuthRequired { reason: String },
+    #[error("AuthFailed: {reason}")]
+    AuthFailed { reason: String },
+    #[error("Unrecognized: {reason}")]
+    Unrecognized { code: u16, reason: String },
+    #[error("SyntaxError: {reason}")]
+    Syntax { code: u16, reason: String },
+    #[error("TorError {code}: {reason}")]
+    Tor { code: u16, reason: String },
+    #[error("MalformedReply: {reason}")]
+    Malformed { reason: String },
+    #[error("IoError: {reason}")]
+    Io { reason: String },
+}
+
+impl ControlError {
+    /// Maps a non-2xx status code and its message to a typed error.
+    pub fn from_status(code: u16, reason: &str) -> Self {
+        let reason = reason.to_string();
+        match code {
+            514 => ControlError::AuthRequired { reason },
+            515 => ControlError::AuthFailed { reason },
+            510 | 513 | 552 => ControlError::Unrecognized { code, reason },
+            500 | 512 => ControlError::Syntax { code, reason },
+            _ => ControlError::Tor { code, reason },
+        }
+    }
+}
+
+impl From<std::io::Error> for ControlError {
+    fn from(e: std::io::Error) -> Self {
+        ControlError::Io {
+            reason: e.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyLine {
+    pub code: u16,
+    /// Text after the status code and separator, e.g. `circuit-status=` or `OK`
+    pub text: String,
+    /// Unescaped data block for `NNN+` lines
+    pub data: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlReply {
+    /// Status code of the final reply line
+    pub code: u16,
+    pub lines: Vec<ReplyLine>,
+}
+
+impl ControlReply {
+    /// Parses one complete reply as read from the control port.
+    pub fn parse(raw: &str) -> Result<ControlReply, ControlError> {
+        let mut lines = Vec::new();
+        let mut raw_lines = raw.lines();
+
+        while let Some(line) = raw_lines.next() {
+            if line.is_empty() {
+                continue;
+            }
+            if line.len() < 4 || !line.is_char_boundary(3) || !line.is_char_boundary(4) {
+                return Err(malformed(line));
+            }
+            let code: u16 = line[..3].parse().map_err(|_| malformed(line))?;
+            let text = line[4..].to_string();
+
+            match line.as_bytes()[3] {
+                b'-' => lines.push(ReplyLine { code, text, data: None }),
+                b'+' => {
+                    let mut data = Vec::new();
+                    let mut terminated = false;
+                    for data_line in raw_lines.by_ref() {
+                        if data_line == "." {
+                            terminated = true;
+                            break;
+                        }
+                        // Lines starting with "." are escaped with an extra leading "."
+                        data.push(data_line.strip_prefix('.').unwrap_or(data_line).to_string());
+                    }
+                    if !terminated {
+                        return Err(ControlError::Malformed {
+                            reason: format!("unterminated data block after '{}'", line),
+                        });
+                    }
+                    lines.push(ReplyLine {
+                        code,
+                        text,
+                        data: Some(data.join("\n")),
+                    });
+                }
+                b' ' => {
+                    lines.push(ReplyLine { code, text, data: None });
+                    return Ok(ControlReply { code, lines });
+                }
+                _ => return Err(malformed(line)),
+            }
+        }
+
+        Err(ControlError::Malformed {
+            reason: "reply has no end line".to_string(),
+        })
+    }
+
+    /// True for 2xx replies
+    pub fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    /// True for asynchronous event notifications (6xx)
+    pub fn is_event(&self) -> bool {
+        (600..700).contains(&self.code)
+    }
+
+    /// Turns 4xx/5xx replies into a typed `ControlError`.
+    pub fn into_result(self) -> Result<ControlReply, ControlError> {
+        if self.is_ok() || self.is_event() {
+            Ok(self)
+        } else {
+            Err(ControlError::from_status(self.code, self.message()))
+        }
+    }
+
+    /// Text of the final reply line, e.g. `OK` or `EXTENDED 42`
+    pub fn message(&self) -> &str {
+        self.lines.last().map(|l| l.text.as_str()).unwrap_or("")
+    }
+
+    /// All `key[=value]` entries in the reply, in order. Data blocks are returned
+    /// as the value and quoted values are unescaped. The trailing `OK` is skipped.
+    pub fn entries(&self) -> Vec<(String, Option<String>)> {
+        let mut entries = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let is_last = i + 1 == self.lines.len();
+            if is_last && line.text == "OK" && line.data.is_none() {
+                continue;
+            }
+            match line.text.find('=') {
+                Some(idx) => {
+                    let key = line.text[..idx].to_string();
+                    let value = match &line.data {
+                        Some(data) => data.clone(),
+                        None => unquote_if_quoted(&line.text[idx + 1..]),
+                    };
+                    entries.push((key, Some(value)));
+                }
+                None => entries.push((line.text.clone(), line.data.clone())),
+            }
+        }
+        entries
+    }
+
+    /// First value for `key` (e.g. `GETINFO circuit-status` → `value("circuit-status")`)
+    pub fn value(&self, key: &str) -> Option<String> {
+        self.values(key).into_iter().next()
+    }
+
+    /// All values for `key`, for multi-valued settings like `GETCONF PaymentLightningNodeConfig`
+    pub fn values(&self, key: &str) -> Vec<String> {
+        self.entries()
+            .into_iter()
+            .filter(|(k, _)| k == key)
+            .filter_map(|(_, v)| v)
+            .collect()
+    }
+}
+
+fn malformed(line: &str) -> ControlError {
+    ControlError::Malformed {
+        reason: format!("invalid reply line '{}'", line),
+    }
+}
+
+fn unquote_if_quoted(value: &str) -> String {
+    if value.starts_with('"') {
+        if let Ok((unquoted, _)) = parse_quoted_string(value) {
+            return unquoted;
+        }
+    }
+    value.to_string()
+}
+
+/// Parses a control-spec QuotedString starting at the opening `"`.
+/// Returns the unescaped string and the remaining input after the closing quote.
+pub fn parse_quoted_string(input: &str) -> Result<(String, &str), ControlError> {
+    let mut chars = input.char_indices();
+    match chars.next() {
+        Some((_, '"')) => {}
+        _ => {
+            return Err(ControlError::Malformed {
+                reason: format!("expected quoted string: {}", input),
+            })
+        }
+    }
+
+    let mut out = String::new();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &input[idx + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 'r')) => out.push('\r'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, escaped)) => out.push(escaped),
+                None => break,
+            },
+            _ => out.push(c),
+        }
+    }
+
+    Err(ControlError::Malformed {
+        reason: format!("unterminated quoted string: {}", input),
+    })
+}
+
+/// Splits space separated `KEY=VALUE` pairs where values may be quoted,
+/// e.g. `PROGRESS=100 TAG=done SUMMARY="Done"`. Bare words get an empty value.
+pub fn parse_key_values(input: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let key_end = rest.find(['=', ' ']).unwrap_or(rest.len());
+        let key = rest[..key_end].to_string();
+        rest = &rest[key_end..];
+
+        let value = if let Some(after_eq) = rest.strip_prefix('=') {
+            if after_eq.starts_with('"') {
+                match parse_quoted_string(after_eq) {
+                    Ok((value, remaining)) => {
+                        rest = remaining;
+                        value
+                    }
+                    Err(_) => {
+                        rest = "";
+                        after_eq.to_string()
+                    }
+                }
+            } else {
+                let end = after_eq.find(' ').unwrap_or(after_eq.len());
+                rest = &after_eq[end..];
+                after_eq[..end].to_string()
+            }
+        } else {
+            String::new()
+        };
+
+        pairs.push((key, value));
+        rest = rest.trim_start();
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_line_ok() {
+        let reply = ControlReply::parse("250 OK\r\n").unwrap();
+        assert_eq!(reply.code, 250);
+        assert!(reply.is_ok());
+        assert_eq!(reply.message(), "OK");
+        assert!(reply.entries().is_empty());
+    }
+
+    #[test]
+    fn test_parse_mid_lines_getconf() {
+        let raw = "250-PaymentLightningNodeConfig=type=phoenixd url=http://url.com default=true\r\n\
+                   250 PaymentLightningNodeConfig=type=lnd url=http://lnd.com\r\n";
+        let reply = ControlReply::parse(raw).unwrap();
+        assert_eq!(
+            reply.values("PaymentLightningNodeConfig"),
+            vec![
+                "type=phoenixd url=http://url.com default=true".to_string(),
+                "type=lnd url=http://lnd.com".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_unset_getconf_key() {
+        let reply = ControlReply::parse("250 PaymentBolt12Offer\r\n").unwrap();
+        assert_eq!(
+            reply.entries(),
+            vec![("PaymentBolt12Offer".to_string(), None)]
+        );
+        assert_eq!(reply.value("PaymentBolt12Offer"), None);
+    }
+
+    #[test]
+    fn test_parse_data_block_with_dot_escaping() {
+        let raw = "250+circuit-status=\r\n\
+                   123 BUILT $FP1~relay1 PURPOSE=GENERAL\r\n\
+                   ..hidden line\r\n\
+                   .\r\n\
+                   250 OK\r\n";
+        let reply = ControlReply::parse(raw).unwrap();
+        assert_eq!(
+            reply.value("circuit-status").unwrap(),
+            "123 BUILT $FP1~relay1 PURPOSE=GENERAL\n.hidden line"
+        );
+    }
+
+    #[test]
+    fn test_parse_unterminated_data_block() {
+        let raw = "250+ns/all=\r\nr test\r\n";
+        assert!(matches!(
+            ControlReply::parse(raw),
+            Err(ControlError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_quoted_value() {
+        let reply = ControlReply::parse("250-version=\"0.4.8 \\\"eltor\\\"\"\r\n250 OK\r\n").unwrap();
+        assert_eq!(reply.value("version").unwrap(), "0.4.8 \"eltor\"");
+    }
+
+    #[test]
+    fn test_error_codes_are_typed() {
+        let err = ControlReply::parse("552 Unrecognized configuration key \"Foo\"\r\n")
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert!(matches!(err, ControlError::Unrecognized { code: 552, .. }));
+
+        let err = ControlReply::parse("515 Authentication failed: Password did not match\r\n")
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert!(matches!(err, ControlError::AuthFailed { .. }));
+
+        let err = ControlReply::parse("514 Authentication required.\r\n")
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert!(matches!(err, ControlError::AuthRequired { .. }));
+
+        let err = ControlReply::parse("551 Circuit not found\r\n")
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ControlError::Tor {
+                code: 551,
+                reason: "Circuit not found".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_line() {
+        assert!(ControlReply::parse("hello\r\n").is_err());
+        assert!(ControlReply::parse("250-no end line\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_key_values_with_quotes() {
+        let pairs = parse_key_values(
+            "NOTICE BOOTSTRAP PROGRESS=85 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"",
+        );
+        assert_eq!(
+            pairs,
+            vec![
+                ("NOTICE".to_string(), String::new()),
+                ("BOOTSTRAP".to_string(), String::new()),
+                ("PROGRESS".to_string(), "85".to_string()),
+                ("TAG".to_string(), "loading_descriptors".to_string()),
+                ("SUMMARY".to_string(), "Loading relay descriptors".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,447 @@
+use super::{authenticate, connect_control_port, ControlError, ControlReply, ControlStream};
+use crate::types::RpcConfig;
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{sleep, Duration};
+
+// Capacity of the async event fan-out channel. Slow subscribers that fall
+// further behind than this will see a `Lagged` error and skip ahead.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+// Reconnect backoff bounds for sessions that have event subscribers
+const RECONNECT_MIN_DELAY_SECS: u64 = 1;
+const RECONNECT_MAX_DELAY_SECS: u64 = 30;
+
+type PendingReplies = Arc<Mutex<VecDeque<oneshot::Sender<Result<String, ControlError>>>>>;
+
+lazy_static::lazy_static! {
+    // One shared session per control port address
+    static ref CONTROL_SESSIONS: Mutex<HashMap<String, ControlSession>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns the shared control session for the control port in `config`.
+///
+/// Sessions are created lazily and connect on first use, so this is cheap to
+/// call from anywhere that previously built a one-off `rpc_client` request.
+pub fn control_session(config: &RpcConfig) -> ControlSession {
+    let mut sessions = CONTROL_SESSIONS.lock().unwrap();
+    sessions
+        .entry(config.addr.clone())
+        .or_insert_with(|| ControlSession::new(config))
+        .clone()
+}
+
+/// A long-lived, authenticated connection to the Tor control port.
+///
+/// Commands are pipelined over a single connection: each call writes its
+/// command immediately and waits for its own reply. Tor answers commands in
+/// the order they were received, so replies are matched to requests FIFO.
+/// Asynchronous `650` events are routed to subscribers instead of to a pending
+/// request. If the connection drops, the next command reconnects, and sessions
+/// with event subscribers reconnect in the background and re-issue `SETEVENTS`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let session = control_session(&rpc_config);
+/// let reply = session.request("GETINFO circuit-status").await?;
+/// let circuits = reply.value("circuit-status");
+/// let mut events = session.subscribe(&["STREAM"]).await?;
+/// while let Ok(event) = events.recv().await {
+///     println!("{}", event);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ControlSession {
+    inner: Arc<SessionInner>,
+}
+
+struct SessionInner {
+    config: RpcConfig,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+    events: broadcast::Sender<String>,
+    subscribed_events: Mutex<BTreeSet<String>>,
+    reconnecting: AtomicBool,
+}
+
+struct Connection {
+    writer: WriteHalf<Box<dyn ControlStream>>,
+    pending: PendingReplies,
+    alive: Arc<AtomicBool>,
+}
+
+impl ControlSession {
+    pub fn new(config: &RpcConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        ControlSession {
+            inner: Arc::new(SessionInner {
+                config: config.clone(),
+                connection: tokio::sync::Mutex::new(None),
+                events,
+                subscribed_events: Mutex::new(BTreeSet::new()),
+                reconnecting: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Sends a command and returns the parsed reply, turning 4xx/5xx status
+    /// codes into a typed `ControlError`.
+    pub async fn request(&self, command: &str) -> Result<ControlReply, ControlError> {
+        let raw = self.command(command).await?;
+        ControlReply::parse(&raw)?.into_result()
+    }
+
+    /// Sends a single command and returns the raw reply (all lines, CRLF terminated).
+    ///
+    /// Multi-line commands (those starting with `+`) are sent as-is; the caller
+    /// is responsible for the terminating `.` line.
+    pub async fn command(&self, command: &str) -> Result<String, ControlError> {
+        let receiver = {
+            let mut connection = self.inner.connection.lock().await;
+            let conn = self.ensure_connected(&mut connection).await?;
+            let (sender, receiver) = oneshot::channel();
+            // Register before writing so the reader can never see a reply without a waiter
+            conn.pending.lock().unwrap().push_back(sender);
+            let line = format!("{}\r\n", command.trim_end());
+            if let Err(e) = write_all_flush(&mut conn.writer, line.as_bytes()).await {
+                conn.alive.store(false, Ordering::SeqCst);
+                *connection = None;
+                return Err(e.into());
+            }
+            receiver
+        };
+
+        match receiver.await {
+            Ok(reply) => reply,
+            Err(_) => Err(connection_closed_error()),
+        }
+    }
+
+    /// Subscribes to async events (e.g. `STREAM`, `CIRC`, `PAYMENT_ID_HASH_RECEIVED`).
+    ///
+    /// The session keeps the union of all requested events registered with Tor.
+    /// The returned receiver yields every `650` event seen on this session, with
+    /// multi-line events joined by CRLF; callers filter for the ones they want.
+    pub async fn subscribe(
+        &self,
+        events: &[&str],
+    ) -> Result<broadcast::Receiver<String>, ControlError> {
+        let receiver = self.inner.events.subscribe();
+        let changed = {
+            let mut subscribed = self.inner.subscribed_events.lock().unwrap();
+            let before = subscribed.len();
+            for event in events {
+                subscribed.insert(event.to_string());
+            }
+            subscribed.len() != before
+        };
+        if changed {
+            let command = setevents_command(&self.inner.subscribed_events.lock().unwrap());
+            self.request(&command).await?;
+        }
+        Ok(receiver)
+    }
+
+    async fn ensure_connected<'a>(
+        &self,
+        connection: &'a mut Option<Connection>,
+    ) -> Result<&'a mut Connection, ControlError> {
+        let is_alive = connection
+            .as_ref()
+            .map(|c| c.alive.load(Ordering::SeqCst))
+            .unwrap_or(false);
+        if !is_alive {
+            *connection = Some(self.connect().await?);
+        }
+        Ok(connection.as_mut().unwrap())
+    }
+
+    async fn connect(&self) -> Result<Connection, ControlError> {
+        debug!("Opening control session to {}", self.inner.config.addr);
+        let stream = connect_control_port(&self.inner.config.addr).await?;
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        authenticate(
+            &mut reader,
+            &mut writer,
+            self.inner.config.rpc_password.as_deref(),
+        )
+        .await?;
+
+        // Re-register events after a reconnect so subscribers keep receiving them
+        let setevents = {
+            let subscribed = self.inner.subscribed_events.lock().unwrap();
+            if subscribed.is_empty() {
+                None
+            } else {
+                Some(setevents_command(&subscribed))
+            }
+        };
+        if let Some(setevents) = setevents {
+            write_all_flush(&mut writer, format!("{}\r\n", setevents).as_bytes()).await?;
+            let reply = read_reply(&mut reader).await?;
+            if let Err(e) = ControlReply::parse(&reply)?.into_result() {
+                warn!("Failed to re-subscribe to events: {}", e);
+            }
+        }
+
+        let pending: PendingReplies = Arc::new(Mutex::new(VecDeque::new()));
+        let alive = Arc::new(AtomicBool::new(true));
+        tokio::spawn(reader_loop(
+            reader,
+            pending.clone(),
+            alive.clone(),
+            self.clone(),
+        ));
+        info!("Control session established to {}", self.inner.config.addr);
+
+        Ok(Connection {
+            writer,
+            pending,
+            alive,
+        })
+    }
+
+    // Keeps reconnecting in the background so event subscribers are not left
+    // hanging when nobody issues a command to trigger the reconnect.
+    fn spawn_reconnect(&self) {
+        if self.inner.subscribed_events.lock().unwrap().is_empty() {
+            return;
+        }
+        if self.inner.reconnecting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let session = self.clone();
+        tokio::spawn(async move {
+            let mut delay = RECONNECT_MIN_DELAY_SECS;
+            loop {
+                sleep(Duration::from_secs(delay)).await;
+                let result = {
+                    let mut connection = session.inner.connection.lock().await;
+                    session.ensure_connected(&mut connection).await.map(|_| ())
+                };
+                match result {
+                    Ok(()) => break,
+                    Err(e) => {
+                        warn!(
+                            "Control session reconnect to {} failed: {}. Retrying in {}s",
+                            session.inner.config.addr, e, delay
+                        );
+                        delay = (delay * 2).min(RECONNECT_MAX_DELAY_SECS);
+                    }
+                }
+            }
+            session.inner.reconnecting.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn connection_closed_error() -> ControlError {
+    ControlError::Io {
+        reason: "control connection closed before reply".to_string(),
+    }
+}
+
+fn setevents_command(subscribed: &BTreeSet<String>) -> String {
+    let mut command = "SETEVENTS".to_string();
+    for event in subscribed.iter() {
+        command.push(' ');
+        command.push_str(event);
+    }
+    command
+}
+
+pub(super) async fn write_all_flush<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    bytes: &[u8],
+) -> io::Result<()> {
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+// Reads one complete reply: any number of `NNN-` / `NNN+` (data block) lines
+// followed by a final `NNN ` line.
+pub(super) async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut reply = String::new();
+    let mut in_data = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "control connection closed",
+            ));
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        reply.push_str(line);
+        reply.push_str("\r\n");
+        if in_data {
+            if line == "." {
+                in_data = false;
+            }
+            continue;
+        }
+        match line.as_bytes().get(3) {
+            Some(b'+') => in_data = true,
+            Some(b'-') => {}
+            _ => return Ok(reply),
+        }
+    }
+}
+
+async fn reader_loop(
+    mut reader: BufReader<ReadHalf<Box<dyn ControlStream>>>,
+    pending: PendingReplies,
+    alive: Arc<AtomicBool>,
+    session: ControlSession,
+) {
+    loop {
+        match read_reply(&mut reader).await {
+            Ok(reply) => {
+                if reply.starts_with('6') {
+                    // Async event, no subscribers is fine
+                    let _ = session.inner.events.send(reply.trim_end().to_string());
+                } else if let Some(waiter) = pending.lock().unwrap().pop_front() {
+                    let _ = waiter.send(Ok(reply));
+                } else {
+                    warn!("Dropping unsolicited control reply: {}", reply.trim());
+                }
+            }
+            Err(e) => {
+                debug!("Control session to {} closed: {}", session.inner.config.addr, e);
+                break;
+            }
+        }
+    }
+
+    alive.store(false, Ordering::SeqCst);
+    for waiter in pending.lock().unwrap().drain(..) {
+        let _ = waiter.send(Err(connection_closed_error()));
+    }
+    session.spawn_reconnect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Minimal fake control port: offers NULL auth, echoes GETINFO keys back
+    // and emits a STREAM event right before answering SETEVENTS.
+    async fn spawn_fake_control_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                tokio::spawn(async move {
+                    let (reader, mut writer) = tokio::io::split(stream);
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = if line.starts_with("PROTOCOLINFO") {
+                            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n".to_string()
+                        } else if line.starts_with("AUTHENTICATE") {
+                            "250 OK\r\n".to_string()
+                        } else if let Some(key) = line.strip_prefix("GETINFO ") {
+                            format!("250-{}=value-of-{}\r\n250 OK\r\n", key, key)
+                        } else if line.starts_with("SETEVENTS") {
+                            "650 STREAM 42 NEW 0 example.com:443\r\n250 OK\r\n".to_string()
+                        } else if line == "QUIT" {
+                            writer.write_all(b"250 closing connection\r\n").await.ok();
+                            return;
+                        } else {
+                            "510 Unrecognized command\r\n".to_string()
+                        };
+                        if writer.write_all(reply.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn test_config(addr: String) -> RpcConfig {
+        RpcConfig {
+            addr,
+            rpc_password: None,
+            command: "".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_get_their_own_replies() {
+        let addr = spawn_fake_control_port().await;
+        let session = ControlSession::new(&test_config(addr));
+
+        let (a, b, c) = tokio::join!(
+            session.command("GETINFO a"),
+            session.command("GETINFO b"),
+            session.command("GETINFO c"),
+        );
+        assert_eq!(a.unwrap(), "250-a=value-of-a\r\n250 OK\r\n");
+        assert_eq!(b.unwrap(), "250-b=value-of-b\r\n250 OK\r\n");
+        assert_eq!(c.unwrap(), "250-c=value-of-c\r\n250 OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_routed_to_subscribers() {
+        let addr = spawn_fake_control_port().await;
+        let session = ControlSession::new(&test_config(addr));
+
+        let mut events = session.subscribe(&["STREAM"]).await.unwrap();
+        let event = events.recv().await.unwrap();
+        assert_eq!(event, "650 STREAM 42 NEW 0 example.com:443");
+
+        // The event must not have been mistaken for a command reply
+        let reply = session.command("GETINFO x").await.unwrap();
+        assert!(reply.starts_with("250-x="));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_closed() {
+        let addr = spawn_fake_control_port().await;
+        let session = ControlSession::new(&test_config(addr));
+
+        // QUIT makes the fake server drop the connection after replying
+        let reply = session.command("QUIT").await.unwrap();
+        assert_eq!(reply, "250 closing connection\r\n");
+        sleep(Duration::from_millis(50)).await;
+
+        let reply = session.command("GETINFO y").await.unwrap();
+        assert!(reply.starts_with("250-y="));
+    }
+
+    #[tokio::test]
+    async fn test_request_returns_typed_errors() {
+        let addr = spawn_fake_control_port().await;
+        let session = ControlSession::new(&test_config(addr));
+
+        let reply = session.request("GETINFO version").await.unwrap();
+        assert_eq!(reply.value("version").unwrap(), "value-of-version");
+
+        let err = session.request("BOGUS").await.unwrap_err();
+        assert!(matches!(err, ControlError::Unrecognized { code: 510, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_shared_session_is_reused_per_address() {
+        let config = test_config("127.0.0.1:1".to_string());
+        let first = control_session(&config);
+        let second = control_session(&config);
+        assert!(Arc::ptr_eq(&first.inner, &second.inner));
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Any byte stream a control connection can run over (TCP or unix socket)
+pub trait ControlStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ControlStream for T {}
+
+/// Returns the socket path if `addr` points at a unix domain socket.
+/// Accepts both `unix:/run/tor/control` and a bare absolute path.
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        Some(path)
+    } else if addr.starts_with('/') {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+/// Opens a connection to the control port in `RpcConfig.addr`, which is either
+/// `host:port` or a unix socket path (see `unix_socket_path`).
+pub async fn connect_control_port(addr: &str) -> io::Result<Box<dyn ControlStream>> {
+    match unix_socket_path(addr) {
+        Some(path) => connect_unix(path).await,
+        None => Ok(Box::new(TcpStream::connect(addr).await?)),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<Box<dyn ControlStream>> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &str) -> io::Result<Box<dyn ControlStream>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("unix socket control port {} is not supported on this platform", path),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_socket_path() {
+        assert_eq!(unix_socket_path("unix:/run/tor/control"), Some("/run/tor/control"));
+        assert_eq!(unix_socket_path("/run/tor/control"), Some("/run/tor/control"));
+        assert_eq!(unix_socket_path("127.0.0.1:9051"), None);
+        assert_eq!(unix_socket_path("localhost:9051"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connects_over_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("eltor_control_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"250 OK\r\n").await.unwrap();
+        });
+
+        let addr = format!("unix:{}", path.display());
+        let mut stream = connect_control_port(&addr).await.unwrap();
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"250 OK\r\n");
+        let _ = std::fs::remove_file(&path);
+    }
+}
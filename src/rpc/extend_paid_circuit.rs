@@ -1,4 +1,4 @@
-use super::rpc_client;
+use super::{control_session, ControlError};
 use std::error::Error;
 use crate::types::RpcConfig;
 
@@ -7,28 +7,37 @@ use crate::types::RpcConfig;
 // fingerprint_middle_relay handshake_fee_payment_hash+handshake_fee_preimage+10_payment_ids_concatinated
 // fingerprint_exit_relay handshake_fee_payment_hash+handshake_fee_preimage+10_payment_ids_concatinated
 // TODO make work with N middle_relays
-pub async fn extend_paid_circuit(config: &RpcConfig, command: String) -> Result<String, Box<dyn Error>> {
-    let rpc = rpc_client(RpcConfig {
-        addr: config.clone().addr,
-        rpc_password: config.clone().rpc_password,
-        command: format!("{}", command).into(),
-    })
-    .await;
+pub async fn extend_paid_circuit(
+    config: &RpcConfig,
+    command: String,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let reply = control_session(config).request(&command).await?;
+    Ok(parse_extended_circuit_id(reply.message())?)
+}
+
+/// Pulls the circuit id out of a `250 EXTENDED <id>` reply
+fn parse_extended_circuit_id(message: &str) -> Result<String, ControlError> {
+    message
+        .strip_prefix("EXTENDED")
+        .map(|rest| rest.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+        .ok_or_else(|| ControlError::Malformed {
+            reason: format!("expected EXTENDED <circuit_id>, got {:?}", message),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match rpc {
-        Ok(rpc) => {
-            if rpc.starts_with("250 ") {
-                let resp = rpc.trim_start_matches("250 ");
-                let start = resp.find("EXTENDED").map(|i| i + "EXTENDED".len()).unwrap_or(0);
-                let end = resp.find("250 closing connection").unwrap_or(resp.len());
-                let circuit_id = &resp[start..end].trim();
-                Ok(circuit_id.to_string())
-            } else {
-                Ok("".to_string())
-            }
-        }
-        Err(e) => {
-            Err(e)
-        }
+    #[test]
+    fn test_parse_extended_circuit_id() {
+        assert_eq!(parse_extended_circuit_id("EXTENDED 42").unwrap(), "42");
+        assert!(matches!(
+            parse_extended_circuit_id("OK"),
+            Err(ControlError::Malformed { .. })
+        ));
+        assert!(parse_extended_circuit_id("EXTENDED").is_err());
     }
 }
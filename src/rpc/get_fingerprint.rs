@@ -0,0 +1,32 @@
+use super::{control_session, ControlError};
+use crate::types::RpcConfig;
+
+/// Gets this relay's own fingerprint with `GETINFO fingerprint`. Tor only answers
+/// it when running as a relay (an ORPort is set), clients get `ControlError::Unrecognized`.
+pub async fn get_relay_fingerprint(config: &RpcConfig) -> Result<String, ControlError> {
+    let reply = control_session(config).request("GETINFO fingerprint").await?;
+    reply
+        .value("fingerprint")
+        .map(|fingerprint| fingerprint.trim().to_string())
+        .ok_or_else(|| ControlError::Malformed {
+            reason: format!("GETINFO fingerprint reply without a fingerprint: {}", reply.message()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::MockControlPort;
+
+    #[tokio::test]
+    async fn test_get_relay_fingerprint() {
+        let mock = MockControlPort::start().await;
+        assert!(get_relay_fingerprint(&mock.rpc_config()).await.is_err());
+
+        mock.set_getinfo("fingerprint", "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333");
+        assert_eq!(
+            get_relay_fingerprint(&mock.rpc_config()).await.unwrap(),
+            "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333"
+        );
+    }
+}
@@ -1,15 +1,12 @@
-use super::rpc_client;
+use super::{contact_field, control_session, PREPAID_CONTACT_FIELD, PROTOCOL_VERSION_CONTACT_FIELD};
 use crate::types::{Relay, RpcConfig};
+use log::warn;
 use std::error::Error;
 
 pub async fn get_relay_descriptors(config: &RpcConfig) -> Result<Vec<Relay>, Box<dyn Error>> {
-    let rpc = rpc_client(RpcConfig {
-        addr: config.clone().addr,
-        rpc_password: config.clone().rpc_password,
-        command: "GETINFO desc/all-recent".into(),
-    })
-    .await
-    .unwrap();
+    let rpc = control_session(config)
+        .command("GETINFO desc/all-recent")
+        .await?;
 
     let mut relays = Vec::new();
     let mut current_relay: Option<Relay> = None;
@@ -40,9 +37,12 @@ pub async fn get_relay_descriptors(config: &RpcConfig) -> Result<Vec<Relay>, Box
                     payment_interval_seconds: None,
                     payment_interval_rounds: None,
                     payment_handshake_fee: None,
+                    payment_prepaid: false,
+                    payment_protocol_version: 1,
                     payment_id_hashes_10: None,
                     payment_handshake_fee_payhash: None,
                     payment_handshake_fee_preimage: None,
+                    payment_method: None,
                     relay_tag: None,
                     hop: None,
                 });
@@ -53,7 +53,15 @@ pub async fn get_relay_descriptors(config: &RpcConfig) -> Result<Vec<Relay>, Box
             }
         } else if line.starts_with("contact ") {
             if let Some(relay) = &mut current_relay {
-                relay.contact = Some(line["contact ".len()..].to_string());
+                let contact = &line["contact ".len()..];
+                relay.contact = Some(contact.to_string());
+                // eltord advertises settings Tor has no option for in ContactInfo
+                if let Some(prepaid) = contact_field(contact, PREPAID_CONTACT_FIELD) {
+                    relay.payment_prepaid = parse_flag(prepaid);
+                }
+                if let Some(Ok(version)) = contact_field(contact, PROTOCOL_VERSION_CONTACT_FIELD).map(str::parse::<u32>) {
+                    relay.payment_protocol_version = relay.payment_protocol_version.max(version);
+                }
             }
         } else if line.starts_with("bandwidth ") {
             if let Some(relay) = &mut current_relay {
@@ -91,11 +99,29 @@ pub async fn get_relay_descriptors(config: &RpcConfig) -> Result<Vec<Relay>, Box
                     relay.payment_interval_seconds = Some(rate);
                 }
             }
+        } else if line.starts_with("PaymentIntervalRounds ") {
+            if let Some(relay) = &mut current_relay {
+                if let Ok(rounds) = line["PaymentIntervalRounds ".len()..].parse::<u32>() {
+                    relay.payment_interval_rounds = Some(rounds);
+                }
+            }
         } else if line.starts_with("PaymentInvervalRounds ") {
-            // TODO Not being used, need to think more about this, hardcode to 10 now so we can pass in 10 payment id hashed during circuit build
+            // Misspelled keyword published by older relays
+            warn_deprecated_interval_rounds();
             if let Some(relay) = &mut current_relay {
-                if let Ok(rate) = line["PaymentInvervalRounds ".len()..].parse::<u32>() {
-                    relay.payment_interval_rounds = Some(rate);
+                if let Ok(rounds) = line["PaymentInvervalRounds ".len()..].parse::<u32>() {
+                    // The correctly spelled keyword wins if a relay publishes both
+                    relay.payment_interval_rounds.get_or_insert(rounds);
+                }
+            }
+        } else if line.starts_with("PaymentPrepaid ") {
+            if let Some(relay) = &mut current_relay {
+                relay.payment_prepaid = parse_flag(&line["PaymentPrepaid ".len()..]);
+            }
+        } else if let Some(version) = line.strip_prefix("PaymentProtocolVersion ") {
+            if let Some(relay) = &mut current_relay {
+                if let Ok(version) = version.trim().parse::<u32>() {
+                    relay.payment_protocol_version = version.max(1);
                 }
             }
         } else if line.starts_with("PaymentHandshakeFee ") {
@@ -114,3 +140,92 @@ pub async fn get_relay_descriptors(config: &RpcConfig) -> Result<Vec<Relay>, Box
 
     Ok(relays)
 }
+
+/// Logs (once per process) that `PaymentInvervalRounds` is deprecated
+pub fn warn_deprecated_interval_rounds() {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| {
+        warn!("PaymentInvervalRounds is deprecated (misspelled), use PaymentIntervalRounds instead");
+    });
+}
+
+/// Parses a boolean descriptor or torrc value: `1` or `true` is on
+pub fn parse_flag(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "1" | "true")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock_control_port::{MockControlPort, MockRelay};
+
+    #[tokio::test]
+    async fn test_parses_payment_interval_rounds() {
+        let mock = MockControlPort::start().await;
+        let mut current = MockRelay::new("current", 1);
+        current.payment_interval_seconds = Some(30);
+        current.payment_interval_rounds = Some(4);
+        let mut legacy = MockRelay::new("legacy", 2);
+        legacy.extra_descriptor_lines.push("PaymentInvervalRounds 6".to_string());
+        let mut both = MockRelay::new("both", 3);
+        both.payment_interval_rounds = Some(3);
+        both.extra_descriptor_lines.push("PaymentInvervalRounds 9".to_string());
+        mock.add_relay(current);
+        mock.add_relay(legacy);
+        mock.add_relay(both);
+
+        let relays = get_relay_descriptors(&mock.rpc_config()).await.unwrap();
+        let cadence = |nickname: &str| {
+            relays
+                .iter()
+                .find(|r| r.nickname == nickname)
+                .unwrap()
+                .payment_cadence()
+        };
+        assert_eq!(cadence("current").interval_seconds, 30);
+        assert_eq!(cadence("current").rounds, 4);
+        assert_eq!(cadence("legacy").rounds, 6);
+        assert_eq!(cadence("both").rounds, 3);
+        assert!(relays.iter().all(|r| !r.payment_prepaid));
+    }
+
+    #[tokio::test]
+    async fn test_parses_payment_prepaid() {
+        let mock = MockControlPort::start().await;
+        let mut prepaid = MockRelay::new("prepaid", 1);
+        prepaid.extra_descriptor_lines.push("PaymentPrepaid 1".to_string());
+        let mut off = MockRelay::new("off", 2);
+        off.extra_descriptor_lines.push("PaymentPrepaid 0".to_string());
+        // Advertised by eltord in ContactInfo, see advertise_in_contact_info
+        let mut contact = MockRelay::new("contact", 3);
+        contact.extra_descriptor_lines.push("contact ops@relay.example eltor_prepaid:1".to_string());
+        mock.add_relay(prepaid);
+        mock.add_relay(off);
+        mock.add_relay(contact);
+
+        let relays = get_relay_descriptors(&mock.rpc_config()).await.unwrap();
+        let prepaid = |nickname: &str| relays.iter().find(|r| r.nickname == nickname).unwrap().payment_prepaid;
+        assert!(prepaid("prepaid"));
+        assert!(!prepaid("off"));
+        assert!(prepaid("contact"));
+    }
+
+    #[tokio::test]
+    async fn test_parses_payment_protocol_version() {
+        let mock = MockControlPort::start().await;
+        let mut current = MockRelay::new("current", 1);
+        current.extra_descriptor_lines.push("PaymentProtocolVersion 2".to_string());
+        mock.add_relay(current);
+        mock.add_relay(MockRelay::new("legacy", 2));
+        let mut contact = MockRelay::new("contact", 3);
+        contact.extra_descriptor_lines.push("contact ops@relay.example eltor_protocol:2".to_string());
+        mock.add_relay(contact);
+
+        let relays = get_relay_descriptors(&mock.rpc_config()).await.unwrap();
+        let relay = |nickname: &str| relays.iter().find(|r| r.nickname == nickname).unwrap();
+        assert!(relay("current").takes_build_timestamp());
+        assert_eq!(relay("legacy").payment_protocol_version, 1);
+        assert!(!relay("legacy").takes_build_timestamp());
+        assert!(relay("contact").takes_build_timestamp());
+    }
+}
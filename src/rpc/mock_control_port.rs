@@ -0,0 +1,642 @@
+//! Scriptable in-process fake of the Tor control port for tests.
+//!
+//! It speaks enough of the control protocol for the client and relay flows:
+//! PROTOCOLINFO/AUTHENTICATE (NULL auth), `GETINFO ns/all`, `desc/all-recent`,
+//! `circuit-status`, `stream-status` and `status/bootstrap-phase`, GETCONF/SETCONF,
+//! SETEVENTS, `+EXTENDPAIDCIRCUIT`, ATTACHSTREAM, TEARDOWNCIRCUIT, SIGNAL and QUIT.
+//! Tests script it by adding relays and torrc values, emitting async events
+//! and inspecting the circuits, attached streams and commands it saw.
+
+use super::parse_key_values;
+use crate::types::RpcConfig;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Duration, Instant};
+
+/// A relay published in both the consensus and the descriptors
+#[derive(Debug, Clone)]
+pub(crate) struct MockRelay {
+    pub nickname: String,
+    /// 40 char uppercase hex fingerprint
+    pub fingerprint: String,
+    pub ip: String,
+    pub or_port: u16,
+    pub flags: Vec<String>,
+    pub payment_rate_msats: Option<u32>,
+    pub payment_interval_seconds: Option<u32>,
+    pub payment_interval_rounds: Option<u32>,
+    pub payment_handshake_fee: Option<u32>,
+    pub payment_bolt12_offer: Option<String>,
+    /// Raw lines appended to the descriptor
+    pub extra_descriptor_lines: Vec<String>,
+}
+
+impl MockRelay {
+    /// Guard + Exit capable relay with a fingerprint derived from `seed`
+    pub fn new(nickname: &str, seed: u8) -> Self {
+        MockRelay {
+            nickname: nickname.to_string(),
+            fingerprint: hex::encode_upper([seed; 20]),
+            ip: format!("127.0.0.{}", seed),
+            or_port: 5000 + seed as u16,
+            flags: ["Exit", "Fast", "Guard", "Running", "Stable", "Valid"]
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            payment_rate_msats: Some(100),
+            payment_interval_seconds: Some(60),
+            payment_interval_rounds: None,
+            payment_handshake_fee: None,
+            payment_bolt12_offer: Some(format!("lno1mock{}", seed)),
+            extra_descriptor_lines: Vec::new(),
+        }
+    }
+
+    fn consensus_entry(&self) -> String {
+        let identity = hex::decode(&self.fingerprint).unwrap_or_default();
+        format!(
+            "r {} {} {} 2038-01-01 00:00:00 {} {} 0\r\ns {}\r\nw Bandwidth=100\r\np accept 1-65535\r\n",
+            self.nickname,
+            base64::encode_config(&identity, base64::STANDARD_NO_PAD),
+            base64::encode_config([0u8; 20], base64::STANDARD_NO_PAD),
+            self.ip,
+            self.or_port,
+            self.flags.join(" ")
+        )
+    }
+
+    fn descriptor(&self) -> String {
+        let grouped: Vec<String> = self
+            .fingerprint
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c).to_string())
+            .collect();
+        let mut desc = format!(
+            "router {} {} {} 0 0\r\nfingerprint {}\r\nbandwidth 1000 2000 500\r\n",
+            self.nickname,
+            self.ip,
+            self.or_port,
+            grouped.join(" ")
+        );
+        if let Some(rate) = self.payment_rate_msats {
+            desc.push_str(&format!("PaymentRateMsats {}\r\n", rate));
+        }
+        if let Some(interval) = self.payment_interval_seconds {
+            desc.push_str(&format!("PaymentInterval {}\r\n", interval));
+        }
+        if let Some(rounds) = self.payment_interval_rounds {
+            desc.push_str(&format!("PaymentIntervalRounds {}\r\n", rounds));
+        }
+        if let Some(fee) = self.payment_handshake_fee {
+            desc.push_str(&format!("PaymentHandshakeFee {}\r\n", fee));
+        }
+        if let Some(offer) = &self.payment_bolt12_offer {
+            desc.push_str(&format!("PaymentBolt12Offer {}\r\n", offer));
+        }
+        for line in &self.extra_descriptor_lines {
+            desc.push_str(line);
+            desc.push_str("\r\n");
+        }
+        desc
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MockCircuit {
+    pub id: String,
+    pub state: String,
+    /// Relay fingerprints in hop order
+    pub path: Vec<String>,
+    /// Payment hash payload sent for each hop in EXTENDPAIDCIRCUIT
+    pub payment_hashes: Vec<String>,
+    status_polls: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MockStream {
+    pub id: String,
+    pub status: String,
+    pub circuit_id: String,
+    pub target: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    relays: Vec<MockRelay>,
+    conf: HashMap<String, Vec<String>>,
+    getinfo: HashMap<String, String>,
+    scripted: Vec<(String, String)>,
+    circuits: BTreeMap<u64, MockCircuit>,
+    next_circuit_id: u64,
+    build_after_polls: u32,
+    streams: Vec<MockStream>,
+    subscriptions: HashSet<String>,
+    payment_event_fingerprint: Option<String>,
+    commands: Vec<String>,
+}
+
+/// Handle to a running fake control port. Dropping it leaves the listener
+/// running until the test runtime shuts down.
+#[derive(Clone)]
+pub(crate) struct MockControlPort {
+    addr: String,
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<String>,
+}
+
+impl MockControlPort {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (events, _) = broadcast::channel(256);
+        let mock = MockControlPort {
+            addr,
+            state: Arc::new(Mutex::new(MockState {
+                next_circuit_id: 1,
+                ..Default::default()
+            })),
+            events,
+        };
+        let server = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+        mock
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn rpc_config(&self) -> RpcConfig {
+        RpcConfig {
+            addr: self.addr.clone(),
+            rpc_password: None,
+            command: "".to_string(),
+        }
+    }
+
+    pub fn add_relay(&self, relay: MockRelay) {
+        self.state.lock().unwrap().relays.push(relay);
+    }
+
+    /// Sets a torrc value returned by GETCONF (call repeatedly for multi-value keys)
+    pub fn set_conf(&self, key: &str, value: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .conf
+            .entry(key.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    pub fn set_getinfo(&self, key: &str, value: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .getinfo
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Replies with `raw_reply` (CRLF terminated) to any command starting with `prefix`
+    pub fn respond_to(&self, prefix: &str, raw_reply: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .push((prefix.to_string(), raw_reply.to_string()));
+    }
+
+    /// New circuits report LAUNCHED for this many `circuit-status` polls before BUILT
+    pub fn set_build_after_polls(&self, polls: u32) {
+        self.state.lock().unwrap().build_after_polls = polls;
+    }
+
+    /// Adds an already built circuit, e.g. one the relay side is watching
+    pub fn add_circuit(&self, id: &str, state: &str) {
+        let mut mock_state = self.state.lock().unwrap();
+        let key = id.parse().unwrap_or(u64::MAX);
+        if key != u64::MAX && key >= mock_state.next_circuit_id {
+            mock_state.next_circuit_id = key + 1;
+        }
+        mock_state.circuits.insert(
+            key,
+            MockCircuit {
+                id: id.to_string(),
+                state: state.to_string(),
+                path: vec![],
+                payment_hashes: vec![],
+                status_polls: 0,
+            },
+        );
+    }
+
+    pub fn set_circuit_state(&self, id: &str, state: &str) {
+        if let Some(circuit) = self
+            .state
+            .lock()
+            .unwrap()
+            .circuits
+            .values_mut()
+            .find(|c| c.id == id)
+        {
+            circuit.state = state.to_string();
+        }
+    }
+
+    /// Acts as the relay `fingerprint`: every EXTENDPAIDCIRCUIT through it emits
+    /// `650 EVENT_PAYMENT_ID_HASH_RECEIVED` with that hop's payment hashes
+    pub fn emit_payment_events_for(&self, fingerprint: &str) {
+        self.state.lock().unwrap().payment_event_fingerprint = Some(fingerprint.to_string());
+    }
+
+    /// Sends a raw async event line (without CRLF) to every subscribed connection
+    pub fn emit(&self, event: &str) {
+        let _ = self.events.send(event.to_string());
+    }
+
+    pub fn emit_stream_new(&self, stream_id: &str, target: &str) {
+        self.state.lock().unwrap().streams.push(MockStream {
+            id: stream_id.to_string(),
+            status: "NEW".to_string(),
+            circuit_id: "0".to_string(),
+            target: target.to_string(),
+        });
+        self.emit(&format!("650 STREAM {} NEW 0 {}", stream_id, target));
+    }
+
+    pub fn emit_payment_id_hash_received(&self, circuit_id: &str, payment_hash: &str) {
+        self.emit(&payment_event(circuit_id, payment_hash));
+    }
+
+    pub fn circuits(&self) -> Vec<MockCircuit> {
+        self.state.lock().unwrap().circuits.values().cloned().collect()
+    }
+
+    pub fn streams(&self) -> Vec<MockStream> {
+        self.state.lock().unwrap().streams.clone()
+    }
+
+    pub fn conf(&self, key: &str) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .conf
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Every command received so far (multi-line commands are joined with `\n`)
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Waits until some connection has subscribed to `event` via SETEVENTS
+    pub async fn wait_for_subscription(&self, event: &str, timeout: Duration) -> bool {
+        self.wait_until(timeout, |state| state.subscriptions.contains(event))
+            .await
+    }
+
+    /// Waits until a command starting with `prefix` has been received
+    pub async fn wait_for_command(&self, prefix: &str, timeout: Duration) -> bool {
+        self.wait_until(timeout, |state| {
+            state.commands.iter().any(|c| c.starts_with(prefix))
+        })
+        .await
+    }
+
+    async fn wait_until<F: Fn(&MockState) -> bool>(&self, timeout: Duration, done: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if done(&self.state.lock().unwrap()) {
+                return true;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let (reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let subscribed: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        // Forward async events this connection asked for via SETEVENTS
+        let mut events = self.events.subscribe();
+        let event_writer = writer.clone();
+        let event_filter = subscribed.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                let wanted = event_filter.lock().unwrap().contains(event_type(&event));
+                if wanted && write_line(&event_writer, &event).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let mut command = line;
+            // Multi-line commands ("+EXTENDPAIDCIRCUIT ...") end with a lone "."
+            if command.starts_with('+') {
+                while let Ok(Some(next)) = lines.next_line().await {
+                    let done = next == ".";
+                    command.push('\n');
+                    command.push_str(&next);
+                    if done {
+                        break;
+                    }
+                }
+            }
+
+            let (reply, follow_up_events) = self.handle(&command, &subscribed);
+            if write_line(&writer, reply.trim_end()).await.is_err() {
+                break;
+            }
+            for event in follow_up_events {
+                self.emit(&event);
+            }
+            if command == "QUIT" {
+                break;
+            }
+        }
+        forwarder.abort();
+    }
+
+    // Returns the reply plus events to emit once the reply has been written
+    fn handle(&self, command: &str, subscribed: &Mutex<HashSet<String>>) -> (String, Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.commands.push(command.to_string());
+
+        if let Some((_, reply)) = state.scripted.iter().find(|(p, _)| command.starts_with(p.as_str())) {
+            return (reply.clone(), vec![]);
+        }
+
+        let (verb, args) = command.split_once(' ').unwrap_or((command, ""));
+        let reply = match verb {
+            "PROTOCOLINFO" => {
+                "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250-VERSION Tor=\"0.4.8.10\"\r\n250 OK\r\n".to_string()
+            }
+            "AUTHENTICATE" | "SIGNAL" => ok(),
+            "QUIT" => "250 closing connection\r\n".to_string(),
+            "GETINFO" => state.getinfo_reply(args),
+            "GETCONF" => state.getconf_reply(args),
+            "SETCONF" => {
+                for (key, value) in parse_key_values(args) {
+                    state.conf.insert(key, vec![value]);
+                }
+                ok()
+            }
+            "SETEVENTS" => {
+                let events: HashSet<String> = args.split_whitespace().map(|e| e.to_string()).collect();
+                state.subscriptions.extend(events.iter().cloned());
+                *subscribed.lock().unwrap() = events;
+                ok()
+            }
+            "+EXTENDPAIDCIRCUIT" => return state.extend_paid_circuit(command),
+            "ATTACHSTREAM" => state.attach_stream(args),
+            "TEARDOWNCIRCUIT" => {
+                let before = state.circuits.len();
+                state.circuits.retain(|_, c| c.id != args.trim());
+                if state.circuits.len() < before {
+                    ok()
+                } else {
+                    format!("552 Unknown circuit \"{}\"\r\n", args.trim())
+                }
+            }
+            _ => format!("510 Unrecognized command \"{}\"\r\n", verb),
+        };
+        (reply, vec![])
+    }
+}
+
+impl MockState {
+    fn getinfo_reply(&mut self, args: &str) -> String {
+        let mut reply = String::new();
+        for key in args.split_whitespace() {
+            let value = match key {
+                "ns/all" => self.relays.iter().map(|r| r.consensus_entry()).collect(),
+                "desc/all-recent" => self.relays.iter().map(|r| r.descriptor()).collect(),
+                "circuit-status" => self.circuit_status(),
+                "stream-status" => self
+                    .streams
+                    .iter()
+                    .map(|s| format!("{} {} {} {}\r\n", s.id, s.status, s.circuit_id, s.target))
+                    .collect(),
+                "status/bootstrap-phase" => self.getinfo.get(key).cloned().unwrap_or_else(|| {
+                    "NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"".to_string()
+                }),
+                _ => match self.getinfo.get(key) {
+                    Some(value) => value.clone(),
+                    None => return format!("552 Unrecognized key \"{}\"\r\n", key),
+                },
+            };
+            if value.contains('\n') {
+                reply.push_str(&format!("250+{}=\r\n{}.\r\n", key, value));
+            } else {
+                reply.push_str(&format!("250-{}={}\r\n", key, value));
+            }
+        }
+        reply.push_str("250 OK\r\n");
+        reply
+    }
+
+    fn getconf_reply(&self, args: &str) -> String {
+        let mut lines = Vec::new();
+        for key in args.split_whitespace() {
+            match self.conf.get(key) {
+                Some(values) if !values.is_empty() => {
+                    lines.extend(values.iter().map(|v| format!("{}={}", key, v)))
+                }
+                _ => lines.push(key.to_string()),
+            }
+        }
+        let last = lines.len().saturating_sub(1);
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("250{}{}\r\n", if i == last { ' ' } else { '-' }, line))
+            .collect()
+    }
+
+    // Advances LAUNCHED circuits towards BUILT each time the status is polled
+    fn circuit_status(&mut self) -> String {
+        let build_after = self.build_after_polls;
+        let relays = self.relays.clone();
+        let mut status = String::new();
+        for circuit in self.circuits.values_mut() {
+            if circuit.state == "LAUNCHED" {
+                circuit.status_polls += 1;
+                if circuit.status_polls > build_after {
+                    circuit.state = "BUILT".to_string();
+                }
+            }
+            let path: Vec<String> = circuit
+                .path
+                .iter()
+                .map(|fp| {
+                    let nickname = relays
+                        .iter()
+                        .find(|r| &r.fingerprint == fp)
+                        .map(|r| r.nickname.as_str())
+                        .unwrap_or("unknown");
+                    format!("${}~{}", fp, nickname)
+                })
+                .collect();
+            status.push_str(&format!(
+                "{} {} {} PURPOSE=GENERAL\r\n",
+                circuit.id,
+                circuit.state,
+                path.join(",")
+            ));
+        }
+        status
+    }
+
+    fn extend_paid_circuit(&mut self, command: &str) -> (String, Vec<String>) {
+        let mut path = Vec::new();
+        let mut payment_hashes = Vec::new();
+        for line in command.lines().skip(1).take_while(|l| *l != ".") {
+            let (fingerprint, hashes) = line.split_once(' ').unwrap_or((line, ""));
+            let fingerprint = fingerprint.trim_start_matches('$').to_uppercase();
+            if !self.relays.iter().any(|r| r.fingerprint == fingerprint) {
+                return (format!("552 No such router \"{}\"\r\n", fingerprint), vec![]);
+            }
+            path.push(fingerprint);
+            payment_hashes.push(hashes.to_string());
+        }
+        if path.is_empty() {
+            return ("512 No relays given\r\n".to_string(), vec![]);
+        }
+
+        let id = self.next_circuit_id;
+        self.next_circuit_id += 1;
+        let mut events = Vec::new();
+        if let Some(fp) = &self.payment_event_fingerprint {
+            if let Some(hop) = path.iter().position(|p| p == fp) {
+                events.push(payment_event(&id.to_string(), &payment_hashes[hop]));
+            }
+        }
+        let state = if self.build_after_polls == 0 { "BUILT" } else { "LAUNCHED" };
+        self.circuits.insert(
+            id,
+            MockCircuit {
+                id: id.to_string(),
+                state: state.to_string(),
+                path,
+                payment_hashes,
+                status_polls: 0,
+            },
+        );
+        (format!("250 EXTENDED {}\r\n", id), events)
+    }
+
+    fn attach_stream(&mut self, args: &str) -> String {
+        let mut parts = args.split_whitespace();
+        let (stream_id, circuit_id) = match (parts.next(), parts.next()) {
+            (Some(s), Some(c)) => (s.to_string(), c.to_string()),
+            _ => return "512 Missing argument to ATTACHSTREAM\r\n".to_string(),
+        };
+        let built = self
+            .circuits
+            .values()
+            .any(|c| c.id == circuit_id && c.state == "BUILT");
+        if !built {
+            return format!("552 Unknown circuit \"{}\"\r\n", circuit_id);
+        }
+        match self.streams.iter_mut().find(|s| s.id == stream_id) {
+            Some(stream) => {
+                stream.status = "SUCCEEDED".to_string();
+                stream.circuit_id = circuit_id;
+                ok()
+            }
+            None => format!("552 Unknown stream \"{}\"\r\n", stream_id),
+        }
+    }
+}
+
+fn ok() -> String {
+    "250 OK\r\n".to_string()
+}
+
+fn payment_event(circuit_id: &str, payment_hash: &str) -> String {
+    format!(
+        "650 EVENT_PAYMENT_ID_HASH_RECEIVED P_CIRC_ID={} N_CIRC_ID=0 PAYMENT_HASH={}",
+        circuit_id, payment_hash
+    )
+}
+
+// "650 EVENT_PAYMENT_ID_HASH_RECEIVED ..." is delivered for SETEVENTS PAYMENT_ID_HASH_RECEIVED
+fn event_type(event: &str) -> &str {
+    let name = event.split_whitespace().nth(1).unwrap_or("");
+    name.strip_prefix("EVENT_").unwrap_or(name)
+}
+
+async fn write_line(
+    writer: &tokio::sync::Mutex<WriteHalf<TcpStream>>,
+    line: &str,
+) -> std::io::Result<()> {
+    let mut writer = writer.lock().await;
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::control_session;
+
+    #[tokio::test]
+    async fn test_mock_answers_getinfo_and_getconf() {
+        let mock = MockControlPort::start().await;
+        mock.add_relay(MockRelay::new("relay1", 1));
+        mock.set_conf("PaymentCircuitMaxFee", "5000");
+        let session = control_session(&mock.rpc_config());
+
+        let reply = session.request("GETINFO ns/all").await.unwrap();
+        assert!(reply.value("ns/all").unwrap().starts_with("r relay1 "));
+        let reply = session.request("GETCONF PaymentCircuitMaxFee").await.unwrap();
+        assert_eq!(reply.value("PaymentCircuitMaxFee").as_deref(), Some("5000"));
+        let reply = session.request("GETCONF ExitNodes").await.unwrap();
+        assert_eq!(reply.entries(), vec![("ExitNodes".to_string(), None)]);
+        assert!(session.request("GETINFO bogus").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_emits_payment_event_for_extended_relay() {
+        let mock = MockControlPort::start().await;
+        let relay = MockRelay::new("relay1", 1);
+        mock.add_relay(relay.clone());
+        mock.emit_payment_events_for(&relay.fingerprint);
+        mock.set_getinfo("version", "0.4.8.10");
+        mock.respond_to("SIGNAL NEWNYM", "552 Unrecognized signal\r\n");
+        let session = control_session(&mock.rpc_config());
+
+        let mut events = session.subscribe(&["PAYMENT_ID_HASH_RECEIVED"]).await.unwrap();
+        let reply = session
+            .request(&format!("+EXTENDPAIDCIRCUIT 0\n{} abcd\n.", relay.fingerprint))
+            .await
+            .unwrap();
+        assert_eq!(reply.message(), "EXTENDED 1");
+        assert_eq!(
+            events.recv().await.unwrap(),
+            "650 EVENT_PAYMENT_ID_HASH_RECEIVED P_CIRC_ID=1 N_CIRC_ID=0 PAYMENT_HASH=abcd"
+        );
+
+        mock.set_circuit_state("1", "FAILED");
+        let reply = session.request("GETINFO circuit-status").await.unwrap();
+        assert!(reply.value("circuit-status").unwrap().starts_with("1 FAILED $"));
+        let reply = session.request("GETINFO version").await.unwrap();
+        assert_eq!(reply.value("version").as_deref(), Some("0.4.8.10"));
+        assert!(session.request("SIGNAL NEWNYM").await.is_err());
+        assert!(mock.addr().starts_with("127.0.0.1:"));
+    }
+}
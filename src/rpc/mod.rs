@@ -1,7 +1,14 @@
 mod attach_stream;
+mod auth;
+mod control_reply;
+mod control_session;
+mod control_stream;
 mod extend_paid_circuit;
 mod get_current_consensus;
+mod get_fingerprint;
 mod get_relay_descriptors;
+#[cfg(test)]
+pub(crate) mod mock_control_port;
 mod rpc_client;
 mod teardown_circuit;
 mod torrc;
@@ -9,8 +16,13 @@ mod wait_for_bootstrap;
 mod wait_for_circuit;
 
 pub use attach_stream::*;
+pub use auth::*;
+pub use control_reply::*;
+pub use control_session::*;
+pub use control_stream::*;
 pub use extend_paid_circuit::*;
 pub use get_current_consensus::*;
+pub use get_fingerprint::*;
 pub use get_relay_descriptors::*;
 pub use rpc_client::*;
 pub use teardown_circuit::*;
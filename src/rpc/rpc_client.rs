@@ -1,9 +1,9 @@
+use super::{authenticate, connect_control_port};
 use crate::types::{EventCallback, RpcConfig};
 use lni::LightningNode;
 use log::{debug, error, info, warn};
 use std::error::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
 
 // TOR RPC Commands
 // https://spec.torproject.org/control-spec/commands.html?highlight=Setevent#extended_events
@@ -14,21 +14,13 @@ unsafe impl Send for RpcConfig {}
 // Returns an RPC client response
 pub async fn rpc_client(config: RpcConfig) -> Result<String, Box<dyn Error>> {
     // info!("Connecting to Tor control port at {}...", config.addr);
-    let stream = TcpStream::connect(config.addr).await?;
+    let stream = connect_control_port(&config.addr).await?;
     let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
 
-    let pw = config.rpc_password.clone().filter(|p| !p.is_empty());
+    authenticate(&mut reader, &mut writer, config.rpc_password.as_deref()).await?;
 
-    let content = if pw.is_some() {
-        format!(
-            "AUTHENTICATE \"{}\"\r\n{}\r\nQUIT\r\n",
-            pw.unwrap(),
-            config.command
-        )
-    } else {
-        format!("AUTHENTICATE\r\n{}\r\nQUIT\r\n", config.command)
-    };
+    let content = format!("{}\r\nQUIT\r\n", config.command);
     writer.write_all(content.as_bytes()).await?;
     writer.flush().await?;
 
@@ -56,22 +48,13 @@ pub async fn rpc_event_listener(
         "Connecting to Tor control port for event listening at {}...",
         config.addr
     );
-    let stream = TcpStream::connect(config.addr.clone()).await?;
+    let stream = connect_control_port(&config.addr).await?;
     let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
 
-    let pw = config.rpc_password.clone().filter(|p| !p.is_empty());
-
     // Authenticate and subscribe to events (e.g., CIRC, NOTICE, etc.)
-    let content = if pw.is_some() {
-        format!(
-            "AUTHENTICATE \"{}\"\r\nSETEVENTS {}\r\n",
-            pw.unwrap(),
-            event
-        )
-    } else {
-        format!("AUTHENTICATE\r\nSETEVENTS {}\r\n", event)
-    };
+    authenticate(&mut reader, &mut writer, config.rpc_password.as_deref()).await?;
+    let content = format!("SETEVENTS {}\r\n", event);
     writer.write_all(content.as_bytes()).await?;
     writer.flush().await?;
 
@@ -1,47 +1,45 @@
-use super::rpc_client;
+use super::{control_session, ControlError};
 use crate::types::RpcConfig;
 use log::{info, warn};
 use std::error::Error;
 
 /// Teardown a circuit by sending TEARDOWNCIRCUIT command to Tor
-/// Returns true if the teardown was successful (250 OK response), false otherwise
+/// Returns true if the teardown was successful (250 OK response), false if Tor rejected it
+/// (e.g. unknown circuit). Connection failures are returned as errors.
 pub async fn teardown_circuit(
     config: &RpcConfig,
     circuit_id: &str,
 ) -> Result<bool, Box<dyn Error>> {
     info!("Initiating teardown for circuit {}", circuit_id);
 
-    let rpc = rpc_client(RpcConfig {
-        addr: config.addr.clone(),
-        rpc_password: config.rpc_password.clone(),
-        command: format!("TEARDOWNCIRCUIT {}", circuit_id),
-    })
-    .await;
+    let result = control_session(config)
+        .request(&format!("TEARDOWNCIRCUIT {}", circuit_id))
+        .await;
+    Ok(teardown_outcome(circuit_id, result)?)
+}
 
-    match rpc {
-        Ok(response) => {
+fn teardown_outcome(
+    circuit_id: &str,
+    result: Result<super::ControlReply, ControlError>,
+) -> Result<bool, ControlError> {
+    match result {
+        Ok(reply) => {
             info!(
                 "Teardown response for circuit {}: {}",
                 circuit_id,
-                response.trim()
+                reply.message()
             );
-
-            // Check if response contains "250 OK" which indicates success
-            if response.contains("250 OK") {
-                info!("✅ Successfully tore down circuit {}", circuit_id);
-                Ok(true)
-            } else {
-                warn!(
-                    "❌ Failed to teardown circuit {}: unexpected response",
-                    circuit_id
-                );
-                Ok(false)
-            }
+            info!("✅ Successfully tore down circuit {}", circuit_id);
+            Ok(true)
         }
-        Err(e) => {
+        Err(e @ (ControlError::Io { .. } | ControlError::Malformed { .. })) => {
             warn!("❌ Error tearing down circuit {}: {}", circuit_id, e);
             Err(e)
         }
+        Err(e) => {
+            warn!("❌ Failed to teardown circuit {}: {}", circuit_id, e);
+            Ok(false)
+        }
     }
 }
 
@@ -70,4 +68,18 @@ mod tests {
         let failure_response = "551 Circuit not found";
         assert!(!failure_response.contains("250 OK"));
     }
+
+    #[test]
+    fn test_teardown_outcome() {
+        let ok = super::super::ControlReply::parse("250 OK\r\n").unwrap();
+        assert!(teardown_outcome("1", Ok(ok)).unwrap());
+
+        let unknown = ControlError::from_status(552, "Unknown circuit \"1\"");
+        assert!(!teardown_outcome("1", Err(unknown)).unwrap());
+
+        let io = ControlError::Io {
+            reason: "connection reset".to_string(),
+        };
+        assert!(teardown_outcome("1", Err(io)).is_err());
+    }
 }
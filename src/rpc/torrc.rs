@@ -1,8 +1,8 @@
-use log::info;
+use log::{debug, info};
 
-use super::rpc_client;
-use crate::types::RpcConfig;
-use std::{error::Error, io::BufRead};
+use super::{control_session, quote_string, ControlError};
+use crate::types::{PaymentCadence, RpcConfig};
+use std::{error::Error, io::BufRead, path::PathBuf};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KV {
@@ -25,50 +25,24 @@ pub async fn get_torrc_value(config: &RpcConfig, keywords: &[String]) -> Vec<Tor
         return results;
     }
     for key in keywords {
-        match get_conf(config, key.clone()).await {
-            Ok(resp) => {
-                let resp = resp.trim();
-                info!("resp: {:?}", resp);
-                if resp.is_empty() {
-                    continue;
-                }
-                for line in resp.lines() {
-                    let line = line.trim_start_matches("250 ").trim();
-                    if line.is_empty() || line == "OK" || line == "closing connection" {
+        match get_conf_entries(config, key).await {
+            Ok(entries) => {
+                for (k, v) in entries {
+                    if !k.eq_ignore_ascii_case(key) {
                         continue;
                     }
-                    if let Some(idx) = line.find('=') {
-                        let (k, v) = line.split_at(idx);
-                        let v = &v[1..];
-                        if k.trim() == key {
-                            let data = parse_kv_data(v.trim());
-                            results.push(TorrcEntry {
-                                key: k.trim().to_string(),
-                                value: v.trim().to_string(),
-                                data,
-                            });
-                        }
-                    } else if let Some(idx) = line.find(' ') {
-                        let (k, v) = line.split_at(idx);
-                        let v = v.trim();
-                        if k.trim() == key {
-                            let data = parse_kv_data(v);
-                            results.push(TorrcEntry {
-                                key: k.trim().to_string(),
-                                value: v.to_string(),
-                                data,
-                            });
-                        }
-                    } else if line == key {
-                        results.push(TorrcEntry {
-                            key: key.clone(),
-                            value: String::new(),
-                            data: vec![],
-                        });
-                    }
+                    // Unset keys come back without a value
+                    let value = v.unwrap_or_default().trim().to_string();
+                    let data = parse_kv_data(&value);
+                    results.push(TorrcEntry {
+                        key: key.clone(),
+                        value,
+                        data,
+                    });
                 }
             }
-            Err(_) => {
+            Err(e) => {
+                debug!("GETCONF {} failed: {}", key, e);
                 continue;
             }
         }
@@ -146,39 +120,123 @@ fn parse_kv_data(val: &str) -> Vec<KV> {
     data
 }
 
-pub async fn get_conf(config: &RpcConfig, setting: String) -> Result<String, Box<dyn Error>> {
-    info!("get_conf: {:?}", config);
-    let rpc = rpc_client(RpcConfig {
-        addr: config.clone().addr,
-        rpc_password: config.clone().rpc_password,
-        command: format!("GETCONF {}", setting).into(),
-    })
-    .await?;
+/// Runs `GETCONF` over the shared control session and returns its `(key, value)` entries.
+/// Unset keys come back with a `None` value; unknown keys return `ControlError::Unrecognized`.
+pub async fn get_conf_entries(
+    config: &RpcConfig,
+    setting: &str,
+) -> Result<Vec<(String, Option<String>)>, ControlError> {
+    let reply = control_session(config)
+        .request(&format!("GETCONF {}", setting))
+        .await?;
+    Ok(reply.entries())
+}
 
-    if rpc.starts_with("250 ") {
-        let resp = rpc.trim_start_matches("250 ");
-        Ok(resp.to_string())
-    } else {
-        Ok("".to_string())
-    }
+/// Returns the `GETCONF` result as one `Key=value` (or bare `Key` if unset) per CRLF separated line.
+pub async fn get_conf(config: &RpcConfig, setting: String) -> Result<String, Box<dyn Error>> {
+    let entries = get_conf_entries(config, &setting).await?;
+    let lines: Vec<String> = entries
+        .into_iter()
+        .map(|(k, v)| match v {
+            Some(v) => format!("{}={}", k, v),
+            None => k,
+        })
+        .collect();
+    Ok(lines.join("\r\n"))
 }
 
 pub async fn get_conf_payment_circuit_max_fee(config: &RpcConfig) -> Result<u64, Box<dyn Error>> {
-    let conf = get_conf(&config, "PaymentCircuitMaxFee".to_string())
-        .await
-        .unwrap();
-    if conf.is_empty() {
-        return Ok(12000);
+    let entries = get_conf_entries(config, "PaymentCircuitMaxFee").await?;
+    Ok(entries
+        .into_iter()
+        .find_map(|(_, v)| v.and_then(|v| v.trim().parse::<u64>().ok()))
+        .unwrap_or(12000))
+}
+
+/// ContactInfo field of relays accepting prepaid rounds (`PAYMENT_PREPAID`), read like `PaymentPrepaid 1`
+pub const PREPAID_CONTACT_FIELD: &str = "eltor_prepaid";
+/// ContactInfo field carrying the relay's payment protocol version, read like `PaymentProtocolVersion`
+pub const PROTOCOL_VERSION_CONTACT_FIELD: &str = "eltor_protocol";
+
+/// The value of a `key:value` field in a descriptor's `contact` line
+pub fn contact_field<'a>(contact: &'a str, key: &str) -> Option<&'a str> {
+    contact
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix(key)?.strip_prefix(':'))
+}
+
+/// Sets a `key:value` field in this relay's ContactInfo with SETCONF, keeping what the
+/// operator set. Tor has no option for eltord's own relay settings but it publishes
+/// ContactInfo in the descriptor. Tor republishes it, the torrc file isn't touched.
+pub async fn advertise_in_contact_info(config: &RpcConfig, key: &str, value: &str) -> Result<(), ControlError> {
+    let contact = get_conf_entries(config, "ContactInfo")
+        .await?
+        .into_iter()
+        .find_map(|(_, v)| v)
+        .unwrap_or_default();
+    if contact_field(&contact, key) == Some(value) {
+        return Ok(());
     }
-    let parts: Vec<&str> = conf.split('=').collect();
-    // println!("Debug: conf = {}", conf);
-    // println!("Debug: parts = {:?}", parts);
-    if parts.len() == 2 {
-        if let Ok(value) = parts[1].trim().parse::<u64>() {
-            return Ok(value);
+    let mut fields: Vec<&str> = contact
+        .split_whitespace()
+        .filter(|field| field.strip_prefix(key).and_then(|rest| rest.strip_prefix(':')).is_none())
+        .collect();
+    let field = format!("{}:{}", key, value);
+    fields.push(&field);
+    let contact = fields.join(" ");
+    control_session(config)
+        .request(&format!("SETCONF ContactInfo={}", quote_string(&contact)))
+        .await?;
+    info!("Advertising {} in ContactInfo: {}", field, contact);
+    Ok(())
+}
+
+/// Reads this relay's own `PaymentInterval` and `PaymentIntervalRounds`. The misspelled
+/// `PaymentInvervalRounds` is still honored (with a deprecation warning) when the
+/// correct keyword isn't set.
+pub async fn get_conf_payment_cadence(config: &RpcConfig) -> PaymentCadence {
+    let first_u32 = |entries: Vec<TorrcEntry>| {
+        entries
+            .into_iter()
+            .find_map(|e| e.value.trim().parse::<u32>().ok())
+    };
+    let interval = first_u32(get_torrc_value(config, &["PaymentInterval".to_string()]).await);
+    let mut rounds = first_u32(get_torrc_value(config, &["PaymentIntervalRounds".to_string()]).await);
+    if rounds.is_none() {
+        rounds = first_u32(get_torrc_value(config, &["PaymentInvervalRounds".to_string()]).await);
+        if rounds.is_some() {
+            super::warn_deprecated_interval_rounds();
         }
     }
-    Ok(12000)
+    PaymentCadence::new(interval, rounds)
+}
+
+/// Reads this relay's own `PaymentHandshakeFee` in msats. `None` (or 0) means clients
+/// can extend circuits through the relay without paying a handshake fee.
+pub async fn get_conf_payment_handshake_fee(config: &RpcConfig) -> Option<u32> {
+    get_torrc_value(config, &["PaymentHandshakeFee".to_string()])
+        .await
+        .into_iter()
+        .find_map(|e| e.value.trim().parse::<u32>().ok())
+        .filter(|fee| *fee > 0)
+}
+
+/// Reads this relay's own `PaymentRateMsats` (default 1000)
+pub async fn get_conf_payment_rate_msats(config: &RpcConfig) -> u32 {
+    get_torrc_value(config, &["PaymentRateMsats".to_string()])
+        .await
+        .into_iter()
+        .find_map(|e| e.value.trim().parse::<u32>().ok())
+        .unwrap_or(1000)
+}
+
+/// Reads this relay's own `PaymentBolt12Offer`, `None` when unset (free mode)
+pub async fn get_conf_payment_bolt12_offer(config: &RpcConfig) -> Option<String> {
+    get_torrc_value(config, &["PaymentBolt12Offer".to_string()])
+        .await
+        .into_iter()
+        .map(|e| e.value)
+        .find(|offer| !offer.is_empty())
 }
 
 /// Gets the ExitNodes setting from torrc and parses the values into a Vec<String>.
@@ -222,25 +280,7 @@ pub async fn get_rpc_config_from_torrc(
     let mut rpc_config: Option<RpcConfig> = None;
 
     if let Ok(entries) = parse_raw_torrc_file(torrc_path).await {
-        // Look for an entry with key "RpcConfig"
-        for entry in &entries {
-            if entry.key == "ControlPort" || entry.key == "Address" {
-                // We'll collect these values to build addr later
-            }
-        }
-        // After collecting all entries, search for Address and ControlPort
-        let mut address = "127.0.0.1".to_string();
-        let mut port = "9999".to_string();
-        for entry in &entries {
-            // TODO - probably remove this becuase a Relay might use a public address and we dont want to use a public IP for the control port
-            // if entry.key == "Address" && !entry.value.is_empty() {
-            //     address = entry.value.clone();
-            // }
-            if entry.key == "ControlPort" && !entry.value.is_empty() {
-                port = entry.value.clone();
-            }
-        }
-        let addr = format!("{}:{}", address, port);
+        let addr = control_addr_from_torrc_entries(&entries);
         rpc_config = Some(RpcConfig {
             addr,
             rpc_password: rpc_password.clone(),
@@ -250,6 +290,73 @@ pub async fn get_rpc_config_from_torrc(
     return rpc_config;
 }
 
+/// Picks the control port address from `ControlPort` / `ControlSocket` entries.
+/// A unix socket wins over TCP so the control port never has to be exposed
+/// over TCP on shared hosts. Falls back to 127.0.0.1:9999.
+pub fn control_addr_from_torrc_entries(entries: &[TorrcEntry]) -> String {
+    let mut unix_addr: Option<String> = None;
+    // TODO - we never use the Address entry becuase a Relay might use a public address and we dont want to use a public IP for the control port
+    let mut tcp_addr = "127.0.0.1:9999".to_string();
+    for entry in entries {
+        let value = entry.value.trim();
+        let unix_path = match entry.key.as_str() {
+            "ControlSocket" => Some(value),
+            "ControlPort" => value.strip_prefix("unix:"),
+            _ => continue,
+        };
+        if let Some(path) = unix_path {
+            // e.g. `ControlPort unix:"/run/tor/control" GroupWritable`
+            let path = first_torrc_token(path);
+            if !path.is_empty() && path != "0" {
+                unix_addr.get_or_insert(format!("unix:{}", path));
+            }
+            continue;
+        }
+        let port = first_torrc_token(value);
+        if port.is_empty() || port == "0" || port.eq_ignore_ascii_case("auto") {
+            continue;
+        }
+        if port.contains(':') {
+            tcp_addr = port;
+        } else {
+            tcp_addr = format!("127.0.0.1:{}", port);
+        }
+    }
+    unix_addr.unwrap_or(tcp_addr)
+}
+
+/// Picks the eltord data directory from the torrc: an `eltor` folder inside Tor's
+/// `DataDirectory`. The last entry wins, like Tor does for single valued options.
+pub fn data_dir_from_torrc_entries(entries: &[TorrcEntry]) -> Option<PathBuf> {
+    entries
+        .iter()
+        .rev()
+        .find(|e| e.key.eq_ignore_ascii_case("DataDirectory"))
+        .map(|e| torrc_path_value(&e.value))
+        .filter(|p| !p.is_empty())
+        .map(|dir| PathBuf::from(dir).join("eltor"))
+}
+
+// A path valued torrc option: either "quoted" or the whole value (paths may contain spaces)
+fn torrc_path_value(value: &str) -> String {
+    let value = value.trim();
+    if value.starts_with('"') {
+        return first_torrc_token(value);
+    }
+    value.to_string()
+}
+
+// First whitespace separated token of a torrc value, honoring "quoted paths"
+fn first_torrc_token(value: &str) -> String {
+    let value = value.trim();
+    if value.starts_with('"') {
+        if let Ok((token, _)) = super::parse_quoted_string(value) {
+            return token;
+        }
+    }
+    value.split_whitespace().next().unwrap_or("").to_string()
+}
+
 pub async fn parse_raw_torrc_file(torrc_path: &str) -> Result<Vec<TorrcEntry>, Box<dyn Error>> {
     let mut torrc = String::new();
     let file = std::fs::File::open(torrc_path)?;
@@ -587,4 +694,99 @@ PaymentLightningNodeConfig type=lnd url=http://lnd.com macaroon=mac1234
             "pass1234"
         );
     }
+
+    fn entry(key: &str, value: &str) -> TorrcEntry {
+        TorrcEntry {
+            key: key.to_string(),
+            value: value.to_string(),
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn test_control_addr_from_torrc_entries() {
+        assert_eq!(control_addr_from_torrc_entries(&[]), "127.0.0.1:9999");
+        assert_eq!(
+            control_addr_from_torrc_entries(&[entry("ControlPort", "9051")]),
+            "127.0.0.1:9051"
+        );
+        assert_eq!(
+            control_addr_from_torrc_entries(&[entry("ControlPort", "127.0.0.1:9052 IsolateDestAddr")]),
+            "127.0.0.1:9052"
+        );
+        assert_eq!(
+            control_addr_from_torrc_entries(&[
+                entry("ControlPort", "9051"),
+                entry("ControlPort", "unix:/run/tor/control GroupWritable"),
+            ]),
+            "unix:/run/tor/control"
+        );
+        assert_eq!(
+            control_addr_from_torrc_entries(&[entry("ControlPort", "unix:\"/var/lib/my tor/control\"")]),
+            "unix:/var/lib/my tor/control"
+        );
+        assert_eq!(
+            control_addr_from_torrc_entries(&[
+                entry("ControlSocket", "/run/tor/control"),
+                entry("ControlPort", "0"),
+            ]),
+            "unix:/run/tor/control"
+        );
+    }
+
+    #[test]
+    fn test_data_dir_from_torrc_entries() {
+        assert_eq!(data_dir_from_torrc_entries(&[]), None);
+        assert_eq!(
+            data_dir_from_torrc_entries(&[entry("DataDirectory", "/home/user/.tor")]),
+            Some(PathBuf::from("/home/user/.tor/eltor"))
+        );
+        assert_eq!(
+            data_dir_from_torrc_entries(&[entry("DataDirectory", "\"/srv/tor two\"")]),
+            Some(PathBuf::from("/srv/tor two/eltor"))
+        );
+        assert_eq!(
+            data_dir_from_torrc_entries(&[
+                entry("DataDirectory", "/var/lib/tor/a"),
+                entry("DataDirectory", "/var/lib/tor/b"),
+            ]),
+            Some(PathBuf::from("/var/lib/tor/b/eltor"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_conf_payment_cadence() {
+        let mock = crate::rpc::mock_control_port::MockControlPort::start().await;
+        let config = mock.rpc_config();
+        assert_eq!(get_conf_payment_cadence(&config).await, PaymentCadence::default());
+
+        mock.set_conf("PaymentInterval", "30");
+        mock.set_conf("PaymentInvervalRounds", "5");
+        assert_eq!(
+            get_conf_payment_cadence(&config).await,
+            PaymentCadence { interval_seconds: 30, rounds: 5 }
+        );
+
+        mock.set_conf("PaymentIntervalRounds", "3");
+        assert_eq!(get_conf_payment_cadence(&config).await.rounds, 3);
+    }
+
+    #[tokio::test]
+    async fn test_advertise_in_contact_info_keeps_the_operators_fields() {
+        let mock = crate::rpc::mock_control_port::MockControlPort::start().await;
+        let config = mock.rpc_config();
+        advertise_in_contact_info(&config, PREPAID_CONTACT_FIELD, "1").await.unwrap();
+        assert_eq!(mock.conf("ContactInfo"), vec!["eltor_prepaid:1"]);
+
+        let mock = crate::rpc::mock_control_port::MockControlPort::start().await;
+        let config = mock.rpc_config();
+        mock.set_conf("ContactInfo", "ops@relay.example eltor_protocol:1");
+        advertise_in_contact_info(&config, PREPAID_CONTACT_FIELD, "1").await.unwrap();
+        advertise_in_contact_info(&config, PREPAID_CONTACT_FIELD, "1").await.unwrap();
+        advertise_in_contact_info(&config, PROTOCOL_VERSION_CONTACT_FIELD, "2").await.unwrap();
+        let contact = mock.conf("ContactInfo");
+        assert_eq!(contact, vec!["ops@relay.example eltor_prepaid:1 eltor_protocol:2"]);
+        assert_eq!(contact_field(&contact[0], PREPAID_CONTACT_FIELD), Some("1"));
+        assert_eq!(contact_field("ops@relay.example eltor_prepaid_x:1", PREPAID_CONTACT_FIELD), None);
+    }
 }
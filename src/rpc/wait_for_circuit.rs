@@ -1,12 +1,14 @@
+use crate::rpc::control_session;
 use crate::types::RpcConfig;
 use log::{debug, info};
+use std::collections::HashMap;
 use std::error::Error;
 use tokio::time::{sleep, Duration};
 
 /// Waits for a Tor circuit to be fully built and ready for use.
 ///
-/// This function polls the circuit status using `GETINFO circuit-status` until
-/// the specified circuit reaches BUILT state. This is critical because:
+/// This function polls the circuit status using `GETINFO circuit-status` over the
+/// shared control session until the specified circuit reaches BUILT state. This is critical because:
 /// - Circuit ID is assigned immediately (LAUNCHED state)
 /// - But SOCKS connections fail until circuit is BUILT
 /// - Can take 2-10 seconds for 3-hop circuit to fully build
@@ -56,6 +58,7 @@ pub async fn wait_for_circuit_ready(
     let start_time = std::time::Instant::now();
     let timeout_duration = Duration::from_secs(timeout_secs);
     let poll_interval = Duration::from_millis(200); // Poll every 200ms for responsive detection
+    let session = control_session(rpc_config);
     
     loop {
         // Check if timeout has been reached
@@ -68,15 +71,7 @@ pub async fn wait_for_circuit_ready(
         }
         
         // Query circuit status using GETINFO circuit-status
-        let circuit_config = RpcConfig {
-            addr: rpc_config.addr.clone(),
-            rpc_password: rpc_config.rpc_password.clone(),
-            command: "GETINFO circuit-status".to_string(),
-        };
-        
-        let response_result = crate::rpc::rpc_client(circuit_config)
-            .await
-            .map_err(|e| e.to_string());
+        let response_result = session.command("GETINFO circuit-status").await;
         
         match response_result {
             Ok(response) => {
@@ -116,6 +111,26 @@ pub async fn wait_for_circuit_ready(
     }
 }
 
+/// Gets the state of every circuit Tor knows about, keyed by circuit ID, with a
+/// single `GETINFO circuit-status`
+pub async fn get_circuit_states(
+    rpc_config: &RpcConfig,
+) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let reply = control_session(rpc_config).request("GETINFO circuit-status").await?;
+    Ok(parse_circuit_states(&reply.value("circuit-status").unwrap_or_default()))
+}
+
+// "123 BUILT $FP1~relay1,..." lines of the circuit-status data block
+fn parse_circuit_states(circuit_status: &str) -> HashMap<String, String> {
+    circuit_status
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        })
+        .collect()
+}
+
 /// Extracts the state of a specific circuit from the circuit-status response.
 ///
 /// # Arguments
@@ -184,6 +199,16 @@ mod tests {
         assert_eq!(extract_circuit_state(response, "123"), None);
     }
 
+    #[test]
+    fn test_parse_circuit_states() {
+        let circuit_status = "123 BUILT $FP1~relay1,$FP2~relay2,$FP3~relay3 PURPOSE=GENERAL\n124 BUILDING $FP1~relay1 PURPOSE=GENERAL\n";
+        let states = parse_circuit_states(circuit_status);
+        assert_eq!(states.len(), 2);
+        assert_eq!(states.get("123").map(String::as_str), Some("BUILT"));
+        assert_eq!(states.get("124").map(String::as_str), Some("BUILDING"));
+        assert!(parse_circuit_states("").is_empty());
+    }
+
     #[test]
     fn test_extract_circuit_state_failed() {
         let response = r#"250-circuit-status=
@@ -17,13 +17,243 @@ pub struct Relay {
     pub payment_interval_seconds: Option<u32>,
     pub payment_interval_rounds: Option<u32>,
     pub payment_handshake_fee: Option<u32>,
+    /// `PaymentPrepaid 1` or `eltor_prepaid:1` in the contact line of the descriptor: the relay accepts
+    /// several rounds paid at once and checks the circuit's prepaid credit instead of one payment per round
+    pub payment_prepaid: bool,
+    /// `PaymentProtocolVersion` in the descriptor, 1 for relays that don't advertise one
+    pub payment_protocol_version: u32,
     pub payment_handshake_fee_payhash: Option<String>,
     pub payment_handshake_fee_preimage: Option<String>,
     pub payment_id_hashes_10: Option<Vec<String>>,
+    /// How the client pays this relay, negotiated against the wallet's capabilities
+    pub payment_method: Option<PaymentMethod>,
     pub relay_tag: Option<RelayTag>,
     pub hop: Option<i64>,
 }
 
+/// The ways a relay can be paid, in the order clients prefer them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentMethod {
+    /// `PaymentBolt12Offer`
+    Bolt12Offer,
+    /// `PaymentBip353`, resolved to a BOLT 12 offer
+    Bip353,
+    /// `PaymentBolt11Lnurl`, an LNURL-pay endpoint serving BOLT 11 invoices
+    Lnurl,
+    /// `PaymentBolt11LightningAddress`, an LNURL-pay endpoint serving BOLT 11 invoices
+    LightningAddress,
+}
+
+impl PaymentMethod {
+    pub const ALL: [PaymentMethod; 4] = [
+        PaymentMethod::Bolt12Offer,
+        PaymentMethod::Bip353,
+        PaymentMethod::Lnurl,
+        PaymentMethod::LightningAddress,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentMethod::Bolt12Offer => "bolt12_offer",
+            PaymentMethod::Bip353 => "bip353",
+            PaymentMethod::Lnurl => "lnurl",
+            PaymentMethod::LightningAddress => "lightning_address",
+        }
+    }
+
+    pub fn parse(method: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|m| m.as_str() == method)
+    }
+
+    /// Whether the relay is paid with a BOLT 12 offer (as opposed to a BOLT 11 invoice)
+    pub fn is_bolt12(&self) -> bool {
+        matches!(self, PaymentMethod::Bolt12Offer | PaymentMethod::Bip353)
+    }
+}
+
+/// Seconds per payment round when a relay doesn't advertise `PaymentInterval`
+pub const DEFAULT_PAYMENT_INTERVAL_SECONDS: u32 = 60;
+/// Rounds per circuit when a relay doesn't advertise `PaymentIntervalRounds`
+pub const DEFAULT_PAYMENT_INTERVAL_ROUNDS: u32 = 10;
+/// EXTENDPAIDCIRCUIT carries at most 10 payment id hashes per hop
+pub const MAX_PAYMENT_INTERVAL_ROUNDS: u32 = 10;
+
+/// How often and how many times a hop is paid over the lifetime of a circuit.
+/// Round `n` (0 based) is paid at `n * interval_seconds` after the circuit is built
+/// and must be settled before `(n + 1) * interval_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PaymentCadence {
+    pub interval_seconds: u32,
+    pub rounds: u32,
+}
+
+impl PaymentCadence {
+    /// Fills in the defaults and clamps the rounds to what the wire format can carry
+    pub fn new(interval_seconds: Option<u32>, rounds: Option<u32>) -> Self {
+        PaymentCadence {
+            interval_seconds: interval_seconds
+                .filter(|s| *s > 0)
+                .unwrap_or(DEFAULT_PAYMENT_INTERVAL_SECONDS),
+            rounds: rounds
+                .filter(|r| *r > 0)
+                .unwrap_or(DEFAULT_PAYMENT_INTERVAL_ROUNDS)
+                .min(MAX_PAYMENT_INTERVAL_ROUNDS),
+        }
+    }
+
+    /// Seconds after circuit build at which `round` (0 based) starts
+    pub fn round_start_secs(&self, round: u32) -> u64 {
+        round as u64 * self.interval_seconds as u64
+    }
+
+    /// Seconds from circuit build until the last paid round ends
+    pub fn lifetime_secs(&self) -> u64 {
+        self.round_start_secs(self.rounds)
+    }
+}
+
+impl Default for PaymentCadence {
+    fn default() -> Self {
+        PaymentCadence::new(None, None)
+    }
+}
+
+/// Seconds a round's payment may still settle after the round ends
+pub const DEFAULT_PAYMENT_GRACE_SECONDS: u64 = 15;
+/// Seconds the client and relay clocks may disagree by
+pub const DEFAULT_PAYMENT_CLOCK_SKEW_SECONDS: u64 = 0;
+
+/// `PaymentProtocolVersion` of relays that take the circuit build timestamp after the
+/// payment ids in EXTENDPAIDCIRCUIT. Relays that don't advertise a version are on 1.
+pub const PAYMENT_PROTOCOL_BUILD_TIMESTAMP: u32 = 2;
+
+/// Wire format chunks are 64 chars, the build timestamp one starts with 48 zeros
+/// that a random payment id hash never does
+const BUILT_AT_WIRE_PREFIX: &str = "000000000000000000000000000000000000000000000000";
+
+/// When each round of a circuit is due, the same for the client paying it and the
+/// relay checking it. Rounds count from `built_at`, the circuit build timestamp the
+/// client sends along with the payment ids in EXTENDPAIDCIRCUIT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundSchedule {
+    pub cadence: PaymentCadence,
+    /// Unix seconds the circuit was built at
+    pub built_at: i64,
+    pub grace_secs: u64,
+    pub clock_skew_secs: u64,
+}
+
+/// How a payment landed relative to its round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundTiming {
+    /// Before the round started, paying ahead is fine
+    Early,
+    OnTime,
+    /// After the round ended and the grace period and clock skew ran out
+    Late,
+}
+
+impl RoundSchedule {
+    pub fn new(cadence: PaymentCadence, built_at: i64) -> Self {
+        RoundSchedule {
+            cadence,
+            built_at,
+            grace_secs: DEFAULT_PAYMENT_GRACE_SECONDS,
+            clock_skew_secs: DEFAULT_PAYMENT_CLOCK_SKEW_SECONDS,
+        }
+    }
+
+    pub fn with_tolerance(self, grace_secs: u64, clock_skew_secs: u64) -> Self {
+        RoundSchedule {
+            grace_secs,
+            clock_skew_secs,
+            ..self
+        }
+    }
+
+    /// The build timestamp a relay anchors the schedule to: the client's, unless it is
+    /// later than the payment ids arrived (give or take the clock skew) or more than an
+    /// interval earlier, then their arrival
+    pub fn anchor(cadence: PaymentCadence, claimed_built_at: Option<i64>, arrived_at: i64, clock_skew_secs: u64) -> i64 {
+        let earliest = arrived_at - cadence.interval_seconds as i64 - clock_skew_secs as i64;
+        let latest = arrived_at + clock_skew_secs as i64;
+        claimed_built_at
+            .filter(|built_at| (earliest..=latest).contains(built_at))
+            .unwrap_or(arrived_at)
+    }
+
+    /// Unix seconds `round` (0 based) starts at
+    pub fn round_start(&self, round: u32) -> i64 {
+        self.built_at + self.cadence.round_start_secs(round) as i64
+    }
+
+    /// Unix seconds `round` (0 based) ends at, when the next one starts
+    pub fn round_end(&self, round: u32) -> i64 {
+        self.round_start(round + 1)
+    }
+
+    /// Last unix second a payment for `round` (0 based) is accepted
+    pub fn round_deadline(&self, round: u32) -> i64 {
+        self.round_end(round) + (self.grace_secs + self.clock_skew_secs) as i64
+    }
+
+    pub fn timing(&self, round: u32, paid_at: i64) -> RoundTiming {
+        if paid_at > self.round_deadline(round) {
+            RoundTiming::Late
+        } else if paid_at < self.round_start(round) - self.clock_skew_secs as i64 {
+            RoundTiming::Early
+        } else {
+            RoundTiming::OnTime
+        }
+    }
+
+    /// The 64 char wire format chunk carrying `built_at`
+    pub fn built_at_to_wire(built_at: i64) -> String {
+        format!("{}{:016x}", BUILT_AT_WIRE_PREFIX, built_at.max(0))
+    }
+
+    /// The build timestamp in a wire format chunk, None for any other chunk
+    pub fn built_at_from_wire(chunk: &str) -> Option<i64> {
+        chunk
+            .strip_prefix(BUILT_AT_WIRE_PREFIX)
+            .filter(|hex| hex.len() == 16)
+            .and_then(|hex| i64::from_str_radix(hex, 16).ok())
+    }
+}
+
+impl Relay {
+    /// The relay's advertised `PaymentInterval` / `PaymentIntervalRounds`
+    pub fn payment_cadence(&self) -> PaymentCadence {
+        PaymentCadence::new(self.payment_interval_seconds, self.payment_interval_rounds)
+    }
+
+    /// Payment methods the relay advertises, in preference order
+    pub fn advertised_payment_methods(&self) -> Vec<PaymentMethod> {
+        PaymentMethod::ALL
+            .iter()
+            .copied()
+            .filter(|method| match method {
+                PaymentMethod::Bolt12Offer => self.payment_bolt12_offer.is_some(),
+                PaymentMethod::Bip353 => self.payment_bip353.is_some(),
+                PaymentMethod::Lnurl => self.payment_bolt11_lnurl.is_some(),
+                PaymentMethod::LightningAddress => self.payment_bolt11_lightning_address.is_some(),
+            })
+            .collect()
+    }
+
+    /// Whether the relay reads the circuit build timestamp, older relays would take it
+    /// for another payment id
+    pub fn takes_build_timestamp(&self) -> bool {
+        self.payment_protocol_version >= PAYMENT_PROTOCOL_BUILD_TIMESTAMP
+    }
+
+    /// Whether the relay charges nothing, so it doesn't need a payment method
+    pub fn is_free(&self) -> bool {
+        self.payment_rate_msats.unwrap_or(0) == 0 && self.payment_handshake_fee.unwrap_or(0) == 0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RpcConfig {
     pub addr: String,
@@ -61,3 +291,37 @@ pub trait EventCallback {
     fn success(&self, response: Option<String>, wallet: &(dyn LightningNode + Send + Sync));
     fn failure(&self, error: Option<String>);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_schedule() {
+        let schedule = RoundSchedule::new(PaymentCadence::new(Some(30), Some(4)), 1000);
+        assert_eq!(schedule.round_start(2), 1060);
+        assert_eq!(schedule.round_end(2), 1090);
+        assert_eq!(schedule.round_deadline(2), 1105);
+        assert_eq!(schedule.timing(2, 1000), RoundTiming::Early);
+        assert_eq!(schedule.timing(2, 1105), RoundTiming::OnTime);
+        assert_eq!(schedule.timing(2, 1106), RoundTiming::Late);
+
+        let skewed = schedule.with_tolerance(0, 5);
+        assert_eq!(skewed.round_deadline(2), 1095);
+        assert_eq!(skewed.timing(2, 1055), RoundTiming::OnTime);
+        assert_eq!(skewed.timing(2, 1054), RoundTiming::Early);
+
+        let cadence = schedule.cadence;
+        assert_eq!(RoundSchedule::anchor(cadence, Some(990), 1000, 5), 990);
+        assert_eq!(RoundSchedule::anchor(cadence, Some(1005), 1000, 5), 1005);
+        // From the future or from long ago
+        assert_eq!(RoundSchedule::anchor(cadence, Some(1006), 1000, 5), 1000);
+        assert_eq!(RoundSchedule::anchor(cadence, Some(964), 1000, 5), 1000);
+        assert_eq!(RoundSchedule::anchor(cadence, None, 1000, 5), 1000);
+
+        let chunk = RoundSchedule::built_at_to_wire(1_700_000_000);
+        assert_eq!(chunk.len(), 64);
+        assert_eq!(RoundSchedule::built_at_from_wire(&chunk), Some(1_700_000_000));
+        assert_eq!(RoundSchedule::built_at_from_wire(&"ab".repeat(32)), None);
+    }
+}
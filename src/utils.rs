@@ -16,6 +16,17 @@ pub fn get_random_payhash_and_preimage() -> (String, String) {
     (hex::encode(payment_hash), hex::encode(preimage))
 }
 
+/// Checks that the hex encoded `preimage` hashes (sha256) to the hex encoded `payment_hash`
+pub fn preimage_matches_payhash(payment_hash: &str, preimage: &str) -> bool {
+    let preimage = match hex::decode(preimage) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&preimage);
+    hex::encode(hasher.finalize()).eq_ignore_ascii_case(payment_hash)
+}
+
 pub fn microdesc_to_fingerprint(base64_id: &str) -> Option<String> {
     // Decode the Base64-encoded identity
     let bytes = decode(base64_id).ok()?;
@@ -32,6 +43,16 @@ pub fn microdesc_to_fingerprint(base64_id: &str) -> Option<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_preimage_matches_payhash() {
+        let (payhash, preimage) = get_random_payhash_and_preimage();
+        assert!(preimage_matches_payhash(&payhash, &preimage));
+        assert!(preimage_matches_payhash(&payhash.to_uppercase(), &preimage));
+        let (other_payhash, _) = get_random_payhash_and_preimage();
+        assert!(!preimage_matches_payhash(&other_payhash, &preimage));
+        assert!(!preimage_matches_payhash(&payhash, "not hex"));
+    }
+
     #[test]
     fn test_microdesc_to_fingerprint() {
         // from /tor/status-vote/current/consensus 